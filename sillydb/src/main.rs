@@ -14,14 +14,12 @@ fn measure_perf(num_iters: i32) {
         let now = Instant::now();
         let mut h2 = LinHash::open("/tmp/measure_perf", 4, 4);
         for k in 0..(10000*i) {
-            h2.put(&linhash::util::i32_to_bytearray(k),
-                   &linhash::util::i32_to_bytearray(k+1));
+            h2.put(&encode(k), &encode(k+1));
         }
 
         let time_get = Instant::now();
         for k in 1000..9000 {
-            assert_eq!(h2.get(&linhash::util::i32_to_bytearray(k)),
-                       Some(linhash::util::i32_to_bytearray(k+1).to_vec()));
+            assert_eq!(h2.get(&encode(k)), Some(encode(k+1)));
             println!("{}", k);
         }
         let time_get_done = Instant::now();
@@ -30,15 +28,15 @@ fn measure_perf(num_iters: i32) {
         let new_now = Instant::now();
         println!("[insert+get]{} million records {:?}", i, new_now.duration_since(now));
         h2.close();
-        fs::remove_file("/tmp/measure_perf");
+        fs::remove_file("/tmp/measure_perf").ok();
     }
 
 }
 
 fn main() {
     let mut h = LinHash::open("/tmp/main_tests", 32, 4);
-    h.put(b"Spin", &i32_to_bytearray(9));
-    h.put(b"Axis", &i32_to_bytearray(6));
+    h.put_i32(b"Spin", 9);
+    h.put_i32(b"Axis", 6);
     h.put(b"foo", &[14]);
     h.put(b"bar", &[15]);
     h.put(b"linear", &[16]);
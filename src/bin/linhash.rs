@@ -0,0 +1,447 @@
+//! Command-line access to a linhash database file.
+//!
+//!     linhash [options] <file> <command> [args]
+//!
+//! Run with no arguments for the list of commands and options. Built
+//! only with the `cli` feature: `cargo build --features cli`.
+
+extern crate env_logger;
+extern crate linhash;
+extern crate log;
+
+use linhash::LinHash;
+use linhash::diff::Difference;
+use linhash::dump;
+use linhash::progress::Throttle;
+use log::LevelFilter;
+use log::kv::{self, Key, Value, VisitSource};
+use std::env;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "\
+usage: linhash [options] <file> <command> [args]
+
+commands:
+  get <key>            print the value stored under <key>
+  put <key> <value>    insert or overwrite <key>
+  del <key>            remove <key>, printing its old value
+  exists <key>         exit with status 0 if <key> is present, 1 if not
+  dump                 write every record to stdout in the dump format
+  restore              read a dump from stdin into <file>, creating it
+                       with the geometry recorded in the dump
+  diff <other>         compare <file> with <other>, printing keys only
+                       in <file> (<), only in <other> (>) and keys with
+                       different values (!)
+  stats                print the table's size and layout
+  verify               check every page for damage, printing each
+                       problem found
+  repair               like verify, then repair the damage, losing
+                       the records on damaged pages
+  compact              rewrite sparse overflow chains densely and give
+                       free pages at the end of the file back
+
+options:
+  --keysize <n>        key width in bytes (default 32; ignored by restore)
+  --valsize <n>        value width in bytes (default 32)
+  --encoding <enc>     how keys and values are written on the command
+                       line and printed: utf8 (default) or hex
+  --progress           report progress of dump, restore, verify and
+                       compact on stderr
+  --max-rate <n>       limit dump, verify and compact to <n> pages per
+                       second
+  --log <level>        print library diagnostics up to <level> on stderr:
+                       error, warn, info, debug or trace
+
+Only `put` and `restore` create <file>; the other commands fail if it
+does not exist, and `get` and `exists` open it read-only.
+`get`, `del` and `exists` exit with status 1 when the key is absent,
+`diff` when the tables differ, and `verify` when it finds damage.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Utf8,
+    Hex,
+}
+
+impl Encoding {
+    fn parse(&self, s: &str) -> Result<Vec<u8>, String> {
+        match *self {
+            Encoding::Utf8 => Ok(s.as_bytes().to_vec()),
+            Encoding::Hex => {
+                if !s.len().is_multiple_of(2) {
+                    return Err(format!("odd-length hex string: {}", s));
+                }
+                (0..s.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&s[i..i + 2], 16)
+                         .map_err(|_| format!("invalid hex string: {}", s)))
+                    .collect()
+            },
+        }
+    }
+
+    /// Formats a stored slot. For utf8 the zero padding is dropped.
+    fn format(&self, b: &[u8]) -> String {
+        match *self {
+            Encoding::Utf8 => {
+                let end = b.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+                String::from_utf8_lossy(&b[..end]).into_owned()
+            },
+            Encoding::Hex => b.iter().map(|c| format!("{:02x}", c)).collect(),
+        }
+    }
+}
+
+struct Options {
+    keysize: usize,
+    valsize: usize,
+    encoding: Encoding,
+    progress: bool,
+    max_rate: f64,
+    log: Option<LevelFilter>,
+    positional: Vec<String>,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut opts = Options {
+        keysize: 32,
+        valsize: 32,
+        encoding: Encoding::Utf8,
+        progress: false,
+        max_rate: f64::INFINITY,
+        log: None,
+        positional: vec![],
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next()
+            .ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--keysize" => opts.keysize = value("--keysize")?.parse()
+                .map_err(|_| "--keysize must be a number".to_string())?,
+            "--valsize" => opts.valsize = value("--valsize")?.parse()
+                .map_err(|_| "--valsize must be a number".to_string())?,
+            "--encoding" => opts.encoding = match value("--encoding")?.as_str() {
+                "utf8" => Encoding::Utf8,
+                "hex" => Encoding::Hex,
+                e => return Err(format!("unknown encoding: {}", e)),
+            },
+            "--progress" => opts.progress = true,
+            "--max-rate" => opts.max_rate = value("--max-rate")?.parse()
+                .ok().filter(|&r: &f64| r > 0.0)
+                .ok_or_else(|| "--max-rate must be a positive number".to_string())?,
+            "--log" => opts.log = Some(match value("--log")?.as_str() {
+                "error" => LevelFilter::Error,
+                "warn" => LevelFilter::Warn,
+                "info" => LevelFilter::Info,
+                "debug" => LevelFilter::Debug,
+                "trace" => LevelFilter::Trace,
+                l => return Err(format!("unknown log level: {}", l)),
+            }),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ => opts.positional.push(arg),
+        }
+    }
+    Ok(opts)
+}
+
+/// The fields of a log event, as ` key=value` each.
+struct Fields(String);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+/// Prints the library's diagnostics up to `level` on stderr, one line
+/// each: `LEVEL target: message key=value ...`.
+fn init_logging(level: LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|out, record| {
+            let mut fields = Fields(String::new());
+            record.key_values().visit(&mut fields).ok();
+            writeln!(out, "{} {}: {}{}", record.level(), record.target(), record.args(), fields.0)
+        })
+        .init();
+}
+
+/// Runs one command, returning the process exit status.
+fn run(opts: Options) -> Result<i32, String> {
+    if let Some(level) = opts.log {
+        init_logging(level);
+    }
+    let enc = opts.encoding;
+    let (file, command, args) = match opts.positional.split_first() {
+        Some((file, rest)) if !rest.is_empty() =>
+            (file.clone(), rest[0].clone(), rest[1..].to_vec()),
+        _ => return Err(USAGE.to_string()),
+    };
+    let arity = match command.as_str() {
+        "dump" | "restore" | "stats" | "verify" | "repair" | "compact" => 0,
+        "get" | "del" | "exists" | "diff" => 1,
+        "put" => 2,
+        _ => return Err(format!("unknown command: {}\n\n{}", command, USAGE)),
+    };
+    if args.len() != arity {
+        return Err(format!("{} takes {} argument(s)", command, arity));
+    }
+    match command.as_str() {
+        "dump" => return dump_table(&file, &opts),
+        "restore" => return restore_table(&file, &opts),
+        "diff" => return diff_tables(&file, &args[0], &opts),
+        "stats" => return print_stats(&file, &opts),
+        "verify" => return verify_table(&file, &opts, false),
+        "repair" => return verify_table(&file, &opts, true),
+        "compact" => return compact_table(&file, &opts),
+        _ => (),
+    }
+
+    let key = enc.parse(&args[0])?;
+    if key.len() > opts.keysize {
+        return Err(format!("key is longer than keysize ({})", opts.keysize));
+    }
+    let mut h = match command.as_str() {
+        "put" => LinHash::try_open(&file, opts.keysize, opts.valsize)
+            .map_err(|e| format!("{}: {}", file, e))?,
+        // lookups only read
+        "get" | "exists" => open_existing(&file, &opts, true)?,
+        _ => open_existing(&file, &opts, false)?,
+    };
+    let status = match command.as_str() {
+        "get" => match h.get(&key) {
+            Some(v) => { println!("{}", enc.format(&v)); 0 },
+            None => 1,
+        },
+        "put" => {
+            let val = enc.parse(&args[1])?;
+            if val.len() > opts.valsize {
+                return Err(format!("value is longer than valsize ({})", opts.valsize));
+            }
+            if !h.update(&key, &val) {
+                h.put(&key, &val);
+            }
+            0
+        },
+        "del" => match h.remove(&key) {
+            Some(v) => { println!("{}", enc.format(&v)); 0 },
+            None => 1,
+        },
+        "exists" => if h.contains(&key) { 0 } else { 1 },
+        _ => unreachable!(),
+    };
+    h.close();
+    Ok(status)
+}
+
+/// Opens the table `file`, failing instead of creating it if there is
+/// none.
+fn open_existing(file: &str, opts: &Options, read_only: bool) -> Result<LinHash, String> {
+    if !Path::new(file).exists() {
+        return Err(format!("{}: no such table", file));
+    }
+    LinHash::options().keysize(opts.keysize).valsize(opts.valsize).read_only(read_only)
+        .open(file).map_err(|e| format!("{}: {}", file, e))
+}
+
+fn dump_table(file: &str, opts: &Options) -> Result<i32, String> {
+    let mut h = open_existing(file, opts, false)?;
+    let stdout = io::stdout();
+    let show = opts.progress;
+    let mut progress = Throttle::new(opts.max_rate, |done, total| if show {
+        eprint!("\rdumped {}/{} pages", done, total);
+    });
+    let result = dump::dump_with_progress(&mut h, &mut stdout.lock(), &mut progress);
+    if show {
+        eprintln!();
+    }
+    h.close();
+    result.map(|_| 0).map_err(|e| e.to_string())
+}
+
+fn restore_table(file: &str, opts: &Options) -> Result<i32, String> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let header = dump::read_header(&mut input).map_err(|e| e.to_string())?;
+    let mut h = LinHash::open(file, header.keysize, header.valsize);
+    let show = opts.progress;
+    let result = dump::restore_with_progress(&mut h, &mut input,
+                                             &mut |done, _| if show {
+        eprint!("\rrestored {} records", done);
+    });
+    if show {
+        eprintln!();
+    }
+    h.close();
+    let count = result.map_err(|e| e.to_string())?;
+    eprintln!("restored {} records", count);
+    Ok(0)
+}
+
+fn diff_tables(file: &str, other: &str, opts: &Options) -> Result<i32, String> {
+    let enc = opts.encoding;
+    let mut a = open_existing(file, opts, false)?;
+    let mut b = open_existing(other, opts, false)?;
+    let count = a.diff(&mut b, |d| match d {
+        Difference::OnlyInA(k) => println!("< {}", enc.format(&k)),
+        Difference::OnlyInB(k) => println!("> {}", enc.format(&k)),
+        Difference::Changed { key, a, b } =>
+            println!("! {}: {} {}", enc.format(&key), enc.format(&a), enc.format(&b)),
+    });
+    a.close();
+    b.close();
+    Ok(if count == 0 { 0 } else { 1 })
+}
+
+fn print_stats(file: &str, opts: &Options) -> Result<i32, String> {
+    let mut h = open_existing(file, opts, false)?;
+    let stats = h.stats().map_err(|e| e.to_string());
+    h.close();
+    let stats = stats?;
+    println!("items           {}", stats.items);
+    println!("buckets         {}", stats.buckets);
+    println!("overflow pages  {}", stats.overflow_pages);
+    println!("free pages      {}", stats.free_pages);
+    println!("chain length    {:.2} average, {} max", stats.avg_chain_len, stats.max_chain_len);
+    println!("bytes on disk   {}", stats.bytes_on_disk);
+    println!("load factor     {:.3}", stats.load_factor);
+    Ok(0)
+}
+
+fn verify_table(file: &str, opts: &Options, repair: bool) -> Result<i32, String> {
+    let mut h = open_existing(file, opts, false)?;
+    let show = opts.progress;
+    let mut progress = Throttle::new(opts.max_rate, |done, total| if show {
+        eprint!("\rchecked {}/{} pages", done, total);
+    });
+    let report = if repair { h.repair() } else { h.verify_with_progress(&mut progress) };
+    if show && !repair {
+        eprintln!();
+    }
+    h.close();
+    let report = report.map_err(|e| e.to_string())?;
+    for problem in &report.problems {
+        println!("{:?}", problem);
+    }
+    println!("checked {} pages, {} records: {} problem(s){}", report.pages,
+             report.records, report.problems.len(),
+             if report.repaired { ", repaired" } else { "" });
+    Ok(if report.is_ok() || report.repaired { 0 } else { 1 })
+}
+
+fn compact_table(file: &str, opts: &Options) -> Result<i32, String> {
+    let mut h = open_existing(file, opts, false)?;
+    let before = h.stats().map_err(|e| e.to_string())?;
+    let show = opts.progress;
+    let mut progress = Throttle::new(opts.max_rate, |done, total| if show {
+        eprint!("\rcompacted {}/{} pages", done, total);
+    });
+    let freed = h.compact_all_with_progress(&mut progress).map_err(|e| e.to_string());
+    if show {
+        eprintln!();
+    }
+    let after = h.stats().map_err(|e| e.to_string());
+    h.close();
+    let (freed, after) = (freed?, after?);
+    println!("freed {} pages; {} bytes on disk, was {}", freed,
+             after.bytes_on_disk, before.bytes_on_disk);
+    Ok(0)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let status = match parse_args(args).and_then(run) {
+        Ok(status) => status,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            2
+        },
+    };
+    process::exit(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use super::{parse_args, run};
+
+    /// Runs the command line `args`, as split on spaces.
+    fn cli(args: &str) -> Result<i32, String> {
+        parse_args(args.split(' ').map(String::from).collect()).and_then(run)
+    }
+
+    #[test]
+    fn key_commands() {
+        let path = "/tmp/test_cli_keys";
+        fs::remove_file(path).ok();
+        // lookups neither create the file nor succeed
+        for command in &["get k", "exists k", "del k", "stats", "verify", "dump"] {
+            assert!(cli(&format!("{} {}", path, command)).is_err(), "{}", command);
+            assert!(!Path::new(path).exists(), "{} created the file", command);
+        }
+
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} put k v1", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} put k v2", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} get k", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} exists k", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} get j", path)), Ok(1));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} exists j", path)), Ok(1));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 --encoding hex {} put 6a 7632", path)),
+                   Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} exists j", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} del k", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} del k", path)), Ok(1));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} exists k", path)), Ok(1));
+
+        // records that do not fit, and another geometry
+        assert!(cli(&format!("--keysize 4 --valsize 4 {} put toolong v", path)).is_err());
+        assert!(cli(&format!("--keysize 4 --valsize 4 {} put k toolong", path)).is_err());
+        assert!(cli(&format!("--keysize 8 --valsize 4 {} get k", path)).is_err());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn table_commands() {
+        let path = "/tmp/test_cli_table";
+        let other = "/tmp/test_cli_table_other";
+        fs::remove_file(path).ok();
+        fs::remove_file(other).ok();
+        for k in 0..100 {
+            assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} put {} v", path, k)), Ok(0));
+        }
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} stats", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} verify", path)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 --max-rate 1000 {} compact", path)),
+                   Ok(0));
+        assert!(cli(&format!("--keysize 4 --valsize 4 {} diff {}", path, other)).is_err());
+        assert!(!Path::new(other).exists());
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} put 0 v", other)), Ok(0));
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} diff {}", path, other)), Ok(1));
+        for k in 1..100 {
+            assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} put {} v", other, k)), Ok(0));
+        }
+        assert_eq!(cli(&format!("--keysize 4 --valsize 4 {} diff {}", path, other)), Ok(0));
+        fs::remove_file(path).ok();
+        fs::remove_file(other).ok();
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(cli("").is_err());
+        assert!(cli("/tmp/test_cli_args").is_err());
+        assert!(cli("/tmp/test_cli_args frobnicate").is_err());
+        assert!(cli("/tmp/test_cli_args get").is_err());
+        assert!(cli("/tmp/test_cli_args put k").is_err());
+        assert!(cli("--frobnicate /tmp/test_cli_args get k").is_err());
+        assert!(cli("--keysize four /tmp/test_cli_args get k").is_err());
+        assert!(cli("--max-rate 0 /tmp/test_cli_args dump").is_err());
+        assert!(cli("--encoding base64 /tmp/test_cli_args get k").is_err());
+        assert!(cli("--encoding hex /tmp/test_cli_args put 6 v").is_err());
+        assert!(!Path::new("/tmp/test_cli_args").exists());
+    }
+}
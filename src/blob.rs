@@ -0,0 +1,438 @@
+//! Values of any length, kept outside the bucket pages.
+//!
+//! A `BlobLinHash` is a table whose records hold, in place of their
+//! value, a reference to it: the first of a chain of value pages,
+//! linked like a bucket's overflow pages, and the value's length. Each
+//! value page holds `VALUE_PAGE_BYTES` bytes of one value. Values are
+//! written and read a page at a time through `BlobWriter` and
+//! `BlobReader`, so neither end needs the whole value in memory:
+//!
+//! ```no_run
+//! use std::io::prelude::*;
+//! use linhash::BlobLinHash;
+//!
+//! let mut h = BlobLinHash::open("/tmp/blobs", 8);
+//! let mut w = h.writer(b"img00001").unwrap();
+//! w.write_all(&[0; 100_000]).unwrap();
+//! w.finish().unwrap();
+//! let mut image = vec![];
+//! h.reader(b"img00001").unwrap().unwrap().read_to_end(&mut image).unwrap();
+//! ```
+//!
+//! Value pages come from the table's free list, and go back to it when
+//! their record is removed or given another value. The table records
+//! that it holds references, so `verify` and `repair` account for value
+//! pages, and opening it as a plain `LinHash` fails. A value's pages
+//! are written out before the reference to them is logged. A crash
+//! after a value is replaced or removed but before the next checkpoint
+//! can leave the old value's pages off the free list, where `verify`
+//! reports them as leaked and `repair` reclaims them.
+
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use disk::{self, DbFile};
+use entry::Entry;
+use error;
+use options::OpenOptions;
+use page::{HEADER_SIZE, PAGE_SIZE};
+use util::*;
+use wal::Durability;
+use LinHash;
+
+/// Bytes of a value each value page holds.
+pub const VALUE_PAGE_BYTES: usize = PAGE_SIZE - HEADER_SIZE;
+/// Width of a record's reference to its value: the first value page
+/// (0 for an empty value) and the length.
+const REF_SIZE: usize = 16;
+
+/// Linear Hashtable of byte keys and values of any length. See the
+/// module documentation.
+pub struct BlobLinHash {
+    table: LinHash,
+}
+
+impl BlobLinHash {
+    /// Opens (or creates) the table stored in `filename`. Panics on
+    /// error; see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize) -> BlobLinHash {
+        BlobLinHash::try_open(filename, keysize).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize) -> error::Result<BlobLinHash> {
+        BlobLinHash::try_open_with(filename, LinHash::options().keysize(keysize))
+    }
+
+    /// Opens `filename` as `options` say; their `valsize` is ignored.
+    pub fn try_open_with<P: AsRef<Path>>(filename: P, mut options: OpenOptions)
+                         -> error::Result<BlobLinHash> {
+        options.valsize = Some(REF_SIZE);
+        options.large_values = true;
+        Ok(BlobLinHash { table: options.open(filename)? })
+    }
+
+    /// Stores `val` under `key`, replacing any value it had. Panics on
+    /// error; see `try_put`.
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.try_put(key, val).unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    pub fn try_put(&mut self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        let mut writer = self.writer(key)?;
+        writer.write_all(val)?;
+        writer.finish()
+    }
+
+    /// A writer of a new value for `key`, which takes its place on
+    /// `finish`. Dropping the writer instead discards what it wrote.
+    pub fn writer(&mut self, key: &[u8]) -> error::Result<BlobWriter<'_>> {
+        self.table.check_writable()?;
+        self.table.check_record(key, &[])?;
+        Ok(BlobWriter { table: &mut self.table, key: key.to_vec(), first: None, last: 0,
+                        filled: VALUE_PAGE_BYTES, len: 0 })
+    }
+
+    /// The value of `key`, read in full.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.try_get(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get(&mut self, key: &[u8]) -> error::Result<Option<Vec<u8>>> {
+        let mut reader = match self.reader(key)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let mut val = Vec::with_capacity(reader.len() as usize);
+        reader.read_to_end(&mut val)?;
+        Ok(Some(val))
+    }
+
+    /// A reader of the value of `key`, if it has one.
+    pub fn reader(&mut self, key: &[u8]) -> error::Result<Option<BlobReader<'_>>> {
+        Ok(self.table.try_get(key)?.map(move |reference| {
+            let (first, len) = decode_ref(&reference);
+            BlobReader { table: &mut self.table, page: first, offset: 0, remaining: len }
+        }))
+    }
+
+    /// Length of the value of `key`, without reading it.
+    pub fn value_len(&mut self, key: &[u8]) -> error::Result<Option<u64>> {
+        Ok(self.table.try_get(key)?.map(|reference| decode_ref(&reference).1))
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.table.contains(key)
+    }
+
+    /// Deletes `key` and frees the pages of its value. Returns whether
+    /// it had one.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.try_remove(key).unwrap_or_else(|e| panic!("remove failed: {}", e))
+    }
+
+    pub fn try_remove(&mut self, key: &[u8]) -> error::Result<bool> {
+        match self.table.try_remove(key)? {
+            Some(reference) => {
+                free_value(&mut self.table.buckets, &reference)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every key, as stored.
+    pub fn keys(&mut self) -> disk::Records<'_, Vec<u8>> {
+        self.table.keys()
+    }
+
+    /// Checks the table, value pages included; see `LinHash::verify`.
+    pub fn verify(&mut self) -> error::Result<::verify::Report> {
+        self.table.verify()
+    }
+
+    /// See `LinHash::repair`. Records whose value is damaged are lost.
+    pub fn repair(&mut self) -> error::Result<::verify::Report> {
+        self.table.repair()
+    }
+
+    pub fn flush(&mut self) {
+        self.table.flush();
+    }
+
+    pub fn close(&mut self) {
+        self.table.close();
+    }
+}
+
+/// Writes a value a page at a time; see `BlobLinHash::writer`.
+pub struct BlobWriter<'a> {
+    table: &'a mut LinHash,
+    key: Vec<u8>,
+    // the chain written so far
+    first: Option<usize>,
+    last: usize,
+    // bytes used in the last page
+    filled: usize,
+    len: u64,
+}
+
+impl<'a> BlobWriter<'a> {
+    /// Bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Makes what was written the value of the key, freeing the pages
+    /// of the value it replaces. The new pages are written out first,
+    /// and synced if the table syncs its log.
+    pub fn finish(mut self) -> error::Result<()> {
+        self.table.checkpoint()?;
+        if let Durability::Always | Durability::EveryN(_) = self.table.durability() {
+            self.table.buckets.sync()?;
+        }
+        let mut reference = encode(self.first.unwrap_or(0) as u64);
+        reference.extend_from_slice(&encode(self.len));
+        let old = match self.table.try_entry(&self.key)? {
+            Entry::Occupied(mut e) => Some(e.try_insert(&reference)?),
+            Entry::Vacant(e) => {
+                e.try_insert(&reference)?;
+                None
+            },
+        };
+        // the pages now belong to the record
+        self.first = None;
+        if let Some(old) = old {
+            free_value(&mut self.table.buckets, &old)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Write for BlobWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.filled == VALUE_PAGE_BYTES {
+            let page_id = self.table.buckets.allocate_page()?;
+            match self.first {
+                Some(_) => self.table.buckets.page_mut(self.last).next = Some(page_id),
+                None => self.first = Some(page_id),
+            }
+            self.last = page_id;
+            self.filled = 0;
+        }
+        let n = buf.len().min(VALUE_PAGE_BYTES - self.filled);
+        self.table.buckets.page_mut(self.last).payload_mut()[self.filled..self.filled + n]
+            .copy_from_slice(&buf[..n]);
+        self.filled += n;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Frees the pages of a value that was never finished.
+impl<'a> Drop for BlobWriter<'a> {
+    fn drop(&mut self) {
+        if let Some(first) = self.first {
+            free_chain(&mut self.table.buckets, first, pages_for(self.len)).ok();
+        }
+    }
+}
+
+/// Reads a value a page at a time; see `BlobLinHash::reader`.
+pub struct BlobReader<'a> {
+    table: &'a mut LinHash,
+    page: usize,
+    // bytes of `page` already read
+    offset: usize,
+    remaining: u64,
+}
+
+impl<'a> BlobReader<'a> {
+    /// Bytes left to read.
+    pub fn len(&self) -> u64 {
+        self.remaining
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offset == VALUE_PAGE_BYTES {
+            let next = self.table.buckets.try_page(self.page)?.next;
+            self.page = next.filter(|&p| p != 0).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, "value pages end before the value"))?;
+            self.offset = 0;
+        }
+        let n = buf.len().min(VALUE_PAGE_BYTES - self.offset)
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let payload = self.table.buckets.try_page(self.page)?.payload();
+        buf[..n].copy_from_slice(&payload[self.offset..self.offset + n]);
+        self.offset += n;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// (first value page, length) of a stored reference.
+fn decode_ref(reference: &[u8]) -> (usize, u64) {
+    (decode::<u64>(&reference[..8]) as usize, decode(&reference[8..REF_SIZE]))
+}
+
+/// Value pages a value of `len` bytes takes.
+fn pages_for(len: u64) -> usize {
+    len.div_ceil(VALUE_PAGE_BYTES as u64) as usize
+}
+
+fn free_value(file: &mut DbFile, reference: &[u8]) -> io::Result<()> {
+    let (first, len) = decode_ref(reference);
+    free_chain(file, first, pages_for(len))
+}
+
+/// Frees up to `npages` pages of the chain from `first`.
+fn free_chain(file: &mut DbFile, first: usize, npages: usize) -> io::Result<()> {
+    let mut next = Some(first);
+    for _ in 0..npages {
+        let page_id = match next.filter(|&p| p != 0 && p < file.num_pages()) {
+            Some(page_id) => page_id,
+            None => break,
+        };
+        next = file.try_page(page_id)?.next;
+        file.free_page(page_id);
+    }
+    Ok(())
+}
+
+/// The pages of the value `reference` refers to, or None if the chain
+/// is broken: cut short, leading outside the file or into a page seen
+/// before, or unreadable. For `verify`.
+pub(crate) fn value_pages(file: &mut DbFile, reference: &[u8])
+                          -> io::Result<Option<Vec<usize>>> {
+    let (first, len) = decode_ref(reference);
+    let mut pages = Vec::with_capacity(pages_for(len));
+    let mut next = Some(first);
+    for _ in 0..pages_for(len) {
+        let page_id = match next {
+            Some(p) if p != 0 && p < file.num_pages() && !pages.contains(&p) => p,
+            _ => return Ok(None),
+        };
+        pages.push(page_id);
+        next = match file.page_header(page_id) {
+            Ok((_, _, link)) => link,
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(Some(pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::prelude::*;
+    use blob::{decode_ref, BlobLinHash, VALUE_PAGE_BYTES};
+    use verify::Problem;
+    use LinHash;
+
+    fn value(k: usize) -> Vec<u8> {
+        (0..k * 997).map(|i| (i * k) as u8).collect()
+    }
+
+    #[test]
+    fn large_values() {
+        let path = "/tmp/test_blobs";
+        fs::remove_file(path).ok();
+        let mut h = BlobLinHash::open(path, 4);
+        for k in 0..40 {
+            h.put(&[k as u8], &value(k));
+        }
+        for k in 0..40 {
+            assert_eq!(h.get(&[k as u8]), Some(value(k)));
+            assert_eq!(h.value_len(&[k as u8]).unwrap(), Some(value(k).len() as u64));
+        }
+        let pages = h.table.pages_in_use();
+        // replacing and removing frees the old pages
+        for k in 0..40 {
+            h.put(&[k as u8], &value(k));
+        }
+        assert_eq!(h.table.pages_in_use(), pages);
+        for k in 20..40 {
+            assert!(h.remove(&[k as u8]));
+        }
+        assert!(!h.remove(&[20]));
+        assert!(h.table.pages_in_use() < pages);
+        assert!(h.verify().unwrap().is_ok());
+
+        // streamed in, and out in small reads
+        let mut w = h.writer(b"big").unwrap();
+        for i in 0..100 {
+            w.write_all(&[i as u8; 1000]).unwrap();
+        }
+        assert_eq!(w.len(), 100_000);
+        w.finish().unwrap();
+        let mut r = h.reader(b"big").unwrap().unwrap();
+        let mut chunk = [0; 1000];
+        for i in 0..100 {
+            r.read_exact(&mut chunk).unwrap();
+            assert!(chunk.iter().all(|&b| b == i as u8));
+        }
+        assert_eq!(r.read(&mut chunk).unwrap(), 0);
+
+        // an unfinished writer leaves nothing behind
+        let pages = h.table.pages_in_use();
+        let mut w = h.writer(b"big").unwrap();
+        w.write_all(&[1; 3 * VALUE_PAGE_BYTES]).unwrap();
+        drop(w);
+        assert_eq!(h.table.pages_in_use(), pages);
+        assert_eq!(h.value_len(b"big").unwrap(), Some(100_000));
+        h.put(b"none", b"");
+        assert_eq!(h.get(b"none"), Some(vec![]));
+        h.close();
+
+        let mut h = BlobLinHash::open(path, 4);
+        assert_eq!(h.len(), 22);
+        assert!(h.contains(b"none"));
+        assert_eq!(h.get(&[7]), Some(value(7)));
+        assert!(h.verify().unwrap().is_ok());
+
+        // a broken chain costs the record its value
+        let (first, _) = decode_ref(&h.table.get(&[7]).unwrap());
+        h.table.buckets.page_mut(first).next = Some(1 << 40);
+        let report = h.verify().unwrap();
+        // its pages are then in no chain
+        assert_eq!(report.problems, [Problem::BrokenValue { bucket: h.table.bucket(&[7]),
+                                                            key: vec![7, 0, 0, 0] },
+                                     Problem::LeakedPages(2)]);
+        h.repair().unwrap();
+        assert!(h.verify().unwrap().is_ok());
+        assert_eq!(h.get(&[7]), None);
+        h.close();
+        assert!(LinHash::try_open(path, 4, 16).is_err());
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,134 @@
+//! Bloom filter over all keys of a table.
+//!
+//! Built once from a table and then held in memory by a front-end so
+//! that most lookups of absent keys never reach the table. The filter
+//! is a snapshot: keys added to the table afterwards are not in it.
+//!
+//! Serialized form (integers little-endian):
+//!
+//! | magic | keysize | nhashes | nbits | bit words (u64) |
+
+use std::io;
+
+use phf::phf_hash;
+use util::*;
+use LinHash;
+
+const MAGIC: &[u8; 8] = b"LHBLOOM1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    keysize: usize,
+    nhashes: usize,
+    nbits: usize,
+    words: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Builds a filter over every key of `table` using about
+    /// `bits_per_key` bits per key; 10 bits gives roughly a 1% false
+    /// positive rate.
+    pub fn from_table(table: &mut LinHash, bits_per_key: usize) -> BloomFilter {
+        let bits_per_key = bits_per_key.max(1);
+        let nbits = (table.nitems * bits_per_key).max(64);
+        let nhashes = ((bits_per_key as f64 * ::std::f64::consts::LN_2).round() as usize)
+            .clamp(1, 30);
+        let mut filter = BloomFilter {
+            keysize: table.buckets.keysize(),
+            nhashes,
+            nbits,
+            words: vec![0; nbits.div_ceil(64)],
+        };
+        for bucket in 0..table.nbuckets {
+            for (_, records) in table.buckets.all_records_in_bucket(bucket) {
+                for (k, _) in records {
+                    filter.insert(&k);
+                }
+            }
+        }
+        filter
+    }
+
+    /// Bit positions for `key`, by double hashing.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let h1 = phf_hash(key, self.keysize, 0);
+        let h2 = phf_hash(key, self.keysize, 1) | 1;
+        let nbits = self.nbits as u64;
+        (0..self.nhashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for bit in self.positions(key).collect::<Vec<_>>() {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// `false` means `key` is definitely not in the table; `true` means
+    /// it probably is.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&usize_to_bytearray(self.keysize));
+        buf.extend_from_slice(&usize_to_bytearray(self.nhashes));
+        buf.extend_from_slice(&usize_to_bytearray(self.nbits));
+        for &w in &self.words {
+            buf.extend_from_slice(&encode(w));
+        }
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<BloomFilter> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let header = MAGIC.len() + 3 * USIZE_WIDTH;
+        if data.len() < header || &data[..8] != MAGIC {
+            return Err(invalid("not a linhash Bloom filter"));
+        }
+        let field = |i| read_usize_at(data, MAGIC.len() + i * USIZE_WIDTH)
+            .map_err(|e| invalid(&e.to_string()));
+        let (keysize, nhashes, nbits) = (field(0)?, field(1)?, field(2)?);
+        let words = &data[header..];
+        if nbits == 0 || nhashes == 0 || words.len() != nbits.div_ceil(64) * 8 {
+            return Err(invalid("Bloom filter is truncated or corrupt"));
+        }
+        Ok(BloomFilter {
+            keysize,
+            nhashes,
+            nbits,
+            words: words.chunks(8).map(decode::<u64>).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn filter_export() {
+        fs::remove_file("/tmp/test_bloom_export").ok();
+        let mut h = LinHash::open("/tmp/test_bloom_export", 4, 4);
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let filter = h.build_filter(10);
+        h.close();
+
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        for k in 0..5000 {
+            assert!(filter.may_contain(&encode(k)));
+        }
+        let false_positives = (5000..15000)
+            .filter(|&k| filter.may_contain(&encode(k)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!(BloomFilter::from_bytes(b"LHBLOOM1").is_err());
+
+        fs::remove_file("/tmp/test_bloom_export").ok();
+    }
+}
@@ -0,0 +1,419 @@
+//! A compact binary encoding for Rust values, used by `CodedLinHash`
+//! to store keys and values of any width.
+//!
+//! `Codec` needs no feature or dependency, and is what tables written
+//! by `CodedLinHash` before the `serde` feature are encoded with, so
+//! they stay readable; new code with serde types can use
+//! `SerdeLinHash` instead. It is implemented here for the numbers,
+//! `bool`, `char`, strings, `Option`, `Result`, `Box`, tuples of up to
+//! six, byte arrays, `Duration` and the std collections.
+//!
+//! Numbers are little-endian at their natural width (`usize` and
+//! `isize` at 64 bits, so files move between platforms), `bool` is a
+//! byte, `char` a u32, and strings, sequences and maps are a u32
+//! length followed by their items. Structs list their fields in
+//! order; `impl_codec!` writes the impl:
+//!
+//! ```
+//! #[macro_use] extern crate linhash;
+//! use linhash::codec::{self, Codec};
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct User { id: u64, name: String, tags: Vec<String> }
+//! impl_codec!(User { id, name, tags });
+//!
+//! # fn main() {
+//! let u = User { id: 7, name: "ada".to_string(), tags: vec![] };
+//! assert_eq!(codec::from_bytes::<User>(&codec::to_bytes(&u)), Some(u));
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// A type that can be written to and read back from bytes.
+pub trait Codec: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Reads a value from the front of `input` and advances it past
+    /// what was read. None if `input` is too short or malformed.
+    fn decode(input: &mut &[u8]) -> Option<Self>;
+}
+
+/// The encoding of `t`.
+pub fn to_bytes<T: Codec>(t: &T) -> Vec<u8> {
+    let mut out = vec![];
+    t.encode(&mut out);
+    out
+}
+
+/// Decodes a `T` from the front of `b`. Trailing bytes (eg. the zero
+/// padding of a key slot) are ignored.
+pub fn from_bytes<T: Codec>(mut b: &[u8]) -> Option<T> {
+    T::decode(&mut b)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if input.len() < n {
+        return None;
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Some(head)
+}
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    (len as u32).encode(out);
+}
+
+fn decode_len(input: &mut &[u8]) -> Option<usize> {
+    u32::decode(input).map(|n| n as usize)
+}
+
+macro_rules! impl_codec_number {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(input: &mut &[u8]) -> Option<$t> {
+                    let mut a = [0; ::std::mem::size_of::<$t>()];
+                    a.copy_from_slice(take(input, ::std::mem::size_of::<$t>())?);
+                    Some(<$t>::from_le_bytes(a))
+                }
+            }
+        )*
+    }
+}
+
+impl_codec_number!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Codec for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<usize> {
+        usize::try_from(u64::decode(input)?).ok()
+    }
+}
+
+impl Codec for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<isize> {
+        isize::try_from(i64::decode(input)?).ok()
+    }
+}
+
+impl Codec for char {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<char> {
+        char::from_u32(u32::decode(input)?)
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<bool> {
+        match u8::decode(input)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn decode(_: &mut &[u8]) -> Option<()> {
+        Some(())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<String> {
+        let len = decode_len(input)?;
+        String::from_utf8(take(input, len)?.to_vec()).ok()
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        for t in self {
+            t.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Vec<T>> {
+        let len = decode_len(input)?;
+        // every item takes a byte at least, except zero-sized ones
+        let mut v = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            v.push(T::decode(input)?);
+        }
+        Some(v)
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.is_some().encode(out);
+        if let Some(ref t) = *self {
+            t.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Option<T>> {
+        if bool::decode(input)? {
+            T::decode(input).map(Some)
+        } else {
+            Some(None)
+        }
+    }
+}
+
+impl<T: Codec, E: Codec> Codec for Result<T, E> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.is_ok().encode(out);
+        match *self {
+            Ok(ref t) => t.encode(out),
+            Err(ref e) => e.encode(out),
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Result<T, E>> {
+        if bool::decode(input)? {
+            T::decode(input).map(Ok)
+        } else {
+            E::decode(input).map(Err)
+        }
+    }
+}
+
+impl<T: Codec> Codec for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Box<T>> {
+        T::decode(input).map(Box::new)
+    }
+}
+
+impl Codec for Duration {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_secs().encode(out);
+        self.subsec_nanos().encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<Duration> {
+        let secs = u64::decode(input)?;
+        let nanos = u32::decode(input)?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(Duration::new(secs, nanos))
+    }
+}
+
+/// Implements `Codec` for a collection of `T`, encoded like `Vec<T>`
+/// in iteration order.
+macro_rules! impl_codec_seq {
+    ($t:ident $(, $bound:path)*) => {
+        impl<T: Codec $(+ $bound)*> Codec for $t<T> {
+            fn encode(&self, out: &mut Vec<u8>) {
+                encode_len(self.len(), out);
+                for t in self {
+                    t.encode(out);
+                }
+            }
+
+            fn decode(input: &mut &[u8]) -> Option<$t<T>> {
+                let len = decode_len(input)?;
+                let mut c = $t::new();
+                for _ in 0..len {
+                    c.extend(Some(T::decode(input)?));
+                }
+                Some(c)
+            }
+        }
+    }
+}
+
+impl_codec_seq!(VecDeque);
+impl_codec_seq!(BTreeSet, Ord);
+impl_codec_seq!(HashSet, Eq, Hash);
+
+/// Implements `Codec` for a map, encoded like `Vec<(K, V)>` in
+/// iteration order.
+macro_rules! impl_codec_map {
+    ($t:ident $(, $bound:path)*) => {
+        impl<K: Codec $(+ $bound)*, V: Codec> Codec for $t<K, V> {
+            fn encode(&self, out: &mut Vec<u8>) {
+                encode_len(self.len(), out);
+                for (k, v) in self {
+                    k.encode(out);
+                    v.encode(out);
+                }
+            }
+
+            fn decode(input: &mut &[u8]) -> Option<$t<K, V>> {
+                let len = decode_len(input)?;
+                let mut m = $t::new();
+                for _ in 0..len {
+                    let k = K::decode(input)?;
+                    m.insert(k, V::decode(input)?);
+                }
+                Some(m)
+            }
+        }
+    }
+}
+
+impl_codec_map!(BTreeMap, Ord);
+impl_codec_map!(HashMap, Eq, Hash);
+
+impl<const N: usize> Codec for [u8; N] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode(input: &mut &[u8]) -> Option<[u8; N]> {
+        let mut a = [0; N];
+        a.copy_from_slice(take(input, N)?);
+        Some(a)
+    }
+}
+
+macro_rules! impl_codec_tuple {
+    ($($t:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($t: Codec),*> Codec for ($($t,)*) {
+            fn encode(&self, out: &mut Vec<u8>) {
+                let ($(ref $t,)*) = *self;
+                $($t.encode(out);)*
+            }
+
+            fn decode(input: &mut &[u8]) -> Option<($($t,)*)> {
+                Some(($($t::decode(input)?,)*))
+            }
+        }
+    }
+}
+
+impl_codec_tuple!(A, B);
+impl_codec_tuple!(A, B, C);
+impl_codec_tuple!(A, B, C, D);
+impl_codec_tuple!(A, B, C, D, E);
+impl_codec_tuple!(A, B, C, D, E, F);
+
+/// Implements `Codec` for a struct with named fields, encoding the
+/// fields listed, in order. Every field must be listed.
+#[macro_export]
+macro_rules! impl_codec {
+    ($t:ident { $($field:ident),* $(,)* }) => {
+        impl $crate::codec::Codec for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $($crate::codec::Codec::encode(&self.$field, out);)*
+            }
+
+            fn decode(input: &mut &[u8]) -> Option<$t> {
+                Some($t { $($field: $crate::codec::Codec::decode(input)?),* })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::{from_bytes, to_bytes, Codec};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Point {
+        x: i32,
+        label: Option<String>,
+        seen: bool,
+    }
+
+    impl_codec!(Point { x, label, seen });
+
+    fn round_trip<T: Codec + PartialEq + ::std::fmt::Debug>(t: T) {
+        let b = to_bytes(&t);
+        assert_eq!(from_bytes::<T>(&b).as_ref(), Some(&t));
+        if !b.is_empty() {
+            assert_eq!(from_bytes::<T>(&b[..b.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(0xdead_beefu32);
+        round_trip(-3i64);
+        round_trip(1.5f64);
+        round_trip(true);
+        round_trip("héllo".to_string());
+        round_trip(vec![1u16, 2, 3]);
+        round_trip(Some(vec!["a".to_string(), String::new()]));
+        round_trip((7u8, "x".to_string(), None::<u64>));
+        round_trip(*b"key\0");
+        round_trip(vec![Point { x: -1, label: Some("p".to_string()), seen: true },
+                        Point { x: 2, label: None, seen: false }]);
+        assert_eq!(to_bytes(&"ab".to_string()), [2, 0, 0, 0, b'a', b'b']);
+    }
+
+    #[test]
+    fn round_trips_std_types() {
+        use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+        use std::time::Duration;
+        round_trip(u128::MAX - 1);
+        round_trip(-5i128);
+        round_trip(usize::MAX);
+        round_trip(-7isize);
+        round_trip('ß');
+        round_trip(Ok::<u8, String>(3));
+        round_trip(Err::<u8, String>("no".to_string()));
+        round_trip(Box::new(9u32));
+        round_trip(Duration::new(5, 123));
+        round_trip((1u8, 2u16, 3u32, 4u64, 5i8, "six".to_string()));
+        round_trip((1..5).collect::<VecDeque<u32>>());
+        round_trip(["b", "a"].iter().map(|s| s.to_string()).collect::<BTreeSet<_>>());
+        round_trip((0..10u64).collect::<HashSet<_>>());
+        round_trip((0..10u32).map(|i| (i, vec![i; i as usize])).collect::<BTreeMap<_, _>>());
+        round_trip((0..10i16).map(|i| (i.to_string(), Some(i))).collect::<HashMap<_, _>>());
+        // usize is 64 bits whatever the platform
+        assert_eq!(to_bytes(&1usize), to_bytes(&1u64));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(from_bytes::<bool>(&[2]), None);
+        assert_eq!(from_bytes::<String>(&[2, 0, 0, 0, 0xff, 0xfe]), None);
+        assert_eq!(from_bytes::<Vec<u64>>(&[0xff, 0xff, 0xff, 0xff]), None);
+        // zero padding after a value is ignored
+        assert_eq!(from_bytes::<u16>(&[1, 0, 0, 0]), Some(1));
+        assert_eq!(from_bytes::<char>(&to_bytes(&0xd800u32)), None);
+        assert_eq!(from_bytes::<::std::time::Duration>(&to_bytes(&(0u64, 1_000_000_000u32))),
+                   None);
+    }
+}
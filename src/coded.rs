@@ -0,0 +1,527 @@
+//! A table of Rust values of any width: keys and values are encoded
+//! into the table's fixed-size slots, with `Codec` by default, or with
+//! bincode for types implementing serde's `Serialize` and
+//! `DeserializeOwned` (`SerdeLinHash`, with the `serde` feature).
+//!
+//! A value slot holds a u32 length, then the encoded value:
+//!
+//! ```text
+//! | len (u32) | value bytes ... | zero padding |
+//! ```
+//!
+//! A value too long for its slot has the top bit of `len` set and its
+//! bytes stored in chunks in a companion table, `<filename>.vals`,
+//! keyed by the record's key and the chunk's number. That table is
+//! only created once a value overflows. The two tables are written one
+//! after the other, so a crash in between can leave an overflowed
+//! value unreadable; reading it is then a `Corruption` error. It can
+//! also leave chunks no record points at. `open` removes those when
+//! either table was not closed cleanly; `remove_orphaned_chunks` does
+//! the same on demand.
+//!
+//! ```
+//! # #[cfg(feature = "serde")] {
+//! extern crate serde_derive;
+//! use linhash::SerdeLinHash;
+//!
+//! #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, PartialEq)]
+//! struct User { id: u64, name: String, tags: Vec<String> }
+//!
+//! # let path = "/tmp/doc_serde_users";
+//! # std::fs::remove_file(path).ok();
+//! let mut users: SerdeLinHash<u64, User> = SerdeLinHash::open(path, 8, 64);
+//! let u = User { id: 7, name: "ada".to_string(), tags: vec![] };
+//! users.insert(&7, &u).unwrap();
+//! assert_eq!(users.get(&7).unwrap(), Some(u));
+//! # }
+//! ```
+//!
+//! A table is read back only with the encoding it was written with:
+//! the two lay values out differently.
+
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use codec::{self, Codec};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+use disk::sidecar_path;
+use error::{self, LinHashError};
+use util::FixedWidth;
+use LinHash;
+
+/// Bytes of a value stored per record of the `.vals` table.
+pub const CHUNK_SIZE: usize = 1024;
+
+const LEN_SIZE: usize = 4;
+const OVERFLOW: u32 = 1 << 31;
+
+/// How a `CodedLinHash` turns its keys and values into bytes.
+pub trait Encoding<T> {
+    fn to_bytes(t: &T) -> error::Result<Vec<u8>>;
+
+    /// Decodes a `T` from the front of `b`, ignoring trailing bytes
+    /// (eg. the zero padding of a key slot). None if `b` is malformed.
+    fn from_bytes(b: &[u8]) -> Option<T>;
+}
+
+/// Encoding with `Codec`, see `codec`.
+pub struct Native;
+
+impl<T: Codec> Encoding<T> for Native {
+    fn to_bytes(t: &T) -> error::Result<Vec<u8>> {
+        Ok(codec::to_bytes(t))
+    }
+
+    fn from_bytes(b: &[u8]) -> Option<T> {
+        codec::from_bytes(b)
+    }
+}
+
+/// Encoding with bincode, for serde's `Serialize` and
+/// `DeserializeOwned`.
+#[cfg(feature = "serde")]
+pub struct Bincode;
+
+#[cfg(feature = "serde")]
+impl<T: Serialize + DeserializeOwned> Encoding<T> for Bincode {
+    fn to_bytes(t: &T) -> error::Result<Vec<u8>> {
+        bincode::serialize(t).map_err(|e| LinHashError::InvalidArgument(format!(
+            "cannot encode with bincode: {}", e)))
+    }
+
+    fn from_bytes(b: &[u8]) -> Option<T> {
+        // bincode's defaults allow trailing bytes
+        bincode::deserialize(b).ok()
+    }
+}
+
+/// Linear Hashtable with keys of type `K` and values of type `V`,
+/// encoded with `E`. Keys must encode to at most `keysize` bytes;
+/// values may be of any length.
+pub struct CodedLinHash<K, V, E = Native> {
+    table: LinHash,
+    values: Overflow,
+    types: PhantomData<(K, V, E)>,
+}
+
+/// A `CodedLinHash` of serde types, encoded with bincode.
+#[cfg(feature = "serde")]
+pub type SerdeLinHash<K, V> = CodedLinHash<K, V, Bincode>;
+
+impl<K, V, E: Encoding<K> + Encoding<V>> CodedLinHash<K, V, E> {
+    /// Opens (or creates) the table stored in `filename`, with room for
+    /// `valsize - 4` bytes of value in each record. Panics on error;
+    /// see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                -> CodedLinHash<K, V, E> {
+        CodedLinHash::try_open(filename, keysize, valsize).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                    -> error::Result<CodedLinHash<K, V, E>> {
+        if valsize <= LEN_SIZE {
+            return Err(LinHashError::InvalidArgument(format!(
+                "valsize must be more than {} to hold a value's length", LEN_SIZE)));
+        }
+        let mut coded = CodedLinHash {
+            table: LinHash::try_open(&filename, keysize, valsize)?,
+            values: Overflow::new(filename.as_ref(), keysize),
+            types: PhantomData,
+        };
+        if coded.values.path.exists() {
+            let recovered = coded.values.table()?.recovered();
+            if recovered || coded.table.recovered() {
+                let n = coded.remove_orphaned_chunks()?;
+                if n > 0 {
+                    info!(chunks = n; "{}: removed orphaned chunks",
+                          coded.values.path.display());
+                }
+            }
+        }
+        Ok(coded)
+    }
+
+    /// Stores `val` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: &K, val: &V) -> error::Result<Option<V>> {
+        let k = self.encode_key(key)?;
+        let old = self.table.try_get(&k)?;
+        let old_val = match old {
+            Some(ref slot) => Some(decode_value::<V, E>(self.values.load(&k, slot)?)?),
+            None => None,
+        };
+        let bytes = E::to_bytes(val)?;
+        let slot = if LEN_SIZE + bytes.len() <= self.table.buckets.valsize() {
+            let mut slot = Vec::with_capacity(LEN_SIZE + bytes.len());
+            (bytes.len() as u32).encode(&mut slot);
+            slot.extend_from_slice(&bytes);
+            slot
+        } else {
+            self.values.store(&k, &bytes)?
+        };
+        if let Some(ref old) = old {
+            self.values.remove(&k, old, chunks_in(&slot))?;
+        }
+        if !self.table.try_update(&k, &slot)? {
+            self.table.try_put(&k, &slot)?;
+        }
+        Ok(old_val)
+    }
+
+    pub fn get(&mut self, key: &K) -> error::Result<Option<V>> {
+        let k = self.encode_key(key)?;
+        match self.table.try_get(&k)? {
+            Some(slot) => decode_value::<V, E>(self.values.load(&k, &slot)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&mut self, key: &K) -> error::Result<bool> {
+        let k = self.encode_key(key)?;
+        Ok(self.table.try_get(&k)?.is_some())
+    }
+
+    /// Deletes `key`, returning its value.
+    pub fn remove(&mut self, key: &K) -> error::Result<Option<V>> {
+        let k = self.encode_key(key)?;
+        let slot = match self.table.try_get(&k)? {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        let val = decode_value::<V, E>(self.values.load(&k, &slot)?)?;
+        self.table.try_remove(&k)?;
+        self.values.remove(&k, &slot, 0)?;
+        Ok(Some(val))
+    }
+
+    /// Deletes the chunks in the `.vals` table that no record points
+    /// at, as a crash between writing the two tables can leave behind.
+    /// Returns how many were deleted.
+    pub fn remove_orphaned_chunks(&mut self) -> error::Result<usize> {
+        if self.values.table.is_none() && !self.values.path.exists() {
+            return Ok(0);
+        }
+        let keysize = self.table.buckets.keysize();
+        let chunk_keys: Vec<Vec<u8>> = self.values.table()?.keys().collect();
+        let mut orphans = Vec::new();
+        for k in chunk_keys {
+            let chunk: u32 = codec::from_bytes(&k[keysize..])
+                .ok_or_else(|| LinHashError::Corruption("chunk key too short".to_string()))?;
+            let used = match self.table.try_get(&k[..keysize])? {
+                Some(slot) => (chunk as usize) < chunks_in(&slot),
+                None => false,
+            };
+            if !used {
+                orphans.push(k);
+            }
+        }
+        let table = self.values.table()?;
+        for k in &orphans {
+            table.try_remove(k)?;
+        }
+        Ok(orphans.len())
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over every (key, value) pair. See `LinHash::iter`.
+    pub fn iter(&mut self) -> impl Iterator<Item = error::Result<(K, V)>> + '_ {
+        let values = &mut self.values;
+        self.table.iter().map(move |(k, slot)| {
+            let key = E::from_bytes(&k).ok_or_else(|| undecodable("key"))?;
+            Ok((key, decode_value::<V, E>(values.load(&k, &slot)?)?))
+        })
+    }
+
+    /// The byte-level table, eg. for `dump` or `stats`. Its values are
+    /// slots as described in the module docs.
+    pub fn raw(&mut self) -> &mut LinHash {
+        &mut self.table
+    }
+
+    pub fn flush(&mut self) -> error::Result<()> {
+        if let Some(ref mut values) = self.values.table {
+            values.flush();
+        }
+        self.table.flush();
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> error::Result<()> {
+        if let Some(ref mut values) = self.values.table {
+            values.try_close()?;
+        }
+        self.table.try_close()
+    }
+
+    fn encode_key(&self, key: &K) -> error::Result<Vec<u8>> {
+        let mut k = E::to_bytes(key)?;
+        let keysize = self.table.buckets.keysize();
+        if k.len() > keysize {
+            return Err(LinHashError::InvalidArgument(format!(
+                "key encodes to {} bytes, more than keysize {}", k.len(), keysize)));
+        }
+        k.resize(keysize, 0);
+        Ok(k)
+    }
+}
+
+/// The `.vals` table of overflowed values, opened when first needed.
+struct Overflow {
+    path: PathBuf,
+    keysize: usize,
+    table: Option<LinHash>,
+}
+
+impl Overflow {
+    fn new(filename: &Path, keysize: usize) -> Overflow {
+        Overflow { path: sidecar_path(filename, ".vals"), keysize, table: None }
+    }
+
+    fn table(&mut self) -> error::Result<&mut LinHash> {
+        if self.table.is_none() {
+            let table = LinHash::try_open(&self.path, self.keysize + u32::WIDTH, CHUNK_SIZE)?;
+            self.table = Some(table);
+        }
+        Ok(self.table.as_mut().unwrap())
+    }
+
+    fn chunk_key(key: &[u8], chunk: usize) -> Vec<u8> {
+        let mut k = key.to_vec();
+        (chunk as u32).encode(&mut k);
+        k
+    }
+
+    /// Writes `bytes` as chunks under `key`; returns the slot pointing
+    /// at them.
+    fn store(&mut self, key: &[u8], bytes: &[u8]) -> error::Result<Vec<u8>> {
+        let table = self.table()?;
+        for (i, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            let k = Overflow::chunk_key(key, i);
+            if !table.try_update(&k, chunk)? {
+                table.try_put(&k, chunk)?;
+            }
+        }
+        Ok(codec::to_bytes(&(bytes.len() as u32 | OVERFLOW)))
+    }
+
+    /// The encoded value in `slot`, read from its chunks if it
+    /// overflowed.
+    fn load(&mut self, key: &[u8], slot: &[u8]) -> error::Result<Vec<u8>> {
+        let (len, overflowed) = slot_len(slot)?;
+        Ok(if overflowed {
+            let table = self.table()?;
+            let mut bytes = Vec::with_capacity(len);
+            for i in 0..len.div_ceil(CHUNK_SIZE) {
+                let chunk = table.try_get(&Overflow::chunk_key(key, i))?
+                    .ok_or_else(|| LinHashError::Corruption(format!(
+                        "chunk {} of an overflowed value is missing", i)))?;
+                let n = (len - bytes.len()).min(CHUNK_SIZE);
+                bytes.extend_from_slice(&chunk[..n]);
+            }
+            bytes
+        } else {
+            slot.get(LEN_SIZE..LEN_SIZE + len)
+                .ok_or_else(|| LinHashError::Corruption(
+                    "value length runs past its slot".to_string()))?
+                .to_vec()
+        })
+    }
+
+    /// Deletes the chunks of the value in `slot`, from chunk `from` on.
+    fn remove(&mut self, key: &[u8], slot: &[u8], from: usize) -> error::Result<()> {
+        let (len, overflowed) = slot_len(slot)?;
+        if !overflowed {
+            return Ok(());
+        }
+        let table = self.table()?;
+        for i in from..len.div_ceil(CHUNK_SIZE) {
+            table.try_remove(&Overflow::chunk_key(key, i))?;
+        }
+        Ok(())
+    }
+}
+
+/// The value length in `slot`, and whether the value overflowed.
+fn slot_len(slot: &[u8]) -> error::Result<(usize, bool)> {
+    let n: u32 = codec::from_bytes(slot)
+        .ok_or_else(|| LinHashError::Corruption("value slot too short".to_string()))?;
+    Ok(((n & !OVERFLOW) as usize, n & OVERFLOW != 0))
+}
+
+/// Number of chunks the value in `slot` has in the `.vals` table.
+fn chunks_in(slot: &[u8]) -> usize {
+    match slot_len(slot) {
+        Ok((len, true)) => len.div_ceil(CHUNK_SIZE),
+        _ => 0,
+    }
+}
+
+fn decode_value<V, E: Encoding<V>>(bytes: Vec<u8>) -> error::Result<V> {
+    E::from_bytes(&bytes).ok_or_else(|| undecodable("value"))
+}
+
+fn undecodable(what: &str) -> LinHashError {
+    LinHashError::Corruption(format!("stored {} does not decode", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use codec;
+    use coded::CodedLinHash;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Doc {
+        title: String,
+        body: Vec<u8>,
+        rev: u32,
+    }
+
+    impl_codec!(Doc { title, body, rev });
+
+    fn doc(title: &str, len: usize) -> Doc {
+        Doc { title: title.to_string(), body: (0..len).map(|i| i as u8).collect(), rev: 1 }
+    }
+
+    #[test]
+    fn coded_ops() {
+        let path = "/tmp/test_coded_ops";
+        let vals = "/tmp/test_coded_ops.vals";
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+        let mut h: CodedLinHash<String, Doc> = CodedLinHash::open(path, 16, 64);
+        for i in 0..200 {
+            assert_eq!(h.insert(&format!("doc{}", i), &doc("small", i % 20)).unwrap(), None);
+        }
+        assert!(fs::metadata(vals).is_err());
+        // too long for a 64-byte slot: overflows into 5 chunks
+        let big = doc("big", 4500);
+        assert_eq!(h.insert(&"doc7".to_string(), &big).unwrap(), Some(doc("small", 7)));
+        assert!(fs::metadata(vals).is_ok());
+        assert!(h.insert(&"a key that is far too long".to_string(), &big).is_err());
+        h.close().unwrap();
+
+        let mut h: CodedLinHash<String, Doc> = CodedLinHash::open(path, 16, 64);
+        assert_eq!(h.len(), 200);
+        assert_eq!(h.get(&"doc7".to_string()).unwrap(), Some(big.clone()));
+        assert_eq!(h.get(&"doc8".to_string()).unwrap(), Some(doc("small", 8)));
+        assert!(h.contains_key(&"doc199".to_string()).unwrap());
+        assert_eq!(h.get(&"doc200".to_string()).unwrap(), None);
+
+        // shrinking an overflowed value drops its extra chunks
+        let smaller = doc("smaller", 1500);
+        assert_eq!(h.insert(&"doc7".to_string(), &smaller).unwrap(), Some(big));
+        assert_eq!(h.values.table().unwrap().len(), 2);
+        assert_eq!(h.get(&"doc7".to_string()).unwrap(), Some(smaller.clone()));
+        assert_eq!(h.remove(&"doc7".to_string()).unwrap(), Some(smaller));
+        assert_eq!(h.values.table().unwrap().len(), 0);
+        assert_eq!(h.remove(&"doc7".to_string()).unwrap(), None);
+
+        let mut all: Vec<(String, Doc)> = h.iter().map(Result::unwrap).collect();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(all.len(), 199);
+        assert_eq!(all[0], ("doc0".to_string(), doc("small", 0)));
+        h.close().unwrap();
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+    }
+
+    #[test]
+    fn orphaned_chunks_are_removed() {
+        let path = "/tmp/test_coded_orphans";
+        let vals = "/tmp/test_coded_orphans.vals";
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+        let mut h: CodedLinHash<String, Doc> = CodedLinHash::open(path, 16, 64);
+        let big = doc("big", 1500);
+        h.insert(&"kept".to_string(), &big).unwrap();
+        let stray = |name: &str, chunk: u32| {
+            let mut k = codec::to_bytes(&name.to_string());
+            k.resize(16, 0);
+            k.extend_from_slice(&codec::to_bytes(&chunk));
+            k
+        };
+        // as if a crash came between writing chunks and their record
+        {
+            let values = h.values.table().unwrap();
+            values.try_put(&stray("gone", 0), &[1; 8]).unwrap();
+            values.try_put(&stray("kept", 2), &[2; 8]).unwrap();
+            values.flush();
+            assert_eq!(values.len(), 4);
+        }
+        h.table.flush();
+        h.values.table().unwrap().abandon();
+        h.table.abandon();
+
+        let mut h: CodedLinHash<String, Doc> = CodedLinHash::open(path, 16, 64);
+        assert_eq!(h.values.table().unwrap().len(), 2);
+        assert_eq!(h.get(&"kept".to_string()).unwrap(), Some(big));
+        h.values.table().unwrap().try_put(&stray("gone", 1), &[3; 8]).unwrap();
+        assert_eq!(h.remove_orphaned_chunks().unwrap(), 1);
+        assert_eq!(h.remove_orphaned_chunks().unwrap(), 0);
+        h.close().unwrap();
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_ops() {
+        use coded::SerdeLinHash;
+
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        enum Kind { Note, Page { words: u32 } }
+
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        struct Entry { title: String, kind: Kind, body: Vec<u8> }
+
+        let entry = |title: &str, len: usize| Entry {
+            title: title.to_string(),
+            kind: if len.is_multiple_of(2) { Kind::Note } else { Kind::Page { words: len as u32 } },
+            body: (0..len).map(|i| i as u8).collect(),
+        };
+        let path = "/tmp/test_coded_serde";
+        let vals = "/tmp/test_coded_serde.vals";
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+        let mut h: SerdeLinHash<(u32, String), Entry> = SerdeLinHash::open(path, 24, 64);
+        for i in 0..100 {
+            assert_eq!(h.insert(&(i, "e".to_string()), &entry("small", i as usize % 10)).unwrap(),
+                       None);
+        }
+        // overflows into the `.vals` table
+        let big = entry("big", 3000);
+        assert_eq!(h.insert(&(5, "e".to_string()), &big).unwrap(), Some(entry("small", 5)));
+        assert!(h.insert(&(5, "a key that is far too long".to_string()), &big).is_err());
+        h.close().unwrap();
+
+        let mut h: SerdeLinHash<(u32, String), Entry> = SerdeLinHash::open(path, 24, 64);
+        assert_eq!(h.len(), 100);
+        assert_eq!(h.get(&(5, "e".to_string())).unwrap(), Some(big.clone()));
+        assert_eq!(h.get(&(6, "e".to_string())).unwrap(), Some(entry("small", 6)));
+        assert_eq!(h.get(&(6, "f".to_string())).unwrap(), None);
+        assert_eq!(h.remove(&(5, "e".to_string())).unwrap(), Some(big));
+        assert_eq!(h.values.table().unwrap().len(), 0);
+        assert_eq!(h.iter().map(Result::unwrap).count(), 99);
+        h.close().unwrap();
+        fs::remove_file(path).ok();
+        fs::remove_file(vals).ok();
+    }
+
+    #[test]
+    fn valsize_holds_length() {
+        let path = "/tmp/test_coded_valsize";
+        fs::remove_file(path).ok();
+        assert!(CodedLinHash::<u32, u32>::try_open(path, 4, 4).is_err());
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,176 @@
+//! A table that can be shared between threads.
+//!
+//! `SyncLinHash` splits its records over a fixed number of shards, each
+//! an independent `LinHash` in its own file behind its own lock, and
+//! routes every key to a shard by a hash of its own. Operations on
+//! keys of different shards run in parallel; those on the same shard
+//! take turns, reads included, since a lookup moves pages through the
+//! shard's buffer pool. More shards mean fewer collisions between
+//! threads, and more files.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use disk::sidecar_path;
+use error;
+use phf::phf_hash;
+use {LinHash, LinHashError};
+
+/// Seed of the hash that picks a key's shard. Fixed, so that a key
+/// routes to the same shard every time the table is opened.
+const SHARD_SEED: u64 = 0x5348_4152_4400;
+
+/// Linear Hashtable usable through `&self`, eg. from an `Arc`.
+pub struct SyncLinHash {
+    shards: Vec<Mutex<LinHash>>,
+    keysize: usize,
+}
+
+fn shard_path<P: AsRef<Path>>(filename: P, shard: usize) -> PathBuf {
+    sidecar_path(filename.as_ref(), &format!(".shard{}", shard))
+}
+
+impl SyncLinHash {
+    /// Opens (or creates) the table stored in `filename.shard0` up to
+    /// `filename.shard<shards - 1>`. Panics on error; see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize, shards: usize)
+                                -> SyncLinHash {
+        SyncLinHash::try_open(filename, keysize, valsize, shards)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `open`, but returns an error instead of panicking, also
+    /// when the table exists with another number of shards.
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize, shards: usize)
+                                    -> error::Result<SyncLinHash> {
+        let filename = filename.as_ref();
+        if shards == 0 {
+            return Err(LinHashError::InvalidArgument("shards must not be 0".to_string()));
+        }
+        let exists = |i| shard_path(filename, i).exists();
+        if exists(shards) || (exists(0) && !exists(shards - 1)) {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} was created with a different number of shards than {}",
+                filename.display(), shards)));
+        }
+        let shards = (0..shards)
+            .map(|i| LinHash::try_open(shard_path(filename, i), keysize, valsize).map(Mutex::new))
+            .collect::<error::Result<Vec<_>>>()?;
+        Ok(SyncLinHash { shards, keysize })
+    }
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, LinHash> {
+        let i = phf_hash(key, self.keysize, SHARD_SEED) % self.shards.len() as u64;
+        self.shards[i as usize].lock().expect("a thread panicked holding a shard")
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.shard(key).get(key)
+    }
+
+    pub fn try_get(&self, key: &[u8]) -> error::Result<Option<Vec<u8>>> {
+        self.shard(key).try_get(key)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.shard(key).contains(key)
+    }
+
+    /// Inserts `key`, which must not be present; see `LinHash::put`.
+    pub fn put(&self, key: &[u8], val: &[u8]) {
+        self.shard(key).put(key, val)
+    }
+
+    pub fn try_put(&self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        self.shard(key).try_put(key, val)
+    }
+
+    /// Stores `val` under `key`, whether or not it is present, as one
+    /// step: no other thread sees the key in between.
+    pub fn upsert(&self, key: &[u8], val: &[u8]) {
+        let mut shard = self.shard(key);
+        if !shard.update(key, val) {
+            shard.put(key, val);
+        }
+    }
+
+    pub fn update(&self, key: &[u8], val: &[u8]) -> bool {
+        self.shard(key).update(key, val)
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.shard(key).remove(key)
+    }
+
+    /// Number of records, summed over the shards one at a time.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().expect("a thread panicked holding a shard").len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn flush(&self) {
+        for shard in &self.shards {
+            shard.lock().expect("a thread panicked holding a shard").flush();
+        }
+    }
+
+    pub fn close(&self) {
+        for shard in &self.shards {
+            shard.lock().expect("a thread panicked holding a shard").close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use concurrent::{shard_path, SyncLinHash};
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use util::*;
+
+    #[test]
+    fn shared_between_threads() {
+        let path = "/tmp/test_sync_linhash";
+        for i in 0..5 {
+            fs::remove_file(shard_path(path, i)).ok();
+        }
+        let h = Arc::new(SyncLinHash::open(path, 4, 4, 4));
+        let writers: Vec<_> = (0..4).map(|t| {
+            let h = h.clone();
+            thread::spawn(move || for k in t * 1000..(t + 1) * 1000 {
+                h.put(&encode(k), &encode(k));
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        let readers: Vec<_> = (0..4).map(|_| {
+            let h = h.clone();
+            thread::spawn(move || for k in 0..4000 {
+                assert_eq!(h.get(&encode(k)), Some(encode(k)));
+            })
+        }).collect();
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(h.len(), 4000);
+        h.close();
+
+        assert!(SyncLinHash::try_open(path, 4, 4, 3).is_err());
+        assert!(SyncLinHash::try_open(path, 4, 4, 5).is_err());
+        let h = SyncLinHash::open(path, 4, 4, 4);
+        h.upsert(&encode(1), &encode(10));
+        h.upsert(&encode(-1), &encode(-1));
+        assert_eq!(h.get(&encode(1)), Some(encode(10)));
+        assert_eq!(h.len(), 4001);
+        h.close();
+        for i in 0..5 {
+            fs::remove_file(shard_path(path, i)).ok();
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! Encryption at rest: an `EncryptedStore` keeps every page sealed
+//! with XChaCha20-Poly1305 under a key supplied when the table is
+//! opened, and the split journal and write-ahead log are sealed with
+//! the same key (see `PageStore::sidecar_cipher`). Needs the
+//! `encryption` feature, which brings in the `chacha20poly1305` and
+//! `getrandom` crates; without it `Cipher` has no values, and no
+//! store seals anything.
+//!
+//! ```no_run
+//! # #[cfg(feature = "encryption")] {
+//! use linhash::LinHash;
+//! use linhash::crypt::EncryptedStore;
+//!
+//! let key = [7; 32];
+//! let store = EncryptedStore::open("/tmp/secret", &key).unwrap();
+//! let table = LinHash::options().keysize(8).valsize(32).store(store)
+//!     .open("/tmp/secret").unwrap();
+//! # }
+//! ```
+//!
+//! The key must be 32 bytes of high entropy, eg. drawn from a random
+//! source or a key management service; it is not a password. Deriving
+//! the page key from it (below) only separates tables, and does
+//! nothing to slow down guessing, so derive the key from a password
+//! with a password hash such as Argon2 first.
+//!
+//! The file starts with a plaintext header, as the ctrl page itself is
+//! encrypted:
+//!
+//! | magic | scheme (u32) | 0 (u32) | salt (16) | key check (40) | zeroes |
+//!
+//! The pages are sealed with a key derived from the one given and the
+//! salt (HChaCha20 as the KDF), so tables opened with the same key do
+//! not share one. Under the only scheme so far, each page is stored as
+//!
+//! | nonce (24) | page, encrypted | tag (16) |
+//!
+//! with a fresh nonce: 16 random bytes drawn from the OS at open, then
+//! a counter. Opening fails if the OS has no random source, rather
+//! than risk repeating nonces. The page id is authenticated along with
+//! the page, so pages cannot be swapped. Only a slot past the end of
+//! the file reads as a page never written: writing a page beyond the
+//! end seals empty pages into the slots before it, so every slot in
+//! the file has to authenticate.
+
+#[cfg(feature = "encryption")]
+pub use self::sealed::*;
+
+/// Sealing with the `encryption` feature off: there is no cipher to
+/// seal with, so no value of this type exists.
+#[cfg(not(feature = "encryption"))]
+pub enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub fn seal(&self, _aad: &[u8], _data: &[u8]) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub fn open(&self, _aad: &[u8], _sealed: &[u8]) -> Option<Vec<u8>> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod sealed {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use chacha20::cipher::consts::U10;
+    use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+    use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+    use page::PAGE_SIZE;
+    use store::{advise_willneed, PageStore};
+    use util::decode;
+
+    /// Bytes a sealed message has over its plaintext: nonce and tag.
+    pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+    /// Size of the plaintext header ahead of the first page.
+    pub const HEADER_SIZE: usize = 128;
+
+    const NONCE_SIZE: usize = 24;
+    const TAG_SIZE: usize = 16;
+    const SALT_SIZE: usize = 16;
+    const MAGIC: &[u8; 8] = b"LHCRYPT1";
+    /// XChaCha20-Poly1305, random nonce prefix and counter.
+    const SCHEME_XCHACHA20_POLY1305: u32 = 1;
+    const KEY_CHECK: &[u8] = b"linhash key check";
+
+    /// HChaCha20: the page key derived from `key` and a table's `salt`.
+    fn derive_key(key: &[u8; 32], salt: &[u8; SALT_SIZE]) -> [u8; 32] {
+        chacha20::hchacha::<U10>(key.into(), salt.into()).into()
+    }
+
+    /// Fills `buf` from the OS's random source. Fails if there is none:
+    /// nothing else is random enough for nonces.
+    fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+        getrandom::getrandom(buf).map_err(|e| io::Error::new(
+            io::ErrorKind::Unsupported, format!("no random source to draw nonces from: {}", e)))
+    }
+
+    /// XChaCha20-Poly1305 under one key. Each sealed message gets a nonce
+    /// of 16 bytes random to this `Cipher` and an 8-byte counter.
+    pub struct Cipher {
+        aead: XChaCha20Poly1305,
+        prefix: [u8; 16],
+        counter: AtomicU64,
+    }
+
+    impl Cipher {
+        /// A cipher under `key` with a nonce prefix drawn from the OS.
+        pub fn new(key: [u8; 32]) -> io::Result<Cipher> {
+            let mut prefix = [0; 16];
+            random_bytes(&mut prefix)?;
+            Ok(Cipher::with_prefix(key, prefix))
+        }
+
+        fn with_prefix(key: [u8; 32], prefix: [u8; 16]) -> Cipher {
+            Cipher { aead: XChaCha20Poly1305::new(&key.into()), prefix, counter: AtomicU64::new(0) }
+        }
+
+        /// `data` encrypted and authenticated along with `aad`, as
+        /// | nonce | ciphertext | tag |.
+        pub fn seal(&self, aad: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut out = Vec::with_capacity(data.len() + OVERHEAD);
+            out.extend_from_slice(&self.prefix);
+            out.extend_from_slice(&self.counter.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+            out.extend_from_slice(data);
+            let (nonce, data) = out.split_at_mut(NONCE_SIZE);
+            let tag = self.aead.encrypt_in_place_detached(XNonce::from_slice(nonce), aad, data)
+                .expect("page too long to seal");
+            out.extend_from_slice(&tag);
+            out
+        }
+
+        /// The plaintext of `sealed`, or None if it is too short or fails
+        /// authentication, eg. because it was torn or tampered with.
+        pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+            if sealed.len() < OVERHEAD {
+                return None;
+            }
+            let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+            let (data, tag) = rest.split_at(rest.len() - TAG_SIZE);
+            let mut data = data.to_vec();
+            self.aead.decrypt_in_place_detached(XNonce::from_slice(nonce), aad, &mut data,
+                                                Tag::from_slice(tag))
+                .ok()?;
+            Some(data)
+        }
+    }
+
+    /// Pages stored sealed in a file, page `n` in the slot at byte
+    /// `HEADER_SIZE + n * (PAGE_SIZE + OVERHEAD)`. See the module docs.
+    pub struct EncryptedStore {
+        file: File,
+        page_key: [u8; 32],
+        cipher: Cipher,
+        // nonce prefixes of sidecar ciphers: random, then told apart by
+        // `sidecars` in the last 8 bytes
+        sidecar_prefix: [u8; 16],
+        sidecars: AtomicU64,
+    }
+
+    impl EncryptedStore {
+        /// Opens `path` with `key`, a 32-byte key of high entropy, not a
+        /// password (see the module docs), creating the file (and its
+        /// header) if need be. Fails with `InvalidInput` if the file was
+        /// created with another key, `InvalidData` if it is not an
+        /// encrypted table, and `Unsupported` if the OS has no random
+        /// source.
+        pub fn open<P: AsRef<Path>>(path: P, key: &[u8; 32]) -> io::Result<EncryptedStore> {
+            let path = path.as_ref();
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            let mut header = [0; HEADER_SIZE];
+            let salt = if file.metadata()?.len() == 0 {
+                let mut salt = [0; SALT_SIZE];
+                random_bytes(&mut salt)?;
+                let cipher = Cipher::new(derive_key(key, &salt))?;
+                header[..8].copy_from_slice(MAGIC);
+                header[8..12].copy_from_slice(&SCHEME_XCHACHA20_POLY1305.to_le_bytes());
+                header[16..32].copy_from_slice(&salt);
+                header[32..32 + OVERHEAD].copy_from_slice(&cipher.seal(KEY_CHECK, &[]));
+                file.write_all(&header)?;
+                file.sync_all()?;
+                salt
+            } else {
+                file.read_exact(&mut header).map_err(|_| not_encrypted(path))?;
+                if &header[..8] != MAGIC {
+                    return Err(not_encrypted(path));
+                }
+                let scheme: u32 = decode(&header[8..12]);
+                if scheme != SCHEME_XCHACHA20_POLY1305 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "{}: unknown encryption scheme {}", path.display(), scheme)));
+                }
+                let mut salt = [0; SALT_SIZE];
+                salt.copy_from_slice(&header[16..32]);
+                let cipher = Cipher::new(derive_key(key, &salt))?;
+                if cipher.open(KEY_CHECK, &header[32..32 + OVERHEAD]).is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "{}: wrong key", path.display())));
+                }
+                salt
+            };
+            let page_key = derive_key(key, &salt);
+            let mut sidecar_prefix = [0; 16];
+            random_bytes(&mut sidecar_prefix)?;
+            Ok(EncryptedStore { file, page_key, cipher: Cipher::new(page_key)?, sidecar_prefix,
+                                sidecars: AtomicU64::new(0) })
+        }
+
+        fn offset(page_id: usize) -> u64 {
+            (HEADER_SIZE + page_id * (PAGE_SIZE + OVERHEAD)) as u64
+        }
+
+        /// Seals `data` into the slot of `page_id`.
+        fn write_slot(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+            let slot = self.cipher.seal(&(page_id as u64).to_le_bytes(), data);
+            self.file.seek(SeekFrom::Start(EncryptedStore::offset(page_id)))?;
+            self.file.write_all(&slot)
+        }
+
+        /// Seals empty pages into the slots from the end of the file up to
+        /// `page_id`, so the file has no unsealed gap.
+        fn fill_to(&mut self, page_id: usize) -> io::Result<()> {
+            let empty = [0; PAGE_SIZE];
+            for gap in self.len()? as usize / PAGE_SIZE..page_id {
+                self.write_slot(gap, &empty)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn not_encrypted(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("{} is not an encrypted table", path.display()))
+    }
+
+    impl PageStore for EncryptedStore {
+        fn prefetch(&mut self, page_id: usize) {
+            advise_willneed(&self.file, EncryptedStore::offset(page_id), PAGE_SIZE + OVERHEAD);
+        }
+
+        fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+            let mut slot = vec![0; PAGE_SIZE + OVERHEAD];
+            self.file.seek(SeekFrom::Start(EncryptedStore::offset(page_id)))?;
+            let mut filled = 0;
+            while filled < slot.len() {
+                match self.file.read(&mut slot[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            if filled == 0 {
+                // past the end of the file
+                data.iter_mut().for_each(|b| *b = 0);
+                return Ok(());
+            }
+            let page = self.cipher.open(&(page_id as u64).to_le_bytes(), &slot)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "page {} fails authentication", page_id)))?;
+            data.copy_from_slice(&page);
+            Ok(())
+        }
+
+        fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+            self.fill_to(page_id)?;
+            self.write_slot(page_id, data)?;
+            self.file.flush()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.file.sync_all()
+        }
+
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            let pages = len as usize / PAGE_SIZE;
+            if pages as u64 * PAGE_SIZE as u64 > self.len()? {
+                return self.fill_to(pages);
+            }
+            self.file.set_len(EncryptedStore::offset(pages))
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            let slots = (self.file.metadata()?.len() as usize).saturating_sub(HEADER_SIZE)
+                / (PAGE_SIZE + OVERHEAD);
+            Ok((slots * PAGE_SIZE) as u64)
+        }
+
+        fn file(&self) -> Option<&File> {
+            Some(&self.file)
+        }
+
+        fn sidecar_cipher(&self) -> Option<Cipher> {
+            let mut prefix = self.sidecar_prefix;
+            let n = self.sidecars.fetch_add(1, Ordering::Relaxed);
+            for (p, b) in prefix[8..].iter_mut().zip(n.to_le_bytes().iter()) {
+                *p ^= b;
+            }
+            Some(Cipher::with_prefix(self.page_key, prefix))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashSet;
+        use std::fs;
+        use std::io;
+        use std::sync::atomic::Ordering;
+        use super::*;
+        use dump::from_hex;
+        use util::*;
+        use LinHash;
+
+        fn hex(s: &str) -> Vec<u8> {
+            from_hex(&s.replace(' ', "")).unwrap()
+        }
+
+        fn array<const N: usize>(b: &[u8]) -> [u8; N] {
+            let mut a = [0; N];
+            a.copy_from_slice(b);
+            a
+        }
+
+        #[test]
+        fn test_vectors() {
+            // draft-irtf-cfrg-xchacha, 2.2.1
+            let key: Vec<u8> = (0..32).collect();
+            let input = hex("000000090000004a0000000031415927");
+            assert_eq!(derive_key(&array(&key), &array(&input)).to_vec(),
+                       hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc"));
+
+            // draft-irtf-cfrg-xchacha, A.3.1: a sealed message is laid out
+            // as nonce, ciphertext, tag
+            let key = hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f");
+            let nonce = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+            let aad = hex("50515253c0c1c2c3c4c5c6c7");
+            let text = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                         only one tip for the future, sunscreen would be it.";
+            let cipher = Cipher::with_prefix(array(&key), array(&nonce[..16]));
+            cipher.counter.store(u64::from_le_bytes(array(&nonce[16..])), Ordering::Relaxed);
+            let sealed = cipher.seal(&aad, text);
+            assert_eq!(sealed[..NONCE_SIZE], nonce[..]);
+            assert_eq!(sealed[NONCE_SIZE..NONCE_SIZE + 16].to_vec(),
+                       hex("bd6d179d3e83d43b9576579493c0e939"));
+            assert_eq!(sealed[sealed.len() - TAG_SIZE..].to_vec(),
+                       hex("c0875924c1c7987947deafd8780acf49"));
+            assert_eq!(cipher.open(&aad, &sealed), Some(text.to_vec()));
+        }
+
+        #[test]
+        fn seal_and_open() {
+            let cipher = Cipher::new([3; 32]).unwrap();
+            let a = cipher.seal(b"aad", b"secret");
+            let b = cipher.seal(b"aad", b"secret");
+            assert_eq!(a.len(), 6 + OVERHEAD);
+            assert_ne!(a, b);
+            assert_eq!(cipher.open(b"aad", &a), Some(b"secret".to_vec()));
+            assert_eq!(cipher.open(b"other", &a), None);
+            let mut bad = a.clone();
+            bad[30] ^= 1;
+            assert_eq!(cipher.open(b"aad", &bad), None);
+            assert_eq!(Cipher::new([4; 32]).unwrap().open(b"aad", &a), None);
+        }
+
+        #[test]
+        fn encrypted_table() {
+            let path = "/tmp/test_encrypted_table";
+            let sidecars = [format!("{}.wal", path), format!("{}.journal", path)];
+            fs::remove_file(path).ok();
+            let key = [9; 32];
+            let open = |key: &[u8; 32]| EncryptedStore::open(path, key)
+                .and_then(|store| LinHash::options().keysize(8).valsize(8).store(store).open(path)
+                          .map_err(io::Error::from));
+            let mut h = open(&key).unwrap();
+            for k in 0..3000u64 {
+                h.put(&encode(k), &encode(k ^ 0x5ec2e7));
+            }
+            // neither the file nor its sidecars hold a record in the clear
+            let needle = encode(2999u64 ^ 0x5ec2e7);
+            h.flush();
+            h.put(&encode(3000u64), &needle);
+            assert!(fs::metadata(&sidecars[0]).unwrap().len() > 0);
+            for f in [path.to_string()].iter().chain(&sidecars) {
+                let data = fs::read(f).unwrap_or_default();
+                assert!(!data.windows(8).any(|w| w == &needle[..]), "{}", f);
+            }
+            h.remove(&encode(3000u64));
+            h.close();
+
+            assert_eq!(open(&[8; 32]).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+            assert!(LinHash::try_open(path, 8, 8).is_err());
+            let mut h = open(&key).unwrap();
+            assert_eq!(h.len(), 3000);
+            // the map would hold the pages as stored
+            assert!(h.set_io_backend(::IoBackend::Mmap).is_err());
+            for k in 0..3000u64 {
+                assert_eq!(h.get_u64(&encode(k)), Some(k ^ 0x5ec2e7));
+            }
+            h.close();
+
+            // a page changed on disk fails authentication
+            let mut data = fs::read(path).unwrap();
+            data[HEADER_SIZE + 2 * (PAGE_SIZE + OVERHEAD) + 100] ^= 1;
+            fs::write(path, &data).unwrap();
+            let mut h = open(&key).unwrap();
+            assert!(!h.verify().unwrap().is_ok());
+            h.close();
+            fs::remove_file(path).ok();
+            for f in &sidecars {
+                fs::remove_file(f).ok();
+            }
+        }
+
+        #[test]
+        fn sealed_slots() {
+            let path = "/tmp/test_sealed_slots";
+            fs::remove_file(path).ok();
+            let key = [5; 32];
+            let page = |n: u8| vec![n; PAGE_SIZE];
+            let mut data = page(9);
+            let mut store = EncryptedStore::open(path, &key).unwrap();
+            store.write_page(0, &page(1)).unwrap();
+            // pages 1 and 2 get sealed empty pages
+            store.write_page(3, &page(4)).unwrap();
+            store.read_page(2, &mut data).unwrap();
+            assert_eq!(data, page(0));
+            // past the end of the file
+            store.read_page(7, &mut data).unwrap();
+            assert_eq!(data, page(0));
+            drop(store);
+            // another opening, so another `Cipher`, with its own nonces
+            let mut store = EncryptedStore::open(path, &key).unwrap();
+            store.write_page(1, &page(2)).unwrap();
+            store.write_page(2, &page(3)).unwrap();
+            drop(store);
+
+            let slot = |n: usize| {
+                EncryptedStore::offset(n) as usize..EncryptedStore::offset(n + 1) as usize
+            };
+            let file = fs::read(path).unwrap();
+            assert_eq!(file.len(), slot(3).end);
+            let nonces: HashSet<&[u8]> = (0..4).map(|n| &file[slot(n)][..NONCE_SIZE]).collect();
+            assert_eq!(nonces.len(), 4);
+            // pages 0 and 1 were the first sealed by their `Cipher`s
+            assert_eq!(file[slot(0)][16..NONCE_SIZE], file[slot(1)][16..NONCE_SIZE]);
+
+            let read = |file: &[u8], page_id: usize| {
+                fs::write(path, file).unwrap();
+                let mut data = vec![0; PAGE_SIZE];
+                EncryptedStore::open(path, &key).unwrap().read_page(page_id, &mut data)
+                    .map(|()| data)
+            };
+            assert_eq!(read(&file, 2).unwrap(), page(3));
+            let fails = |file: &[u8], page_id: usize| {
+                read(file, page_id).unwrap_err().kind() == io::ErrorKind::InvalidData
+            };
+            // a changed tag
+            let mut bad = file.clone();
+            bad[slot(3).end - 1] ^= 1;
+            assert!(fails(&bad, 3));
+            // page 1 copied over page 2
+            let mut bad = file.clone();
+            bad.copy_within(slot(1), slot(2).start);
+            assert!(fails(&bad, 2));
+            // a zeroed slot is not a page never written
+            let mut bad = file.clone();
+            bad[slot(1)].iter_mut().for_each(|b| *b = 0);
+            assert!(fails(&bad, 1));
+            // nor is a torn last slot
+            assert!(fails(&file[..slot(3).end - 100], 3));
+            fs::remove_file(path).ok();
+        }
+    }
+}
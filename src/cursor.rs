@@ -0,0 +1,252 @@
+//! A cursor over a table that stays put while the table changes under
+//! it, for paging through a table between other operations.
+//!
+//! Records are visited in a fixed order that does not depend on how
+//! many buckets the table has: by the bit-reversed hash of the key,
+//! then by key and value. Reversing the hash turns the low bits that
+//! pick a bucket into the high ones, so every bucket holds one range
+//! of that order, and a split or merge only cuts a range in two or
+//! joins two. A cursor is a position in the order, not in a bucket:
+//! after puts and removes that split or merge buckets it goes on from
+//! where it was, never skipping or visiting again a record that was
+//! in the table the whole time. Records put behind the cursor are not
+//! seen; those put ahead of it are.
+
+use std::cmp::Ordering;
+
+use disk::Record;
+use error::{self, LinHashError};
+use page::PageLayout;
+use LinHash;
+
+/// A position among the records of a `LinHash`. It does not borrow
+/// the table; each step takes it, so the table can be changed between
+/// steps. See `LinHash::cursor`.
+pub struct Cursor {
+    // the table the cursor belongs to
+    file_id: usize,
+    // the record last returned, and its place in the order
+    current: Option<(u64, Record)>,
+    // where the next step starts looking
+    bound: Bound,
+}
+
+enum Bound {
+    // at or after this position
+    From(u64, Vec<u8>, Vec<u8>),
+    // strictly after this position
+    After(u64, Vec<u8>, Vec<u8>),
+    End,
+}
+
+impl Cursor {
+    pub(crate) fn new(table: &LinHash) -> Cursor {
+        Cursor {
+            file_id: table.buckets.file_id(),
+            current: None,
+            bound: Bound::From(0, vec![], vec![]),
+        }
+    }
+
+    /// Moves to the record of `key`, or if there is none to the first
+    /// record after where it would be, and returns it. In a table with
+    /// duplicate keys, moves to the first of the key's records.
+    pub fn seek(&mut self, table: &mut LinHash, key: &[u8])
+                -> error::Result<Option<Record>> {
+        let mut padded = key.to_vec();
+        if table.buckets.page_layout() != PageLayout::Slotted {
+            padded.resize(table.buckets.keysize().max(key.len()), 0);
+        }
+        self.bound = Bound::From(table.hash(&padded).reverse_bits(), padded, vec![]);
+        self.next(table)
+    }
+
+    /// Moves to the next record and returns it; None once past the
+    /// last. Reads one bucket, or more if the next few are empty.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self, table: &mut LinHash) -> error::Result<Option<Record>> {
+        Ok(self.next_batch(table, 1)?.pop())
+    }
+
+    /// Moves over up to `n` records and returns them, in order: the
+    /// rest of the current bucket in one read, then as many following
+    /// buckets as needed. Returns an empty Vec once past the last.
+    pub fn next_batch(&mut self, table: &mut LinHash, n: usize)
+                      -> error::Result<Vec<Record>> {
+        if self.file_id != table.buckets.file_id() {
+            return Err(LinHashError::InvalidArgument(
+                "cursor belongs to another table".to_string()));
+        }
+        if table.buckets.partial_expansions() {
+            // its buckets do not hold ranges of the order
+            return Err(LinHashError::InvalidArgument(
+                "a table split in partial expansions has no cursor order".to_string()));
+        }
+        table.check_handle()?;
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let batch = self.find(table, n);
+        self.current = batch.last().cloned();
+        self.bound = match self.current {
+            Some((pos, (ref key, ref val))) => Bound::After(pos, key.clone(), val.clone()),
+            None => Bound::End,
+        };
+        Ok(batch.into_iter().map(|(_, record)| record).collect())
+    }
+
+    /// The record the last `seek` or `next` returned.
+    pub fn current(&self) -> Option<&Record> {
+        self.current.as_ref().map(|(_, record)| record)
+    }
+
+    /// Goes back to before the first record.
+    pub fn rewind(&mut self) {
+        self.current = None;
+        self.bound = Bound::From(0, vec![], vec![]);
+    }
+
+    /// The first `n` records at or after `bound`, bucket by bucket.
+    fn find(&self, table: &mut LinHash, n: usize) -> Vec<(u64, Record)> {
+        let mut found = vec![];
+        let (mut pos, mut inclusive, mut key, mut val) = match self.bound {
+            Bound::From(pos, ref key, ref val) => (pos, true, &key[..], &val[..]),
+            Bound::After(pos, ref key, ref val) => (pos, false, &key[..], &val[..]),
+            Bound::End => return found,
+        };
+        while found.len() < n {
+            let bucket = table.bucket_of_hash(pos.reverse_bits());
+            let mut wanted: Vec<(u64, Record)> = table.bucket_records(bucket).into_iter()
+                .map(|(k, v)| (table.hash(&k).reverse_bits(), (k, v)))
+                .filter(|&(p, (ref k, ref v))| {
+                    let order = (p, &k[..], &v[..]).cmp(&(pos, key, val));
+                    order == Ordering::Greater || (inclusive && order == Ordering::Equal)
+                })
+                .collect();
+            wanted.sort_unstable();
+            wanted.truncate(n - found.len());
+            found.append(&mut wanted);
+            // every record of the next bucket in the order comes after
+            // those of this one
+            let next = 1u64.checked_shl((64 - table.bucket_depth(bucket)) as u32)
+                .and_then(|width| (bucket as u64).reverse_bits().checked_add(width));
+            pos = match next {
+                Some(next) => next,
+                None => break,
+            };
+            inclusive = true;
+            key = &[];
+            val = &[];
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use util::*;
+    use LinHash;
+
+    #[test]
+    fn cursor_survives_splits() {
+        let path = "/tmp/test_cursor";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..500 {
+            h.put(&encode(k), &encode(k));
+        }
+        let mut cursor = h.cursor();
+        let mut seen = vec![];
+        for _ in 0..100 {
+            seen.push(cursor.next(&mut h).unwrap().unwrap().0);
+        }
+        assert_eq!(cursor.current().map(|r| &r.0), seen.last());
+        // splits and merges between pages of results
+        for k in 500..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 2000..5000 {
+            h.remove(&encode(k));
+        }
+        while let Some((k, _)) = cursor.next(&mut h).unwrap() {
+            seen.push(k);
+        }
+        assert!(cursor.current().is_none());
+        let unique: HashSet<_> = seen.iter().cloned().collect();
+        assert_eq!(unique.len(), seen.len());
+        for k in 0..500 {
+            assert!(unique.contains(&encode(k)));
+        }
+
+        let mut cursor = h.cursor();
+        let first = cursor.next(&mut h).unwrap().unwrap();
+        let second = cursor.next(&mut h).unwrap().unwrap();
+        assert_eq!(cursor.seek(&mut h, &first.0).unwrap(), Some(first.clone()));
+        assert_eq!(cursor.next(&mut h).unwrap(), Some(second));
+        h.remove(&first.0);
+        cursor.rewind();
+        assert_ne!(cursor.next(&mut h).unwrap(), Some(first));
+        h.close();
+
+        let other_path = "/tmp/test_cursor_other";
+        fs::remove_file(other_path).ok();
+        let mut other = LinHash::open(other_path, 4, 4);
+        assert!(cursor.next(&mut other).is_err());
+        other.close();
+        fs::remove_file(other_path).ok();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn cursor_batches() {
+        let path = "/tmp/test_cursor_batches";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let mut cursor = h.cursor();
+        let mut one_by_one = vec![];
+        while let Some(record) = cursor.next(&mut h).unwrap() {
+            one_by_one.push(record);
+        }
+
+        cursor.rewind();
+        let mut batched = vec![];
+        loop {
+            let batch = cursor.next_batch(&mut h, 37).unwrap();
+            assert!(batch.len() <= 37);
+            if batch.is_empty() {
+                break;
+            }
+            assert_eq!(cursor.current(), batch.last());
+            batched.extend(batch);
+        }
+        assert_eq!(batched, one_by_one);
+        assert!(cursor.current().is_none());
+
+        // splits between batches neither skip nor repeat a record
+        cursor.rewind();
+        let mut seen = cursor.next_batch(&mut h, 1000).unwrap();
+        for k in 3000..8000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(cursor.next_batch(&mut h, 0).unwrap().is_empty());
+        loop {
+            let batch = cursor.next_batch(&mut h, 500).unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            seen.extend(batch);
+        }
+        let unique: HashSet<_> = seen.iter().map(|(k, _)| k.clone()).collect();
+        assert_eq!(unique.len(), seen.len());
+        for k in 0..3000 {
+            assert!(unique.contains(&encode(k)));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+}
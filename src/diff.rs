@@ -0,0 +1,114 @@
+//! Comparing the contents of two tables, eg. to check a migration, a
+//! restored backup or a replica against its source.
+//!
+//! Both tables are streamed one bucket at a time and each key is
+//! looked up in the other table, so memory use does not depend on the
+//! size of either. Keys and values are compared as stored, ie. equal
+//! up to zero padding, so tables of different `keysize` or `valsize`
+//! can be compared.
+
+use util::key_eq;
+use LinHash;
+
+/// One way in which table `a` differs from table `b`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    OnlyInA(Vec<u8>),
+    OnlyInB(Vec<u8>),
+    Changed { key: Vec<u8>, a: Vec<u8>, b: Vec<u8> },
+}
+
+/// `key` shortened to `width` bytes, if what is cut off is padding.
+fn fit(key: &[u8], width: usize) -> Option<&[u8]> {
+    if key.len() <= width {
+        Some(key)
+    } else if key[width..].iter().all(|&b| b == 0) {
+        Some(&key[..width])
+    } else {
+        None
+    }
+}
+
+fn values_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() >= b.len() { key_eq(a, b) } else { key_eq(b, a) }
+}
+
+/// Calls `report` for every difference between `a` and `b`: first
+/// keys of `a` that are missing from `b` or hold a different value,
+/// then keys of `b` missing from `a`. Returns the number of
+/// differences.
+pub fn diff<F: FnMut(Difference)>(a: &mut LinHash, b: &mut LinHash,
+                                  mut report: F) -> usize {
+    let mut count = 0;
+    for bucket in 0..a.nbuckets {
+        for (_, records) in a.buckets.all_records_in_bucket(bucket) {
+            for (k, va) in records {
+                let vb = fit(&k, b.buckets.keysize()).and_then(|key| b.get(key));
+                match vb {
+                    None => report(Difference::OnlyInA(k)),
+                    Some(vb) => {
+                        if values_eq(&va, &vb) {
+                            continue;
+                        }
+                        report(Difference::Changed { key: k, a: va, b: vb });
+                    },
+                }
+                count += 1;
+            }
+        }
+    }
+    for bucket in 0..b.nbuckets {
+        for (_, records) in b.buckets.all_records_in_bucket(bucket) {
+            for (k, _) in records {
+                if !fit(&k, a.buckets.keysize()).is_some_and(|key| a.contains(key)) {
+                    report(Difference::OnlyInB(k));
+                    count += 1;
+                }
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use util::encode;
+
+    #[test]
+    fn table_diff() {
+        fs::remove_file("/tmp/test_diff_a").ok();
+        fs::remove_file("/tmp/test_diff_b").ok();
+        let mut a = LinHash::open("/tmp/test_diff_a", 4, 4);
+        let mut b = LinHash::open("/tmp/test_diff_b", 8, 8);
+        for k in 0..2000 {
+            a.put(&encode(k), &encode(k));
+            if k != 10 {
+                b.put(&encode(k), &encode(if k == 20 { 21 } else { k }));
+            }
+        }
+        b.put(&encode(5000), &encode(0));
+
+        let mut diffs = vec![];
+        assert_eq!(a.diff(&mut b, |d| diffs.push(d)), 3);
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs.contains(&Difference::OnlyInA(encode(10))));
+        assert!(diffs.contains(&Difference::OnlyInB(
+            [encode(5000), vec![0; 4]].concat())));
+        assert!(diffs.iter().any(|d| match *d {
+            Difference::Changed { ref key, .. } => key == &encode(20),
+            _ => false,
+        }));
+
+        b.remove(&encode(5000));
+        b.put(&encode(10), &encode(10));
+        b.update(&encode(20), &encode(20));
+        assert!(a.content_eq(&mut b));
+
+        a.close();
+        b.close();
+        fs::remove_file("/tmp/test_diff_a").ok();
+        fs::remove_file("/tmp/test_diff_b").ok();
+    }
+}
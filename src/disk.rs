@@ -1,14 +1,309 @@
-use std::collections::VecDeque;
+//! The pager: a file of fixed-size pages with a small buffer pool, a
+//! free list of recycled pages, and a control page (page 0) holding
+//! the file's metadata.
+//!
+//! `LinHash` uses the bucket-oriented methods (`search_bucket`,
+//! `clear_bucket`, ...), but the page-level API below is independent
+//! of hashing and can back other on-disk structures:
+//!
+//! * `DbFile::new_pager` (or `try_new_pager`) opens a file with no
+//!   pages reserved for buckets.
+//! * `allocate_page`/`free_page` hand out and recycle page ids.
+//! * `page`/`page_mut` give access to a cached page; `page_mut` marks
+//!   it dirty so it is written back on eviction or `flush`.
+//! * `pin`/`unpin` keep a page resident in the buffer pool.
+//!
+//! Page 0 is always the control page. The three header words passed
+//! to `write_ctrlpage` belong to the client structure; the pager
+//! stores its own page count and free list alongside them.
+//!
+//! Files created since format version 4 keep a shadow copy of the
+//! control page in page 1. Writes alternate between the two copies,
+//! each stamped with a sequence number and a checksum, so a write torn
+//! by a crash leaves the other copy intact: reading picks the intact
+//! copy with the higher sequence number.
+//!
+//! Since version 5 the control page also records the key, value and
+//! page sizes, so a file is not read with the wrong ones.
+//!
+//! Since version 6 a bucket map too long for the control page goes on
+//! in a chain of map pages. They are only ever taken from past the end
+//! of the file and written before the control page that links them,
+//! and the entries of buckets a written control page counts never
+//! change in place, so a crash leaves every copy of it a sound map.
+
+use std::collections::{HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::io::prelude::*;
+use std::ffi::OsString;
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::SeekFrom;
-use std::mem;
+use std::path::{Path, PathBuf};
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::process;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use page::{Page, PAGE_SIZE, HEADER_SIZE};
+use crypt::Cipher;
+use hasher::{seed_check, SIP_HASHER_ID, STABLE_HASHER_ID};
+use journal;
+use page::{Page, PageLayout, PageType, PageView, EXTENDED_HEADER_SIZE, HEADER_SIZE, LSN_HEADER_SIZE,
+           PAGE_SIZE};
+use shared;
+use store::{FileStore, IoBackend, PageStore};
 use util::*;
 
-const NUM_BUFFERS : usize = 16;
+/// Default size of the buffer pool, in pages.
+pub const NUM_BUFFERS : usize = 16;
+
+/// Smallest buffer pool: enough for the few pages an operation works
+/// on at once.
+pub const MIN_BUFFERS : usize = 4;
+
+/// Most overflow pages `all_records_in_bucket` reads in one go.
+pub const CHAIN_READAHEAD: usize = 8;
+
+/// Offset of the bucket map within the control page.
+pub(crate) const CTRL_MAP_START: usize = 56;
+/// Offset of the layout word: a magic number identifying linhash
+/// files in its upper six bytes and the format version in the lower
+/// two. Files written before it existed (version 1) have the bucket
+/// map here, at `LEGACY_MAP_START`, so its first entry (a small page
+/// id) sits where the layout word now is.
+pub(crate) const CTRL_LAYOUT: usize = 48;
+/// "LHCTRL", the magic part of the layout word.
+const CTRL_MAGIC: u64 = 0x4c48_4354_524c_0000;
+const VERSION_MASK: u64 = 0xffff;
+/// Format version written by this library.
+pub const FORMAT_VERSION: usize = 6;
+/// Layout word written by this library: "LHCTRL" and `FORMAT_VERSION`.
+pub(crate) const LAYOUT_WORD: u64 = CTRL_MAGIC | FORMAT_VERSION as u64;
+/// Where layout 1 (and the original, untagged layout) put the map.
+const LEGACY_MAP_START: usize = 48;
+/// Offset of the epoch counter: the last word of the control page, so
+/// files written before it existed read it as 0. The epoch doubles as
+/// the file's generation: it is bumped on every structural change.
+pub(crate) const CTRL_EPOCH: usize = PAGE_SIZE - USIZE_WIDTH;
+/// Offset of the file id, a random word chosen when the file is
+/// created, just below the epoch. 0 in files written before it existed.
+pub(crate) const CTRL_FILE_ID: usize = CTRL_EPOCH - USIZE_WIDTH;
+/// Size of the application metadata area (see `set_meta`).
+pub const META_SIZE: usize = 256;
+/// Offset of the length of the application metadata.
+pub(crate) const CTRL_META_LEN: usize = CTRL_FILE_ID - USIZE_WIDTH;
+/// Offset of the application metadata itself.
+pub(crate) const CTRL_META: usize = CTRL_META_LEN - META_SIZE;
+/// Offset of the unclean-shutdown flag: non-zero while a writer has
+/// the file open.
+pub(crate) const CTRL_OPEN_FLAG: usize = CTRL_META - USIZE_WIDTH;
+/// Offset of the table's `PageLayout`.
+pub(crate) const CTRL_PAGE_LAYOUT: usize = CTRL_OPEN_FLAG - USIZE_WIDTH;
+/// Offset of the seed of the built-in hasher (version 3 on).
+pub(crate) const CTRL_HASH_SEED: usize = CTRL_PAGE_LAYOUT - USIZE_WIDTH;
+/// Offset of `hasher::seed_check` of the seed.
+pub(crate) const CTRL_HASH_CHECK: usize = CTRL_HASH_SEED - USIZE_WIDTH;
+/// Offset of the sequence number, bumped on every ctrl page write
+/// (version 4 on).
+pub(crate) const CTRL_SEQ: usize = CTRL_HASH_CHECK - USIZE_WIDTH;
+/// Offset of the shadow flag: non-zero if page 1 holds the other copy
+/// of the control page. Files upgraded from before version 4 have
+/// none, as their page 1 belongs to a bucket.
+pub(crate) const CTRL_SHADOW: usize = CTRL_SEQ - USIZE_WIDTH;
+/// Offset of the crc32 of the control page, taken with this word
+/// zeroed.
+pub(crate) const CTRL_CHECKSUM: usize = CTRL_SHADOW - USIZE_WIDTH;
+/// Offset of the record geometry (version 5 on); see
+/// `geometry_word`.
+pub(crate) const CTRL_GEOMETRY: usize = CTRL_CHECKSUM - USIZE_WIDTH;
+/// Offset of the first map page (version 6 on), 0 if there is none.
+pub(crate) const CTRL_MAP_PAGES: usize = CTRL_GEOMETRY - USIZE_WIDTH;
+/// End of the bucket map within the control page.
+pub(crate) const CTRL_MAP_END: usize = CTRL_MAP_PAGES;
+/// Entries of the bucket map the control page holds; the rest are in
+/// map pages.
+pub(crate) const CTRL_MAP_ENTRIES: usize = (CTRL_MAP_END - CTRL_MAP_START) / USIZE_WIDTH;
+/// Entries per map page, after the link to the next one (0 for none).
+pub(crate) const MAP_PAGE_ENTRIES: usize = PAGE_SIZE / USIZE_WIDTH - 1;
+/// Where the shadow copy of the control page lives.
+pub(crate) const SHADOW_PAGE: usize = 1;
+/// Set in the page layout word if data pages carry checksums.
+pub(crate) const PAGE_CHECKSUMS: u64 = 1 << 32;
+/// Set in the page layout word if the table keeps duplicate keys.
+pub(crate) const DUPLICATE_KEYS: u64 = 1 << 31;
+/// Set in the page layout word if records refer to values kept in
+/// value pages; see `blob`.
+pub(crate) const LARGE_VALUES: u64 = 1 << 30;
+/// Set in the page layout word if buckets split in partial
+/// expansions; see `linear::partial_bucket_index`. Versions that
+/// predate it read it as an unknown page layout.
+pub(crate) const PARTIAL_EXPANSIONS: u64 = 1 << 29;
+/// Set in the page layout word if data pages have extended headers;
+/// see `page::PageType`.
+pub(crate) const EXTENDED_HEADERS: u64 = 1 << 28;
+/// Set in the page layout word if extended headers end in the page
+/// LSN; see `page::LSN_HEADER_SIZE`.
+pub(crate) const PAGE_LSNS: u64 = 1 << 27;
+/// The `PageLayout` part of the page layout word.
+pub(crate) const LAYOUT_MASK: u64 = 0x07ff_ffff;
+/// Where the split threshold, in thousandths, sits in the page layout
+/// word. 0 in files that never set one.
+pub(crate) const THRESHOLD_SHIFT: usize = 33;
+/// Largest split threshold the page layout word can hold.
+pub const MAX_SPLIT_THRESHOLD: usize = 0x7fff;
+/// Where the id of the table's `KeyHasher` sits in the page layout word.
+pub(crate) const HASHER_SHIFT: usize = 48;
+
+/// Width of each field of the geometry word.
+const GEOMETRY_BITS: usize = 20;
+
+/// The geometry word of a file of `keysize` and `valsize` records in
+/// `PAGE_SIZE` pages: the three sizes, `GEOMETRY_BITS` bits each.
+pub(crate) fn geometry_word(keysize: usize, valsize: usize) -> u64 {
+    keysize as u64 | (valsize as u64) << GEOMETRY_BITS
+        | (PAGE_SIZE as u64) << (2 * GEOMETRY_BITS)
+}
+
+/// The key, value and page sizes in a geometry word.
+pub(crate) fn split_geometry(word: u64) -> (usize, usize, usize) {
+    let mask = (1 << GEOMETRY_BITS) - 1;
+    ((word & mask) as usize, ((word >> GEOMETRY_BITS) & mask) as usize,
+     (word >> (2 * GEOMETRY_BITS)) as usize)
+}
+
+/// Where entry `bucket` of a version 6 bucket map is: an offset into
+/// the ctrl page, or into the map page that many links down the chain.
+pub(crate) fn map_slot(bucket: usize) -> (Option<usize>, usize) {
+    if bucket < CTRL_MAP_ENTRIES {
+        return (None, CTRL_MAP_START + bucket * USIZE_WIDTH);
+    }
+    let i = bucket - CTRL_MAP_ENTRIES;
+    (Some(i / MAP_PAGE_ENTRIES), (1 + i % MAP_PAGE_ENTRIES) * USIZE_WIDTH)
+}
+
+/// Offset of the bucket map in a ctrl page of any layout.
+pub(crate) fn map_start(ctrl: &[u8]) -> usize {
+    match format_version(ctrl) {
+        Some(v) if v >= 2 => CTRL_MAP_START,
+        _ => LEGACY_MAP_START,
+    }
+}
+
+/// The format version in a tagged ctrl page; `None` for files from
+/// before the layout word, and for files that are not linhash files
+/// at all.
+pub(crate) fn format_version(ctrl: &[u8]) -> Option<usize> {
+    read_u64_at(ctrl, CTRL_LAYOUT).ok()
+        .filter(|word| word & !VERSION_MASK == CTRL_MAGIC)
+        .map(|word| (word & VERSION_MASK) as usize)
+}
+
+fn ctrl_checksum(ctrl: &[u8]) -> usize {
+    let crc = crc32(&ctrl[..CTRL_CHECKSUM]);
+    crc32_extend(crc, &ctrl[CTRL_CHECKSUM + USIZE_WIDTH..PAGE_SIZE]) as usize
+}
+
+/// Stores the checksum of the finished ctrl page `ctrl`.
+pub(crate) fn stamp_checksum(ctrl: &mut [u8]) {
+    let checksum = ctrl_checksum(ctrl);
+    write_usize_at(ctrl, CTRL_CHECKSUM, checksum)
+        .expect("ctrl page too short");
+}
+
+/// Trims a page header that cannot be right to what can still be
+/// read safely, as `DbFile::check_header` describes, returning the
+/// problem found.
+fn trim_header(page: &mut Page, num_pages: usize) -> Option<String> {
+    let max_records = page.max_records();
+    let mut problem = None;
+    if page.num_records > max_records {
+        problem = Some(format!("page {} claims {} records, capacity is {}",
+                               page.id, page.num_records, max_records));
+        page.num_records = max_records;
+    }
+    if let Some(next) = page.next.filter(|&n| n >= num_pages) {
+        problem = Some(format!("page {} links to page {} past the end of the file",
+                               page.id, next));
+        page.next = None;
+    }
+    problem
+}
+
+/// Fills in the entries of `found` still None for the first of the
+/// `stored` keys of page `page_id`, in row order, that matches `keys[i]`.
+fn note_matches<'a, I>(layout: PageLayout, keys: &[&[u8]], found: &mut [Option<(usize, usize)>],
+                       page_id: usize, stored: I)
+    where I: Iterator<Item = &'a [u8]> {
+    for (row_num, stored) in stored.enumerate() {
+        for (i, key) in keys.iter().enumerate() {
+            if found[i].is_none() && layout.key_eq(stored, key) {
+                found[i] = Some((page_id, row_num));
+            }
+        }
+    }
+}
+
+/// Whether `ctrl` is a whole version 4 (or later) ctrl page, ie. not
+/// torn by a crash mid-write.
+pub(crate) fn ctrl_intact(ctrl: &[u8]) -> bool {
+    ctrl.len() >= PAGE_SIZE
+        && format_version(ctrl) >= Some(4)
+        && read_usize_at(ctrl, CTRL_CHECKSUM).ok() == Some(ctrl_checksum(ctrl))
+}
+
+/// The copy of the ctrl page to read: `shadow` if it is intact and
+/// newer than `primary`, else `primary`.
+pub(crate) fn latest_ctrl<'a>(primary: &'a [u8], shadow: Option<&'a [u8]>) -> &'a [u8] {
+    match shadow {
+        Some(shadow) if ctrl_intact(shadow) && (!ctrl_intact(primary)
+            || read_usize_at(shadow, CTRL_SEQ).ok() > read_usize_at(primary, CTRL_SEQ).ok()) =>
+            shadow,
+        _ => primary,
+    }
+}
+
+/// A fresh, non-zero file id.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn new_file_id() -> usize {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut s = DefaultHasher::new();
+    (nanos, process::id()).hash(&mut s);
+    (s.finish() as usize).max(1)
+}
+
+/// A fresh, non-zero file id. There is no clock or process id to go
+/// on, so ids are only unique within this instance of the module.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn new_file_id() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+    let mut s = DefaultHasher::new();
+    NEXT_ID.fetch_add(1, Ordering::Relaxed).hash(&mut s);
+    (s.finish() as usize).max(1)
+}
+
+/// The path of a file kept next to the table at `db_path`: the
+/// table's path with `suffix` appended, whatever its encoding.
+pub fn sidecar_path(db_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(db_path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The on-disk identity of `file`, if the platform has one.
+#[cfg(unix)]
+fn inode(file: &File) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    file.metadata().ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_file: &File) -> Option<(u64, u64)> {
+    None
+}
 
 pub struct SearchResult {
     pub page_id: Option<usize>,
@@ -16,6 +311,9 @@ pub struct SearchResult {
     pub val: Option<Vec<u8>>
 }
 
+/// An owned (key, value) pair read out of a page.
+pub type Record = (Vec<u8>, Vec<u8>);
+
 fn flatten<T>(v: Vec<(usize, Vec<T>)>) -> Vec<T> {
     let mut result = vec![];
     for (_, mut i) in v {
@@ -24,35 +322,118 @@ fn flatten<T>(v: Vec<(usize, Vec<T>)>) -> Vec<T> {
     result
 }
 
+/// A split whose records are moved a page at a time; see
+/// `DbFile::start_split`.
+struct PendingSplit {
+    from: usize,
+    to: usize,
+    // the next page of `from` to go through, and the page before it
+    page: Option<usize>,
+    prev: Option<usize>,
+}
+
 pub struct DbFile {
-    path: String,
-    file: File,
+    path: PathBuf,
+    store: Box<dyn PageStore>,
     ctrl_buffer: Page,
     pub buffers: VecDeque<Page>,
     pub records_per_page: usize,
     bucket_to_page: Vec<usize>,
+    // the map pages, in chain order
+    map_pages: Vec<usize>,
+    // entries of the map known to be on disk as they are
+    map_synced: usize,
     keysize: usize,
     valsize: usize,
     num_pages: usize,
     // overflow pages no longer in use
     free_list: Option<usize>,
     num_free: usize,
+    // bumped whenever pages are allocated or freed
+    epoch: usize,
+    file_id: usize,
+    meta: Vec<u8>,
+    open_flag: bool,
+    corruption: Option<String>,
+    legacy_layout: bool,
+    page_layout: PageLayout,
+    page_checksums: bool,
+    extended_headers: bool,
+    page_lsns: bool,
+    // see `lsn`
+    lsn: u64,
+    // the LSN handed out last, whose change may still be under way
+    issued_lsn: u64,
+    hasher_id: u16,
+    hash_seed: u64,
+    duplicate_keys: bool,
+    large_values: bool,
+    partial_expansions: bool,
+    // in thousandths; 0 for the default
+    split_threshold: usize,
+    // allocation fails once the file would exceed this many pages
+    max_pages: Option<usize>,
+    // client header last read from or written to the ctrl page
+    header: Option<(usize, usize, usize)>,
+    // `epoch` as of the last ctrl page read or write
+    ctrl_epoch: Option<usize>,
+    // set by `close`/`discard`; until then, drop flushes
+    closed: bool,
+    // ticks on every page access, for LRU eviction
+    clock: u64,
+    // how a `FileStore` reads pages
+    io_backend: IoBackend,
+    // the file store bypasses the page cache; see `FileStore::open_direct`
+    direct_io: bool,
+    // key and value sizes the ctrl page last read records, if any
+    stored_record_size: Option<(usize, usize)>,
+    // see `PageStore::sidecar_files`; if unset splits are not journaled
+    sidecar_files: bool,
+    // seals the split journal of an encrypted table
+    cipher: Option<Cipher>,
+    // whether page 1 is the shadow copy of the ctrl page
+    shadow: bool,
+    // sequence number of the last ctrl page read or written
+    ctrl_seq: usize,
+    pending_split: Option<PendingSplit>,
 }
 
 impl DbFile {
-    pub fn new(filename: &str, keysize: usize, valsize: usize) -> DbFile {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename);
-        let file = match file {
-            Ok(f) => f,
-            Err(e) => panic!(e),
-        };
+    pub fn new<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize) -> DbFile {
+        DbFile::try_new(filename, keysize, valsize)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let total_size = keysize + valsize;
-        let records_per_page = (PAGE_SIZE - HEADER_SIZE) / total_size;
+    /// Like `new`, but returns the error if the file cannot be opened.
+    pub fn try_new<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                   -> io::Result<DbFile> {
+        let store = FileStore::open(&filename)?;
+        Ok(DbFile::with_store(filename, Box::new(store), keysize, valsize))
+    }
+
+    /// Like `try_new`, opening the existing file without write
+    /// permission.
+    pub fn try_new_read_only<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                             -> io::Result<DbFile> {
+        let store = FileStore::open_read_only(&filename)?;
+        Ok(DbFile::with_store(filename, Box::new(store), keysize, valsize))
+    }
+
+    /// Like `try_new`, or `try_new_read_only`, bypassing the OS page
+    /// cache; see `FileStore::open_direct`.
+    pub fn try_new_direct<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                          read_only: bool) -> io::Result<DbFile> {
+        let store = FileStore::open_direct(&filename, read_only)?;
+        let mut dbfile = DbFile::with_store(filename, Box::new(store), keysize, valsize);
+        dbfile.direct_io = true;
+        Ok(dbfile)
+    }
+
+    /// A pager over `store`. `filename` names the sidecar files (the
+    /// split journal), if the store has them, and appears in errors.
+    pub fn with_store<P: AsRef<Path>>(filename: P, store: Box<dyn PageStore>, keysize: usize,
+                                      valsize: usize) -> DbFile {
+        let records_per_page = Page::capacity(keysize, valsize);
 
         let mut buffers : VecDeque<Page> =
             VecDeque::with_capacity(NUM_BUFFERS);
@@ -60,97 +441,1156 @@ impl DbFile {
             buffers.push_back(Page::new(keysize, valsize));
         }
 
+        let sidecar_files = store.sidecar_files();
+        let cipher = store.sidecar_cipher();
         DbFile {
-            path: String::from(filename),
-            file: file,
+            path: filename.as_ref().to_path_buf(),
+            store,
             ctrl_buffer: Page::new(0, 0),
-            buffers: buffers,
-            records_per_page: records_per_page,
-            bucket_to_page: vec![1, 2],
-            keysize: keysize,
-            valsize: valsize,
-            num_pages: 3,
-            free_list: Some(3),
+            buffers,
+            records_per_page,
+            bucket_to_page: vec![2, 3],
+            map_pages: vec![],
+            map_synced: 0,
+            keysize,
+            valsize,
+            num_pages: 4,
+            free_list: Some(4),
             num_free: 0,
+            epoch: 0,
+            file_id: new_file_id(),
+            meta: vec![],
+            open_flag: false,
+            corruption: None,
+            legacy_layout: false,
+            page_layout: PageLayout::Row,
+            page_checksums: false,
+            extended_headers: false,
+            page_lsns: false,
+            lsn: 0,
+            issued_lsn: 0,
+            hasher_id: 0,
+            hash_seed: new_file_id() as u64,
+            duplicate_keys: false,
+            large_values: false,
+            partial_expansions: false,
+            split_threshold: 0,
+            max_pages: None,
+            header: None,
+            ctrl_epoch: None,
+            closed: false,
+            clock: 0,
+            io_backend: IoBackend::File,
+            direct_io: false,
+            stored_record_size: None,
+            sidecar_files,
+            cipher,
+            shadow: true,
+            ctrl_seq: 0,
+            pending_split: None,
+        }
+    }
+
+    /// Opens `filename` as a plain pager: unlike `new`, no pages are
+    /// reserved for hash buckets. An existing file's page count and
+    /// free list are loaded from its control page.
+    pub fn new_pager<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize) -> DbFile {
+        DbFile::try_new_pager(filename, keysize, valsize)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `new_pager`, but returns the error if the file cannot be
+    /// opened or its control page cannot be read. The file is left
+    /// as it was.
+    pub fn try_new_pager<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                         -> io::Result<DbFile> {
+        let mut dbfile = DbFile::try_new(filename, keysize, valsize)?;
+        let existing = match dbfile.is_empty() {
+            Ok(empty) => !empty,
+            Err(e) => {
+                dbfile.discard();
+                return Err(e);
+            },
+        };
+        if existing {
+            if let Err(e) = dbfile.read_ctrlpage() {
+                dbfile.discard();
+                return Err(e);
+            }
+        } else {
+            dbfile.bucket_to_page = vec![];
+            dbfile.num_pages = 2;
+            dbfile.free_list = Some(2);
+        }
+        Ok(dbfile)
+    }
+
+    /// Number of pages in the file, including the control pages and
+    /// free pages.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Pages at the start of the file holding the control page: 2 if
+    /// it has a shadow copy, else 1.
+    pub fn ctrl_pages(&self) -> usize {
+        1 + self.shadow as usize
+    }
+
+    /// Structural change counter: bumped every time a page is
+    /// allocated or freed, and persisted in the control page so that
+    /// readers in other processes can notice that bucket chains or the
+    /// bucket map may have changed under them.
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    pub fn file_id(&self) -> usize {
+        self.file_id
+    }
+
+    /// Whether the ctrl page says a writer has the file open. Seen set
+    /// by a new writer, it means the last one did not close cleanly.
+    pub fn open_flag(&self) -> bool {
+        self.open_flag
+    }
+
+    /// Sets the open flag. It reaches disk with the next
+    /// `write_ctrlpage`.
+    pub fn set_open_flag(&mut self, open: bool) {
+        self.open_flag = open;
+    }
+
+    /// An empty page in this file's layout.
+    /// Key and value sizes recorded in the ctrl page last read; None
+    /// for a new file or one from before format version 5.
+    pub fn stored_record_size(&self) -> Option<(usize, usize)> {
+        self.stored_record_size
+    }
+
+    /// Changes the size of records, eg. to the stored one, before any
+    /// page is read.
+    pub fn set_record_size(&mut self, keysize: usize, valsize: usize) {
+        self.keysize = keysize;
+        self.valsize = valsize;
+        self.records_per_page = Page::capacity_after(self.header_size(), keysize, valsize);
+        self.set_page_layout(self.page_layout);
+    }
+
+    fn header_size(&self) -> usize {
+        match (self.extended_headers, self.page_lsns) {
+            (false, _) => HEADER_SIZE,
+            (true, false) => EXTENDED_HEADER_SIZE,
+            (true, true) => LSN_HEADER_SIZE,
+        }
+    }
+
+    fn blank_page(&self) -> Page {
+        let mut page = Page::with_layout(self.keysize, self.valsize, self.page_layout);
+        page.checksums = self.page_checksums;
+        page.extended = self.extended_headers;
+        page.lsns = self.page_lsns;
+        page
+    }
+
+    pub fn page_layout(&self) -> PageLayout {
+        self.page_layout
+    }
+
+    /// Sets the layout of the pages of a new file. Existing files keep
+    /// the layout recorded in their ctrl page.
+    pub fn set_page_layout(&mut self, layout: PageLayout) {
+        self.page_layout = layout;
+        for i in 0..self.buffers.len() {
+            let id = self.buffers[i].id;
+            self.buffers[i] = self.blank_page();
+            self.buffers[i].id = id;
+        }
+    }
+
+    /// Whether data pages carry checksums, verified on every read.
+    pub fn page_checksums(&self) -> bool {
+        self.page_checksums
+    }
+
+    /// Turns page checksums on or off for a new file. Existing files
+    /// keep the setting recorded in their ctrl page; files from before
+    /// checksums have none.
+    pub fn set_page_checksums(&mut self, enabled: bool) {
+        self.page_checksums = enabled;
+        for page in self.buffers.iter_mut() {
+            page.checksums = enabled;
+        }
+    }
+
+    /// Whether data pages have extended headers, saying what each page
+    /// is and which bucket it belongs to; see `page::PageType`.
+    pub fn extended_headers(&self) -> bool {
+        self.extended_headers
+    }
+
+    /// Gives the pages of a new file extended headers, with page LSNs,
+    /// which leave room for fewer records. Existing files keep the
+    /// setting recorded in their ctrl page; files from before it have
+    /// plain headers, and files from before page LSNs have none.
+    pub fn set_extended_headers(&mut self, enabled: bool) {
+        self.set_headers(enabled, enabled);
+    }
+
+    fn set_headers(&mut self, extended: bool, lsns: bool) {
+        self.extended_headers = extended;
+        self.page_lsns = extended && lsns;
+        self.records_per_page = Page::capacity_after(self.header_size(), self.keysize,
+                                                     self.valsize);
+        self.set_page_layout(self.page_layout);
+    }
+
+    /// Whether extended headers hold the LSN of each page.
+    pub fn page_lsns(&self) -> bool {
+        self.page_lsns
+    }
+
+    /// Log sequence number of the latest change made in full: every
+    /// page is stamped with it as it is written, so a page on disk
+    /// holds every change up to its LSN that touched it. A change
+    /// still being made when a page is written is not, and is replayed
+    /// if the log holds it.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// Hands out the LSN for a change about to be logged, the previous
+    /// one having been made. LSNs only grow: their upper half is at
+    /// least the sequence number of the last ctrl page write, which
+    /// every file written since has seen.
+    pub fn next_lsn(&mut self) -> u64 {
+        let floor = (self.ctrl_seq as u64) << 32;
+        self.lsn = self.issued_lsn;
+        self.issued_lsn = (self.issued_lsn + 1).max(floor + 1);
+        self.issued_lsn
+    }
+
+    /// Like `next_lsn`, for replaying the logged change `lsn`: the
+    /// changes logged after it, and after the ctrl page write they may
+    /// be stamped with, get later ones.
+    pub fn replay_lsn(&mut self, lsn: u64) {
+        self.lsn = self.issued_lsn;
+        self.issued_lsn = self.issued_lsn.max(lsn);
+        self.note_lsn(lsn);
+    }
+
+    /// Makes sure that LSNs handed out from now on exceed `lsn`, seen
+    /// on disk.
+    fn note_lsn(&mut self, lsn: u64) {
+        self.ctrl_seq = self.ctrl_seq.max((lsn >> 32) as usize);
+    }
+
+    /// Lowest LSN of the pages in the chain of `bucket_id`: every
+    /// change to the bucket up to it is on disk. 0 in files without
+    /// page LSNs, and while a split is in progress, as the bucket's
+    /// records may be in another chain.
+    pub fn bucket_lsn(&mut self, bucket_id: usize) -> io::Result<u64> {
+        if !self.page_lsns || self.pending_split.is_some() {
+            return Ok(0);
+        }
+        let mut lsn = u64::MAX;
+        let mut page_id = Some(self.bucket_to_page(bucket_id));
+        while let Some(id) = page_id.filter(|&id| id != 0) {
+            let page = self.try_page(id)?;
+            lsn = lsn.min(page.lsn);
+            page_id = page.next;
+        }
+        Ok(lsn)
+    }
+
+    /// Id of the `KeyHasher` the table's keys are hashed with.
+    pub fn hasher_id(&self) -> u16 {
+        self.hasher_id
+    }
+
+    /// Records the hasher of a new file. Existing files keep the id
+    /// recorded in their ctrl page; files from before it have 0.
+    pub fn set_hasher_id(&mut self, id: u16) {
+        self.hasher_id = id;
+    }
+
+    /// Seed of the built-in stable hasher, chosen when the file is
+    /// created.
+    pub fn hash_seed(&self) -> u64 {
+        self.hash_seed
+    }
+
+    /// Whether records with equal keys are kept side by side, rather
+    /// than a key having one record.
+    pub fn duplicate_keys(&self) -> bool {
+        self.duplicate_keys
+    }
+
+    /// Allows duplicate keys in a new file. Existing files keep the
+    /// setting recorded in their ctrl page.
+    pub fn set_duplicate_keys(&mut self, enabled: bool) {
+        self.duplicate_keys = enabled;
+    }
+
+    /// Whether values live in value pages, the records holding
+    /// references to them; see `blob`.
+    pub fn large_values(&self) -> bool {
+        self.large_values
+    }
+
+    /// Keeps values in value pages in a new file. Existing files keep
+    /// the setting recorded in their ctrl page.
+    pub fn set_large_values(&mut self, enabled: bool) {
+        self.large_values = enabled;
+    }
+
+    /// Whether buckets split in partial expansions, rather than one
+    /// bucket at a time into two.
+    pub fn partial_expansions(&self) -> bool {
+        self.partial_expansions
+    }
+
+    /// Splits the buckets of a new file in partial expansions. Existing
+    /// files keep the setting recorded in their ctrl page.
+    pub fn set_partial_expansions(&mut self, enabled: bool) {
+        self.partial_expansions = enabled;
+    }
+
+    /// The table's split threshold in thousandths, 0 if it never set
+    /// one.
+    pub fn split_threshold(&self) -> usize {
+        self.split_threshold
+    }
+
+    /// Records a split threshold, up to `MAX_SPLIT_THRESHOLD`
+    /// thousandths. Unlike the layout, this applies to existing files
+    /// too.
+    pub fn set_split_threshold(&mut self, thousandths: usize) {
+        assert!(thousandths <= MAX_SPLIT_THRESHOLD, "split threshold too large");
+        self.split_threshold = thousandths;
+    }
+
+    /// Most buckets a table splits into. Past the first
+    /// `CTRL_MAP_ENTRIES` the bucket map goes on in map pages, so this
+    /// is only a bound: 2^32 buckets of a page each fill 16 TiB. Hosts
+    /// with a 32-bit `usize` stop just short of it.
+    pub fn max_buckets() -> usize {
+        (1u64 << 32).min(usize::MAX as u64) as usize
+    }
+
+    /// The pages the bucket map goes on in, past what the control page
+    /// holds. A table that shrinks keeps them, to grow back into.
+    pub fn map_pages(&self) -> &[usize] {
+        &self.map_pages
+    }
+
+    /// Whether the ctrl page last read predates the layout word. It is
+    /// upgraded by the next `write_ctrlpage`.
+    pub fn legacy_layout(&self) -> bool {
+        self.legacy_layout
+    }
+
+    /// Description of the first corrupt page read, if any. See
+    /// `fetch_page`.
+    pub fn corruption(&self) -> Option<&str> {
+        self.corruption.as_deref()
+    }
+
+    /// Forgets `corruption`, once the damage has been repaired.
+    pub fn clear_corruption(&mut self) {
+        self.corruption = None;
+    }
+
+    /// Application metadata stored in the ctrl page.
+    pub fn meta(&self) -> &[u8] {
+        &self.meta
+    }
+
+    /// Replaces the application metadata. It reaches disk with the next
+    /// `write_ctrlpage`. Fails if `meta` is longer than `META_SIZE`.
+    pub fn set_meta(&mut self, meta: &[u8]) -> Result<(), LengthError> {
+        if meta.len() > META_SIZE {
+            return Err(LengthError { expected: META_SIZE, actual: meta.len() });
+        }
+        self.meta = meta.to_vec();
+        Ok(())
+    }
+
+    /// Fails if the file at `path` is no longer the one this handle
+    /// has open, eg. because it was deleted, or replaced by a restore
+    /// or migration. Writes through a stale handle would be lost.
+    /// Stores other than files have nothing at `path` to compare with.
+    pub fn check_identity(&self) -> io::Result<()> {
+        let file = match self.store.file() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let stale = |why: &str| Err(io::Error::other(
+            format!("stale handle for {}: {}", self.path.display(), why)));
+        let on_disk = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                return stale("file was removed"),
+            Err(e) => return Err(e),
+        };
+        if let (Some(ours), Some(theirs)) = (inode(file), inode(&on_disk)) {
+            if ours != theirs {
+                return stale("file was replaced");
+            }
+        }
+        // the ctrl page of an encrypted table is not readable from here
+        if self.cipher.is_some() {
+            return Ok(());
+        }
+        let mut pages = Vec::with_capacity(2 * PAGE_SIZE);
+        on_disk.take(2 * PAGE_SIZE as u64).read_to_end(&mut pages)?;
+        pages.resize(2 * PAGE_SIZE, 0);
+        let (primary, shadow) = pages.split_at(PAGE_SIZE);
+        let ctrl = latest_ctrl(primary, Some(shadow));
+        let file_id = read_usize_at(ctrl, CTRL_FILE_ID).unwrap_or(0);
+        let epoch = read_usize_at(ctrl, CTRL_EPOCH).unwrap_or(0);
+        if file_id != 0 && file_id != self.file_id {
+            return stale("file id changed");
+        }
+        if epoch > self.epoch {
+            return stale("file generation is ahead of this handle");
         }
+        Ok(())
     }
 
-    // Control page layout:
+    /// The header of page `page_id` as it stands, ie. before the
+    /// trimming `fetch_page` does: `(num_records, max_records, next)`.
+    /// Fails for a page that fails its checksum.
+    pub fn page_header(&mut self, page_id: usize)
+                       -> io::Result<(usize, usize, Option<usize>)> {
+        let mut page = match self.search_buffer_pool(page_id) {
+            // not yet written back: this is the page as it stands
+            Some(i) if self.buffers[i].dirty => {
+                let page = &self.buffers[i];
+                return Ok((page.num_records, page.max_records(), page.next));
+            },
+            _ => self.blank_page(),
+        };
+        self.store.read_page(page_id, &mut page.storage)?;
+        counter!(PAGES_READ, 1);
+        if self.page_checksums && !page.checksum_ok() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("page {} fails its checksum", page_id)));
+        }
+        page.read_header();
+        Ok((page.num_records, page.max_records(), page.next))
+    }
+
+    /// First page of the free list; the first page past the end of
+    /// the file once the list is empty.
+    pub fn free_list_head(&self) -> Option<usize> {
+        self.free_list
+    }
+
+    /// Number of recycled pages waiting on the free list.
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    /// Returns the cached page `page_id`, reading it in if needed.
+    pub fn page(&mut self, page_id: usize) -> &Page {
+        let buffer_index = self.fetch_page(page_id);
+        &self.buffers[buffer_index]
+    }
+
+    /// Like `page`, but returns an error if the page cannot be read.
+    pub fn try_page(&mut self, page_id: usize) -> io::Result<&Page> {
+        let buffer_index = self.try_fetch_page(page_id)?;
+        Ok(&self.buffers[buffer_index])
+    }
+
+    /// The value of record `row_num` of page `page_id`, borrowed from
+    /// the buffer pool, or under `IoBackend::Mmap` from the map if the
+    /// pool does not hold the page; see `mapped_view`.
+    pub fn try_value(&mut self, page_id: usize, row_num: usize) -> io::Result<&[u8]> {
+        if self.mapped_view(page_id).is_none() {
+            let buffer_index = self.try_fetch_page(page_id)?;
+            return Ok(self.buffers[buffer_index].value(row_num));
+        }
+        counter!(PAGES_READ, 1);
+        Ok(self.mapped_view(page_id).expect("page was mapped").record(row_num).1)
+    }
+
+    /// Page `page_id` read in place out of the map under
+    /// `IoBackend::Mmap`, rather than copied into the buffer pool. None
+    /// if the pool holds the page, as its copy may be newer, if the map
+    /// does not reach it yet, or if it fails a check `try_fetch_page`
+    /// makes, which is left for that to report.
+    fn mapped_view(&self, page_id: usize) -> Option<PageView<'_>> {
+        if self.io_backend != IoBackend::Mmap || page_id >= self.num_pages
+            || self.search_buffer_pool(page_id).is_some() {
+            return None;
+        }
+        let view = PageView::new(self.store.mapped_page(page_id)?, self.keysize,
+                                 self.valsize, self.page_layout, self.header_size());
+        let sound = (!self.page_checksums || view.checksum_ok())
+            && view.num_records <= view.max_records()
+            && view.next.is_none_or(|next| next < self.num_pages);
+        if sound { Some(view) } else { None }
+    }
+
+    /// Like `page`, but marks the page dirty so changes are written
+    /// back.
+    pub fn page_mut(&mut self, page_id: usize) -> &mut Page {
+        let buffer_index = self.fetch_page(page_id);
+        self.buffers[buffer_index].dirty = true;
+        &mut self.buffers[buffer_index]
+    }
+
+    /// Keeps `page_id` in the buffer pool until a matching `unpin`.
+    /// Pins nest. Panics if every buffer is already pinned.
+    pub fn pin(&mut self, page_id: usize) {
+        let buffer_index = self.fetch_page(page_id);
+        self.buffers[buffer_index].pin_count += 1;
+    }
+
+    /// Releases one `pin` of `page_id`.
+    pub fn unpin(&mut self, page_id: usize) {
+        if let Some(i) = self.search_buffer_pool(page_id) {
+            let page = &mut self.buffers[i];
+            page.pin_count = page.pin_count.saturating_sub(1);
+        }
+    }
+
+    /// Number of pages the buffer pool holds.
+    pub fn pool_size(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn io_backend(&self) -> IoBackend {
+        self.io_backend
+    }
+
+    /// Switches how pages are read; see `IoBackend`. Only a file can
+    /// be mapped: other stores, and encrypted ones, fail for
+    /// `IoBackend::Mmap`.
+    pub fn set_io_backend(&mut self, backend: IoBackend) -> io::Result<()> {
+        // the store is only replaced by a `FileStore` for a change
+        if backend == self.io_backend {
+            return Ok(());
+        }
+        // a map would show the pages as they are stored, ie. encrypted
+        if self.cipher.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                      "an encrypted store cannot be mapped"));
+        }
+        let file = match self.store.file() {
+            Some(file) => file.try_clone()?,
+            None if backend == IoBackend::File => return Ok(()),
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                              "only a file store can be mapped")),
+        };
+        let mut store = if self.direct_io {
+            FileStore::with_direct_file(file)
+        } else {
+            FileStore::with_file(file)
+        };
+        store.set_io_backend(backend)?;
+        self.io_backend = store.io_backend();
+        self.store = Box::new(store);
+        Ok(())
+    }
+
+    /// Whether the pages live in a file at `path`, which `reopen` can
+    /// open again.
+    pub fn has_file(&self) -> bool {
+        self.store.file().is_some()
+    }
+
+    /// Drops the cached pages and reads the file at `path` afresh,
+    /// after it was replaced, eg. by a compacted copy. Pinned pages are
+    /// unpinned. Returns the client header of the new file.
+    pub fn reopen(&mut self) -> io::Result<(usize, usize, usize)> {
+        if !self.has_file() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                      "only a file store can be reopened"));
+        }
+        let mut store = if self.direct_io {
+            FileStore::open_direct(&self.path, false)?
+        } else {
+            FileStore::open(&self.path)?
+        };
+        store.set_io_backend(self.io_backend)?;
+        self.store = Box::new(store);
+        for b in 0..self.buffers.len() {
+            self.buffers[b] = self.blank_page();
+        }
+        self.corruption = None;
+        self.read_ctrlpage()
+    }
+
+    /// Size of the store in bytes.
+    pub fn store_len(&self) -> io::Result<u64> {
+        self.store.len()
+    }
+
+    /// Whether the store holds no pages yet, ie. the table is new.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        self.store.is_empty()
+    }
+
+    /// Resizes the buffer pool to `pages` pages (at least
+    /// `MIN_BUFFERS`). Shrinking writes back and drops the least
+    /// recently used unpinned pages; it fails if that would drop a
+    /// pinned page.
+    pub fn set_pool_size(&mut self, pages: usize) -> io::Result<()> {
+        let pages = pages.max(MIN_BUFFERS);
+        while self.buffers.len() > pages {
+            let victim = self.lru_victim().ok_or_else(|| io::Error::other(
+                "cannot shrink the buffer pool below its pinned pages"))?;
+            if self.buffers[victim].dirty {
+                self.write_buffer_page(victim)?;
+            }
+            self.buffers.remove(victim);
+        }
+        while self.buffers.len() < pages {
+            let page = self.blank_page();
+            self.buffers.push_back(page);
+        }
+        Ok(())
+    }
+
+    /// The least recently used unpinned buffer.
+    fn lru_victim(&self) -> Option<usize> {
+        self.buffers.iter().enumerate()
+            .filter(|&(_, p)| p.pin_count == 0)
+            .min_by_key(|&(_, p)| p.last_used)
+            .map(|(i, _)| i)
+    }
+
+    /// Writes every dirty cached page back to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for b in 0..self.buffers.len() {
+            if self.buffers[b].dirty {
+                self.write_buffer_page(b)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Caps the file at `max_pages` pages (including the control
+    /// page). Allocations beyond the cap fail with
+    /// `ErrorKind::StorageFull`, exactly as if the disk were full.
+    pub fn set_max_pages(&mut self, max_pages: Option<usize>) {
+        self.max_pages = max_pages;
+    }
+
+    /// Width of the key slot in every record.
+    pub fn keysize(&self) -> usize {
+        self.keysize
+    }
+
+    /// Width of the value slot in every record.
+    pub fn valsize(&self) -> usize {
+        self.valsize
+    }
+
+    /// Path of the backing file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the ctrl page last read or written.
+    pub fn ctrl_seq(&self) -> usize {
+        self.ctrl_seq
+    }
+
+    // Control page layout (version 6):
     //
     // | nbits | nitems | nbuckets | num_pages | free_list root |
-    // num_free | bucket_to_page mappings .... |
-    pub fn read_ctrlpage(&mut self) -> (usize, usize, usize) {
-        self.get_ctrl_page();
-        let nbits : usize = bytearray_to_usize(self.ctrl_buffer.storage[0..8].to_vec());
-        let nitems : usize =
-            bytearray_to_usize(self.ctrl_buffer.storage[8..16].to_vec());
-        let nbuckets : usize =
-            bytearray_to_usize(self.ctrl_buffer.storage[16..24].to_vec());
-
-        self.num_pages =
-            bytearray_to_usize(self.ctrl_buffer.storage[24..32].to_vec());
-        let free_list_head = bytearray_to_usize(self.ctrl_buffer.storage[32..40].to_vec());
+    // num_free | layout | bucket_to_page mappings .... | map pages |
+    // geometry | checksum | shadow | seq | hash check | hash seed |
+    // page layout | open flag | meta | meta_len | file_id | epoch |
+    //
+    // Each region has a fixed extent; the map holds `CTRL_MAP_ENTRIES`
+    // entries, and the rest of them are in the chain of map pages that
+    // starts at the page in the map pages word. Version 5 had no map
+    // pages, and its map ran up to the geometry. Version 4 had no
+    // geometry, and its map ran up to the checksum. Version 3 had no checksum, shadow flag or
+    // sequence number, and its map ran up to the hash check. Version 2
+    // had no hash seed or check either, and its map ran up to the page
+    // layout word.
+    //
+    // Older files have no layout word and the map at byte 48. In
+    // layout 1 it ended where the open flag starts, and the tail fields
+    // were as above; in the original layout the map ran to the end of
+    // the page and there was no tail, which reads the same as a layout
+    // 1 tail of zeroes as long as the map is short enough. Such files
+    // are read here and rewritten in the current layout by the next
+    // `write_ctrlpage`.
+    pub fn read_ctrlpage(&mut self) -> io::Result<(usize, usize, usize)> {
+        self.get_ctrl_page()?;
+        let version = format_version(&self.ctrl_buffer.storage);
+        match version {
+            Some(FORMAT_VERSION) => (),
+            Some(v) if v > FORMAT_VERSION => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has format version {}, newer than this library supports ({})",
+                        self.path.display(), v, FORMAT_VERSION))),
+            Some(v) => self.upgrade(v)?,
+            None => self.upgrade(1)?,
+        }
+        let ctrl = &self.ctrl_buffer.storage;
+        if version >= Some(4) && !ctrl_intact(ctrl) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "{}: control page is corrupt", self.path.display())));
+        }
+        let map_start = map_start(ctrl);
+        self.legacy_layout = map_start != CTRL_MAP_START;
+        let field = |i: usize| read_usize_at(ctrl, i * USIZE_WIDTH)
+            .expect("ctrl page too short");
+        let nbits = field(0);
+        let nitems = field(1);
+        let nbuckets = field(2);
+
+        self.num_pages = field(3);
+        let free_list_head = field(4);
         self.free_list =
             if free_list_head == 0 {
                 None
             } else {
                 Some(free_list_head)
             };
-        self.num_free =
-            bytearray_to_usize(self.ctrl_buffer.storage[40..48].to_vec());
-        self.bucket_to_page =
-            bytevec_to_usize_vec(self.ctrl_buffer.storage[48..PAGE_SIZE].to_vec());
-        (nbits, nitems, nbuckets)
-    }
-
-    pub fn write_ctrlpage(&mut self,
-                          (nbits, nitems, nbuckets):
-                          (usize, usize, usize)) {
-        self.get_ctrl_page();
-
-        let nbits_bytes = usize_to_bytearray(nbits);
-        let nitems_bytes = usize_to_bytearray(nitems);
-        let nbuckets_bytes = usize_to_bytearray(nbuckets);
-        let num_pages_bytes = usize_to_bytearray(self.num_pages);
-        let free_list_bytes = usize_to_bytearray(self.free_list.unwrap_or(0));
-        let num_free_bytes = usize_to_bytearray(self.num_free);
-        let bucket_to_page_bytevec = usize_vec_to_bytevec(self.bucket_to_page.clone());
-        let mut bucket_to_page_bytearray = vec![];
-        bucket_to_page_bytearray.write(&bucket_to_page_bytevec)
-            .expect("Write to ctrlpage failed");
-
-        println!("nbits: {:?} nitems: {:?} nbuckets: {:?}", nbits_bytes,
-                 nitems_bytes, nbuckets_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[0..8],
-                 &nbits_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[8..16],
-                 &nitems_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[16..24],
-                 &nbuckets_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[24..32],
-                 &num_pages_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[32..40],
-                 &free_list_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[40..48],
-                 &num_free_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[48..PAGE_SIZE],
-                 &bucket_to_page_bytearray);
-        DbFile::write_page(&mut self.file,
-                           0,
-                           &self.ctrl_buffer.storage);
-    }
-
-    pub fn get_ctrl_page(&mut self) {
-        self.file.seek(SeekFrom::Start(0))
-            .expect("Could not seek to offset");
-        self.file.read(&mut self.ctrl_buffer.storage)
-            .expect("Could not read file");
+        self.num_free = field(5);
+        let map_end = if version >= Some(6) { CTRL_MAP_END } else { PAGE_SIZE };
+        self.bucket_to_page = bytevec_to_usize_vec(&ctrl[map_start..map_end])
+            .expect("bucket map is not a whole number of entries");
+        // the rest of the map region is unused
+        self.bucket_to_page.truncate(nbuckets);
+        let first_map_page = if version >= Some(6) {
+            read_usize_at(ctrl, CTRL_MAP_PAGES).expect("ctrl page too short")
+        } else {
+            0
+        };
+        self.read_map_pages(first_map_page, nbuckets)?;
+        self.check_ctrlpage(nbuckets, version.is_some())?;
+        let ctrl = &self.ctrl_buffer.storage;
+        if version.is_none() && map_start + nbuckets * USIZE_WIDTH > CTRL_OPEN_FLAG {
+            // an original-layout map reaching into today's tail fields
+            self.shadow = false;
+            self.hasher_id = SIP_HASHER_ID;
+            self.header = Some((nbits, nitems, nbuckets));
+            return Ok((nbits, nitems, nbuckets));
+        }
+        let (page_layout, page_checksums, hasher_id, split_threshold, duplicate_keys,
+             large_values, partial_expansions, extended_headers, page_lsns) =
+            if self.legacy_layout {
+                (PageLayout::Row, false, 0, 0, false, false, false, false, false)
+            } else {
+                let word = read_u64_at(ctrl, CTRL_PAGE_LAYOUT)
+                    .expect("ctrl page too short");
+                let layout = PageLayout::from_word((word & LAYOUT_MASK) as usize)
+                    .ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: unknown page layout {}", self.path.display(), word)))?;
+                (layout, word & PAGE_CHECKSUMS != 0, (word >> HASHER_SHIFT) as u16,
+                 (word >> THRESHOLD_SHIFT) as usize & MAX_SPLIT_THRESHOLD,
+                 word & DUPLICATE_KEYS != 0, word & LARGE_VALUES != 0,
+                 word & PARTIAL_EXPANSIONS != 0, word & EXTENDED_HEADERS != 0,
+                 word & PAGE_LSNS != 0)
+            };
+        self.stored_record_size = None;
+        if version >= Some(5) {
+            let word = read_u64_at(ctrl, CTRL_GEOMETRY).expect("ctrl page too short");
+            let (keysize, valsize, page_size) = split_geometry(word);
+            if page_size != PAGE_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} has {}-byte pages; this build reads {}-byte ones",
+                    self.path.display(), page_size, PAGE_SIZE)));
+            }
+            self.stored_record_size = Some((keysize, valsize));
+        }
+        if version >= Some(4) {
+            self.ctrl_seq = read_usize_at(ctrl, CTRL_SEQ)
+                .expect("ctrl page too short");
+            self.shadow = read_usize_at(ctrl, CTRL_SHADOW)
+                .expect("ctrl page too short") != 0;
+        } else {
+            self.ctrl_seq = 0;
+            self.shadow = false;
+        }
+        if version >= Some(3) {
+            self.hash_seed = read_u64_at(ctrl, CTRL_HASH_SEED)
+                .expect("ctrl page too short");
+            let check = read_u64_at(ctrl, CTRL_HASH_CHECK)
+                .expect("ctrl page too short");
+            if hasher_id == STABLE_HASHER_ID && check != seed_check(self.hash_seed) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{}: hash seed check failed; the file was written with an \
+                     incompatible hash function", self.path.display())));
+            }
+        }
+
+        self.epoch = read_usize_at(ctrl, CTRL_EPOCH)
+            .expect("ctrl page too short");
+        let file_id = read_usize_at(ctrl, CTRL_FILE_ID)
+            .expect("ctrl page too short");
+        if file_id != 0 {
+            self.file_id = file_id;
+        }
+        let meta_len = read_usize_at(ctrl, CTRL_META_LEN)
+            .expect("ctrl page too short")
+            .min(META_SIZE);
+        self.meta = ctrl[CTRL_META..CTRL_META + meta_len].to_vec();
+        self.open_flag = read_usize_at(ctrl, CTRL_OPEN_FLAG)
+            .expect("ctrl page too short") != 0;
+        self.page_layout = page_layout;
+        self.set_headers(extended_headers, page_lsns);
+        self.set_page_checksums(page_checksums);
+        self.hasher_id = hasher_id;
+        self.split_threshold = split_threshold;
+        self.duplicate_keys = duplicate_keys;
+        self.large_values = large_values;
+        self.partial_expansions = partial_expansions;
+        self.header = Some((nbits, nitems, nbuckets));
+        self.ctrl_epoch = Some(self.epoch);
+        Ok((nbits, nitems, nbuckets))
+    }
+
+    /// Reads the chain of map pages from `page_id` on, taking the
+    /// entries of the buckets past those the ctrl page holds, up to
+    /// `nbuckets`.
+    fn read_map_pages(&mut self, mut page_id: usize, nbuckets: usize) -> io::Result<()> {
+        self.map_pages.clear();
+        let mut page = vec![0; PAGE_SIZE];
+        while page_id != 0 {
+            // a corrupt link could point anywhere, or loop
+            if page_id < self.ctrl_pages() || page_id >= self.num_pages
+                || self.map_pages.contains(&page_id) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{}: map page {} is out of place", self.path.display(), page_id)));
+            }
+            self.store.read_page(page_id, &mut page)?;
+            counter!(PAGES_READ, 1);
+            self.map_pages.push(page_id);
+            let entries = bytevec_to_usize_vec(&page).expect("page is whole words");
+            let wanted = nbuckets.saturating_sub(self.bucket_to_page.len()).min(MAP_PAGE_ENTRIES);
+            self.bucket_to_page.extend_from_slice(&entries[1..1 + wanted]);
+            page_id = entries[0];
+        }
+        self.map_synced = self.bucket_to_page.len();
+        Ok(())
+    }
+
+    /// Writes the entries of the bucket map that changed since the map
+    /// pages were last written, first adding the map pages it outgrew.
+    /// Those come from past the end of the file, like `reserve_pages`,
+    /// and not from the free list: then whatever is on disk as the ctrl
+    /// page links none of them until the next ctrl page write.
+    fn write_map_pages(&mut self) -> io::Result<()> {
+        let spilled = self.bucket_to_page.len().saturating_sub(CTRL_MAP_ENTRIES);
+        while self.map_pages.len() * MAP_PAGE_ENTRIES < spilled {
+            let page_id = self.num_pages;
+            if self.max_pages.is_some_and(|max| page_id >= max) {
+                return Err(io::Error::new(io::ErrorKind::StorageFull,
+                                          "page limit reached"));
+            }
+            self.store.allocate(page_id)?;
+            if self.free_list == Some(page_id) {
+                self.free_list = Some(page_id + 1);
+            }
+            self.num_pages += 1;
+            self.epoch += 1;
+            trace!(page = page_id; "allocating map page");
+            // the page before links to the new one
+            let linked = CTRL_MAP_ENTRIES + self.map_pages.len().saturating_sub(1) * MAP_PAGE_ENTRIES;
+            self.map_synced = self.map_synced.min(linked);
+            self.map_pages.push(page_id);
+        }
+        if self.map_synced >= self.bucket_to_page.len() {
+            return Ok(());
+        }
+        let first = self.map_synced.saturating_sub(CTRL_MAP_ENTRIES) / MAP_PAGE_ENTRIES;
+        let chunks = self.bucket_to_page.get(CTRL_MAP_ENTRIES..).unwrap_or(&[])
+            .chunks(MAP_PAGE_ENTRIES);
+        for (i, entries) in chunks.enumerate().skip(first) {
+            let mut page = vec![0; PAGE_SIZE];
+            let mut words = vec![self.map_pages.get(i + 1).cloned().unwrap_or(0)];
+            words.extend_from_slice(entries);
+            mem_move(&mut page, &usize_vec_to_bytevec(&words))
+                .expect("map page entries fit in a page");
+            self.store.write_page(self.map_pages[i], &page)?;
+            counter!(PAGES_WRITTEN, 1);
+        }
+        self.map_synced = self.bucket_to_page.len();
+        Ok(())
+    }
+
+    /// Writes the ctrl page: to whichever copy the last write did not
+    /// go to, if the file has a shadow copy.
+    pub fn write_ctrlpage(&mut self, header: (usize, usize, usize))
+                          -> io::Result<()> {
+        self.write_map_pages()?;
+        self.ctrl_seq += 1;
+        self.fill_ctrlpage(header);
+        let slot = if self.shadow && self.ctrl_seq.is_multiple_of(2) { SHADOW_PAGE } else { 0 };
+        self.store.write_page(slot, &self.ctrl_buffer.storage)?;
+        counter!(PAGES_WRITTEN, 1);
+        self.ctrl_epoch = Some(self.epoch);
+        Ok(())
+    }
+
+    /// Like `write_ctrlpage`, but skips the write unless pages were
+    /// allocated or freed since the ctrl page was last written: then
+    /// only the client header changed, which the next `write_ctrlpage`
+    /// (or drop) stores. After a crash the open flag tells the client
+    /// to recount it.
+    pub fn update_ctrlpage(&mut self, header: (usize, usize, usize))
+                           -> io::Result<()> {
+        if self.ctrl_epoch == Some(self.epoch) {
+            self.header = Some(header);
+            return Ok(());
+        }
+        self.write_ctrlpage(header)
+    }
+
+    /// Brings a ctrl page of format `version` up to `FORMAT_VERSION`.
+    /// Each older version gets an arm that rewrites the ctrl page
+    /// buffer in memory (or arranges for `read_ctrlpage` to read it
+    /// correctly); the next ctrl page write stores the current format.
+    fn upgrade(&mut self, version: usize) -> io::Result<()> {
+        match version {
+            // untagged: the map is read from `LEGACY_MAP_START`
+            1 => Ok(()),
+            // no hash seed; the hasher id is kept, 0 unless one was chosen
+            2 => Ok(()),
+            // no checksum; page 1 belongs to a bucket, so the file goes
+            // on without a shadow copy
+            3 => Ok(()),
+            // no geometry: the sizes given on open are taken on trust
+            4 => Ok(()),
+            // no map pages: the map ran up to the geometry
+            5 => Ok(()),
+            v => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has unknown format version {}", self.path.display(), v))),
+        }
+    }
+
+    /// Rejects a ctrl page whose page counts and bucket map cannot
+    /// describe this file. For an untagged ctrl page this is all that
+    /// tells an old linhash file from any other file.
+    fn check_ctrlpage(&self, nbuckets: usize, tagged: bool) -> io::Result<()> {
+        let sound = self.bucket_to_page.len() == nbuckets
+            && self.num_pages >= 1
+            && self.free_list.is_none_or(|head| head <= self.num_pages)
+            && self.num_free < self.num_pages
+            && self.bucket_to_page.iter().all(|&p| p >= 1 && p < self.num_pages);
+        if sound {
+            return Ok(());
+        }
+        let msg = if tagged {
+            format!("{}: control page is corrupt", self.path.display())
+        } else {
+            format!("{} is not a linhash file", self.path.display())
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Serializes the current metadata into the ctrl page buffer.
+    fn fill_ctrlpage(&mut self,
+                     (nbits, nitems, nbuckets):
+                     (usize, usize, usize)) {
+        self.header = Some((nbits, nitems, nbuckets));
+
+        trace!(nbits = nbits, nitems = nitems, nbuckets = nbuckets; "ctrl page");
+        let fields = [nbits, nitems, nbuckets, self.num_pages,
+                      self.free_list.unwrap_or(0), self.num_free];
+        let ctrl = &mut self.ctrl_buffer.storage;
+        for (i, &f) in fields.iter().enumerate() {
+            write_usize_at(ctrl, i * USIZE_WIDTH, f)
+                .expect("ctrl page too short");
+        }
+        write_u64_at(ctrl, CTRL_LAYOUT, LAYOUT_WORD)
+            .expect("ctrl page too short");
+        let inline = self.bucket_to_page.len().min(CTRL_MAP_ENTRIES);
+        mem_move(&mut ctrl[CTRL_MAP_START..CTRL_MAP_END],
+                 &usize_vec_to_bytevec(&self.bucket_to_page[..inline]))
+            .expect("bucket map does not fit in ctrl page");
+        write_usize_at(ctrl, CTRL_MAP_PAGES, self.map_pages.first().cloned().unwrap_or(0))
+            .expect("ctrl page too short");
+        mem_move(&mut ctrl[CTRL_META..CTRL_META_LEN], &self.meta)
+            .expect("metadata longer than META_SIZE");
+        for b in &mut ctrl[CTRL_META + self.meta.len()..CTRL_META_LEN] {
+            *b = 0;
+        }
+        write_usize_at(ctrl, CTRL_META_LEN, self.meta.len())
+            .expect("ctrl page too short");
+        write_usize_at(ctrl, CTRL_OPEN_FLAG, self.open_flag as usize)
+            .expect("ctrl page too short");
+        let checksums = if self.page_checksums { PAGE_CHECKSUMS } else { 0 };
+        let threshold = (self.split_threshold as u64) << THRESHOLD_SHIFT;
+        let hasher = (self.hasher_id as u64) << HASHER_SHIFT;
+        let duplicates = if self.duplicate_keys { DUPLICATE_KEYS } else { 0 };
+        let large_values = if self.large_values { LARGE_VALUES } else { 0 };
+        let partial = if self.partial_expansions { PARTIAL_EXPANSIONS } else { 0 };
+        let extended = if self.extended_headers { EXTENDED_HEADERS } else { 0 };
+        let lsns = if self.page_lsns { PAGE_LSNS } else { 0 };
+        write_u64_at(ctrl, CTRL_PAGE_LAYOUT, self.page_layout.to_word() as u64 | checksums
+                       | threshold | hasher | duplicates | large_values | partial | extended
+                       | lsns)
+            .expect("ctrl page too short");
+        write_u64_at(ctrl, CTRL_HASH_SEED, self.hash_seed)
+            .expect("ctrl page too short");
+        write_u64_at(ctrl, CTRL_HASH_CHECK, seed_check(self.hash_seed))
+            .expect("ctrl page too short");
+        write_usize_at(ctrl, CTRL_FILE_ID, self.file_id)
+            .expect("ctrl page too short");
+        write_usize_at(ctrl, CTRL_EPOCH, self.epoch)
+            .expect("ctrl page too short");
+        write_usize_at(ctrl, CTRL_SEQ, self.ctrl_seq)
+            .expect("ctrl page too short");
+        write_usize_at(ctrl, CTRL_SHADOW, self.shadow as usize)
+            .expect("ctrl page too short");
+        write_u64_at(ctrl, CTRL_GEOMETRY, geometry_word(self.keysize, self.valsize))
+            .expect("ctrl page too short");
+        stamp_checksum(ctrl);
+    }
+
+    /// Journals everything a split of `bucket_ids` may overwrite: the
+    /// control page as of `header`, the buckets' chains, and the page
+    /// the new bucket will get if it is a recycled one. See `journal`.
+    /// Compacting a bucket is journaled the same way. Only a table
+    /// split in partial expansions splits more than one bucket at once.
+    pub fn begin_split(&mut self, bucket_ids: &[usize],
+                       header: (usize, usize, usize)) -> io::Result<()> {
+        self.journal_buckets(bucket_ids, header)
+    }
+
+    /// Like `begin_split`, for merging the last bucket, `bucket_id`,
+    /// into `siblings`: journals every chain. The pages the merged
+    /// records need come from the last bucket's freed chain, or else
+    /// the journaled head of the free list.
+    pub fn begin_merge(&mut self, bucket_id: usize, siblings: &[usize],
+                       header: (usize, usize, usize)) -> io::Result<()> {
+        let mut bucket_ids = vec![bucket_id];
+        bucket_ids.extend_from_slice(siblings);
+        self.journal_buckets(&bucket_ids, header)
+    }
+
+    /// Journals what adding an empty bucket may overwrite: the control
+    /// page as of `header`, and the recycled page it may get.
+    pub fn begin_grow(&mut self, header: (usize, usize, usize)) -> io::Result<()> {
+        let page_ids = self.free_list.filter(|&p| p < self.num_pages).into_iter().collect();
+        self.journal_pages(page_ids, header)
+    }
+
+    fn journal_buckets(&mut self, bucket_ids: &[usize],
+                       header: (usize, usize, usize)) -> io::Result<()> {
+        let mut page_ids = vec![];
+        for &bucket_id in bucket_ids {
+            page_ids.extend(self.all_records_in_bucket(bucket_id)
+                            .into_iter()
+                            .map(|(page_id, _)| page_id));
+        }
+        if let Some(next_free) = self.free_list {
+            if next_free < self.num_pages {
+                page_ids.push(next_free);
+            }
+        }
+        self.journal_pages(page_ids, header)
+    }
+
+    /// Journals the control page as of `header` and `page_ids`, if the
+    /// store has sidecar files.
+    fn journal_pages(&mut self, page_ids: Vec<usize>,
+                     header: (usize, usize, usize)) -> io::Result<()> {
+        // the image must not link map pages that are not written yet
+        self.write_map_pages()?;
+        self.fill_ctrlpage(header);
+        if !self.sidecar_files {
+            return Ok(());
+        }
+        let mut images = vec![(0, self.ctrl_buffer.storage.to_vec())];
+        for page_id in page_ids {
+            let buffer_index = self.fetch_page(page_id);
+            let page = &mut self.buffers[buffer_index];
+            page.write_header();
+            images.push((page_id, page.storage.to_vec()));
+        }
+        journal::write(journal::journal_path(&self.path), &images, self.cipher.as_ref())
+    }
+
+    /// A cipher for the table's sidecar files, if its store is
+    /// encrypted; see `PageStore::sidecar_cipher`.
+    pub fn sidecar_cipher(&self) -> Option<Cipher> {
+        self.store.sidecar_cipher()
+    }
+
+    /// Makes every page written so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.store.sync()
+    }
+
+    /// Makes a finished split durable, then retires its journal.
+    pub fn commit_split(&mut self, header: (usize, usize, usize))
+                        -> io::Result<()> {
+        self.flush()?;
+        self.write_ctrlpage(header)?;
+        self.store.sync()?;
+        if !self.sidecar_files {
+            return Ok(());
+        }
+        journal::clear(journal::journal_path(&self.path))
+    }
+
+    /// Rolls back a split interrupted by a crash, if the journal holds
+    /// one. Must run before the ctrl page is read. Returns whether
+    /// anything was restored.
+    pub fn recover_split(&mut self) -> io::Result<bool> {
+        if !self.sidecar_files {
+            return Ok(false);
+        }
+        let path = journal::journal_path(&self.path);
+        let restored = match journal::read(&path, self.cipher.as_ref())? {
+            Some(images) => {
+                for (page_id, image) in images {
+                    self.store.write_page(page_id, &image)?;
+                    // over both copies, lest the newer one win
+                    if page_id == 0 && format_version(&image) >= Some(4)
+                        && read_usize_at(&image, CTRL_SHADOW).unwrap_or(0) != 0 {
+                        self.store.write_page(SHADOW_PAGE, &image)?;
+                    }
+                }
+                self.store.sync()?;
+                true
+            },
+            None => false,
+        };
+        journal::clear(&path)?;
+        Ok(restored)
+    }
+
+    /// Reads the latest intact copy of the ctrl page into the ctrl
+    /// page buffer.
+    pub fn get_ctrl_page(&mut self) -> io::Result<()> {
+        let primary = self.store.read_page(0, &mut self.ctrl_buffer.storage);
+        // a file upgraded from before version 4 has a bucket page here,
+        // which never passes for an intact ctrl page
+        let mut shadow = vec![0; PAGE_SIZE];
+        if self.store.read_page(SHADOW_PAGE, &mut shadow).is_ok() && ctrl_intact(&shadow)
+            && (primary.is_err()
+                || latest_ctrl(&self.ctrl_buffer.storage, Some(&shadow)) == &shadow[..]) {
+            self.ctrl_buffer.storage.copy_from_slice(&shadow);
+            return Ok(());
+        }
+        primary
     }
 
     fn bucket_to_page(&self, bucket_id: usize) -> usize {
         self.bucket_to_page[bucket_id]
     }
 
+    /// First page of bucket `bucket_id`.
+    pub fn bucket_page(&self, bucket_id: usize) -> usize {
+        self.bucket_to_page(bucket_id)
+    }
+
     fn search_buffer_pool(&self, page_id: usize) -> Option<usize> {
         for (i, b) in self.buffers.iter().enumerate() {
             if b.id == page_id {
@@ -162,46 +1602,116 @@ impl DbFile {
 
     /// Reads page to self.buffer
     pub fn fetch_page(&mut self, page_id: usize) -> usize {
-        let bufpool_index = self.search_buffer_pool(page_id);
-        match bufpool_index {
-            None => {
-                match self.buffers.pop_front() {
-                    Some(mut old_page) => {
-                        if old_page.dirty {
-                            old_page.write_header();
-                            DbFile::write_page(&self.file,
-                                               old_page.id,
-                                               &old_page.storage);
-                        }
-                    },
-                    _ => (),
-                }
+        self.try_fetch_page(page_id)
+            .unwrap_or_else(|e| panic!("could not fetch page {}: {}", page_id, e))
+    }
+
+    /// Like `fetch_page`, but returns I/O errors from reading the page
+    /// or writing back the page it evicts. The buffer pool is
+    /// unchanged on error.
+    ///
+    /// The page takes the place of the least recently used unpinned
+    /// one, so the indices of other cached pages never change.
+    pub fn try_fetch_page(&mut self, page_id: usize) -> io::Result<usize> {
+        self.clock += 1;
+        if let Some(p) = self.search_buffer_pool(page_id) {
+            self.buffers[p].last_used = self.clock;
+            return Ok(p);
+        }
+        let victim = self.lru_victim()
+            .expect("buffer pool exhausted: every page is pinned");
 
-                let offset = (page_id * PAGE_SIZE) as u64;
-                let mut new_page = Page::new(self.keysize, self.valsize);
-                new_page.id = page_id;
-                let buffer_index = NUM_BUFFERS - 1;
+        let mut new_page = self.blank_page();
+        new_page.id = page_id;
+        new_page.last_used = self.clock;
+        self.store.read_page(page_id, &mut new_page.storage)?;
+        counter!(PAGES_READ, 1);
+        if self.page_checksums && !new_page.checksum_ok() {
+            return Err(self.checksum_error(page_id));
+        }
+
+        let seq = self.ctrl_seq + 1;
+        let lsn = self.lsn;
+        let old_page = &mut self.buffers[victim];
+        if old_page.dirty {
+            old_page.seq = seq;
+            old_page.lsn = lsn;
+            old_page.write_header();
+            self.store.write_page(old_page.id, &old_page.storage)?;
+            counter!(PAGES_WRITTEN, 1);
+        }
+
+        self.buffers[victim] = new_page;
+        self.buffers[victim].read_header();
+        self.check_header(victim);
+        Ok(victim)
+    }
 
-                self.file.seek(SeekFrom::Start(offset))
-                    .expect("Could not seek to offset");
-                self.file.read(&mut new_page.storage)
-                    .expect("Could not read file");
-                self.buffers.push_back(new_page);
-                self.buffers[buffer_index].read_header();
+    /// Catches page headers that cannot be right, recording the first
+    /// in `corruption`. The cached copy is trimmed to what can still be
+    /// read safely: no more records than fit, and no overflow link
+    /// outside the file. It is not marked dirty, so the trimming only
+    /// reaches disk if the page is modified.
+    fn check_header(&mut self, buffer_index: usize) {
+        let num_pages = self.num_pages;
+        let problem = trim_header(&mut self.buffers[buffer_index], num_pages);
+        self.note_problem(self.buffers[buffer_index].id, problem);
+    }
 
-                buffer_index
-            },
-            Some(p) => p,
+    /// Logs `problem` with page `page_id`, if any, and records it in
+    /// `corruption` unless an earlier one is.
+    fn note_problem(&mut self, page_id: usize, problem: Option<String>) {
+        if let Some(ref problem) = problem {
+            warn!(page = page_id; "{}: {}", self.path.display(), problem);
         }
+        if self.corruption.is_none() {
+            self.corruption = problem;
+        }
+    }
+
+    /// Notes that page `page_id` fails its checksum, returning the
+    /// error to fail its read with.
+    fn checksum_error(&mut self, page_id: usize) -> io::Error {
+        let problem = format!("page {} fails its checksum", page_id);
+        self.note_problem(page_id, Some(problem.clone()));
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("{}: {}", self.path.display(), problem))
     }
 
-    /// Writes data in `data` into page `page_id` in file.
-    pub fn write_page(mut file: &File, page_id: usize, data: &[u8]) {
-        let offset = (page_id * PAGE_SIZE) as u64;
-        file.seek(SeekFrom::Start(offset))
-            .expect("Could not seek to offset");
-        file.write(data).expect("write failed");
-        file.flush().expect("flush failed");
+    /// Reads up to `max` pages from `first` on in one go, into pages of
+    /// their own rather than the buffer pool, stopping before a page
+    /// the pool holds, whose cached copy may be newer. Pages are
+    /// checked as `try_fetch_page` checks them.
+    fn read_run(&mut self, first: usize, max: usize) -> io::Result<VecDeque<Page>> {
+        let mut count = 0;
+        while count < max && first + count < self.num_pages
+            && self.search_buffer_pool(first + count).is_none() {
+            count += 1;
+        }
+        let mut run: VecDeque<Page> = (0..count.max(1)).map(|_| self.blank_page()).collect();
+        {
+            let mut data: Vec<&mut [u8]> = run.iter_mut().map(|p| &mut p.storage[..]).collect();
+            self.store.read_pages(first, &mut data)?;
+            counter!(PAGES_READ, data.len());
+        }
+        let mut problems = vec![];
+        let mut failed = None;
+        for (i, page) in run.iter_mut().enumerate() {
+            page.id = first + i;
+            if self.page_checksums && !page.checksum_ok() {
+                failed = Some(page.id);
+                break;
+            }
+            page.read_header();
+            problems.push((page.id, trim_header(page, self.num_pages)));
+        }
+        for (page_id, problem) in problems {
+            self.note_problem(page_id, problem);
+        }
+        match failed {
+            Some(page_id) => Err(self.checksum_error(page_id)),
+            None => Ok(run),
+        }
     }
 
     /// Write record but don't increment `num_records`. Used when
@@ -220,9 +1730,11 @@ impl DbFile {
     /// new record.
     pub fn write_record_incr(&mut self, page_id: usize, row_num: usize,
                              key: &[u8], val: &[u8]) {
+        // counted after writing, so that a slotted page sees a new slot
         let buffer_index = self.fetch_page(page_id);
+        self.buffers[buffer_index].dirty = true;
+        self.buffers[buffer_index].write_record(row_num, key, val);
         self.buffers[buffer_index].incr_num_records();
-        self.write_record(page_id, row_num, key, val);
     }
 
     /// Searches for `key` in `bucket`. A bucket is a linked list of
@@ -236,32 +1748,178 @@ impl DbFile {
     ///
     ///   2. there is not enough space in last page, returns
     ///      (last_page_id, None, None)
-    pub fn search_bucket(&mut self, bucket_id: usize, key: &[u8]) -> SearchResult {
+    ///
+    /// While `bucket_id` is the new bucket of a split in progress, a
+    /// key not yet moved is found in the bucket being split.
+    pub fn search_bucket(&mut self, bucket_id: usize, key: &[u8])
+                         -> io::Result<SearchResult> {
+        Ok(self.find(bucket_id, key)?.1)
+    }
+
+    /// `search_bucket`, along with the bucket whose chain the key was
+    /// found in.
+    fn find(&mut self, bucket_id: usize, key: &[u8]) -> io::Result<(usize, SearchResult)> {
+        let found = self.scan_bucket(bucket_id, Some(key))?;
+        if found.val.is_none() {
+            if let Some(from) = self.split_source(bucket_id) {
+                let unmoved = self.scan_bucket(from, Some(key))?;
+                if unmoved.val.is_some() {
+                    return Ok((from, unmoved));
+                }
+            }
+        }
+        Ok((bucket_id, found))
+    }
+
+    /// The bucket being split into `bucket_id`, if a split is in
+    /// progress and has records left to move.
+    pub fn split_source(&self, bucket_id: usize) -> Option<usize> {
+        self.pending_split.as_ref().filter(|s| s.to == bucket_id).map(|s| s.from)
+    }
+
+    /// Page and row of the record with `key` in `bucket_id`, found
+    /// without copying records out of the pages. Under
+    /// `IoBackend::Mmap` pages the buffer pool does not hold are
+    /// searched in the map, without reading them into the pool.
+    pub fn locate(&mut self, bucket_id: usize, key: &[u8])
+                  -> io::Result<Option<(usize, usize)>> {
+        match self.locate_in(bucket_id, key)? {
+            None => match self.split_source(bucket_id) {
+                Some(from) => self.locate_in(from, key),
+                None => Ok(None),
+            },
+            found => Ok(found),
+        }
+    }
+
+    fn locate_in(&mut self, bucket_id: usize, key: &[u8])
+                 -> io::Result<Option<(usize, usize)>> {
+        let mut found = [None];
+        self.locate_many_in(bucket_id, &[key], &mut found)?;
+        Ok(found[0])
+    }
+
+    /// `locate` for several keys of `bucket_id`, walking its chain
+    /// once: `result[i]` is `locate(bucket_id, keys[i])`.
+    pub fn locate_many(&mut self, bucket_id: usize, keys: &[&[u8]])
+                       -> io::Result<Vec<Option<(usize, usize)>>> {
+        let mut found = vec![None; keys.len()];
+        self.locate_many_in(bucket_id, keys, &mut found)?;
+        if let Some(from) = self.split_source(bucket_id) {
+            if found.iter().any(Option::is_none) {
+                self.locate_many_in(from, keys, &mut found)?;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Fills in the entries of `found` still None with where the
+    /// first record with the matching key in `bucket_id` is.
+    fn locate_many_in(&mut self, bucket_id: usize, keys: &[&[u8]],
+                      found: &mut [Option<(usize, usize)>]) -> io::Result<()> {
+        let layout = self.page_layout;
+        let mut next = Some(self.bucket_to_page(bucket_id));
+        let mut chain_length = 0;
+        while let Some(page_id) = next {
+            if found.iter().all(Option::is_some) {
+                return Ok(());
+            }
+            chain_length += 1;
+            next = match self.mapped_view(page_id) {
+                Some(page) => {
+                    counter!(PAGES_READ, 1);
+                    let stored = (0..page.num_records).map(|row_num| page.record(row_num).0);
+                    note_matches(layout, keys, found, page_id, stored);
+                    page.next
+                },
+                None => {
+                    let buffer_index = self.try_fetch_page(page_id)?;
+                    let next_page = self.buffers[buffer_index].next;
+                    self.prefetch(next_page);
+                    let page = &self.buffers[buffer_index];
+                    let stored = (0..page.num_records).map(|row_num| page.read_record(row_num).0);
+                    note_matches(layout, keys, found, page_id, stored);
+                    next_page
+                },
+            };
+        }
+        histogram!(CHAIN_LENGTH, chain_length);
+        Ok(())
+    }
+
+    /// Where a new record would go in `bucket_id`, as `search_bucket`
+    /// reports it for a key not in the bucket. Used to add a record
+    /// with a key that may already be there.
+    pub fn free_row(&mut self, bucket_id: usize) -> io::Result<SearchResult> {
+        self.scan_bucket(bucket_id, None)
+    }
+
+    /// The values of every record with `key` in `bucket_id`, in chain
+    /// order.
+    pub fn values_of(&mut self, bucket_id: usize, key: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut values = vec![];
+        let source = self.split_source(bucket_id);
+        let layout = self.page_layout;
+        for bucket_id in Some(bucket_id).into_iter().chain(source) {
+            let mut next = Some(self.bucket_to_page(bucket_id));
+            while let Some(page_id) = next {
+                let buffer_index = self.try_fetch_page(page_id)?;
+                let page = &mut self.buffers[buffer_index];
+                for row_num in 0..page.num_records {
+                    let (k, v) = page.read_record(row_num);
+                    if layout.key_eq(k, key) {
+                        values.push(v.to_vec());
+                    }
+                }
+                next = page.next;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Lets the store start reading `page_id`, the next page of a
+    /// chain being walked, unless the buffer pool holds it.
+    fn prefetch(&mut self, page_id: Option<usize>) {
+        if let Some(page_id) = page_id.filter(|&p| self.search_buffer_pool(p).is_none()) {
+            self.store.prefetch(page_id);
+        }
+    }
+
+    /// Walks the chain of `bucket_id` comparing keys where they lie in
+    /// the buffer pool, so only the value of a match is copied.
+    fn scan_bucket(&mut self, bucket_id: usize, key: Option<&[u8]>)
+                   -> io::Result<SearchResult> {
+        let layout = self.page_layout;
         let mut page_id = self.bucket_to_page(bucket_id);
-        let mut buffer_index;
         let mut first_free_row = SearchResult {
             page_id: None,
             row_num: None,
             val: None,
         };
+        let mut chain_length = 0;
         loop {
-            buffer_index = self.fetch_page(page_id);
+            let buffer_index = self.try_fetch_page(page_id)?;
+            chain_length += 1;
             let next_page = self.buffers[buffer_index].next;
-            let page_records = self.all_records_in_page(page_id);
+            self.prefetch(next_page);
 
-            let len = page_records.len();
-            for (row_num, (k,v)) in page_records.into_iter().enumerate() {
-                if slices_eq(&k, key) {
-                    return SearchResult{
-                        page_id: Some(page_id),
-                        row_num: Some(row_num),
-                        val: Some(v)
+            let page = &mut self.buffers[buffer_index];
+            if let Some(key) = key {
+                for row_num in 0..page.num_records {
+                    let (k, v) = page.read_record(row_num);
+                    if layout.key_eq(k, key) {
+                        return Ok(SearchResult{
+                            page_id: Some(page_id),
+                            row_num: Some(row_num),
+                            val: Some(v.to_vec())
+                        })
                     }
                 }
             }
 
-            let row_num = if len < self.records_per_page {
-                Some(len)
+            // room for the widest record, as the caller may be a `put`
+            let row_num = if page.has_room(self.keysize, self.valsize) {
+                Some(page.num_records)
             } else {
                 None
             };
@@ -273,7 +1931,7 @@ impl DbFile {
                 (None, _) => {
                     first_free_row = SearchResult {
                         page_id: Some(page_id),
-                        row_num: row_num,
+                        row_num,
                         val: None,
                     }
                 },
@@ -287,16 +1945,98 @@ impl DbFile {
             }
         }
 
-        first_free_row
+        histogram!(CHAIN_LENGTH, chain_length);
+        Ok(first_free_row)
+    }
+
+    /// Removes the record with `key` from `bucket_id` and returns its
+    /// value. The freed slot is filled with the last record of the
+    /// same page; an overflow page left empty is unlinked from the
+    /// bucket and freed.
+    pub fn remove_record(&mut self, bucket_id: usize, key: &[u8])
+                         -> io::Result<Option<Vec<u8>>> {
+        let (bucket_id, SearchResult { page_id, row_num, val }) =
+            self.find(bucket_id, key)?;
+        match (page_id, row_num, val) {
+            (Some(page_id), Some(row_num), Some(val)) => {
+                let buffer_index = self.try_fetch_page(page_id)?;
+                self.buffers[buffer_index].remove_record(row_num);
+                if self.buffers[buffer_index].num_records == 0
+                    && page_id != self.bucket_to_page(bucket_id) {
+                    self.unlink_overflow(bucket_id, page_id);
+                }
+                Ok(Some(val))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes every record of the chain of `bucket_id` for which
+    /// `keep` is false, and returns how many there were. Each page is
+    /// dirtied at most once, and overflow pages left empty are freed.
+    pub fn retain_in_bucket(&mut self, bucket_id: usize,
+                            keep: &mut dyn FnMut(&[u8], &[u8]) -> bool)
+                            -> io::Result<usize> {
+        let root = self.bucket_to_page(bucket_id);
+        let mut removed = 0;
+        let mut emptied = vec![];
+        let mut next = Some(root);
+        while let Some(page_id) = next {
+            let buffer_index = self.try_fetch_page(page_id)?;
+            let page = &mut self.buffers[buffer_index];
+            // backwards, as a removal moves the last record into the gap
+            for row_num in (0..page.num_records).rev() {
+                let (k, v) = page.read_record(row_num);
+                if !keep(k, v) {
+                    page.remove_record(row_num);
+                    removed += 1;
+                }
+            }
+            if page.num_records == 0 && page_id != root {
+                emptied.push(page_id);
+            }
+            next = page.next.filter(|&p| p != 0);
+        }
+        for page_id in emptied {
+            self.unlink_overflow(bucket_id, page_id);
+        }
+        Ok(removed)
+    }
+
+    /// Removes overflow page `page_id` from the chain of `bucket_id`
+    /// and puts it on the free list.
+    fn unlink_overflow(&mut self, bucket_id: usize, page_id: usize) {
+        let mut prev = self.bucket_to_page(bucket_id);
+        loop {
+            match self.page(prev).next {
+                Some(next) if next == page_id => break,
+                Some(next) => prev = next,
+                None => panic!("page {} is not in bucket {}", page_id, bucket_id),
+            }
+        }
+        let after = self.page(page_id).next;
+        self.page_mut(prev).next = after;
+        self.free_page(page_id);
+        if let Some(split) = self.pending_split.as_mut().filter(|s| s.from == bucket_id) {
+            if split.page == Some(page_id) {
+                split.page = after;
+            }
+            if split.prev == Some(page_id) {
+                split.prev = Some(prev);
+            }
+        }
     }
 
-    /// Add a new overflow page to a `bucket`.
+    /// Add a new overflow page to a `bucket`. On error (eg. the disk
+    /// is full) the bucket is unchanged.
     pub fn allocate_overflow(&mut self, bucket_id: usize,
-                             last_page_id: usize) -> (usize, usize) {
-        let physical_index = self.allocate_new_page();
+                             last_page_id: usize) -> io::Result<(usize, usize)> {
+        let physical_index = self.allocate_page()?;
 
         let new_page_buffer_index = self.fetch_page(physical_index);
         self.buffers[new_page_buffer_index].next = None;
+        self.buffers[new_page_buffer_index].kind = PageType::Overflow;
+        self.buffers[new_page_buffer_index].owner = bucket_id;
         self.buffers[new_page_buffer_index].dirty = true;
 
         // Write next of old page
@@ -304,135 +2044,716 @@ impl DbFile {
         self.buffers[old_page_buffer_index].next = Some(physical_index);
         self.buffers[old_page_buffer_index].dirty = true;
 
-        println!("setting next of buffer_id {}(page_id: {}) to {:?}",
-                 bucket_id,
-                 self.buffers[old_page_buffer_index].id,
-                 self.buffers[old_page_buffer_index].next);
+        debug!(bucket = bucket_id, page = last_page_id, next = physical_index;
+               "new overflow page");
 
-        (physical_index, 0)
+        Ok((physical_index, 0))
     }
 
-    /// Write out page in bufferpool to file.
-    pub fn write_buffer_page(&mut self, buffer_index: usize) {
+    /// Write out page in bufferpool to file. It stays dirty if the
+    /// write fails, so a later flush retries it.
+    pub fn write_buffer_page(&mut self, buffer_index: usize) -> io::Result<()> {
         // Ignore page 0(ctrlpage)
         if self.buffers[buffer_index].id != 0 {
-            self.buffers[buffer_index].dirty = false;
+            self.buffers[buffer_index].seq = self.ctrl_seq + 1;
+            self.buffers[buffer_index].lsn = self.lsn;
             self.buffers[buffer_index].write_header();
-            DbFile::write_page(&mut self.file,
-                               self.buffers[buffer_index].id,
-                               &self.buffers[buffer_index].storage);
+            self.store.write_page(self.buffers[buffer_index].id,
+                                  &self.buffers[buffer_index].storage)?;
+            counter!(PAGES_WRITTEN, 1);
+            self.buffers[buffer_index].dirty = false;
         }
+        Ok(())
     }
 
-    fn all_records_in_page(&mut self, page_id: usize)
-                           -> Vec<(Vec<u8>, Vec<u8>)> {
-        let buffer_index = self.fetch_page(page_id);
+    pub(crate) fn all_records_in_page(&mut self, page_id: usize)
+                                      -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.project_records_in_page(page_id, |k, v| (k.to_vec(), v.to_vec()))
+    }
+
+    /// `project` of every record of a page, in row order.
+    fn project_records_in_page<T>(&mut self, page_id: usize, project: fn(&[u8], &[u8]) -> T)
+                                  -> io::Result<Vec<T>> {
+        let buffer_index = self.try_fetch_page(page_id)?;
         let mut page_records = vec![];
         for i in 0..self.buffers[buffer_index].num_records {
             let (k, v) = self.buffers[buffer_index].read_record(i);
-            let (dk, dv) = (k.to_vec(), v.to_vec());
-            page_records.push((dk, dv));
+            page_records.push(project(k, v));
         }
 
-        page_records
+        Ok(page_records)
+    }
+
+    /// Walks every bucket chain after an unclean shutdown, cutting
+    /// links that point outside the file or back into a chain already
+    /// walked, and clamping record counts to the page capacity. Returns
+    /// the number of records found, which replaces the item count from
+    /// the ctrl page.
+    pub fn repair_buckets(&mut self) -> usize {
+        let mut nrecords = 0;
+        let mut seen = HashSet::new();
+        for bucket_id in 0..self.bucket_to_page.len() {
+            let mut page_id = self.bucket_to_page(bucket_id);
+            loop {
+                seen.insert(page_id);
+                let (num_records, max_records, next, lsn) = {
+                    let page = self.page(page_id);
+                    (page.num_records, page.max_records(), page.next, page.lsn)
+                };
+                if num_records > max_records {
+                    self.page_mut(page_id).num_records = max_records;
+                }
+                // the page may have been written after a ctrl page write
+                // that never made it to disk
+                self.note_lsn(lsn);
+                nrecords += num_records.min(max_records);
+                match next {
+                    Some(0) | None => break,
+                    Some(next) if next < self.num_pages && !seen.contains(&next) =>
+                        page_id = next,
+                    Some(_) => {
+                        self.page_mut(page_id).next = None;
+                        break;
+                    },
+                }
+            }
+        }
+        nrecords
     }
 
     /// Returns a vec of (page_id, records_in_vec). ie. each inner
     /// vector represents the records in a page in the bucket.
-    fn all_records_in_bucket(&mut self, bucket_id: usize)
-                             -> Vec<(usize, Vec<(Vec<u8>,Vec<u8>)>)> {
+    ///
+    /// Overflow pages outside the buffer pool are read without being
+    /// cached, in runs: while the chain goes on to the next page of
+    /// the file, as after `fill_bucket`, each run reads twice as many
+    /// pages as the last, up to `CHAIN_READAHEAD`.
+    pub(crate) fn all_records_in_bucket(&mut self, bucket_id: usize)
+                             -> Vec<(usize, Vec<Record>)> {
         let first_page_id = self.bucket_to_page(bucket_id);
         let buffer_index = self.fetch_page(first_page_id);
         let mut records = Vec::new();
         records.push((self.buffers[buffer_index].id,
-                      self.all_records_in_page(first_page_id)));
+                      self.all_records_in_page(first_page_id)
+                          .expect("page is in the buffer pool")));
 
+        let mut prev_page = first_page_id;
         let mut next_page = self.buffers[buffer_index].next;
+        let mut run = VecDeque::new();
+        let mut readahead = 1;
         while let Some(page_id) = next_page {
             if page_id == 0 {
                 break;
             }
+            readahead = if page_id == prev_page + 1 {
+                (readahead * 2).min(CHAIN_READAHEAD)
+            } else {
+                1
+            };
+            prev_page = page_id;
 
-            let buffer_index = self.fetch_page(page_id);
-            records.push((page_id,
-                          self.all_records_in_page(page_id)));
-
-            next_page = self.buffers[buffer_index].next;
+            if let Some(buffer_index) = self.search_buffer_pool(page_id) {
+                records.push((page_id,
+                              self.all_records_in_page(page_id)
+                                  .expect("page is in the buffer pool")));
+                next_page = self.buffers[buffer_index].next;
+                continue;
+            }
+            if run.front().is_none_or(|p: &Page| p.id != page_id) {
+                run = self.read_run(page_id, readahead)
+                    .unwrap_or_else(|e| panic!("could not fetch page {}: {}", page_id, e));
+            }
+            let page = run.pop_front().expect("run starts with the page");
+            let mut page_records = vec![];
+            for i in 0..page.num_records {
+                let (k, v) = page.read_record(i);
+                page_records.push((k.to_vec(), v.to_vec()));
+            }
+            records.push((page_id, page_records));
+            next_page = page.next;
         }
 
         records
     }
 
-    /// Allocate a new page. If available uses recycled overflow
-    /// pages.
-    fn allocate_new_page(&mut self) -> usize {
+    /// Allocate a new, empty page. If available uses recycled
+    /// pages from the free list, otherwise extends the file.
+    ///
+    /// A page that extends the file is written out (as zeroes) before
+    /// anything else changes, so running out of disk space surfaces
+    /// here as an error, with the pager's state untouched, rather
+    /// than later when the page is evicted.
+    pub fn allocate_page(&mut self) -> io::Result<usize> {
         let p = self.free_list;
         let page_id = p.expect("no page in free_list");
-        println!("[allocate_new_page] allocating page_id: {}", page_id);
+        if page_id >= self.num_pages {
+            if self.max_pages.is_some_and(|max| page_id >= max) {
+                return Err(io::Error::new(io::ErrorKind::StorageFull,
+                                          "page limit reached"));
+            }
+            self.store.allocate(page_id)?;
+        }
+        self.epoch += 1;
+        trace!(page = page_id; "allocating page");
         let buffer_index = self.fetch_page(page_id);
 
-        self.free_list = match self.buffers[buffer_index].next {
-            Some(0) | None => {
-                self.num_pages += 1;
-                Some(self.num_pages)
-            },
-            _ => {
-                self.num_free -= 1;
-                self.buffers[buffer_index].next
-            },
+        self.free_list = if page_id >= self.num_pages {
+            self.num_pages += 1;
+            Some(self.num_pages)
+        } else {
+            // the last recycled page links nowhere: the list goes on
+            // with the first page past the end
+            self.num_free -= 1;
+            match self.buffers[buffer_index].next {
+                Some(0) | None => Some(self.num_pages),
+                next => next,
+            }
         };
 
-        let new_page = Page::new(self.keysize, self.valsize);
-        mem::replace(&mut self.buffers[buffer_index], new_page);
+        // A recycled page still holds its old contents on disk, so
+        // the blank page must be written back.
+        let new_page = self.blank_page();
+        self.buffers[buffer_index] = new_page;
         self.buffers[buffer_index].id = page_id;
-        self.buffers[buffer_index].dirty = false;
+        self.buffers[buffer_index].dirty = true;
         self.buffers[buffer_index].next = None;
 
-        page_id
+        Ok(page_id)
+    }
+
+    /// Makes sure allocating `n` pages cannot fail: the pages the free
+    /// list lacks are reserved in the store now, past its last page, as
+    /// `allocate_page` would. Nothing else changes, so a caller can call
+    /// this before changing anything and give up on error.
+    pub fn reserve_pages(&mut self, n: usize) -> io::Result<()> {
+        let short = n.saturating_sub(self.num_free);
+        for page_id in self.num_pages..self.num_pages + short {
+            if self.max_pages.is_some_and(|max| page_id >= max) {
+                return Err(io::Error::new(io::ErrorKind::StorageFull,
+                                          "page limit reached"));
+            }
+            self.store.allocate(page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `page_id` to the free list. Its contents are discarded.
+    pub fn free_page(&mut self, page_id: usize) {
+        let buffer_index = self.fetch_page(page_id);
+        let mut page = self.blank_page();
+        page.id = page_id;
+        // not a link to the first page past the end, which would read
+        // back as pointing outside the file
+        page.next = self.free_list.filter(|&p| p < self.num_pages);
+        page.kind = PageType::Free;
+        page.dirty = true;
+        self.buffers[buffer_index] = page;
+        self.free_list = Some(page_id);
+        self.num_free += 1;
+        self.epoch += 1;
+    }
+
+    /// The pages on the free list, head first.
+    fn free_pages(&mut self) -> io::Result<Vec<usize>> {
+        let mut pages = vec![];
+        let mut next = self.free_list;
+        while let Some(page_id) = next.filter(|&p| p < self.num_pages) {
+            // a corrupt list could loop
+            if pages.len() == self.num_free {
+                break;
+            }
+            pages.push(page_id);
+            let buffer_index = self.try_fetch_page(page_id)?;
+            next = self.buffers[buffer_index].next;
+        }
+        Ok(pages)
+    }
+
+    /// Makes `pages` the free list, blanking them, in page order: new
+    /// pages then come from the front of the file, leaving free ones at
+    /// the end for `truncate_free_tail`. Nothing is read, so unreadable
+    /// pages can be freed too.
+    pub fn set_free_pages(&mut self, mut pages: Vec<usize>) -> io::Result<()> {
+        pages.sort_unstable();
+        for (i, &page_id) in pages.iter().enumerate() {
+            let buffer_index = self.blank_buffer(page_id)?;
+            self.buffers[buffer_index].next = pages.get(i + 1).cloned();
+            self.buffers[buffer_index].kind = PageType::Free;
+        }
+        self.num_free = pages.len();
+        self.free_list = Some(pages.first().cloned().unwrap_or(self.num_pages));
+        self.epoch += 1;
+        Ok(())
+    }
+
+    /// Replaces page `page_id` with an empty one, to be written back,
+    /// without reading it. Returns its buffer.
+    pub fn blank_buffer(&mut self, page_id: usize) -> io::Result<usize> {
+        self.clock += 1;
+        let buffer_index = match self.search_buffer_pool(page_id) {
+            Some(i) => i,
+            None => {
+                let victim = self.lru_victim()
+                    .expect("buffer pool exhausted: every page is pinned");
+                if self.buffers[victim].dirty {
+                    self.write_buffer_page(victim)?;
+                }
+                victim
+            },
+        };
+        let mut page = self.blank_page();
+        page.id = page_id;
+        page.dirty = true;
+        page.last_used = self.clock;
+        self.buffers[buffer_index] = page;
+        Ok(buffer_index)
+    }
+
+    /// Gives free pages at the end of the file back to the store, which
+    /// shrinks to the last page still in use, unless a `SharedReader`
+    /// has the file mapped. The free pages left are
+    /// relinked (see `set_free_pages`) under the split journal, and the new page count made
+    /// durable before the store is truncated, so a crash at any point
+    /// leaves a sound file (at worst, one longer than it says).
+    /// Returns the number of pages given back.
+    pub fn truncate_free_tail(&mut self, header: (usize, usize, usize))
+                              -> io::Result<usize> {
+        if self.num_free == 0 {
+            return Ok(0);
+        }
+        // a `SharedReader`'s map would fault past the new end
+        let _readers = if self.has_file() && self.store.sidecar_files() {
+            match shared::exclude_readers(&self.path)? {
+                Some(lock) => Some(lock),
+                None => return Ok(0),
+            }
+        } else {
+            None
+        };
+        let mut free = self.free_pages()?;
+        let mut num_pages = self.num_pages;
+        while free.contains(&(num_pages - 1)) {
+            num_pages -= 1;
+        }
+        let trimmed = self.num_pages - num_pages;
+        if trimmed == 0 {
+            return Ok(0);
+        }
+        free.retain(|&p| p < num_pages);
+        self.journal_pages(free.clone(), header)?;
+        for page in self.buffers.iter_mut().filter(|p| p.id >= num_pages) {
+            page.id = 0;
+            page.dirty = false;
+        }
+        debug!(pages = trimmed; "{}: truncating free pages", self.path.display());
+        self.num_pages = num_pages;
+        self.set_free_pages(free)?;
+        self.commit_split(header)?;
+        self.store.truncate((num_pages * PAGE_SIZE) as u64)?;
+        Ok(trimmed)
+    }
+
+    /// Empties the file down to its ctrl pages and two blank buckets,
+    /// as if new. Journaled like a split as of `header`, with the pages
+    /// the new buckets take, so a crash leaves either the old table or
+    /// the empty one; `empty` is the client header committed with it.
+    /// The store is truncated last.
+    pub fn clear(&mut self, header: (usize, usize, usize), empty: (usize, usize, usize))
+                 -> io::Result<()> {
+        let first = self.ctrl_pages();
+        self.journal_pages(vec![first, first + 1], header)?;
+        for page in self.buffers.iter_mut() {
+            page.id = 0;
+            page.dirty = false;
+        }
+        self.blank_buffer(first)?;
+        self.blank_buffer(first + 1)?;
+        self.bucket_to_page = vec![first, first + 1];
+        self.tag_bucket(0);
+        self.tag_bucket(1);
+        self.map_pages.clear();
+        self.map_synced = 0;
+        self.num_pages = first + 2;
+        self.free_list = Some(self.num_pages);
+        self.num_free = 0;
+        self.epoch += 1;
+        self.pending_split = None;
+        self.commit_split(empty)?;
+        self.store.truncate((self.num_pages * PAGE_SIZE) as u64)
     }
 
     /// Empties out root page for bucket. Overflow pages are added to
     /// `free_list`
-    pub fn clear_bucket(&mut self, bucket_id: usize) -> Vec<(Vec<u8>,Vec<u8>)> {
+    pub fn clear_bucket(&mut self, bucket_id: usize) -> io::Result<Vec<Record>> {
         let all_records = self.all_records_in_bucket(bucket_id);
         let records = flatten(all_records.clone());
 
-        // Add overflow pages to free_list
-        let bucket_len = all_records.len();
-        if bucket_len > 1 {
-            // second page onwards are overflow pages
-            let (second_page_id, _) = all_records[1];
-            println!("[clear_bucket] adding overflow chain starting page {} to free_list", second_page_id);
-            let temp = self.free_list;
-            self.free_list = Some(second_page_id);
-
-            let second_page_buffer_index =
-                self.fetch_page(second_page_id);
-            // overflow pages only
-            self.num_free += bucket_len - 1;
-            self.buffers[second_page_buffer_index].next = temp;
+        // Add overflow pages(second page onwards) to free_list
+        for &(overflow_page_id, _) in all_records.iter().skip(1) {
+            trace!(bucket = bucket_id, page = overflow_page_id; "freeing overflow page");
+            self.free_page(overflow_page_id);
         }
 
         let page_id = self.bucket_to_page(bucket_id);
         let buffer_index = self.fetch_page(page_id);
-        let new_page = Page::new(self.keysize, self.valsize);
-        mem::replace(&mut self.buffers[buffer_index], new_page);
+        let new_page = self.blank_page();
+        self.buffers[buffer_index] = new_page;
         self.buffers[buffer_index].id = page_id;
-        self.buffers[buffer_index].dirty = false;
-        self.write_buffer_page(buffer_index);
+        // written back with the rest, by `commit_split` at the latest
+        self.tag_bucket(bucket_id);
 
-        records
+        Ok(records)
+    }
+
+    /// Whether a page of the chain of `bucket_id`, other than its last,
+    /// has room for another record, so that rewriting the chain with
+    /// `clear_bucket` and `fill_bucket` would pack it tighter.
+    pub fn chain_is_sparse(&mut self, bucket_id: usize) -> io::Result<bool> {
+        let mut page_id = self.bucket_to_page(bucket_id);
+        loop {
+            let buffer_index = self.try_fetch_page(page_id)?;
+            let page = &self.buffers[buffer_index];
+            match page.next {
+                Some(_) if page.has_room(self.keysize, self.valsize) => return Ok(true),
+                Some(next) => page_id = next,
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Number of pages in the chain of `bucket_id`.
+    pub fn chain_length(&mut self, bucket_id: usize) -> io::Result<usize> {
+        let mut page_id = self.bucket_to_page(bucket_id);
+        let mut len = 1;
+        loop {
+            let buffer_index = self.try_fetch_page(page_id)?;
+            match self.buffers[buffer_index].next {
+                Some(next) => page_id = next,
+                None => return Ok(len),
+            }
+            len += 1;
+        }
+    }
+
+    /// Drops the last bucket from the map and frees its pages, root
+    /// included. Returns its records, to be merged into its sibling.
+    pub fn remove_last_bucket(&mut self) -> io::Result<Vec<Record>> {
+        let bucket_id = self.bucket_to_page.len() - 1;
+        let all_records = self.all_records_in_bucket(bucket_id);
+        for &(page_id, _) in &all_records {
+            trace!(bucket = bucket_id, page = page_id; "freeing merged page");
+            self.free_page(page_id);
+        }
+        self.bucket_to_page.pop();
+        self.map_synced = self.map_synced.min(bucket_id);
+        Ok(flatten(all_records))
     }
 
-    pub fn allocate_new_bucket(&mut self) {
-        let page_id = self.allocate_new_page();
+    /// Adds a bucket with a fresh root page. On error the bucket map
+    /// is unchanged.
+    pub fn allocate_new_bucket(&mut self) -> io::Result<()> {
+        let page_id = self.allocate_page()?;
         self.bucket_to_page.push(page_id);
+        self.tag_bucket(self.bucket_to_page.len() - 1);
+        Ok(())
+    }
+
+    /// Labels the first page of `bucket_id` as such, in its extended
+    /// header (see `page::PageType`), and marks it dirty.
+    pub fn tag_bucket(&mut self, bucket_id: usize) {
+        let page = self.page_mut(self.bucket_to_page(bucket_id));
+        page.kind = PageType::Bucket;
+        page.owner = bucket_id;
+    }
+
+    /// Labels `pages`, the chain of `bucket_id` in order, as its pages.
+    pub fn tag_chain(&mut self, bucket_id: usize, pages: &[usize]) {
+        for (i, &page_id) in pages.iter().enumerate() {
+            let page = self.page_mut(page_id);
+            page.kind = if i == 0 { PageType::Bucket } else { PageType::Overflow };
+            page.owner = bucket_id;
+        }
+    }
+
+    /// Appends `records` to bucket `bucket_id`, whose keys must not be
+    /// in it yet, filling its last page and then new overflow pages in
+    /// order. Unlike `insert`, nothing is searched, so a bulk load
+    /// touches each page once.
+    pub fn fill_bucket<I>(&mut self, bucket_id: usize, records: I) -> io::Result<()>
+        where I: IntoIterator<Item = Record> {
+        let mut page_id = self.bucket_to_page(bucket_id);
+        while let Some(next) = self.page(page_id).next {
+            page_id = next;
+        }
+        for (key, val) in records {
+            let buffer_index = self.try_fetch_page(page_id)?;
+            let (keysize, valsize) = (self.keysize, self.valsize);
+            if !self.buffers[buffer_index].has_room(keysize, valsize) {
+                page_id = self.allocate_overflow(bucket_id, page_id)?.0;
+            }
+            let row_num = self.page(page_id).num_records;
+            self.write_record_incr(page_id, row_num, &key, &val);
+        }
+        Ok(())
+    }
+
+    /// Starts splitting `from` into the new, empty bucket `to` a page
+    /// at a time, by `split_step`. Until the last page of `from` is
+    /// done, `search_bucket` and the like look for the keys of `to` in
+    /// both chains.
+    pub fn start_split(&mut self, from: usize, to: usize) {
+        let page = Some(self.bucket_to_page(from));
+        self.pending_split = Some(PendingSplit { from, to, page, prev: None });
+    }
+
+    /// The buckets split from and into, while a split is in progress.
+    pub fn pending_split(&self) -> Option<(usize, usize)> {
+        self.pending_split.as_ref().map(|s| (s.from, s.to))
+    }
+
+    /// Moves the records of the next page of the split in progress for
+    /// which `moves` is true to the end of the new bucket, and returns
+    /// their keys. The page is freed if that leaves an overflow page
+    /// empty. Bracketed like a split, with only the pages it changes
+    /// journaled; a page with nothing to move changes nothing.
+    pub fn split_step(&mut self, moves: &mut dyn FnMut(&[u8]) -> bool,
+                      header: (usize, usize, usize)) -> io::Result<Vec<Vec<u8>>> {
+        let (from, to, page_id, prev) = match self.pending_split {
+            Some(PendingSplit { from, to, page: Some(page), prev }) => (from, to, page, prev),
+            _ => {
+                self.pending_split = None;
+                return Ok(vec![]);
+            },
+        };
+        let buffer_index = self.try_fetch_page(page_id)?;
+        let page = &mut self.buffers[buffer_index];
+        let mut rows = vec![];
+        let mut records = vec![];
+        for row_num in 0..page.num_records {
+            let (k, v) = page.read_record(row_num);
+            if moves(k) {
+                rows.push(row_num);
+                records.push((k.to_vec(), v.to_vec()));
+            }
+        }
+        let next = page.next;
+        let emptied = !rows.is_empty() && rows.len() == page.num_records
+            && page_id != self.bucket_to_page(from);
+        if !records.is_empty() {
+            let mut last = self.bucket_to_page(to);
+            while let Some(next) = self.try_page(last)?.next {
+                last = next;
+            }
+            let mut page_ids = vec![page_id, last];
+            page_ids.extend(prev.filter(|_| emptied));
+            page_ids.extend(self.free_list.filter(|&p| p < self.num_pages));
+            self.journal_pages(page_ids, header)?;
+            // the records came from one page, so they fit in a new one:
+            // once it is allocated nothing can fail
+            if !self.try_page(last)?.has_room_for(&records) {
+                last = self.allocate_overflow(to, last)?.0;
+            }
+            let buffer_index = self.fetch_page(page_id);
+            let page = &mut self.buffers[buffer_index];
+            // backwards, as a removal moves the last record into the gap
+            for &row_num in rows.iter().rev() {
+                page.remove_record(row_num);
+            }
+            for (key, val) in &records {
+                let row_num = self.page(last).num_records;
+                self.write_record_incr(last, row_num, key, val);
+            }
+            if emptied {
+                let prev = prev.expect("an overflow page has a page before it");
+                self.page_mut(prev).next = next;
+                self.free_page(page_id);
+            }
+            self.commit_split(header)?;
+            debug!(bucket = from, page = page_id, into = to, moved = records.len();
+                   "split step");
+        }
+        let split = self.pending_split.as_mut().expect("split in progress");
+        split.page = next;
+        if !emptied {
+            split.prev = Some(page_id);
+        }
+        if next.is_none() {
+            self.pending_split = None;
+        }
+        Ok(records.into_iter().map(|(k, _)| k).collect())
+    }
+
+    /// Writes the buckets into `dest`, a new file of the same record
+    /// size, along with the settings kept in the ctrl page. Each bucket
+    /// is packed densely; the root pages come first, then the overflow
+    /// pages, and no page is free. `header` is the client's. `copied`
+    /// is called with the number of pages read so far after each
+    /// bucket; an error from it stops the copy.
+    pub fn copy_into<F>(&mut self, dest: &mut DbFile, header: (usize, usize, usize),
+                        mut copied: F) -> io::Result<()>
+        where F: FnMut(usize) -> io::Result<()> {
+        dest.page_layout = self.page_layout;
+        dest.set_extended_headers(self.extended_headers);
+        dest.page_checksums = self.page_checksums;
+        dest.hasher_id = self.hasher_id;
+        dest.hash_seed = self.hash_seed;
+        dest.duplicate_keys = self.duplicate_keys;
+        dest.large_values = self.large_values;
+        dest.partial_expansions = self.partial_expansions;
+        dest.split_threshold = self.split_threshold;
+        dest.meta = self.meta.clone();
+        let nbuckets = header.2;
+        for bucket_id in 0..dest.bucket_to_page.len() {
+            dest.tag_bucket(bucket_id);
+        }
+        while dest.bucket_to_page.len() < nbuckets {
+            dest.allocate_new_bucket()?;
+        }
+        let mut pages = 0;
+        for bucket_id in 0..nbuckets {
+            let mut records = vec![];
+            let mut page_id = Some(self.bucket_to_page(bucket_id));
+            while let Some(id) = page_id {
+                records.append(&mut self.all_records_in_page(id)?);
+                page_id = self.try_page(id)?.next;
+                pages += 1;
+            }
+            dest.fill_bucket(bucket_id, records)?;
+            copied(pages)?;
+        }
+        dest.write_ctrlpage(header)?;
+        dest.try_close()?;
+        dest.sync()
     }
 
     pub fn close(&mut self) {
-        for b in 0..NUM_BUFFERS {
-            self.write_buffer_page(b);
+        self.try_close().expect("write failed");
+    }
+
+    /// Like `close`, but returns the first write error. Pages that
+    /// could not be written stay dirty, so a retry writes them; pages
+    /// that were only read are not written at all.
+    pub fn try_close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Gives up on the cached pages: dropping the file afterwards
+    /// writes nothing.
+    pub fn discard(&mut self) {
+        self.closed = true;
+    }
+
+    /// Iterates over every record, bucket by bucket and following
+    /// each overflow chain. See `Records`.
+    pub fn records(&mut self) -> Records<'_> {
+        self.project_records(|k, v| (k.to_vec(), v.to_vec()))
+    }
+
+    /// Like `records`, but yields `project` of each record, so that
+    /// only the part it copies is copied.
+    pub fn project_records<T>(&mut self, project: fn(&[u8], &[u8]) -> T) -> Records<'_, T> {
+        Records {
+            file: self,
+            bucket: 0,
+            next_page: None,
+            pending: Vec::new(),
+            project,
+        }
+    }
+}
+
+/// A file neither closed nor discarded writes back its dirty pages and
+/// the last ctrl page header, so forgetting `close` loses nothing.
+/// Errors are ignored, as drop cannot report them.
+impl Drop for DbFile {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if self.flush().is_ok() {
+            if let Some(header) = self.header {
+                self.write_ctrlpage(header).ok();
+            }
+        }
+    }
+}
+
+/// Iterator over every record of a `DbFile`, or some part of each
+/// (see `project_records`). Pages are read as the iteration reaches
+/// them, and only the records of the current page are held in memory.
+pub struct Records<'a, T = Record> {
+    file: &'a mut DbFile,
+    bucket: usize,              // next bucket to start
+    next_page: Option<usize>,   // next page in the current chain
+    pending: Vec<T>,            // rest of the current page, reversed
+    project: fn(&[u8], &[u8]) -> T,
+}
+
+impl<'a, T> Records<'a, T> {
+    /// Up to `n` records in one call, decoded straight from each page
+    /// buffer in turn, so a page is fetched once however many records
+    /// it gives the batch. Returns an empty Vec once every record has
+    /// been visited.
+    pub fn next_batch(&mut self, n: usize) -> Vec<T> {
+        let mut batch = Vec::with_capacity(n);
+        while batch.len() < n {
+            if !self.pending.is_empty() {
+                let take = (n - batch.len()).min(self.pending.len());
+                let rest = self.pending.len() - take;
+                batch.extend(self.pending.drain(rest..).rev());
+                continue;
+            }
+            let page_id = match self.next_page_id() {
+                Some(p) => p,
+                None => break,
+            };
+            let buffer_index = self.file.fetch_page(page_id);
+            let page = &mut self.file.buffers[buffer_index];
+            self.next_page = page.next;
+            let take = (n - batch.len()).min(page.num_records);
+            for i in 0..take {
+                let (k, v) = page.read_record(i);
+                batch.push((self.project)(k, v));
+            }
+            // the rest of the page, reversed like in `next`
+            for i in (take..page.num_records).rev() {
+                let (k, v) = page.read_record(i);
+                self.pending.push((self.project)(k, v));
+            }
+        }
+        batch
+    }
+
+    /// The next page to read: the next one of the current chain, or
+    /// the first of the next bucket. None after the last bucket.
+    fn next_page_id(&mut self) -> Option<usize> {
+        match self.next_page {
+            Some(p) if p != 0 => Some(p),
+            _ => {
+                if self.bucket >= self.file.bucket_to_page.len() {
+                    return None;
+                }
+                self.bucket += 1;
+                Some(self.file.bucket_to_page(self.bucket - 1))
+            },
+        }
+    }
+}
+
+impl<'a, T> Iterator for Records<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while self.pending.is_empty() {
+            let page_id = self.next_page_id()?;
+            self.next_page = self.file.page(page_id).next;
+            self.pending = self.file.project_records_in_page(page_id, self.project)
+                .expect("page is in the buffer pool");
+            self.pending.reverse();
         }
+        self.pending.pop()
     }
 }
 
@@ -447,18 +2768,195 @@ mod tests {
         let mut bp = DbFile::new("/tmp/dbfile_tests", 4, 4);
         let bark = b"bark";
         let krab = b"krab";
-        // write to page 1
-        bp.write_record(1, 14, bark, krab);
-        assert_eq!(bp.buffers[disk::NUM_BUFFERS-1].read_record(14),
+        // write to page 2
+        bp.write_record(2, 14, bark, krab);
+        let buffer_index = bp.search_buffer_pool(2).unwrap();
+        assert_eq!(bp.buffers[buffer_index].read_record(14),
                    (&bark[..], &krab[..]));
         bp.close();
 
         let mut bp2 = DbFile::new("/tmp/dbfile_tests", 4, 4);
-        // read from page 1
-        let buffer_index = bp2.fetch_page(1);
+        // read from page 2
+        let buffer_index = bp2.fetch_page(2);
         assert_eq!(bp2.buffers[buffer_index].read_record(14),
                    (&bark[..], &krab[..]));
 
         fs::remove_file("/tmp/dbfile_tests").ok();
     }
+
+    #[test]
+    fn pager_tests() {
+        fs::remove_file("/tmp/pager_tests").ok();
+        let mut pager = DbFile::new_pager("/tmp/pager_tests", 0, 0);
+        let a = pager.allocate_page().unwrap();
+        let b = pager.allocate_page().unwrap();
+        assert_eq!((a, b), (2, 3));
+        pager.page_mut(a).storage[100] = 7;
+        pager.pin(a);
+        // cycle enough pages through the pool to evict anything unpinned
+        for _ in 0..disk::NUM_BUFFERS * 2 {
+            pager.allocate_page().unwrap();
+        }
+        assert!(pager.search_buffer_pool(a).is_some());
+        pager.unpin(a);
+
+        pager.free_page(b);
+        assert_eq!(pager.num_free(), 1);
+        assert_eq!(pager.allocate_page().unwrap(), b);
+        pager.flush().unwrap();
+        pager.write_ctrlpage((0, 0, 0)).unwrap();
+
+        let mut pager2 = DbFile::new_pager("/tmp/pager_tests", 0, 0);
+        assert_eq!(pager2.page(a).storage[100], 7);
+        assert_eq!(pager2.num_pages(), pager.num_pages());
+        fs::remove_file("/tmp/pager_tests").ok();
+    }
+
+    #[test]
+    fn torn_ctrl_write() {
+        use page::PAGE_SIZE;
+        let path = "/tmp/test_torn_ctrl_write";
+        fs::remove_file(path).ok();
+        let mut pager = DbFile::new_pager(path, 0, 0);
+        pager.write_ctrlpage((1, 2, 0)).unwrap();
+        pager.write_ctrlpage((4, 5, 0)).unwrap();
+        pager.close();
+        let mut pager = DbFile::new_pager(path, 0, 0);
+        assert_eq!(pager.read_ctrlpage().unwrap(), (4, 5, 0));
+        pager.discard();
+
+        // the second write went to the shadow copy; tear it
+        let mut file = fs::read(path).unwrap();
+        file[PAGE_SIZE + 8] ^= 0xff;
+        fs::write(path, &file).unwrap();
+        let mut pager = DbFile::new_pager(path, 0, 0);
+        assert_eq!(pager.read_ctrlpage().unwrap(), (1, 2, 0));
+        // and the next write replaces it
+        pager.write_ctrlpage((7, 8, 0)).unwrap();
+        pager.close();
+        let mut pager = DbFile::new_pager(path, 0, 0);
+        assert_eq!(pager.read_ctrlpage().unwrap(), (7, 8, 0));
+        pager.discard();
+
+        let mut file = fs::read(path).unwrap();
+        file[8] ^= 0xff;
+        file[PAGE_SIZE + 8] ^= 0xff;
+        fs::write(path, &file).unwrap();
+        let mut pager = DbFile::try_new(path, 0, 0).unwrap();
+        assert!(pager.read_ctrlpage().is_err());
+        pager.discard();
+        assert!(DbFile::try_new_pager(path, 0, 0).is_err());
+        assert_eq!(fs::read(path).unwrap(), file);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn mapped_lookups() {
+        use store::IoBackend;
+        use LinHash;
+        let path = "/tmp/test_mapped_lookups";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..2000u32 {
+            h.put(&k.to_le_bytes(), &(k + 1).to_le_bytes());
+        }
+        h.close();
+
+        let mut h = LinHash::options().io_backend(IoBackend::Mmap).open(path).unwrap();
+        h.set_cache_pages(disk::MIN_BUFFERS).unwrap();
+        for k in 0..2000u32 {
+            let key = k.to_le_bytes();
+            let bucket = h.bucket(&key);
+            let (page_id, _) = h.buckets.locate(bucket, &key).unwrap().unwrap();
+            assert_eq!(h.get_ref(&key), Some(&(k + 1).to_le_bytes()[..]));
+            // searched and borrowed in the map, not read into the pool
+            assert_eq!(h.buckets.search_buffer_pool(page_id).is_none(), cfg!(unix));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn lru_eviction() {
+        fs::remove_file("/tmp/lru_eviction").ok();
+        let mut pager = DbFile::new_pager("/tmp/lru_eviction", 0, 0);
+        pager.set_pool_size(disk::MIN_BUFFERS).unwrap();
+        let pages: Vec<usize> = (0..8).map(|_| pager.allocate_page().unwrap()).collect();
+        for &p in &pages {
+            pager.page_mut(p).storage[100] = p as u8;
+        }
+        // keep touching the first page while the others cycle through
+        for &p in &pages[1..] {
+            pager.page(pages[0]);
+            pager.page(p);
+        }
+        assert!(pager.search_buffer_pool(pages[0]).is_some());
+        assert!(pager.search_buffer_pool(pages[1]).is_none());
+        // evicted dirty pages were written back
+        for &p in &pages {
+            assert_eq!(pager.page(p).storage[100], p as u8);
+        }
+
+        pager.set_pool_size(64).unwrap();
+        assert_eq!(pager.pool_size(), 64);
+        pager.set_pool_size(0).unwrap();
+        assert_eq!(pager.pool_size(), disk::MIN_BUFFERS);
+        fs::remove_file("/tmp/lru_eviction").ok();
+    }
+
+    #[test]
+    fn bucket_map_pages() {
+        use disk::{map_slot, CTRL_MAP_END, CTRL_MAP_ENTRIES, CTRL_MAP_START,
+                   MAP_PAGE_ENTRIES};
+        use page::PAGE_SIZE;
+        use util::USIZE_WIDTH;
+        assert_eq!(map_slot(0), (None, CTRL_MAP_START));
+        assert_eq!(map_slot(CTRL_MAP_ENTRIES - 1), (None, CTRL_MAP_END - USIZE_WIDTH));
+        // each map page starts with the link to the next
+        assert_eq!(map_slot(CTRL_MAP_ENTRIES), (Some(0), USIZE_WIDTH));
+        assert_eq!(map_slot(CTRL_MAP_ENTRIES + MAP_PAGE_ENTRIES - 1),
+                   (Some(0), PAGE_SIZE - USIZE_WIDTH));
+        assert_eq!(map_slot(CTRL_MAP_ENTRIES + MAP_PAGE_ENTRIES), (Some(1), USIZE_WIDTH));
+
+        let path = "/tmp/test_map_pages";
+        fs::remove_file(path).ok();
+        let header = |n: usize| (n.next_power_of_two().trailing_zeros() as usize, 0, n);
+        let mut db = DbFile::new(path, 4, 4);
+        let n = CTRL_MAP_ENTRIES + MAP_PAGE_ENTRIES + 10;
+        while db.bucket_to_page.len() < n {
+            db.allocate_new_bucket().unwrap();
+        }
+        db.write_ctrlpage(header(n)).unwrap();
+        assert_eq!(db.map_pages().len(), 2);
+        let map = db.bucket_to_page.clone();
+        let map_pages = db.map_pages().to_vec();
+        // grown further and flushed, but the ctrl page not written, as
+        // if the process died: the last written map is still whole
+        for _ in 0..MAP_PAGE_ENTRIES {
+            db.allocate_new_bucket().unwrap();
+        }
+        db.flush().unwrap();
+        db.discard();
+
+        let mut db = DbFile::new(path, 4, 4);
+        assert_eq!(db.read_ctrlpage().unwrap(), header(n));
+        assert_eq!(db.bucket_to_page, map);
+        assert_eq!(db.map_pages(), &map_pages[..]);
+
+        // growing on adds a map page, and leaves the others where they are
+        let n = n + MAP_PAGE_ENTRIES;
+        while db.bucket_to_page.len() < n {
+            db.allocate_new_bucket().unwrap();
+        }
+        db.write_ctrlpage(header(n)).unwrap();
+        let map = db.bucket_to_page.clone();
+        db.close();
+        let mut db = DbFile::new(path, 4, 4);
+        assert_eq!(db.read_ctrlpage().unwrap(), header(n));
+        assert_eq!(db.bucket_to_page, map);
+        assert_eq!(db.map_pages().len(), 3);
+        assert_eq!(db.map_pages()[..2], map_pages[..]);
+        db.discard();
+        fs::remove_file(path).ok();
+    }
 }
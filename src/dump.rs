@@ -0,0 +1,234 @@
+//! A stable, line-oriented text format holding every record of a
+//! table, for backups, format migrations and disaster recovery.
+//!
+//! ```text
+//! # linhash-dump v1 keysize=4 valsize=8
+//! 2a000000<TAB>0100000000000000
+//! ...
+//! ```
+//!
+//! The header line records the geometry needed to rebuild the table.
+//! Each following line is one record: the key and the value in
+//! lowercase hex, separated by a single tab, each exactly as stored (ie.
+//! zero-padded to keysize and valsize). Blank lines and further lines
+//! starting with `#` are ignored.
+
+use std::io;
+use std::io::prelude::*;
+
+use LinHash;
+use progress::{CancelToken, NoProgress, Progress};
+
+const MAGIC: &str = "# linhash-dump v1";
+
+/// Geometry recorded in a dump's header line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpHeader {
+    pub keysize: usize,
+    pub valsize: usize,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Writes every record of `table` to `w`, one bucket at a time.
+/// Returns the number of records written.
+pub fn dump<W: Write>(table: &mut LinHash, w: &mut W) -> io::Result<usize> {
+    dump_with_progress(table, w, &mut NoProgress)
+}
+
+/// Like `dump`, reporting pages written out of `table.pages_in_use()`
+/// after each bucket.
+pub fn dump_with_progress<W: Write, P: Progress>(table: &mut LinHash, w: &mut W,
+                                                 progress: &mut P)
+                                                 -> io::Result<usize> {
+    dump_cancellable(table, w, progress, &CancelToken::new())
+}
+
+/// Like `dump_with_progress`, stopping between buckets once `cancel`
+/// is cancelled. The table is only read, so it is unaffected; the
+/// output then holds a partial dump.
+pub fn dump_cancellable<W: Write, P: Progress>(table: &mut LinHash, w: &mut W,
+                                               progress: &mut P,
+                                               cancel: &CancelToken)
+                                               -> io::Result<usize> {
+    writeln!(w, "{} keysize={} valsize={}", MAGIC,
+             table.buckets.keysize(), table.buckets.valsize())?;
+    let total = table.pages_in_use();
+    let mut pages = 0;
+    let mut count = 0;
+    for bucket in 0..table.nbuckets {
+        cancel.check()?;
+        for (_, records) in table.buckets.all_records_in_bucket(bucket) {
+            for (k, v) in records {
+                writeln!(w, "{}\t{}", to_hex(&k), to_hex(&v))?;
+                count += 1;
+            }
+            pages += 1;
+        }
+        progress.report(pages, total);
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+/// Reads the header line of a dump, to learn the geometry of the
+/// table to restore into.
+pub fn read_header<R: BufRead>(r: &mut R) -> io::Result<DumpHeader> {
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let rest = line.trim_end().strip_prefix(MAGIC)
+        .ok_or_else(|| invalid("not a linhash dump".to_string()))?;
+
+    let mut header = DumpHeader { keysize: 0, valsize: 0 };
+    for field in rest.split_whitespace() {
+        let (name, value) = match field.split_once('=') {
+            Some(f) => f,
+            None => continue,
+        };
+        let value = value.parse()
+            .map_err(|_| invalid(format!("bad header field: {}", field)))?;
+        match name {
+            "keysize" => header.keysize = value,
+            "valsize" => header.valsize = value,
+            _ => (),
+        }
+    }
+    if header.keysize == 0 {
+        return Err(invalid("dump header has no keysize".to_string()));
+    }
+    Ok(header)
+}
+
+/// Inserts (or overwrites) every record from the body of a dump, ie.
+/// everything after the header consumed by `read_header`. A table with
+/// duplicate keys gets every record added. Returns the number of
+/// records restored.
+pub fn restore<R: BufRead>(table: &mut LinHash, r: &mut R) -> io::Result<usize> {
+    restore_with_progress(table, r, &mut NoProgress)
+}
+
+/// How many records `restore_with_progress` restores between reports.
+const RESTORE_REPORT_INTERVAL: usize = 1000;
+
+/// Like `restore`, reporting the number of records restored so far
+/// every `RESTORE_REPORT_INTERVAL` records and at the end. The total
+/// is reported as 0, as it is not known until the input ends.
+pub fn restore_with_progress<R: BufRead, P: Progress>(table: &mut LinHash, r: &mut R,
+                                                      progress: &mut P)
+                                                      -> io::Result<usize> {
+    restore_cancellable(table, r, progress, &CancelToken::new())
+}
+
+/// Like `restore_with_progress`, stopping between records once
+/// `cancel` is cancelled. Every record is inserted completely or not
+/// at all, so the table stays consistent, holding the records restored
+/// so far.
+pub fn restore_cancellable<R: BufRead, P: Progress>(table: &mut LinHash, r: &mut R,
+                                                    progress: &mut P,
+                                                    cancel: &CancelToken)
+                                                    -> io::Result<usize> {
+    let mut count = 0;
+    for (lineno, line) in r.lines().enumerate() {
+        cancel.check()?;
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || invalid(format!("line {}: malformed record", lineno + 2));
+        let (k, v) = line.split_once('\t').ok_or_else(bad)?;
+        let (k, v) = (from_hex(k).ok_or_else(bad)?, from_hex(v).ok_or_else(bad)?);
+        if k.len() > table.buckets.keysize() || v.len() > table.buckets.valsize() {
+            return Err(invalid(format!("line {}: record wider than table",
+                                       lineno + 2)));
+        }
+        if table.duplicate_keys() || !table.update(&k, &v) {
+            table.put(&k, &v);
+        }
+        count += 1;
+        if count % RESTORE_REPORT_INTERVAL == 0 {
+            progress.report(count, 0);
+        }
+    }
+    progress.report(count, 0);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use LinHash;
+    use dump;
+    use std::fs;
+    use progress::CancelToken;
+    use std::io;
+    use std::io::BufReader;
+    use util::*;
+
+    #[test]
+    fn dump_and_restore() {
+        fs::remove_file("/tmp/test_dump_src").ok();
+        fs::remove_file("/tmp/test_dump_dst").ok();
+        let mut h = LinHash::open("/tmp/test_dump_src", 4, 8);
+        for k in 0..1500 {
+            h.put(&encode(k), &encode(k as u64 * 3));
+        }
+        let mut out = vec![];
+        let mut reports = vec![];
+        assert_eq!(dump::dump_with_progress(&mut h, &mut out,
+                                            &mut |done, total| reports.push((done, total)))
+                   .unwrap(), 1500);
+        let total = h.pages_in_use();
+        assert_eq!(reports.len(), h.nbuckets);
+        assert_eq!(reports.last(), Some(&(total, total)));
+        h.close();
+
+        let mut r = BufReader::new(&out[..]);
+        let header = dump::read_header(&mut r).unwrap();
+        assert_eq!((header.keysize, header.valsize), (4, 8));
+        let mut h2 = LinHash::open("/tmp/test_dump_dst", header.keysize,
+                                   header.valsize);
+        assert_eq!(dump::restore(&mut h2, &mut r).unwrap(), 1500);
+        for k in 0..1500 {
+            assert_eq!(h2.get_u64(&encode(k)), Some(k as u64 * 3));
+        }
+        h2.close();
+
+        // cancel a restore part-way through
+        fs::remove_file("/tmp/test_dump_cancel").ok();
+        let mut h3 = LinHash::open("/tmp/test_dump_cancel", 4, 8);
+        let mut r = BufReader::new(&out[..]);
+        dump::read_header(&mut r).unwrap();
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        let err = dump::restore_cancellable(&mut h3, &mut r, &mut |done, _| {
+            if done == 1000 {
+                canceller.cancel();
+            }
+        }, &cancel).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(h3.nitems, 1000);
+        h3.close();
+
+        let mut garbage = BufReader::new(&b"hello\n"[..]);
+        assert!(dump::read_header(&mut garbage).is_err());
+
+        fs::remove_file("/tmp/test_dump_src").ok();
+        fs::remove_file("/tmp/test_dump_dst").ok();
+        fs::remove_file("/tmp/test_dump_cancel").ok();
+    }
+}
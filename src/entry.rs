@@ -0,0 +1,231 @@
+//! In-place access to a single record, in the manner of
+//! `HashMap::entry`: one search of the key's bucket finds out whether
+//! the record is there, and reading, replacing or inserting it then
+//! goes straight to the page and row that search found.
+
+use disk::SearchResult;
+use error;
+use page::PageLayout;
+use LinHash;
+
+/// A record of a `LinHash` that may or may not be present. See
+/// `LinHash::entry`.
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+/// A record that is present, with its location in the bucket.
+pub struct OccupiedEntry<'a> {
+    table: &'a mut LinHash,
+    key: Vec<u8>,
+    bucket: usize,
+    page_id: usize,
+    row_num: usize,
+    val: Vec<u8>,
+}
+
+/// A key that is absent, with where in its bucket it would go.
+pub struct VacantEntry<'a> {
+    table: &'a mut LinHash,
+    key: Vec<u8>,
+    found: SearchResult,
+}
+
+impl<'a> Entry<'a> {
+    /// Searches the bucket of `key` on behalf of `LinHash::try_entry`.
+    pub(crate) fn find(table: &'a mut LinHash, key: &[u8]) -> error::Result<Entry<'a>> {
+        let bucket = table.bucket(key);
+        let found = table.buckets.search_bucket(bucket, key)?;
+        Ok(match found {
+            SearchResult { page_id: Some(page_id), row_num: Some(row_num), val: Some(val) } =>
+                Entry::Occupied(OccupiedEntry {
+                    table, key: key.to_vec(), bucket, page_id, row_num, val,
+                }),
+            found => Entry::Vacant(VacantEntry { table, key: key.to_vec(), found }),
+        })
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match *self {
+            Entry::Occupied(ref e) => e.key(),
+            Entry::Vacant(ref e) => e.key(),
+        }
+    }
+
+    /// Inserts `default` if the record is absent. Returns the value
+    /// the record ends up with.
+    pub fn or_insert(self, default: &[u8]) -> Vec<u8> {
+        self.or_insert_with(|| default.to_vec())
+    }
+
+    /// Like `or_insert`, computing the value only if it is needed.
+    pub fn or_insert_with<F: FnOnce() -> Vec<u8>>(self, default: F) -> Vec<u8> {
+        self.try_or_insert_with(default).unwrap_or_else(|e| panic!("put failed: {}", e))
+    }
+
+    /// Like `or_insert_with`, but returns an error instead of
+    /// panicking.
+    pub fn try_or_insert_with<F: FnOnce() -> Vec<u8>>(self, default: F)
+                                                      -> error::Result<Vec<u8>> {
+        match self {
+            Entry::Occupied(e) => Ok(e.val),
+            Entry::Vacant(e) => {
+                let mut val = default();
+                let layout = e.table.buckets.page_layout();
+                let valsize = e.table.buckets.valsize();
+                e.try_insert(&val)?;
+                // as stored
+                if layout != PageLayout::Slotted {
+                    val.resize(valsize.max(val.len()), 0);
+                }
+                Ok(val)
+            },
+        }
+    }
+
+    /// Rewrites a present record's value with `f`.
+    pub fn and_modify<F: FnOnce(&mut Vec<u8>)>(self, f: F) -> Entry<'a> {
+        match self {
+            Entry::Occupied(mut e) => {
+                let mut val = e.val.clone();
+                f(&mut val);
+                e.insert(&val);
+                Entry::Occupied(e)
+            },
+            vacant => vacant,
+        }
+    }
+}
+
+impl<'a> OccupiedEntry<'a> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The value, as stored.
+    pub fn get(&self) -> &[u8] {
+        &self.val
+    }
+
+    /// Replaces the value, returning the old one. Panics on error; see
+    /// `try_insert`.
+    pub fn insert(&mut self, val: &[u8]) -> Vec<u8> {
+        self.try_insert(val).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_insert(&mut self, val: &[u8]) -> error::Result<Vec<u8>> {
+        self.table.check_writable()?;
+        self.table.check_record(&self.key, val)?;
+        let in_place = self.table.replace(self.bucket, self.page_id, self.row_num,
+                                          &self.key, val)?;
+        if !in_place {
+            let found = self.table.buckets.search_bucket(self.bucket, &self.key)?;
+            self.page_id = found.page_id.expect("moved record is in its bucket");
+            self.row_num = found.row_num.expect("moved record is in its bucket");
+        }
+        Ok(::std::mem::replace(&mut self.val, val.to_vec()))
+    }
+
+    /// Deletes the record, returning its value. Panics on error; see
+    /// `try_remove`.
+    pub fn remove(self) -> Vec<u8> {
+        self.try_remove().unwrap_or_else(|e| panic!("remove failed: {}", e))
+    }
+
+    pub fn try_remove(self) -> error::Result<Vec<u8>> {
+        self.table.try_remove(&self.key)?;
+        Ok(self.val)
+    }
+}
+
+impl<'a> VacantEntry<'a> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Inserts the record. Panics on error; see `try_insert`.
+    pub fn insert(self, val: &[u8]) {
+        self.try_insert(val).unwrap_or_else(|e| panic!("put failed: {}", e))
+    }
+
+    /// Like `LinHash::try_put`, starting from the free slot (or the
+    /// full last page) the search found.
+    pub fn try_insert(self, val: &[u8]) -> error::Result<()> {
+        self.table.put_found(&self.key, val, Some(self.found))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use entry::Entry;
+    use std::fs;
+    use util::*;
+    use {LinHash, PageLayout};
+
+    #[test]
+    fn entries() {
+        fs::remove_file("/tmp/test_entries").ok();
+        let mut h = LinHash::open("/tmp/test_entries", 4, 4);
+        for k in 0..1000 {
+            let expected = if k < 500 { 1 } else { 2 };
+            assert_eq!(h.entry(&encode(k % 500)).or_insert(&encode(1)), encode(expected));
+            h.entry(&encode(k % 500)).and_modify(|v| *v = encode(decode::<i32>(v) + 1));
+        }
+        assert_eq!(h.nitems, 500);
+        match h.entry(&encode(7)) {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.get(), &encode(3)[..]);
+                assert_eq!(e.insert(&encode(70)), encode(3));
+                assert_eq!(e.get(), &encode(70)[..]);
+            },
+            Entry::Vacant(_) => panic!("7 is present"),
+        }
+        match h.entry(&encode(8)) {
+            Entry::Occupied(e) => assert_eq!(e.remove(), encode(3)),
+            Entry::Vacant(_) => panic!("8 is present"),
+        }
+        assert_eq!(h.get(&encode(7)), Some(encode(70)));
+        assert_eq!(h.get(&encode(8)), None);
+        assert!(h.try_entry(&[0; 5]).is_err());
+        h.close();
+        fs::remove_file("/tmp/test_entries").ok();
+
+        // a value that outgrows its slotted page moves
+        fs::remove_file("/tmp/test_entries_slotted").ok();
+        let mut h = LinHash::open_with_layout("/tmp/test_entries_slotted", 4, 2000,
+                                              PageLayout::Slotted);
+        h.put(&encode(1), &[1; 10]);
+        h.put(&encode(2), &[2; 1500]);
+        if let Entry::Occupied(mut e) = h.entry(&encode(1)) {
+            e.insert(&[1; 2000]);
+            assert_eq!(e.insert(&[3; 3]), vec![1; 2000]);
+        }
+        assert_eq!(h.get(&encode(1)), Some(vec![3; 3]));
+        assert_eq!(h.get(&encode(2)), Some(vec![2; 1500]));
+        h.close();
+        fs::remove_file("/tmp/test_entries_slotted").ok();
+    }
+
+    #[test]
+    fn get_or_insert() {
+        fs::remove_file("/tmp/test_get_or_insert").ok();
+        let mut h = LinHash::open("/tmp/test_get_or_insert", 4, 4);
+        let mut calls = 0;
+        for k in 0..2000 {
+            let val = h.get_or_insert_with(&encode(k % 1000), || {
+                calls += 1;
+                vec![k as u8]
+            });
+            // returned as stored
+            assert_eq!(val, vec![(k % 1000) as u8, 0, 0, 0]);
+        }
+        assert_eq!(calls, 1000);
+        assert_eq!(h.len(), 1000);
+        assert_eq!(h.get(&encode(5)), Some(vec![5, 0, 0, 0]));
+        assert!(h.try_get_or_insert_with(&encode(5000), || vec![0; 5]).is_err());
+        assert_eq!(h.get(&encode(5000)), None);
+        h.close();
+        fs::remove_file("/tmp/test_get_or_insert").ok();
+    }
+}
@@ -0,0 +1,109 @@
+//! The error type of the `try_*` API.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use util::{LengthError, UsizeError};
+
+#[derive(Debug)]
+pub enum LinHashError {
+    /// Reading or writing the file failed, eg. because the disk is
+    /// full or permission was denied.
+    Io(io::Error),
+    /// The file holds something it never should, or the handle has
+    /// stopped writing after detecting that it does.
+    Corruption(String),
+    /// An argument can never work, eg. a key wider than `keysize`.
+    InvalidArgument(String),
+}
+
+pub type Result<T> = ::std::result::Result<T, LinHashError>;
+
+/// Why `LinHash::compare_and_swap` did not swap.
+#[derive(Debug)]
+pub enum CasError {
+    /// The key's value is not the one expected. Holds the value it
+    /// has, as stored, or None if the key has no record.
+    Mismatch(Option<Vec<u8>>),
+    /// The table failed.
+    Table(LinHashError),
+}
+
+impl fmt::Display for LinHashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinHashError::Io(ref e) => write!(f, "I/O error: {}", e),
+            LinHashError::Corruption(ref msg) => write!(f, "corruption: {}", msg),
+            LinHashError::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
+        }
+    }
+}
+
+impl Error for LinHashError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            LinHashError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CasError::Mismatch(Some(_)) => write!(f, "the value is not the one expected"),
+            CasError::Mismatch(None) => write!(f, "the key has no value"),
+            CasError::Table(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for CasError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CasError::Table(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<LinHashError> for CasError {
+    fn from(e: LinHashError) -> CasError {
+        CasError::Table(e)
+    }
+}
+
+/// `InvalidData` is how the pager reports a file it cannot make sense
+/// of, so it becomes `Corruption`; everything else stays `Io`.
+impl From<io::Error> for LinHashError {
+    fn from(e: io::Error) -> LinHashError {
+        if e.kind() == io::ErrorKind::InvalidData {
+            LinHashError::Corruption(e.to_string())
+        } else {
+            LinHashError::Io(e)
+        }
+    }
+}
+
+impl From<LengthError> for LinHashError {
+    fn from(e: LengthError) -> LinHashError {
+        LinHashError::InvalidArgument(e.to_string())
+    }
+}
+
+impl From<UsizeError> for LinHashError {
+    fn from(e: UsizeError) -> LinHashError {
+        LinHashError::InvalidArgument(e.to_string())
+    }
+}
+
+impl From<LinHashError> for io::Error {
+    fn from(e: LinHashError) -> io::Error {
+        match e {
+            LinHashError::Io(e) => e,
+            LinHashError::Corruption(msg) => io::Error::new(io::ErrorKind::InvalidData, msg),
+            LinHashError::InvalidArgument(msg) => io::Error::new(io::ErrorKind::InvalidInput, msg),
+        }
+    }
+}
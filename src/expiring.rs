@@ -0,0 +1,325 @@
+//! A table whose records can expire, for use as a persistent cache.
+//!
+//! Every value slot ends with the record's expiry time, in
+//! milliseconds since the Unix epoch (0 for never):
+//!
+//! ```text
+//! | value (valsize bytes) | expires at (u64) |
+//! ```
+//!
+//! so the table underneath has a valsize 8 bytes larger than the one
+//! given to `open`. An expired record reads as absent. It stays in the
+//! file until a `get` finds it, which reclaims every expired record of
+//! that bucket, or until `purge_expired`.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::{self, LinHashError};
+use progress::{CancelToken, NoProgress, Progress};
+use util::{decode, FixedWidth};
+use LinHash;
+
+const EXPIRY_SIZE: usize = u64::WIDTH;
+
+/// Linear Hashtable of byte keys and values, each record optionally
+/// with a time to live. The table underneath is reachable through
+/// `raw`; it should only ever be opened through this type.
+pub struct ExpiringLinHash {
+    table: LinHash,
+}
+
+impl ExpiringLinHash {
+    /// Opens (or creates) the table stored in `filename`. Panics on
+    /// error; see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                -> ExpiringLinHash {
+        ExpiringLinHash::try_open(filename, keysize, valsize).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                    -> error::Result<ExpiringLinHash> {
+        Ok(ExpiringLinHash {
+            table: LinHash::try_open(filename, keysize, valsize + EXPIRY_SIZE)?,
+        })
+    }
+
+    /// Stores `val` under `key` with no expiry, replacing any record
+    /// (and time to live) the key had.
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.try_put(key, val).unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    /// Like `put`, but returns an error instead of panicking.
+    pub fn try_put(&mut self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        self.store(key, val, 0)
+    }
+
+    /// Stores `val` under `key`, to read as absent once `ttl` has
+    /// passed.
+    pub fn put_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) {
+        self.try_put_with_ttl(key, val, ttl).unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    /// Like `put_with_ttl`, but returns an error instead of panicking.
+    pub fn try_put_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration)
+                            -> error::Result<()> {
+        let ttl = ttl.as_millis().min(u64::MAX as u128) as u64;
+        // never 0, which would mean no expiry
+        self.store(key, val, now().saturating_add(ttl).max(1))
+    }
+
+    fn store(&mut self, key: &[u8], val: &[u8], expires: u64) -> error::Result<()> {
+        let valsize = self.valsize();
+        if val.len() > valsize {
+            return Err(LinHashError::InvalidArgument(format!(
+                "value is {} bytes, valsize is {}", val.len(), valsize)));
+        }
+        let mut stored = val.to_vec();
+        stored.resize(valsize, 0);
+        stored.extend_from_slice(&expires.to_le_bytes());
+        if !self.table.try_update(key, &stored)? {
+            self.table.try_put(key, &stored)?;
+        }
+        Ok(())
+    }
+
+    /// Looks up `key`. An expired record is absent, and reclaimed along
+    /// with the other expired records of its bucket.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let stored = self.table.get(key)?;
+        let (val, expires) = split(&stored);
+        if expired(expires, now()) {
+            self.reclaim_bucket(self.table.bucket(key));
+            return None;
+        }
+        Some(val.to_vec())
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// When the record of `key` expires; None if it is absent or never
+    /// expires.
+    pub fn expires_at(&mut self, key: &[u8]) -> Option<SystemTime> {
+        let stored = self.table.get(key)?;
+        match split(&stored).1 {
+            0 => None,
+            ms if expired(ms, now()) => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+
+    /// Deletes `key`, returning its value unless it had expired.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let stored = self.table.remove(key)?;
+        let (val, expires) = split(&stored);
+        if expired(expires, now()) { None } else { Some(val.to_vec()) }
+    }
+
+    /// Deletes every expired record, returning how many there were.
+    pub fn purge_expired(&mut self) -> error::Result<usize> {
+        self.purge_expired_with_progress(&mut NoProgress)
+    }
+
+    /// Like `purge_expired`, reporting pages swept out of the table's
+    /// `pages_in_use` after each bucket.
+    pub fn purge_expired_with_progress<P: Progress>(&mut self, progress: &mut P)
+                                                    -> error::Result<usize> {
+        self.purge_expired_cancellable(progress, &CancelToken::new())
+    }
+
+    /// Like `purge_expired_with_progress`, stopping between buckets
+    /// once `cancel` is cancelled. Every remove is complete, so the
+    /// table stays consistent; the rest of the expired records are
+    /// left for later.
+    pub fn purge_expired_cancellable<P: Progress>(&mut self, progress: &mut P,
+                                                  cancel: &CancelToken) -> error::Result<usize> {
+        let now = now();
+        let total = self.table.pages_in_use();
+        let mut pages = 0;
+        let mut purged = 0;
+        // from the last bucket down, so that a merge caused by the
+        // removes only moves records swept already
+        let mut bucket = self.table.nbuckets;
+        while bucket > 0 {
+            cancel.check()?;
+            bucket = (bucket - 1).min(self.table.nbuckets - 1);
+            let chain = self.table.buckets.all_records_in_bucket(bucket);
+            pages += chain.len();
+            let keys: Vec<Vec<u8>> = chain.into_iter()
+                .flat_map(|(_, records)| records)
+                .filter(|(_, stored)| expired(split(stored).1, now))
+                .map(|(k, _)| k)
+                .collect();
+            for k in &keys {
+                self.table.try_remove(k)?;
+            }
+            purged += keys.len();
+            progress.report(pages, total);
+        }
+        Ok(purged)
+    }
+
+    fn reclaim_bucket(&mut self, bucket: usize) {
+        let now = now();
+        let keys: Vec<Vec<u8>> = self.table.buckets.all_records_in_bucket(bucket)
+            .into_iter()
+            .flat_map(|(_, records)| records)
+            .filter(|(_, stored)| expired(split(stored).1, now))
+            .map(|(k, _)| k)
+            .collect();
+        for k in keys {
+            self.table.remove(&k);
+        }
+    }
+
+    /// Number of records, counting expired ones not yet reclaimed.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Width of the values, as given to `open`.
+    pub fn valsize(&self) -> usize {
+        self.table.buckets.valsize() - EXPIRY_SIZE
+    }
+
+    /// Iterates over every (key, value) pair that has not expired. See
+    /// `LinHash::iter`.
+    pub fn iter(&mut self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let now = now();
+        self.table.iter().filter_map(move |(k, stored)| {
+            let (val, expires) = split(&stored);
+            if expired(expires, now) { None } else { Some((k, val.to_vec())) }
+        })
+    }
+
+    /// The table underneath, whose values carry their expiry.
+    pub fn raw(&mut self) -> &mut LinHash {
+        &mut self.table
+    }
+
+    pub fn flush(&mut self) {
+        self.table.flush();
+    }
+
+    pub fn close(&mut self) {
+        self.table.close();
+    }
+}
+
+/// A stored value and its expiry time.
+fn split(stored: &[u8]) -> (&[u8], u64) {
+    let at = stored.len() - EXPIRY_SIZE;
+    (&stored[..at], decode(&stored[at..]))
+}
+
+fn expired(expires: u64, now: u64) -> bool {
+    expires != 0 && expires <= now
+}
+
+/// Milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::time::{Duration, Instant};
+    use expiring::ExpiringLinHash;
+    use progress::{CancelToken, NoProgress, Throttle};
+    use util::*;
+
+    #[test]
+    fn expiry() {
+        let path = "/tmp/test_expiry";
+        fs::remove_file(path).ok();
+        let mut h = ExpiringLinHash::open(path, 4, 4);
+        let hour = Duration::from_secs(3600);
+        for k in 0..1000 {
+            match k % 3 {
+                0 => h.put(&encode(k), &encode(k)),
+                1 => h.put_with_ttl(&encode(k), &encode(k), hour),
+                _ => h.put_with_ttl(&encode(k), &encode(k), Duration::ZERO),
+            }
+        }
+        assert_eq!(h.len(), 1000);
+        assert_eq!(h.get(&encode(0)), Some(encode(0)));
+        assert_eq!(h.get(&encode(1)), Some(encode(1)));
+        assert!(h.expires_at(&encode(1)).is_some());
+        assert!(h.expires_at(&encode(0)).is_none());
+        // reclaims the expired records of the bucket of 2
+        assert_eq!(h.get(&encode(2)), None);
+        assert!(h.len() < 1000 - 1);
+        assert_eq!(h.iter().count(), 667);
+        assert!(h.try_put(&encode(1), &[0; 5]).is_err());
+        h.close();
+
+        let mut h = ExpiringLinHash::open(path, 4, 4);
+        assert_eq!(h.valsize(), 4);
+        let left = h.len() - 667;
+        assert_eq!(h.purge_expired().unwrap(), left);
+        assert_eq!(h.len(), 667);
+        // a put without a ttl clears it
+        h.put(&encode(4), &encode(40));
+        assert!(h.expires_at(&encode(4)).is_none());
+        assert_eq!(h.remove(&encode(4)), Some(encode(40)));
+        h.put_with_ttl(&encode(5), &encode(5), Duration::ZERO);
+        assert_eq!(h.remove(&encode(5)), None);
+        assert!(!h.contains_key(&encode(5)));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn purge_reports_progress() {
+        let path = "/tmp/test_purge_progress";
+        fs::remove_file(path).ok();
+        let mut h = ExpiringLinHash::open(path, 4, 4);
+        for k in 0..5000 {
+            h.put_with_ttl(&encode(k), &encode(k), if k % 2 == 0 { Duration::ZERO } else {
+                Duration::from_secs(3600)
+            });
+        }
+        let in_use = h.raw().pages_in_use();
+        let nbuckets = h.raw().bucket_count();
+        let mut seen = vec![];
+        let purged = h.purge_expired_with_progress(&mut |done, total| seen.push((done, total)))
+            .unwrap();
+        assert_eq!(purged, 2500);
+        assert_eq!(seen.len(), nbuckets);
+        assert!(seen.iter().all(|&(_, total)| total == in_use));
+        assert_eq!(h.len(), 2500);
+        assert_eq!(h.iter().count(), 2500);
+        assert!(h.raw().verify().unwrap().is_ok());
+
+        // stop after the first bucket swept
+        for k in 0..5000 {
+            if k % 2 == 0 {
+                h.put_with_ttl(&encode(k), &encode(k), Duration::ZERO);
+            }
+        }
+        let cancel = CancelToken::new();
+        let err = h.purge_expired_cancellable(&mut |_, _| cancel.cancel(), &cancel).unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Interrupted);
+        assert!(h.len() > 2500 && h.len() < 5000);
+        assert!(h.raw().verify().unwrap().is_ok());
+        assert_eq!(h.iter().count(), 2500);
+
+        // throttled to a fifth of a second for the whole table
+        let rate = h.raw().pages_in_use() as f64 * 5.0;
+        let start = Instant::now();
+        h.purge_expired_with_progress(&mut Throttle::new(rate, NoProgress)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(h.len(), 2500);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+}
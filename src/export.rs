@@ -0,0 +1,300 @@
+//! Moving records in and out of a table as CSV or JSON lines, for
+//! migrations and for looking at a table with standard tools.
+//!
+//! ```text
+//! key,value
+//! 2a000000,0100000000000000
+//! ```
+//!
+//! ```text
+//! {"key":"KgAAAA==","value":"AQAAAAAAAAA="}
+//! ```
+//!
+//! Keys and values are binary, so they are written in hex or base64,
+//! exactly as stored (ie. zero-padded to keysize and valsize). Unlike
+//! a dump (see `dump`), neither format records the table's geometry.
+
+use std::io;
+use std::io::prelude::*;
+
+use dump::{from_hex, to_hex};
+use LinHash;
+
+/// How keys and values are written as text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Lowercase hex; either case is read.
+    Hex,
+    /// Standard base64, padded; padding is optional when read.
+    Base64,
+}
+
+/// The layout of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A `key,value` header line, then a line per record. Fields may
+    /// be quoted when read.
+    Csv(Encoding),
+    /// A JSON object per line, with string members `key` and `value`.
+    JsonLines(Encoding),
+}
+
+impl Format {
+    pub fn encoding(self) -> Encoding {
+        match self {
+            Format::Csv(e) | Format::JsonLines(e) => e,
+        }
+    }
+}
+
+const CSV_HEADER: &str = "key,value";
+
+const BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn to_base64(b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len().div_ceil(3) * 4);
+    for chunk in b.chunks(3) {
+        let n = chunk.iter().enumerate()
+            .fold(0u32, |n, (i, &c)| n | (c as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut n, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = BASE64.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    // a lone sextet cannot end a base64 string
+    if bits >= 6 {
+        return None;
+    }
+    Some(out)
+}
+
+fn encode(b: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Hex => to_hex(b),
+        Encoding::Base64 => to_base64(b),
+    }
+}
+
+fn decode(s: &str, encoding: Encoding) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::Hex => from_hex(&s.to_ascii_lowercase()),
+        Encoding::Base64 => from_base64(s),
+    }
+}
+
+/// Writes every record of `table` to `w` in `format`, one bucket at a
+/// time. Returns the number of records written.
+pub fn export<W: Write>(table: &mut LinHash, w: &mut W, format: Format)
+                        -> io::Result<usize> {
+    let encoding = format.encoding();
+    if let Format::Csv(_) = format {
+        writeln!(w, "{}", CSV_HEADER)?;
+    }
+    let mut count = 0;
+    for bucket in 0..table.nbuckets {
+        for (_, records) in table.buckets.all_records_in_bucket(bucket) {
+            for (k, v) in records {
+                let (k, v) = (encode(&k, encoding), encode(&v, encoding));
+                match format {
+                    Format::Csv(_) => writeln!(w, "{},{}", k, v)?,
+                    Format::JsonLines(_) =>
+                        writeln!(w, "{{\"key\":\"{}\",\"value\":\"{}\"}}", k, v)?,
+                }
+                count += 1;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+/// Inserts (or overwrites; with duplicate keys, adds) every record
+/// read from `r` in `format`. Blank lines are skipped. Stops at the first malformed line or
+/// record wider than the table, keeping the records before it.
+/// Returns the number of records imported.
+pub fn import<R: BufRead>(table: &mut LinHash, r: &mut R, format: Format)
+                          -> io::Result<usize> {
+    let encoding = format.encoding();
+    let mut count = 0;
+    for (lineno, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (lineno == 0 && line == CSV_HEADER) {
+            continue;
+        }
+        let bad = || invalid(format!("line {}: malformed record", lineno + 1));
+        let (k, v) = match format {
+            Format::Csv(_) => parse_csv(line),
+            Format::JsonLines(_) => parse_json(line),
+        }.ok_or_else(bad)?;
+        let (k, v) = (decode(&k, encoding).ok_or_else(bad)?,
+                      decode(&v, encoding).ok_or_else(bad)?);
+        if k.len() > table.buckets.keysize() || v.len() > table.buckets.valsize() {
+            return Err(invalid(format!("line {}: record wider than table", lineno + 1)));
+        }
+        if table.duplicate_keys() || !table.try_update(&k, &v)? {
+            table.try_put(&k, &v)?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn unquote(field: &str) -> &str {
+    let field = field.trim();
+    field.strip_prefix('"').and_then(|f| f.strip_suffix('"')).unwrap_or(field)
+}
+
+fn parse_csv(line: &str) -> Option<(String, String)> {
+    let (k, v) = line.split_once(',')?;
+    Some((unquote(k).to_string(), unquote(v).to_string()))
+}
+
+/// The `key` and `value` members of a flat JSON object of strings.
+/// Other string members are ignored.
+fn parse_json(line: &str) -> Option<(String, String)> {
+    let mut rest = line.strip_prefix('{')?.trim_start();
+    let (mut key, mut value) = (None, None);
+    loop {
+        if let Some(r) = rest.strip_prefix('}') {
+            return if r.trim().is_empty() { Some((key?, value?)) } else { None };
+        }
+        let (name, r) = json_string(rest)?;
+        let r = r.trim_start().strip_prefix(':')?;
+        let (s, r) = json_string(r.trim_start())?;
+        match name.as_str() {
+            "key" => key = Some(s),
+            "value" => value = Some(s),
+            _ => (),
+        }
+        let r = r.trim_start();
+        rest = r.strip_prefix(',').map_or(r, str::trim_start);
+    }
+}
+
+/// A JSON string at the start of `s`, and what follows it.
+fn json_string(s: &str) -> Option<(String, &str)> {
+    let s = s.strip_prefix('"')?;
+    let mut out = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                '"' => out.push('"'),
+                '\\' => out.push('\\'),
+                '/' => out.push('/'),
+                // nothing else can appear in hex or base64
+                _ => return None,
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use export::{self, Encoding, Format};
+    use std::fs;
+    use std::io::BufReader;
+    use util::*;
+    use LinHash;
+
+    #[test]
+    fn base64() {
+        for len in 0..8 {
+            let b: Vec<u8> = (0..len).map(|i| 250 - i as u8).collect();
+            let s = export::to_base64(&b);
+            assert_eq!(s.len() % 4, 0);
+            assert_eq!(export::from_base64(&s), Some(b.clone()));
+            assert_eq!(export::from_base64(s.trim_end_matches('=')), Some(b));
+        }
+        assert_eq!(export::to_base64(b"hello"), "aGVsbG8=");
+        assert_eq!(export::from_base64("aGVsbG8"), Some(b"hello".to_vec()));
+        assert_eq!(export::from_base64("a"), None);
+        assert_eq!(export::from_base64("a*=="), None);
+    }
+
+    #[test]
+    fn export_and_import() {
+        let formats = [Format::Csv(Encoding::Hex), Format::Csv(Encoding::Base64),
+                       Format::JsonLines(Encoding::Hex), Format::JsonLines(Encoding::Base64)];
+        for (i, &format) in formats.iter().enumerate() {
+            let src = format!("/tmp/test_export_src{}", i);
+            let dst = format!("/tmp/test_export_dst{}", i);
+            fs::remove_file(&src).ok();
+            fs::remove_file(&dst).ok();
+            let mut h = LinHash::open(&src, 4, 8);
+            for k in 0..500 {
+                h.put(&encode(k), &encode(k as u64 * 3));
+            }
+            let mut out = vec![];
+            assert_eq!(export::export(&mut h, &mut out, format).unwrap(), 500);
+            h.close();
+
+            let mut h2 = LinHash::open(&dst, 4, 8);
+            h2.put(&encode(1), &encode(7u64));
+            let mut r = BufReader::new(&out[..]);
+            assert_eq!(export::import(&mut h2, &mut r, format).unwrap(), 500);
+            assert_eq!(h2.len(), 500);
+            for k in 0..500 {
+                assert_eq!(h2.get_u64(&encode(k)), Some(k as u64 * 3));
+            }
+            h2.close();
+            fs::remove_file(&src).ok();
+            fs::remove_file(&dst).ok();
+        }
+    }
+
+    #[test]
+    fn import_by_hand() {
+        let path = "/tmp/test_import_by_hand";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        let csv = "key,value\n\"01000000\", \"0A000000\"\n\n02000000,0b\n";
+        let mut r = BufReader::new(csv.as_bytes());
+        assert_eq!(export::import(&mut h, &mut r, Format::Csv(Encoding::Hex)).unwrap(), 2);
+        assert_eq!(h.get(&encode(1)), Some(encode(10)));
+        assert_eq!(h.get(&encode(2)), Some(encode(11)));
+
+        let json = "{ \"value\" : \"DAAAAA==\", \"note\": \"x\\\"y\", \"key\": \"AwAAAA\" }\n";
+        let mut r = BufReader::new(json.as_bytes());
+        assert_eq!(export::import(&mut h, &mut r, Format::JsonLines(Encoding::Base64))
+                   .unwrap(), 1);
+        assert_eq!(h.get(&encode(3)), Some(encode(12)));
+
+        for bad in &["{\"key\":\"AQAAAA==\"}", "{\"key\":\"AQ\",\"value\":\"AQ\"} x",
+                     "{\"key\":\"AQAAAAAA\",\"value\":\"AQ==\"}"] {
+            let mut r = BufReader::new(bad.as_bytes());
+            assert!(export::import(&mut h, &mut r, Format::JsonLines(Encoding::Base64))
+                    .is_err());
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+}
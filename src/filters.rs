@@ -0,0 +1,215 @@
+//! A Bloom filter per bucket, so that most lookups of absent keys
+//! return without reading the bucket's chain.
+//!
+//! Every bucket's filter has the same size, about `BITS_PER_RECORD`
+//! bits per record a page holds: a bucket at the split threshold
+//! gets some 12 bits per key. Inserts set a key's bits in its
+//! bucket's filter; removes leave them, which only costs false
+//! positives. A split empties the split bucket's filter before its
+//! records are inserted again, and a merge refills the filter of the
+//! bucket merged into.
+//!
+//! The filters live in memory and are saved to `<file>.filters` when
+//! the table is closed, stamped with the table's hash seed and the
+//! sequence number of the ctrl page written with them (see `disk`).
+//! They are only loaded back if the table and its ctrl page still
+//! match, ie. nothing wrote the table since; otherwise they are
+//! rebuilt from the table.
+//!
+//! Format (integers little-endian), sealed if the table is encrypted:
+//!
+//! | magic | hash seed | ctrl seq | nbuckets | words per bucket |
+//! | bit words (u64) | crc32 |
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crypt::Cipher;
+use disk::sidecar_path;
+use hasher::phf_hash;
+use util::*;
+
+const MAGIC: &[u8; 8] = b"LHBFLTR1";
+
+/// Filter bits per record that fits in a page.
+pub const BITS_PER_RECORD: usize = 10;
+/// Bits set per key.
+const NHASHES: usize = 4;
+
+pub fn filters_path<P: AsRef<Path>>(db_path: P) -> PathBuf {
+    sidecar_path(db_path.as_ref(), ".filters")
+}
+
+pub(crate) struct BucketFilters {
+    keysize: usize,
+    // u64 words in the filter of one bucket
+    width: usize,
+    // the filters of all buckets, one after the other
+    words: Vec<u64>,
+}
+
+impl BucketFilters {
+    /// Empty filters for `nbuckets` buckets of a table with
+    /// `records_per_page` records to a page.
+    pub fn new(keysize: usize, records_per_page: usize, nbuckets: usize) -> BucketFilters {
+        let width = (records_per_page * BITS_PER_RECORD).div_ceil(64).max(1);
+        BucketFilters { keysize, width, words: vec![0; width * nbuckets] }
+    }
+
+    /// Bit positions for `key` in its bucket's filter, by double
+    /// hashing. Keys equal up to zero padding share them.
+    fn positions(&self, key: &[u8]) -> [usize; NHASHES] {
+        let h1 = phf_hash(key, self.keysize, 2);
+        let h2 = phf_hash(key, self.keysize, 3) | 1;
+        let nbits = (self.width * 64) as u64;
+        let mut bits = [0; NHASHES];
+        for (i, bit) in bits.iter_mut().enumerate() {
+            *bit = (h1.wrapping_add((i as u64).wrapping_mul(h2)) % nbits) as usize;
+        }
+        bits
+    }
+
+    pub fn insert(&mut self, bucket: usize, key: &[u8]) {
+        let base = bucket * self.width * 64;
+        for bit in self.positions(key) {
+            self.words[(base + bit) / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// `false` means `key` is definitely not in `bucket`.
+    pub fn may_contain(&self, bucket: usize, key: &[u8]) -> bool {
+        let base = bucket * self.width * 64;
+        self.positions(key).iter()
+            .all(|&bit| self.words[(base + bit) / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Forgets every key of `bucket`.
+    pub fn reset(&mut self, bucket: usize) {
+        for w in &mut self.words[bucket * self.width..(bucket + 1) * self.width] {
+            *w = 0;
+        }
+    }
+
+    /// Adds an empty filter for a new last bucket.
+    pub fn push(&mut self) {
+        self.words.resize(self.words.len() + self.width, 0);
+    }
+
+    /// Drops the filter of the last bucket.
+    pub fn pop(&mut self) {
+        self.words.truncate(self.words.len() - self.width);
+    }
+
+    /// Saves the filters to `path`, stamped with `hash_seed` and
+    /// `ctrl_seq`.
+    pub fn save(&self, path: &Path, hash_seed: u64, ctrl_seq: usize, cipher: Option<&Cipher>)
+                -> io::Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&encode(hash_seed));
+        buf.extend_from_slice(&usize_to_bytearray(ctrl_seq));
+        buf.extend_from_slice(&usize_to_bytearray(self.words.len() / self.width));
+        buf.extend_from_slice(&usize_to_bytearray(self.width));
+        for &w in &self.words {
+            buf.extend_from_slice(&encode(w));
+        }
+        let crc = crc32(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        if let Some(cipher) = cipher {
+            buf = cipher.seal(MAGIC, &buf);
+        }
+        fs::write(path, &buf)
+    }
+
+    /// Loads saved filters into these empty ones, if they were saved
+    /// for the table with `hash_seed`, with its ctrl page numbered
+    /// `ctrl_seq`, and for as many buckets of the same size. Returns
+    /// false, leaving them empty, if there are none, or they are stale
+    /// or damaged.
+    pub fn load(&mut self, path: &Path, hash_seed: u64, ctrl_seq: usize,
+                cipher: Option<&Cipher>) -> io::Result<bool> {
+        let mut buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if let Some(cipher) = cipher {
+            buf = match cipher.open(MAGIC, &buf) {
+                Some(buf) => buf,
+                None => return Ok(false),
+            };
+        }
+        let seq_at = MAGIC.len() + 8;
+        let header = seq_at + 3 * USIZE_WIDTH;
+        let len = header + self.words.len() * 8 + 4;
+        let stamp = (hash_seed, ctrl_seq, self.words.len() / self.width, self.width);
+        if buf.len() != len || &buf[..MAGIC.len()] != MAGIC
+            || decode::<u32>(&buf[len - 4..]) != crc32(&buf[..len - 4])
+            || (decode::<u64>(&buf[MAGIC.len()..seq_at]),
+                read_usize_at(&buf, seq_at).unwrap_or(0),
+                read_usize_at(&buf, seq_at + USIZE_WIDTH).unwrap_or(0),
+                read_usize_at(&buf, seq_at + 2 * USIZE_WIDTH).unwrap_or(0)) != stamp {
+            return Ok(false);
+        }
+        for (w, chunk) in self.words.iter_mut().zip(buf[header..len - 4].chunks(8)) {
+            *w = decode(chunk);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use filters::filters_path;
+    use util::*;
+    use LinHash;
+
+    fn open(path: &str) -> LinHash {
+        LinHash::options().keysize(4).valsize(4).bucket_filters(true).open(path).unwrap()
+    }
+
+    #[test]
+    fn bucket_filters() {
+        let path = "/tmp/test_bucket_filters";
+        fs::remove_file(path).ok();
+        fs::remove_file(filters_path(path)).ok();
+        let mut h = open(path);
+        // splits, then merges
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 1000..5000 {
+            h.remove(&encode(k));
+        }
+        for k in 0..1000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        let ruled_out = (10000..11000)
+            .filter(|&k: &i32| {
+                let key = encode(k);
+                !h.may_contain(h.bucket(&key), &key)
+            })
+            .count();
+        assert!(ruled_out > 950, "{}", ruled_out);
+        let words = h.filters.as_ref().unwrap().words.clone();
+        h.close();
+
+        // reopened as saved
+        let mut h = open(path);
+        assert_eq!(h.filters.as_ref().unwrap().words, words);
+        h.close();
+
+        // but not after the table changed without them
+        let mut h = LinHash::open(path, 4, 4);
+        h.put(&encode(20000), &encode(1));
+        h.close();
+        let mut h = open(path);
+        assert_eq!(h.get(&encode(20000)), Some(encode(1)));
+        h.clear();
+        assert_eq!(h.get(&encode(0)), None);
+        h.close();
+        fs::remove_file(path).ok();
+        fs::remove_file(filters_path(path)).ok();
+    }
+}
@@ -0,0 +1,122 @@
+//! Choice of hash function for a table's keys.
+//!
+//! A table remembers the id of the hasher it was created with in its
+//! control page, and refuses to open with any other: its records are
+//! placed by their hashes, so a different hasher would not find them.
+//! The hasher itself (and any keys it was seeded with) is not stored,
+//! so it has to be supplied again on every open.
+//!
+//! Tables opened without a hasher use a built-in one, which needs no
+//! supplying: new tables get `phf_hash` with a random seed kept in the
+//! control page, along with the hash of a probe key under it, which
+//! `open` checks so that a change to the algorithm is caught instead
+//! of misplacing every key. Tables from before it keep `DefaultHasher`.
+
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+#[cfg(feature = "std")]
+use std::hash::BuildHasherDefault;
+use std::hash::BuildHasher;
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+/// Id of `DefaultHasher`, whose output may change between Rust
+/// releases. Tables created before the stable hasher use it.
+pub const SIP_HASHER_ID: u16 = 0;
+/// Id of the seeded `phf_hash`, the hasher of new tables.
+pub const STABLE_HASHER_ID: u16 = 1;
+
+/// Seeded 64-bit hash of `key` zero-padded to `keysize`: FNV-1a
+/// followed by a splitmix64 finalizer. Fixed here, rather than using
+/// `DefaultHasher`, so exported files stay readable across Rust
+/// releases.
+pub fn phf_hash(key: &[u8], keysize: usize, seed: u64) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325 ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let padding = keysize.saturating_sub(key.len());
+    for &b in key.iter().chain(::std::iter::repeat_n(&0, padding)) {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Key whose stable hash is stored next to the seed.
+const PROBE: &[u8] = b"linhash";
+
+/// The hash of `PROBE` under `seed`, to check the seed against.
+pub fn seed_check(seed: u64) -> u64 {
+    phf_hash(PROBE, 0, seed)
+}
+
+type HashFn = Box<dyn Fn(&[u8]) -> u64 + Send + Sync>;
+
+/// A `BuildHasher` together with the id recorded for it on disk.
+pub struct KeyHasher {
+    id: u16,
+    hash: HashFn,
+}
+
+impl KeyHasher {
+    /// Hashes keys with `build`, recorded in new tables as `id`. Pick a
+    /// different id for every hasher, and for every set of keys a
+    /// seeded hasher is used with. Panics if `id` is 0 or 1, which
+    /// stand for the built-in hashers.
+    pub fn new<S>(id: u16, build: S) -> KeyHasher
+        where S: BuildHasher + Send + Sync + 'static {
+        assert!(id > STABLE_HASHER_ID, "hasher ids 0 and 1 are reserved for the built-in hashers");
+        KeyHasher::with_id(id, build)
+    }
+
+    /// The built-in hasher `id`, seeded with `seed` if it takes one.
+    /// `DefaultHasher` needs `std`.
+    pub fn builtin(id: u16, seed: u64) -> Option<KeyHasher> {
+        match id {
+            #[cfg(feature = "std")]
+            SIP_HASHER_ID => Some(KeyHasher::with_id(
+                id, BuildHasherDefault::<DefaultHasher>::default())),
+            STABLE_HASHER_ID => Some(KeyHasher {
+                id,
+                hash: Box::new(move |key| phf_hash(key, 0, seed)),
+            }),
+            _ => None,
+        }
+    }
+
+    fn with_id<S>(id: u16, build: S) -> KeyHasher
+        where S: BuildHasher + Send + Sync + 'static {
+        KeyHasher {
+            id,
+            hash: Box::new(move |key| build.hash_one(key)),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Keys are hashed as they are stored, ie. zero-padded to
+    /// `keysize`, so that a record re-inserted from its page during a
+    /// split lands in the same bucket a lookup with the short key
+    /// would search.
+    pub fn hash_key(&self, key: &[u8], keysize: usize) -> u64 {
+        if key.len() < keysize {
+            let mut padded = key.to_vec();
+            padded.resize(keysize, 0);
+            (self.hash)(&padded)
+        } else {
+            (self.hash)(key)
+        }
+    }
+}
+
+impl fmt::Debug for KeyHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyHasher").field("id", &self.id).finish()
+    }
+}
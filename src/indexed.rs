@@ -0,0 +1,316 @@
+//! A table with secondary indexes, kept up to date as records change.
+//!
+//! Each index is a table of its own in `<file>.idx.<name>`, a multimap
+//! from a secondary key, which a closure extracts from a record, to the
+//! primary keys of the records that have it:
+//!
+//! ```no_run
+//! use linhash::IndexedLinHash;
+//!
+//! // values hold a 4-byte owner id, then the rest of the record
+//! let mut h = IndexedLinHash::open("/tmp/orders", 8, 64);
+//! h.add_index("owner", 4, |_, val| Some(val[..4].to_vec())).unwrap();
+//! h.put(b"order001", b"u042...");
+//! let orders = h.get_by_index("owner", b"u042").unwrap();
+//! ```
+//!
+//! Index entries are changed after the record itself, so a crash in
+//! between can leave an index out of date: an entry for a record that
+//! no longer has that secondary key is skipped by `get_by_index`, and
+//! `rebuild_index` repairs a missing one. Changes made through `raw`
+//! bypass the indexes altogether.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use error::{self, LinHashError};
+use disk::{sidecar_path, Record};
+use page::PageLayout;
+use LinHash;
+
+/// Extracts a record's secondary key, or None if the record is not in
+/// the index. Gets the key and value as stored, zero-padded.
+type Extract = Box<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>>>;
+
+struct Index {
+    name: String,
+    table: LinHash,
+    extract: Extract,
+}
+
+/// Linear Hashtable of byte keys and values with any number of
+/// secondary indexes. See the module documentation.
+pub struct IndexedLinHash {
+    table: LinHash,
+    indexes: Vec<Index>,
+}
+
+impl IndexedLinHash {
+    /// Opens (or creates) the table stored in `filename`, with no
+    /// indexes yet. Panics on error; see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                -> IndexedLinHash {
+        IndexedLinHash::try_open(filename, keysize, valsize).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                    -> error::Result<IndexedLinHash> {
+        IndexedLinHash::new(LinHash::try_open(filename, keysize, valsize)?)
+    }
+
+    /// Indexes an open table. Fails for a table with duplicate keys,
+    /// whose records a primary key does not identify.
+    pub fn new(table: LinHash) -> error::Result<IndexedLinHash> {
+        if table.duplicate_keys() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} allows duplicate keys and cannot be indexed", table.buckets.path().display())));
+        }
+        Ok(IndexedLinHash { table, indexes: vec![] })
+    }
+
+    /// Registers the index `name`, of secondary keys up to `keysize`
+    /// bytes. Its file is created, and filled from the table, if it
+    /// does not exist yet; an existing one is taken as up to date.
+    pub fn add_index<F>(&mut self, name: &str, keysize: usize, extract: F) -> error::Result<()>
+        where F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + 'static {
+        if name.is_empty() || name.contains('/') {
+            return Err(LinHashError::InvalidArgument(format!("bad index name {:?}", name)));
+        }
+        if self.indexes.iter().any(|i| i.name == name) {
+            return Err(LinHashError::InvalidArgument(format!("index {} already exists", name)));
+        }
+        let path = sidecar_path(self.table.buckets.path(), &format!(".idx.{}", name));
+        let table = LinHash::options()
+            .keysize(keysize)
+            .valsize(self.table.buckets.keysize())
+            .duplicate_keys(true)
+            .open(&path)?;
+        let build = table.is_empty() && !self.table.is_empty();
+        self.indexes.push(Index { name: name.to_string(), table, extract: Box::new(extract) });
+        if build {
+            self.rebuild_index(name)?;
+        }
+        Ok(())
+    }
+
+    /// Refills index `name` from the table.
+    pub fn rebuild_index(&mut self, name: &str) -> error::Result<()> {
+        let i = self.index_of(name)?;
+        let index = &mut self.indexes[i];
+        index.table.try_clear()?;
+        for (key, val) in self.table.iter() {
+            if let Some(skey) = (index.extract)(&key, &val) {
+                index.table.try_put_dup(&skey, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_of(&self, name: &str) -> error::Result<usize> {
+        self.indexes.iter().position(|i| i.name == name)
+            .ok_or_else(|| LinHashError::InvalidArgument(format!("no index {}", name)))
+    }
+
+    /// Names of the registered indexes.
+    pub fn index_names(&self) -> Vec<&str> {
+        self.indexes.iter().map(|i| &i.name[..]).collect()
+    }
+
+    /// Stores `val` under `key`, replacing any value it had, and
+    /// updates every index. Panics on error; see `try_put`.
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.try_put(key, val).unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    pub fn try_put(&mut self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        let key = self.stored(key, self.table.buckets.keysize());
+        let val = self.stored(val, self.table.buckets.valsize());
+        let old = self.table.try_get(&key)?;
+        if !self.table.try_update(&key, &val)? {
+            self.table.try_put(&key, &val)?;
+        }
+        self.reindex(&key, old.as_deref(), Some(&val))
+    }
+
+    /// Replaces the value of `key`, if it has one. Returns whether it
+    /// did.
+    pub fn update(&mut self, key: &[u8], val: &[u8]) -> bool {
+        self.try_update(key, val).unwrap_or_else(|e| panic!("update failed: {}", e))
+    }
+
+    pub fn try_update(&mut self, key: &[u8], val: &[u8]) -> error::Result<bool> {
+        let key = self.stored(key, self.table.buckets.keysize());
+        let val = self.stored(val, self.table.buckets.valsize());
+        let old = match self.table.try_get(&key)? {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        self.table.try_update(&key, &val)?;
+        self.reindex(&key, Some(&old), Some(&val))?;
+        Ok(true)
+    }
+
+    /// Deletes `key` from the table and every index, returning its
+    /// value.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.try_remove(key).unwrap_or_else(|e| panic!("remove failed: {}", e))
+    }
+
+    pub fn try_remove(&mut self, key: &[u8]) -> error::Result<Option<Vec<u8>>> {
+        let key = self.stored(key, self.table.buckets.keysize());
+        let old = self.table.try_remove(&key)?;
+        if old.is_some() {
+            self.reindex(&key, old.as_deref(), None)?;
+        }
+        Ok(old)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.table.get(key)
+    }
+
+    /// Every record whose secondary key in index `name` is `skey`, as
+    /// stored.
+    pub fn get_by_index(&mut self, name: &str, skey: &[u8]) -> error::Result<Vec<Record>> {
+        let i = self.index_of(name)?;
+        let index = &mut self.indexes[i];
+        let skey = pad(&index.table, skey, index.table.buckets.keysize());
+        let mut records = vec![];
+        let mut seen = HashSet::new();
+        for key in index.table.try_get_all(&skey)? {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            if let Some(val) = self.table.try_get(&key)? {
+                // skip entries a crash left behind
+                let current = (index.extract)(&key, &val)
+                    .map(|s| pad(&index.table, &s, skey.len()));
+                if current.as_ref() == Some(&skey) {
+                    records.push((key, val));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Moves `key` in every index from the secondary key of its `old`
+    /// value to that of its `new` one.
+    fn reindex(&mut self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>)
+               -> error::Result<()> {
+        for index in &mut self.indexes {
+            let width = index.table.buckets.keysize();
+            let before = old.and_then(|v| (index.extract)(key, v))
+                .map(|s| pad(&index.table, &s, width));
+            let after = new.and_then(|v| (index.extract)(key, v))
+                .map(|s| pad(&index.table, &s, width));
+            if before == after {
+                continue;
+            }
+            if let Some(skey) = before {
+                let keys = index.table.try_get_all(&skey)?;
+                index.table.try_remove_all(&skey)?;
+                for k in keys.into_iter().filter(|k| k[..] != *key) {
+                    index.table.try_put_dup(&skey, &k)?;
+                }
+            }
+            if let Some(skey) = after {
+                index.table.try_put_dup(&skey, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `b` as the table stores it.
+    fn stored(&self, b: &[u8], width: usize) -> Vec<u8> {
+        pad(&self.table, b, width)
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The table underneath. Changes made through it are not indexed.
+    pub fn raw(&mut self) -> &mut LinHash {
+        &mut self.table
+    }
+
+    pub fn flush(&mut self) {
+        self.table.flush();
+        for index in &mut self.indexes {
+            index.table.flush();
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.table.close();
+        for index in &mut self.indexes {
+            index.table.close();
+        }
+    }
+}
+
+/// `b` zero-padded to `width`, as `table` stores it; only a slotted
+/// table stores bytes unpadded.
+fn pad(table: &LinHash, b: &[u8], width: usize) -> Vec<u8> {
+    let mut padded = b.to_vec();
+    if table.buckets.page_layout() != PageLayout::Slotted {
+        padded.resize(width.max(b.len()), 0);
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use indexed::IndexedLinHash;
+    use util::*;
+
+    #[test]
+    fn secondary_index() {
+        let path = "/tmp/test_indexed";
+        let index_path = "/tmp/test_indexed.idx.parity";
+        fs::remove_file(path).ok();
+        fs::remove_file(index_path).ok();
+        let mut h = IndexedLinHash::open(path, 4, 8);
+        // indexed by the parity of the first half of the value
+        let parity = |_: &[u8], v: &[u8]| Some(encode(decode::<i32>(&v[..4]) % 2));
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.add_index("parity", 4, parity).unwrap();
+        assert!(h.add_index("parity", 4, parity).is_err());
+        assert_eq!(h.get_by_index("parity", &encode(1)).unwrap().len(), 50);
+
+        for k in 0..10 {
+            h.put(&encode(k), &encode(1));
+        }
+        assert!(h.update(&encode(10), &encode(3)));
+        assert!(!h.update(&encode(1000), &encode(3)));
+        assert_eq!(h.remove(&encode(11)).map(|v| v[..4].to_vec()), Some(encode(11)));
+        let odd = h.get_by_index("parity", &encode(1)).unwrap();
+        assert_eq!(odd.len(), 55);
+        assert!(odd.iter().all(|(_, v)| decode::<i32>(&v[..4]) % 2 == 1));
+        assert_eq!(h.get_by_index("parity", &encode(0)).unwrap().len(), 44);
+        assert!(h.get_by_index("size", &encode(0)).is_err());
+        h.close();
+
+        // an existing index is reopened as it is
+        let mut h = IndexedLinHash::open(path, 4, 8);
+        h.add_index("parity", 4, parity).unwrap();
+        assert_eq!(h.index_names(), ["parity"]);
+        assert_eq!(h.get_by_index("parity", &encode(1)).unwrap().len(), 55);
+        // changes behind its back are repaired by a rebuild
+        h.raw().put(&encode(500), &encode(1));
+        assert_eq!(h.get_by_index("parity", &encode(1)).unwrap().len(), 55);
+        h.rebuild_index("parity").unwrap();
+        assert_eq!(h.get_by_index("parity", &encode(1)).unwrap().len(), 56);
+        h.close();
+        fs::remove_file(path).ok();
+        fs::remove_file(index_path).ok();
+    }
+}
@@ -0,0 +1,147 @@
+//! Undo journal for bucket splits.
+//!
+//! A split rewrites several pages (the split bucket's chain, the new
+//! bucket's root, the free list and the control page) and holds the
+//! records being moved only in memory while it does so. Before a
+//! split starts, the pre-split image of every page it may touch is
+//! written to `<file>.journal` and synced; once the split's pages and
+//! control page have been synced to the database file, the journal is
+//! truncated. If the process dies in between, the next `open` finds a
+//! complete journal and writes the images back, returning the table to
+//! its state just before the split. A journal that was itself only
+//! partially written fails its checksum and is discarded: the split it
+//! described had not started yet.
+//!
+//! Replay is idempotent: the journal holds whole page images rather
+//! than logical records, so writing them back a second time, eg. after
+//! a crash during recovery itself, leaves the same bytes on disk. The
+//! images keep the page LSNs they had (see `wal`), which are never
+//! later than the changes the pages hold.
+//!
+//! Format (integers little-endian):
+//!
+//! | magic | nimages | (page_id | page image) * nimages | crc32 |
+//!
+//! The journal of an encrypted table (see `crypt`) is all of that,
+//! sealed.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crypt::Cipher;
+use disk::sidecar_path;
+use page::PAGE_SIZE;
+use util::*;
+
+const MAGIC: &[u8; 8] = b"LHSPLIT1";
+
+/// A page id and the bytes to restore it to.
+pub type PageImage = (usize, Vec<u8>);
+
+pub fn journal_path<P: AsRef<Path>>(db_path: P) -> PathBuf {
+    sidecar_path(db_path.as_ref(), ".journal")
+}
+
+/// Durably records `images` in the journal at `path`, sealed with
+/// `cipher` if there is one.
+pub fn write<P: AsRef<Path>>(path: P, images: &[PageImage], cipher: Option<&Cipher>) -> io::Result<()> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&usize_to_bytearray(images.len()));
+    for &(page_id, ref image) in images {
+        assert_eq!(image.len(), PAGE_SIZE);
+        buf.extend_from_slice(&usize_to_bytearray(page_id));
+        buf.extend_from_slice(image);
+    }
+    let crc = crc32(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    if let Some(cipher) = cipher {
+        buf = cipher.seal(MAGIC, &buf);
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(&buf)?;
+    file.sync_all()
+}
+
+/// Reads back a complete journal. Returns `None` if there is no
+/// journal, it is empty, or it was torn while being written.
+pub fn read<P: AsRef<Path>>(path: P, cipher: Option<&Cipher>) -> io::Result<Option<Vec<PageImage>>> {
+    let mut buf = vec![];
+    match File::open(path) {
+        Ok(mut f) => { f.read_to_end(&mut buf)?; },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if let Some(cipher) = cipher {
+        buf = match cipher.open(MAGIC, &buf) {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+    }
+    if buf.len() < MAGIC.len() + USIZE_WIDTH + 4 || &buf[..8] != MAGIC {
+        return Ok(None);
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32(body) != decode::<u32>(crc) {
+        return Ok(None);
+    }
+
+    let nimages = read_usize_at(body, 8).unwrap_or(0);
+    let entry = USIZE_WIDTH + PAGE_SIZE;
+    if body.len() != 16 + nimages * entry {
+        return Ok(None);
+    }
+    let images = body[16..].chunks(entry)
+        .map(|c| (bytearray_to_usize(&c[..USIZE_WIDTH]).unwrap_or(0),
+                  c[USIZE_WIDTH..].to_vec()))
+        .collect();
+    Ok(Some(images))
+}
+
+/// Marks the journal as done. The file is truncated and synced rather
+/// than removed, so that a crash cannot resurrect it.
+pub fn clear<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    match OpenOptions::new().write(true).open(path) {
+        Ok(f) => {
+            f.set_len(0)?;
+            f.sync_all()
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn journal_roundtrip() {
+        let path = "/tmp/test_split_journal";
+        fs::remove_file(path).ok();
+        assert_eq!(read(path, None).unwrap(), None);
+
+        let images = vec![(0, vec![1; PAGE_SIZE]), (7, vec![2; PAGE_SIZE])];
+        write(path, &images, None).unwrap();
+        // reading does not consume the journal, so a recovery that is
+        // itself interrupted replays the same images again
+        assert_eq!(read(path, None).unwrap(), Some(images.clone()));
+        assert_eq!(read(path, None).unwrap(), Some(images));
+
+        // a torn write fails the checksum
+        let data = fs::read(path).unwrap();
+        fs::write(path, &data[..data.len() - 100]).unwrap();
+        assert_eq!(read(path, None).unwrap(), None);
+
+        clear(path).unwrap();
+        assert_eq!(read(path, None).unwrap(), None);
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,279 @@
+//! An adapter exposing linhash through the `Store`/`Bucket` interface
+//! of the `kv` crate, so code written against that API can switch
+//! stores by changing its imports. Enabled by the `kv` feature, which
+//! depends on `kv`: keys and values are any types implementing its
+//! `Key` and `Value` traits, including its `Integer` and codec types.
+//!
+//! Each named bucket is a separate `LinHash` file inside the store's
+//! directory. Values are stored with a 4-byte length prefix, so any
+//! value up to `valsize - 4` bytes round-trips exactly; keys are
+//! zero-padded to `keysize` like in `LinHash` itself.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use kv_crate::{self, Key, Raw, Value};
+
+use LinHash;
+
+const LEN_PREFIX: usize = 4;
+const DEFAULT_BUCKET: &str = "__default";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// Encoding or decoding through the `kv` crate's traits failed.
+    Kv(kv_crate::Error),
+    /// The encoded key is wider than the store's `keysize`.
+    KeyTooLarge(usize),
+    /// The encoded value doesn't fit in `valsize - 4` bytes.
+    ValueTooLarge(usize),
+    /// A stored value's length runs past its slot.
+    InvalidValue,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Kv(ref e) => write!(f, "{}", e),
+            Error::KeyTooLarge(n) => write!(f, "key of {} bytes is too large", n),
+            Error::ValueTooLarge(n) => write!(f, "value of {} bytes is too large", n),
+            Error::InvalidValue => write!(f, "stored value could not be decoded"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<kv_crate::Error> for Error {
+    fn from(e: kv_crate::Error) -> Error {
+        Error::Kv(e)
+    }
+}
+
+/// Store configuration: a directory plus the record geometry used for
+/// every bucket in it.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    pub keysize: usize,
+    /// Slot width, including the 4-byte length prefix.
+    pub valsize: usize,
+}
+
+impl Config {
+    /// Defaults to 64-byte keys and 256-byte value slots.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Config {
+        Config { path: path.into(), keysize: 64, valsize: 256 }
+    }
+
+    pub fn keysize(mut self, keysize: usize) -> Config {
+        self.keysize = keysize;
+        self
+    }
+
+    pub fn valsize(mut self, valsize: usize) -> Config {
+        self.valsize = valsize;
+        self
+    }
+}
+
+/// A directory of named buckets.
+pub struct Store {
+    config: Config,
+    open: Mutex<HashMap<String, Arc<Mutex<LinHash>>>>,
+}
+
+impl Store {
+    pub fn new(config: Config) -> Result<Store, Error> {
+        fs::create_dir_all(&config.path)?;
+        Ok(Store { config, open: Mutex::new(HashMap::new()) })
+    }
+
+    /// Opens the bucket `name` (or the default bucket), creating it if
+    /// needed. Handles to the same bucket share one table.
+    pub fn bucket<'a, K: Key<'a>, V: Value>(&self, name: Option<&str>)
+                                            -> Result<Bucket<'a, K, V>, Error> {
+        let name = name.unwrap_or(DEFAULT_BUCKET);
+        let mut open = self.open.lock().expect("store lock poisoned");
+        let table = match open.get(name) {
+            Some(t) => t.clone(),
+            None => {
+                let path = self.config.path.join(format!("{}.linhash", name));
+                let t = Arc::new(Mutex::new(
+                    LinHash::open(path, self.config.keysize, self.config.valsize)));
+                open.insert(name.to_string(), t.clone());
+                t
+            },
+        };
+        Ok(Bucket {
+            table,
+            keysize: self.config.keysize,
+            valsize: self.config.valsize,
+            _types: PhantomData,
+        })
+    }
+
+    /// Names of buckets opened through this store.
+    pub fn buckets(&self) -> Vec<String> {
+        self.open.lock().expect("store lock poisoned").keys().cloned().collect()
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Ok(open) = self.open.lock() {
+            for t in open.values() {
+                if let Ok(mut t) = t.lock() {
+                    t.close();
+                }
+            }
+        }
+    }
+}
+
+/// A typed view of one bucket.
+pub struct Bucket<'a, K: Key<'a>, V: Value> {
+    table: Arc<Mutex<LinHash>>,
+    keysize: usize,
+    valsize: usize,
+    _types: PhantomData<(&'a (), K, V)>,
+}
+
+impl<'a, K: Key<'a>, V: Value> Clone for Bucket<'a, K, V> {
+    fn clone(&self) -> Bucket<'a, K, V> {
+        Bucket {
+            table: self.table.clone(),
+            keysize: self.keysize,
+            valsize: self.valsize,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<'a, K: Key<'a>, V: Value> Bucket<'a, K, V> {
+    fn raw_key(&self, key: &K) -> Result<Raw, Error> {
+        let k = key.to_raw_key()?;
+        if k.len() > self.keysize {
+            return Err(Error::KeyTooLarge(k.len()));
+        }
+        Ok(k)
+    }
+
+    fn decode(&self, slot: Vec<u8>) -> Result<V, Error> {
+        let mut len = [0; LEN_PREFIX];
+        len.copy_from_slice(&slot[..LEN_PREFIX]);
+        let len = u32::from_le_bytes(len) as usize;
+        let body = slot.get(LEN_PREFIX..LEN_PREFIX + len)
+            .ok_or(Error::InvalidValue)?;
+        Ok(V::from_raw_value(Raw::from(body))?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        let k = self.raw_key(key)?;
+        let slot = self.table.lock().expect("bucket lock poisoned").get(&k);
+        match slot {
+            Some(slot) => self.decode(slot).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key`, returning the previous value.
+    pub fn set(&self, key: &K, value: &V) -> Result<Option<V>, Error> {
+        let k = self.raw_key(key)?;
+        let v = value.to_raw_value()?;
+        if v.len() + LEN_PREFIX > self.valsize {
+            return Err(Error::ValueTooLarge(v.len()));
+        }
+        let mut slot = (v.len() as u32).to_le_bytes().to_vec();
+        slot.extend_from_slice(v.as_ref());
+
+        let mut table = self.table.lock().expect("bucket lock poisoned");
+        let old = table.get(&k);
+        if old.is_some() {
+            table.update(&k, &slot);
+        } else {
+            table.put(&k, &slot);
+        }
+        drop(table);
+        match old {
+            Some(old) => self.decode(old).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes `key`, returning its value.
+    pub fn remove(&self, key: &K) -> Result<Option<V>, Error> {
+        let k = self.raw_key(key)?;
+        let old = self.table.lock().expect("bucket lock poisoned").remove(&k);
+        match old {
+            Some(old) => self.decode(old).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> Result<bool, Error> {
+        let k = self.raw_key(key)?;
+        Ok(self.table.lock().expect("bucket lock poisoned").contains(&k))
+    }
+
+    /// Writes buffered changes to disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.table.lock().expect("bucket lock poisoned").flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kv::{Config, Error, Store};
+    use kv_crate::Integer;
+    use std::fs;
+
+    #[test]
+    fn kv_store_adapter() {
+        fs::remove_dir_all("/tmp/test_kv_store").ok();
+        {
+            let store = Store::new(Config::new("/tmp/test_kv_store")
+                                   .keysize(16).valsize(32)).unwrap();
+            let b = store.bucket::<&str, String>(Some("names")).unwrap();
+            assert_eq!(b.set(&"a", &"alpha".to_string()).unwrap(), None);
+            assert_eq!(b.set(&"a", &"aleph".to_string()).unwrap(),
+                       Some("alpha".to_string()));
+            b.set(&"b", &"beta".to_string()).unwrap();
+            assert_eq!(b.remove(&"b").unwrap(), Some("beta".to_string()));
+            assert!(!b.contains(&"b").unwrap());
+            match b.set(&"c", &"x".repeat(40)) {
+                Err(Error::ValueTooLarge(40)) => (),
+                _ => panic!("expected ValueTooLarge"),
+            }
+        }
+
+        let store = Store::new(Config::new("/tmp/test_kv_store")
+                               .keysize(16).valsize(32)).unwrap();
+        let b = store.bucket::<&str, String>(Some("names")).unwrap();
+        assert_eq!(b.get(&"a").unwrap(), Some("aleph".to_string()));
+        // any of the `kv` crate's key types
+        let counts = store.bucket::<Integer, Vec<u8>>(Some("counts")).unwrap();
+        counts.set(&Integer::from(7u64), &vec![7]).unwrap();
+        assert_eq!(counts.get(&Integer::from(7u64)).unwrap(), Some(vec![7]));
+        assert_eq!(counts.get(&Integer::from(8u64)).unwrap(), None);
+        drop(counts);
+        drop(b);
+        drop(store);
+        fs::remove_dir_all("/tmp/test_kv_store").ok();
+    }
+}
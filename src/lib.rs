@@ -1,269 +1,4785 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::path::Path;
+//! Linear hashing: a hash table on disk that grows and shrinks one
+//! bucket at a time.
+//!
+//! With the default `std` feature this is the whole table, `LinHash`,
+//! and everything around it. Without it the crate is `no_std` (it
+//! needs `alloc`) and holds only what does not touch files: the page
+//! layout (`page`), hashing (`hasher`), the addressing of linear
+//! hashing (`linear`) and the byte-level helpers (`util`), for use over
+//! other storage, eg. raw flash pages.
+//!
+//! Diagnostics go through the `log` crate: recovery and repairs at
+//! `info` and `warn`, splits, merges and new overflow pages at `debug`,
+//! and per-operation detail at `trace`. Events carry fields such as
+//! `bucket` and `page` as key-values, so install a logger that prints
+//! them to see where in the file an event happened.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
-// TODO: implement remove
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+// so that `std::` paths in the modules below resolve to `core`
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "serde")]
+extern crate bincode;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+// renamed, as `kv` is the module adapting it
+#[cfg(feature = "kv")]
+extern crate kv as kv_crate;
+// renamed, as `metrics` is the module naming what is recorded
+#[cfg(feature = "metrics")]
+extern crate metrics as metrics_crate;
+#[cfg(feature = "encryption")]
+extern crate chacha20;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+#[cfg(feature = "encryption")]
+extern crate getrandom;
 
+#[cfg(feature = "metrics")]
+#[macro_use]
+pub mod metrics;
+// without the `metrics` feature, nothing is recorded
+#[cfg(all(feature = "std", not(feature = "metrics")))]
+macro_rules! counter {
+    ($name:ident, $value:expr) => { let _ = $value; };
+}
+#[cfg(all(feature = "std", not(feature = "metrics")))]
+macro_rules! histogram {
+    ($name:ident, $value:expr) => { let _ = $value; };
+}
 pub mod util;
 pub mod page;
+pub mod hasher;
+pub mod linear;
+#[cfg(feature = "std")]
 pub mod disk;
+#[cfg(feature = "std")]
+pub mod set;
+#[cfg(feature = "std")]
+pub mod phf;
+#[cfg(feature = "std")]
+pub mod mmap;
+#[cfg(feature = "std")]
+pub mod shared;
+#[cfg(feature = "std")]
+pub mod dump;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "std")]
+pub mod journal;
+#[cfg(feature = "std")]
+mod misscache;
+#[cfg(feature = "std")]
+mod filters;
+#[cfg(feature = "std")]
+pub mod hll;
+#[cfg(feature = "std")]
+pub mod bloom;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod typed;
+#[cfg(feature = "std")]
+#[macro_use]
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod coded;
+#[cfg(feature = "std")]
+pub mod expiring;
+#[cfg(feature = "std")]
+pub mod indexed;
+#[cfg(feature = "std")]
+pub mod blob;
+#[cfg(feature = "std")]
+pub mod wal;
+#[cfg(feature = "std")]
+pub mod entry;
+#[cfg(feature = "std")]
+pub mod cursor;
+#[cfg(feature = "std")]
+pub mod concurrent;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod crypt;
+#[cfg(feature = "std")]
+pub mod options;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "kv")]
+pub mod kv;
+#[cfg(feature = "async")]
+pub mod nonblocking;
 
-use disk::{DbFile,SearchResult};
+/// What `alloc` provides that the `std` prelude would.
+#[cfg(not(feature = "std"))]
+mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::vec::Vec;
+}
+
+pub use hasher::KeyHasher;
+pub use page::PageLayout;
+#[cfg(feature = "std")]
+pub use {bloom::BloomFilter, concurrent::SyncLinHash, cursor::Cursor, entry::Entry,
+         error::{CasError, LinHashError}, options::OpenOptions, set::LinSet,
+         shared::SharedReader, stats::Stats,
+         store::{FileStore, IoBackend, MemStore, PageStore, TempStore}, typed::TypedLinHash,
+         coded::CodedLinHash, expiring::ExpiringLinHash, indexed::IndexedLinHash,
+         blob::BlobLinHash, wal::Durability};
+#[cfg(feature = "serde")]
+pub use coded::SerdeLinHash;
+#[cfg(feature = "async")]
+pub use nonblocking::AsyncLinHash;
+
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use {diff::Difference, disk::{DbFile, SearchResult}, filters::BucketFilters,
+     linear::bucket_index, misscache::MissCache, progress::{CancelToken, NoProgress, Progress},
+     shared::WriterLock, util::FixedWidth, verify::Report, wal::Wal};
 
 /// Linear Hashtable
+#[cfg(feature = "std")]
 pub struct LinHash {
     buckets: DbFile,
     nbits: usize,               // no of bits used from hash
     nitems: usize,              // number of items in hashtable
     nbuckets: usize,            // number of buckets
+    lock: Option<WriterLock>,   // held until `close`; None once closed
+    recovered: bool,            // previous writer did not close cleanly
+    // opened with `OpenOptions::read_only`
+    read_only: bool,
+    read_only_on_corruption: bool,
+    misses: MissCache,          // keys recently looked up and not found
+    filters: Option<BucketFilters>, // per bucket; see `OpenOptions::bucket_filters`
+    wal: Option<Wal>,           // None while replaying it
+    hasher: KeyHasher,
+    threshold: f32,             // load factor that triggers a split
+    // see `OpenOptions::incremental_splits`
+    incremental_splits: bool,
+    // see `OpenOptions::split_on_overflow`
+    split_on_overflow: bool,
 }
 
+#[cfg(feature = "std")]
 impl LinHash {
-    /// "load factor" needed before the hashmap needs to grow.
-    const THRESHOLD: f32 = 0.8;
-
-    /// Creates a new Linear Hashtable.
-    pub fn open(filename: &str, keysize: usize, valsize: usize) -> LinHash {
-        let file_exists = Path::new(filename).exists();
-        let mut dbfile = DbFile::new(filename, keysize, valsize);
-        let (nbits, nitems, nbuckets) =
+    /// Default "load factor" needed before the hashmap needs to grow;
+    /// see `OpenOptions::threshold`. Below half of it, the hashmap
+    /// shrinks, well enough apart that a merge is not soon undone by a
+    /// split.
+    pub const THRESHOLD: f32 = 0.8;
+
+    /// Default number of absent keys remembered by `get`.
+    pub const MISS_CACHE_CAPACITY: usize = 1024;
+
+    /// Size of the write-ahead log that triggers a checkpoint.
+    pub const WAL_CHECKPOINT_BYTES: u64 = 4 << 20;
+
+    /// Settings for opening a table, to be chained and finished with
+    /// `OpenOptions::open`.
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// Creates a new Linear Hashtable. Only one `LinHash` may have a
+    /// file open at a time (see `SharedReader` for concurrent
+    /// readers); panics if another writer holds its lock file.
+    /// Shorthand for `options().keysize(keysize).valsize(valsize)`;
+    /// any other setting goes through `options`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize) -> LinHash {
+        LinHash::open_with_layout(filename, keysize, valsize, PageLayout::Row)
+    }
+
+    /// Like `open`, but returns an error instead of panicking, eg.
+    /// when the file cannot be opened, its control page is corrupt, or
+    /// no record of this geometry fits in a page.
+    pub fn try_open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                    -> error::Result<LinHash> {
+        LinHash::try_open_with_layout(filename, keysize, valsize, PageLayout::Row)
+    }
+
+    /// Like `open`, but a table created by this call lays out its pages
+    /// as `layout`. An existing table keeps the layout it was created
+    /// with.
+    pub fn open_with_layout<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                            layout: PageLayout) -> LinHash {
+        LinHash::try_open_with_layout(filename, keysize, valsize, layout)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `open_with_layout`, but returns an error instead of
+    /// panicking. See `try_open`.
+    pub fn try_open_with_layout<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                                layout: PageLayout) -> error::Result<LinHash> {
+        LinHash::options().keysize(keysize).valsize(valsize).layout(layout).open(filename)
+    }
+
+    /// Like `open_with_layout`, hashing keys with `hasher` instead of
+    /// the built-in hasher. A table created by this call records the
+    /// hasher's id, and opening it with a hasher of another id, or
+    /// without one, fails.
+    pub fn open_with_hasher<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                            layout: PageLayout, hasher: KeyHasher) -> LinHash {
+        LinHash::try_open_with_hasher(filename, keysize, valsize, layout, hasher)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `open_with_hasher`, but returns an error instead of
+    /// panicking. See `try_open`.
+    pub fn try_open_with_hasher<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                                layout: PageLayout, hasher: KeyHasher)
+                                                -> error::Result<LinHash> {
+        LinHash::options().keysize(keysize).valsize(valsize).layout(layout).hasher(hasher)
+            .open(filename)
+    }
+
+    /// Like `open_with_layout`, keeping the table's pages in `store`
+    /// instead of the file `filename`. The table is new if `store` is
+    /// empty. `filename` still names the lock file, the split journal
+    /// and the write-ahead log.
+    pub fn open_with_store<P, S>(filename: P, keysize: usize, valsize: usize,
+                                 layout: PageLayout, store: S) -> LinHash
+        where P: AsRef<Path>, S: PageStore + 'static {
+        LinHash::try_open_with_store(filename, keysize, valsize, layout, store)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `open_with_store`, but returns an error instead of
+    /// panicking. See `try_open`.
+    pub fn try_open_with_store<P, S>(filename: P, keysize: usize, valsize: usize,
+                                     layout: PageLayout, store: S) -> error::Result<LinHash>
+        where P: AsRef<Path>, S: PageStore + 'static {
+        LinHash::options().keysize(keysize).valsize(valsize).layout(layout).store(store)
+            .open(filename)
+    }
+
+    /// Creates a table in a new file in the temp dir, which is removed
+    /// when the table is dropped; see `TempStore`. Panics on error;
+    /// see `try_open_temp`.
+    pub fn open_temp(keysize: usize, valsize: usize) -> LinHash {
+        LinHash::try_open_temp(keysize, valsize).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open_temp(keysize: usize, valsize: usize) -> error::Result<LinHash> {
+        let store = TempStore::new()?;
+        let path = store.path().to_path_buf();
+        LinHash::options().keysize(keysize).valsize(valsize).store(store).open(path)
+    }
+
+    /// Opens `filename` as `options` say; see `OpenOptions`.
+    pub(crate) fn try_open_table(filename: &Path, options: OpenOptions)
+                                 -> error::Result<LinHash> {
+        let OpenOptions { keysize, valsize, layout, hasher, threshold, page_size,
+                          duplicate_keys, durability, read_only, bucket_filters, direct_io,
+                          io_backend, incremental_splits, split_on_overflow, partial_expansions,
+                          extended_headers, large_values, store } = options;
+        if keysize == Some(0) {
+            return Err(LinHashError::InvalidArgument("keysize must not be 0".to_string()));
+        }
+        if let Some(size) = page_size.filter(|&size| size != page::PAGE_SIZE) {
+            return Err(LinHashError::InvalidArgument(format!(
+                "pages are {} bytes, not {}", page::PAGE_SIZE, size)));
+        }
+        // sizes read from the file fit; only given ones need checking
+        if let Some(k) = keysize {
+            if page::Page::capacity(k, valsize.unwrap_or(0)) == 0 {
+                return Err(LinHashError::InvalidArgument(format!(
+                    "a {}-byte key and {}-byte value do not fit in a page",
+                    k, valsize.unwrap_or(0))));
+            }
+        }
+        let max_threshold = disk::MAX_SPLIT_THRESHOLD as f32 / 1000.0;
+        if threshold.is_some_and(|t| !(t > 0.0 && t <= max_threshold)) {
+            return Err(LinHashError::InvalidArgument(format!(
+                "the split threshold must be above 0 and at most {}", max_threshold)));
+        }
+        if direct_io && store.is_some() {
+            return Err(LinHashError::InvalidArgument(
+                "direct I/O applies to the default file store only".to_string()));
+        }
+        let sidecar_files = store.as_ref().is_none_or(|s| s.sidecar_files());
+        let lock = if !sidecar_files {
+            WriterLock::unlocked()
+        } else if read_only {
+            WriterLock::acquire_shared(filename)?
+        } else {
+            WriterLock::acquire(filename)?
+        };
+        // the real sizes are set once the ctrl page is read
+        let (k, v) = (keysize.unwrap_or(0), valsize.unwrap_or(0));
+        let mut dbfile = match store {
+            Some(store) => DbFile::with_store(filename, store, k, v),
+            None if direct_io => DbFile::try_new_direct(filename, k, v, read_only)?,
+            None if read_only => DbFile::try_new_read_only(filename, k, v)?,
+            None => DbFile::try_new(filename, k, v)?,
+        };
+        if read_only {
+            // dropping it must not write the ctrl page either
+            dbfile.discard();
+        }
+        // an empty file, eg. from a temp file helper, is a new table
+        let file_exists = !dbfile.is_empty()?;
+        if read_only && !file_exists {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} is empty; a read-only table cannot be created", filename.display())));
+        }
+        dbfile.set_page_layout(layout);
+        dbfile.set_page_checksums(true);
+        // value pages have no room for an extended header
+        dbfile.set_extended_headers(extended_headers && !large_values);
+        dbfile.set_duplicate_keys(duplicate_keys);
+        dbfile.set_large_values(large_values);
+        dbfile.set_partial_expansions(partial_expansions);
+        dbfile.set_hasher_id(hasher.as_ref().map_or(hasher::STABLE_HASHER_ID, KeyHasher::id));
+        let (nbits, mut nitems, nbuckets) =
             if file_exists {
-                dbfile.read_ctrlpage()
+                // a journal is only left by a writer, which also sets
+                // the open flag refused below
+                if !read_only {
+                    dbfile.recover_split()?;
+                }
+                dbfile.read_ctrlpage()?
             } else {
                 (1, 0, 2)
             };
-        println!("{:?}", (nbits, nitems, nbuckets));
-        LinHash {
+        let (keysize, valsize) = match (dbfile.stored_record_size(), keysize) {
+            (Some((k, v)), _) if keysize.unwrap_or(k) != k || valsize.unwrap_or(v) != v => {
+                dbfile.discard();
+                return Err(LinHashError::InvalidArgument(format!(
+                    "{} holds {}-byte keys and {}-byte values, not {} and {}",
+                    filename.display(), k, v, keysize.unwrap_or(k), valsize.unwrap_or(v))));
+            },
+            (Some(sizes), _) => sizes,
+            (None, Some(k)) => (k, valsize.unwrap_or(0)),
+            (None, None) => {
+                dbfile.discard();
+                return Err(LinHashError::InvalidArgument(format!(
+                    "{} records no key size; give one to open it", filename.display())));
+            },
+        };
+        dbfile.set_record_size(keysize, valsize);
+        if dbfile.records_per_page == 0 {
+            dbfile.discard();
+            return Err(LinHashError::InvalidArgument(format!(
+                "a {}-byte key and {}-byte value do not fit in a page of {}",
+                keysize, valsize, filename.display())));
+        }
+        if !file_exists {
+            dbfile.tag_bucket(0);
+            dbfile.tag_bucket(1);
+        }
+        // saved filters were stamped with the ctrl page as closed
+        let closed_seq = dbfile.ctrl_seq();
+        let hasher = match hasher {
+            Some(h) => if h.id() == dbfile.hasher_id() { Some(h) } else { None },
+            None => KeyHasher::builtin(dbfile.hasher_id(), dbfile.hash_seed()),
+        };
+        let hasher = match hasher {
+            Some(h) => h,
+            None => {
+                dbfile.discard();
+                return Err(LinHashError::InvalidArgument(format!(
+                    "{} was created with hasher {}; open it with that hasher",
+                    filename.display(), dbfile.hasher_id())));
+            },
+        };
+        if dbfile.large_values() != large_values {
+            dbfile.discard();
+            return Err(LinHashError::InvalidArgument(if large_values {
+                format!("{} does not hold large values; open it as a LinHash", filename.display())
+            } else {
+                format!("{} holds large values; open it as a BlobLinHash", filename.display())
+            }));
+        }
+        if incremental_splits && dbfile.partial_expansions() {
+            dbfile.discard();
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} splits in partial expansions, which are not done incrementally",
+                filename.display())));
+        }
+        // The previous writer did not close the file: pages evicted or
+        // flushed after its last ctrl page write may disagree with it.
+        let recovered = dbfile.open_flag();
+        if recovered && read_only {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} was not closed cleanly and needs repairing by a writer", filename.display())));
+        }
+        if recovered {
+            nitems = dbfile.repair_buckets();
+            dbfile.flush()?;
+            info!(nitems = nitems; "{} was not closed cleanly; repaired", filename.display());
+        }
+        debug!(nbits = nbits, nitems = nitems, nbuckets = nbuckets; "opened {}", filename.display());
+        if let Some(t) = threshold {
+            dbfile.set_split_threshold(((t * 1000.0).round() as usize).max(1));
+        }
+        let threshold = match dbfile.split_threshold() {
+            0 => LinHash::THRESHOLD,
+            t => t as f32 / 1000.0,
+        };
+        if !read_only {
+            dbfile.set_open_flag(true);
+            dbfile.write_ctrlpage((nbits, nitems, nbuckets))?;
+        }
+        dbfile.set_io_backend(io_backend)?;
+        let mut table = LinHash {
             buckets: dbfile,
-            nbits: nbits,
-            nitems: nitems,
-            nbuckets: nbuckets,
+            nbits,
+            nitems,
+            nbuckets,
+            lock: Some(lock),
+            recovered,
+            read_only,
+            read_only_on_corruption: false,
+            misses: MissCache::new(LinHash::MISS_CACHE_CAPACITY),
+            filters: None,
+            wal: None,
+            hasher,
+            threshold,
+            incremental_splits,
+            split_on_overflow,
+        };
+        if recovered && nbuckets > 2 && !table.buckets.partial_expansions() {
+            // the last split may have been left part-way
+            let from = linear::shrink(nbits, nbuckets).1;
+            table.buckets.start_split(from, nbuckets - 1);
+            table.finish_split()?;
+        }
+        let cipher = table.buckets.sidecar_cipher();
+        if bucket_filters {
+            let mut filters = table.empty_filters();
+            let loaded = sidecar_files && file_exists && !recovered
+                && filters.load(&filters::filters_path(filename), table.buckets.hash_seed(),
+                                closed_seq, cipher.as_ref())?;
+            if !loaded {
+                table.fill_filters(&mut filters);
+            }
+            table.filters = Some(filters);
+        }
+        if !sidecar_files || read_only {
+            return Ok(table);
+        }
+        let mut wal = Wal::open_sealed(wal::wal_path(filename), cipher)?;
+        let entries = wal.entries()?;
+        if !entries.is_empty() {
+            info!(entries = entries.len(); "replaying write-ahead log of {}", filename.display());
+            table.recovered = true;
+            let skipped = table.replay(entries)?;
+            debug!(skipped = skipped; "log entries already on disk");
+            table.checkpoint()?;
+        }
+        wal.clear()?;
+        wal.set_durability(durability);
+        table.wal = Some(wal);
+        Ok(table)
+    }
+
+    /// Bucket filters for the table, holding no keys.
+    fn empty_filters(&self) -> BucketFilters {
+        BucketFilters::new(self.buckets.keysize(), self.buckets.records_per_page, self.nbuckets)
+    }
+
+    /// Inserts the key of every record into `filters`.
+    fn fill_filters(&mut self, filters: &mut BucketFilters) {
+        for bucket in 0..self.nbuckets {
+            for (_, records) in self.buckets.all_records_in_bucket(bucket) {
+                for (key, _) in records {
+                    filters.insert(bucket, &key);
+                }
+            }
+        }
+    }
+
+    /// Reapplies logged changes on top of the table as found on disk,
+    /// skipping those whose bucket's pages all have them already (see
+    /// `wal`). Returns how many were skipped.
+    fn replay(&mut self, entries: Vec<(u64, wal::Entry)>) -> error::Result<usize> {
+        let mut skipped = 0;
+        for (lsn, entry) in entries {
+            self.buckets.replay_lsn(lsn);
+            let key = match entry {
+                wal::Entry::Put(ref k, _) | wal::Entry::Remove(ref k)
+                    | wal::Entry::PutAll(ref k, _) => k,
+            };
+            if lsn != 0 && self.buckets.bucket_lsn(self.bucket(key))? >= lsn {
+                skipped += 1;
+                continue;
+            }
+            match entry {
+                wal::Entry::Put(k, v) => if !self.try_update(&k, &v)? {
+                    self.try_put(&k, &v)?;
+                },
+                wal::Entry::Remove(k) => { self.try_remove(&k)?; },
+                wal::Entry::PutAll(k, vals) => {
+                    self.try_remove_all(&k)?;
+                    for v in vals {
+                        self.try_put(&k, &v)?;
+                    }
+                },
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Appends `entry` to the write-ahead log, returning where it
+    /// starts so that a change that fails can be `unlog`ged.
+    fn log(&mut self, entry: wal::Entry) -> io::Result<Option<u64>> {
+        match self.wal {
+            Some(ref mut wal) => wal.append(self.buckets.next_lsn(), &entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Logs that `key` gets a record holding `val`, or, if `update`,
+    /// that its first record now holds `val`. With duplicate keys a
+    /// `Put` would be ambiguous, so every value the key will have is
+    /// logged instead.
+    fn log_put(&mut self, key: &[u8], val: &[u8], update: bool) -> io::Result<Option<u64>> {
+        if self.wal.is_none() || !self.buckets.duplicate_keys() {
+            return self.log(wal::Entry::Put(key.to_vec(), val.to_vec()));
+        }
+        let mut vals = self.buckets.values_of(self.bucket(key), key)?;
+        match vals.first_mut() {
+            Some(first) if update => *first = val.to_vec(),
+            _ => vals.push(val.to_vec()),
+        }
+        self.log(wal::Entry::PutAll(key.to_vec(), vals))
+    }
+
+    /// Logs that the first record of `key` is removed; see `log_put`.
+    fn log_remove(&mut self, key: &[u8]) -> io::Result<Option<u64>> {
+        if self.wal.is_none() || !self.buckets.duplicate_keys() {
+            return self.log(wal::Entry::Remove(key.to_vec()));
+        }
+        let mut vals = self.buckets.values_of(self.bucket(key), key)?;
+        if !vals.is_empty() {
+            vals.remove(0);
+        }
+        self.log(wal::Entry::PutAll(key.to_vec(), vals))
+    }
+
+    fn unlog(&mut self, mark: Option<u64>) -> io::Result<()> {
+        match (self.wal.as_mut(), mark) {
+            (Some(wal), Some(len)) => wal.truncate(len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes every page and the ctrl page, after which the
+    /// write-ahead log is no longer needed.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.buckets.flush()?;
+        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets))?;
+        self.clear_wal()
+    }
+
+    fn clear_wal(&mut self) -> io::Result<()> {
+        if let Some(ref mut wal) = self.wal {
+            if wal.sync() {
+                self.buckets.sync()?;
+            }
+            wal.clear()?;
+        }
+        Ok(())
+    }
+
+    fn maybe_checkpoint(&mut self) -> io::Result<()> {
+        match self.wal {
+            Some(ref wal) if wal.len() > LinHash::WAL_CHECKPOINT_BYTES => self.checkpoint(),
+            _ => Ok(()),
+        }
+    }
+
+    /// If set, each change is synced to the write-ahead log before it
+    /// is made, so it survives power loss rather than only a crash of
+    /// the process. Off by default, as it costs a sync per change.
+    /// Short for `Durability::Always`, or `Durability::Never`.
+    pub fn set_sync_writes(&mut self, sync: bool) {
+        self.set_durability(if sync { Durability::Always } else { Durability::Never });
+    }
+
+    /// Sets when changes are synced to disk (default
+    /// `Durability::Never`).
+    pub fn set_durability(&mut self, durability: Durability) {
+        if let Some(ref mut wal) = self.wal {
+            wal.set_durability(durability);
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.wal.as_ref().map_or(Durability::Never, Wal::durability)
+    }
+
+    /// Writes every change so far to the database file and syncs it,
+    /// whatever the durability mode, so that it survives power loss.
+    pub fn sync(&mut self) -> error::Result<()> {
+        self.check_handle()?;
+        if self.is_read_only() {
+            return Ok(());
+        }
+        self.buckets.flush()?;
+        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.sync()?;
+        if let Some(ref mut wal) = self.wal {
+            wal.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Sets how many pages the buffer pool caches (default
+    /// `disk::NUM_BUFFERS`, at least `disk::MIN_BUFFERS`). Pages are
+    /// evicted least recently used first; dirty ones are written back.
+    pub fn set_cache_pages(&mut self, pages: usize) -> io::Result<()> {
+        self.buckets.set_pool_size(pages)
+    }
+
+    /// Chooses how pages are read from the file, eg.
+    /// `IoBackend::Mmap` to read them out of a memory map instead of
+    /// with a system call each. Best called right after `open`, or
+    /// given as `OpenOptions::io_backend`.
+    pub fn set_io_backend(&mut self, backend: IoBackend) -> io::Result<()> {
+        self.buckets.set_io_backend(backend)
+    }
+
+    /// Like `set_cache_pages`, sized in bytes.
+    pub fn set_cache_bytes(&mut self, bytes: usize) -> io::Result<()> {
+        self.set_cache_pages(bytes / page::PAGE_SIZE)
+    }
+
+    /// Sets how many absent keys `get` remembers, so that looking them
+    /// up again does not scan their bucket. 0 disables the cache. The
+    /// default is `LinHash::MISS_CACHE_CAPACITY`.
+    pub fn set_miss_cache_capacity(&mut self, capacity: usize) {
+        self.misses.set_capacity(capacity);
+    }
+
+    /// If set, the table stops accepting writes once a corrupt page has
+    /// been read (see `corruption`), so that further changes cannot
+    /// compound the damage. Lookups keep working on whatever can still
+    /// be read. `close` then writes nothing back, and the next `open`
+    /// repairs the file as after an unclean shutdown.
+    pub fn set_read_only_on_corruption(&mut self, enabled: bool) {
+        self.read_only_on_corruption = enabled;
+    }
+
+    /// Description of the first corrupt page this handle has read.
+    pub fn corruption(&self) -> Option<&str> {
+        self.buckets.corruption()
+    }
+
+    /// Whether writes are being refused, because the table was opened
+    /// with `OpenOptions::read_only` or because of `corruption`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || (self.read_only_on_corruption && self.corruption().is_some())
+    }
+
+    fn check_writable(&self) -> error::Result<()> {
+        if self.read_only {
+            return Err(LinHashError::Io(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is open read-only", self.buckets.path().display()))));
+        }
+        match self.corruption() {
+            Some(c) if self.read_only_on_corruption => Err(LinHashError::Corruption(
+                format!("table is read-only after detecting corruption: {}", c))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects a record wider than the table's geometry.
+    fn check_record(&self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        if key.len() > self.buckets.keysize() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "key is {} bytes, keysize is {}", key.len(), self.buckets.keysize())));
+        }
+        if val.len() > self.buckets.valsize() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "value is {} bytes, valsize is {}", val.len(), self.buckets.valsize())));
         }
+        Ok(())
+    }
+
+    /// Whether `open` found the table had not been closed cleanly and
+    /// repaired it. Records written after the last `flush` may be lost.
+    pub fn recovered(&self) -> bool {
+        self.recovered
     }
 
     fn hash(&self, key: &[u8]) -> u64 {
-        let mut s = DefaultHasher::new();
-        key.hash(&mut s);
-        s.finish()
+        self.hasher.hash_key(key, self.buckets.keysize())
     }
 
-    /// Which bucket to place the key-value pair in. If the target
-    /// bucket does not yet exist, it is guaranteed that the MSB is a
-    /// `1`. To find the bucket, the pair should be placed in,
-    /// subtract this `1`.
+    /// Which bucket to place the key-value pair in.
     fn bucket(&self, key: &[u8]) -> usize {
-        let hash = self.hash(key);
-        let bucket = (hash & ((1 << self.nbits) - 1)) as usize;
-        let adjusted_bucket_index =
-            if bucket < self.nbuckets {
-                bucket
-            } else {
-                bucket - (1 << (self.nbits-1))
-            };
+        self.bucket_of_hash(self.hash(key))
+    }
+
+    /// Which bucket a key with hash `hash` is in.
+    fn bucket_of_hash(&self, hash: u64) -> usize {
+        if self.buckets.partial_expansions() {
+            linear::partial_bucket_index(hash, self.nbuckets)
+        } else {
+            bucket_index(hash, self.nbits, self.nbuckets)
+        }
+    }
 
-        adjusted_bucket_index
+    /// How many low bits of their hashes the keys of `bucket` share:
+    /// `nbits`, or one fewer if the bucket has yet to split.
+    fn bucket_depth(&self, bucket: usize) -> usize {
+        let half = 1 << (self.nbits - 1);
+        if bucket < half && bucket + half >= self.nbuckets {
+            self.nbits - 1
+        } else {
+            self.nbits
+        }
+    }
+
+    /// Returns true if the `load` with `nitems` records exceeds
+    /// the split threshold. Once the table has `DbFile::max_buckets`
+    /// buckets it stops splitting and its chains grow instead.
+    fn split_needed(&self, nitems: usize) -> bool {
+        (nitems as f32 / (self.buckets.records_per_page * self.nbuckets) as f32) >
+            self.threshold && self.nbuckets < DbFile::max_buckets()
     }
 
-    /// Returns true if the `load` exceeds `LinHash::THRESHOLD`
-    fn split_needed(&self) -> bool {
-        (self.nitems as f32 / (self.buckets.records_per_page * self.nbuckets) as f32) >
-            LinHash::THRESHOLD
+    /// With `OpenOptions::split_on_overflow`, whether a bucket growing
+    /// another overflow page splits the table too: only if the load
+    /// after the split is at least half the split threshold, below
+    /// which the next remove would merge the new bucket back.
+    fn overflow_split_needed(&self) -> bool {
+        let load = self.nitems as f32 /
+            (self.buckets.records_per_page * (self.nbuckets + 1)) as f32;
+        self.split_on_overflow && load >= self.threshold / 2.0
+            && self.nbuckets < DbFile::max_buckets()
     }
 
     /// If necessary, allocates new bucket. If there's no more space
     /// in the buckets vector(ie. n > 2^i), increment number of bits
     /// used(i).
-
+    ///
     /// Note that, the bucket split is not necessarily the one just
     /// inserted to.
-    fn maybe_split(&mut self) -> bool {
-        if self.split_needed() {
-            self.nbuckets += 1;
+    ///
+    /// The only page that can need new disk space is the new bucket's
+    /// root, which is allocated before anything else changes: the
+    /// records redistributed afterwards fit in that page, the root of
+    /// the split bucket and the overflow pages the split just freed.
+    ///
+    /// The split is bracketed by `begin_split`/`commit_split`, so a
+    /// crash part-way through is rolled back on the next `open`.
+    ///
+    /// With `OpenOptions::incremental_splits`, each call first moves
+    /// the records of one more page of the split in progress.
+    ///
+    /// `chained` says the insert just made gave a bucket a second or
+    /// later overflow page; see `OpenOptions::split_on_overflow`.
+    fn maybe_split(&mut self, chained: bool) -> io::Result<bool> {
+        self.split_step()?;
+        if self.split_needed(self.nitems) || (chained && self.overflow_split_needed()) {
+            self.split()?;
+            counter!(SPLITS, 1);
+            return Ok(true)
+        }
 
-            self.buckets.allocate_new_bucket();
-            if self.nbuckets > (1 << self.nbits) {
-                self.nbits += 1;
-            }
+        Ok(false)
+    }
+
+    /// Adds the next bucket, splitting the bucket it takes records
+    /// from. See `maybe_split`.
+    fn split(&mut self) -> io::Result<()> {
+        self.finish_split()?;
+        if self.buckets.partial_expansions() {
+            return self.split_group();
+        }
+        if self.incremental_splits {
+            return self.start_split();
+        }
+        let nbuckets = self.nbuckets + 1;
+        let (nbits, bucket_to_split) = linear::grow(self.nbits, self.nbuckets);
 
-            // Take index of last item added and subtract the 1 at the
-            // MSB position. eg: after bucket 11 is added, bucket 01
-            // needs to be split
-            let bucket_to_split =
-                (self.nbuckets-1) ^ (1 << (self.nbits-1));
-            println!("nbits: {} nitems: {} nbuckets: {} splitting {} and {}",
-                     self.nbits, self.nitems, self.nbuckets, bucket_to_split, (self.nbuckets-1));
-            // Replace the bucket to split with a fresh, empty
-            // page. And get a list of all records stored in the bucket
-            let old_bucket_records =
-                self.buckets.clear_bucket(bucket_to_split);
+        self.misses.clear();
+        self.buckets.begin_split(&[bucket_to_split],
+                                 (self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.allocate_new_bucket()?;
+        self.nbuckets = nbuckets;
+        self.nbits = nbits;
+        let new_bucket = self.nbuckets - 1;
 
-            // Re-hash all records in old_bucket. Ideally, about half
-            // of the records will go into the new bucket.
-            for (k, v) in old_bucket_records.into_iter() {
-                self.reinsert(&k, &v);
+        debug!(bucket = bucket_to_split, new_bucket = new_bucket,
+               nbits = self.nbits, nitems = self.nitems; "splitting bucket");
+        // Replace the bucket to split with a fresh, empty
+        // page. And get a list of all records stored in the bucket
+        let old_bucket_records =
+            self.buckets.clear_bucket(bucket_to_split)?;
+
+        // Re-hash all records in old_bucket. Ideally, about half
+        // of the records will go into the new bucket. Neither bucket
+        // can hold their keys already, so they are appended to the
+        // pages without a search.
+        let (kept, moved): (Vec<_>, Vec<_>) = old_bucket_records.into_iter()
+            .partition(|(k, _)| self.bucket(k) == bucket_to_split);
+        if let Some(ref mut filters) = self.filters {
+            filters.push();
+            filters.reset(bucket_to_split);
+            for (key, _) in &kept {
+                filters.insert(bucket_to_split, key);
+            }
+            for (key, _) in &moved {
+                filters.insert(new_bucket, key);
             }
-            return true
         }
+        self.buckets.fill_bucket(bucket_to_split, kept)?;
+        self.buckets.fill_bucket(new_bucket, moved)?;
+        self.buckets.commit_split((self.nbits, self.nitems, self.nbuckets))
+    }
+
+    /// `split` of a table that splits in partial expansions: the new
+    /// bucket takes its share of the records of every bucket of its
+    /// group. Each bucket's chain is rewritten like in a plain split;
+    /// the pages the group may need beyond those it frees are reserved
+    /// before anything changes.
+    fn split_group(&mut self) -> io::Result<()> {
+        let new_bucket = self.nbuckets;
+        let (first, stride, members) = linear::partial_group(new_bucket);
+        let group: Vec<usize> = (0..members).map(|i| first + i * stride).collect();
+
+        self.misses.clear();
+        self.buckets.begin_split(&group, (self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.allocate_new_bucket()?;
+        if let Err(e) = self.buckets.reserve_pages(members - 1) {
+            // not a split yet: the new bucket goes back to the free list
+            self.buckets.remove_last_bucket()?;
+            return Err(e);
+        }
+        self.nbits = linear::grow(self.nbits, self.nbuckets).0;
+        self.nbuckets += 1;
 
-        false
+        debug!(bucket = first, stride = stride, new_bucket = new_bucket,
+               nitems = self.nitems; "splitting group");
+        if let Some(ref mut filters) = self.filters {
+            filters.push();
+        }
+        let mut moved = vec![];
+        for &bucket in &group {
+            let (kept, mut gone): (Vec<_>, Vec<_>) = self.buckets.clear_bucket(bucket)?
+                .into_iter()
+                .partition(|(k, _)| self.bucket(k) == bucket);
+            if let Some(ref mut filters) = self.filters {
+                filters.reset(bucket);
+                for (key, _) in &kept {
+                    filters.insert(bucket, key);
+                }
+            }
+            self.buckets.fill_bucket(bucket, kept)?;
+            moved.append(&mut gone);
+        }
+        if let Some(ref mut filters) = self.filters {
+            for (key, _) in &moved {
+                filters.insert(new_bucket, key);
+            }
+        }
+        self.buckets.fill_bucket(new_bucket, moved)?;
+        self.buckets.commit_split((self.nbits, self.nitems, self.nbuckets))
     }
 
-    /// Does the hashmap contain a record with key `key`?
-    pub fn contains(&mut self, key: &[u8]) -> bool {
-        match self.get(key) {
-            Some(_) => true,
-            None => false,
+    /// `split` with `OpenOptions::incremental_splits`: adds the new
+    /// bucket, empty, and leaves moving its records to `split_step`.
+    /// Only the new root page is journaled.
+    fn start_split(&mut self) -> io::Result<()> {
+        let (nbits, bucket_to_split) = linear::grow(self.nbits, self.nbuckets);
+
+        self.misses.clear();
+        self.buckets.begin_grow((self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.allocate_new_bucket()?;
+        self.nbuckets += 1;
+        self.nbits = nbits;
+        let new_bucket = self.nbuckets - 1;
+        if let Some(ref mut filters) = self.filters {
+            filters.push();
         }
+        debug!(bucket = bucket_to_split, new_bucket = new_bucket,
+               nbits = self.nbits, nitems = self.nitems; "starting split");
+        self.buckets.start_split(bucket_to_split, new_bucket);
+        self.buckets.commit_split((self.nbits, self.nitems, self.nbuckets))
     }
 
-    /// Update the mapping of record with key `key`.
-    pub fn update(&mut self, key: &[u8], val: &[u8]) -> bool {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key.clone()) {
-            SearchResult { page_id, row_num, val: old_val } => {
-                match (page_id, row_num, old_val) {
-                    (Some(page_id), Some(row_num), Some(_)) => {
-                        println!("update: {:?}", (page_id, row_num, key.clone(), val.clone()));
-                        self.buckets.write_record(page_id, row_num, key, val);
-                        true
-                    }
-                    _ => false,
-                }
-            },
+    /// Moves the records of one more page of the split in progress, if
+    /// there is one, to the new bucket. The bucket split keeps its
+    /// filter, which still rules out every key it does not hold.
+    fn split_step(&mut self) -> io::Result<()> {
+        let to = match self.buckets.pending_split() {
+            Some((_, to)) => to,
+            None => return Ok(()),
+        };
+        let header = (self.nbits, self.nitems, self.nbuckets);
+        let keysize = self.buckets.keysize();
+        let hasher = &self.hasher;
+        let mut moves = |key: &[u8]| {
+            bucket_index(hasher.hash_key(key, keysize), header.0, header.2) == to
+        };
+        let moved = self.buckets.split_step(&mut moves, header)?;
+        if let Some(ref mut filters) = self.filters {
+            for key in &moved {
+                filters.insert(to, key);
+            }
         }
+        Ok(())
     }
 
-    /// Insert (key,value) pair into the hashtable.
-    pub fn put(&mut self, key: &[u8], val: &[u8]) {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key.clone()) {
-            SearchResult { page_id, row_num, val: old_val } => {
-                match (page_id, row_num, old_val) {
-                    // new insert
-                    (Some(page_id), Some(pos), None) => {
-                        self.buckets.write_record_incr(page_id, pos, key, val);
-                        self.nitems += 1;
-                    },
-                    // case for update
-                    (Some(_page_id), Some(pos), Some(_old_val)) => {
-                        panic!("can't use put to reinsert old item: {:?}", (key, val));
-                    },
-                    // new insert, in overflow page
-                    (Some(last_page_id), None, None) => { // overflow
-                        self.buckets.allocate_overflow(bucket_index, last_page_id);
-                        self.put(key, val);
-                    },
-                    _ => panic!("impossible case"),
-                }
-            },
+    /// Completes the split in progress, if there is one.
+    fn finish_split(&mut self) -> io::Result<()> {
+        while self.buckets.pending_split().is_some() {
+            self.split_step()?;
         }
+        Ok(())
+    }
 
-        self.maybe_split();
-        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+    /// Returns true if the load with `nitems` records has dropped below
+    /// half the split threshold. The table never shrinks below its
+    /// initial two buckets.
+    fn merge_needed(&self, nitems: usize) -> bool {
+        (nitems as f32 / (self.buckets.records_per_page * self.nbuckets) as f32) <
+            self.threshold / 2.0 && self.nbuckets > 2
     }
 
-    /// Re-insert (key, value) pair after a split
-    fn reinsert(&mut self, key: &[u8], val: &[u8]) {
-        self.put(key, val);
-        // correct for nitems increment in `put`
-        self.nitems -= 1;
+    /// The reverse of `split`: moves the records of the last bucket
+    /// back into the bucket it was split from, frees its pages and
+    /// drops it from the map, bracketed by `begin_merge`/
+    /// `commit_split` like a split. The merged bucket is rewritten
+    /// densely along the way, as with `compact_bucket`, and the free
+    /// pages at the end of the file are then given back.
+    fn merge(&mut self) -> io::Result<()> {
+        self.finish_split()?;
+        if self.buckets.partial_expansions() {
+            return self.merge_group();
+        }
+        let last = self.nbuckets - 1;
+        let (nbits, sibling) = linear::shrink(self.nbits, self.nbuckets);
+
+        self.misses.clear();
+        self.buckets.begin_merge(last, &[sibling], (self.nbits, self.nitems, self.nbuckets))?;
+        let mut records = self.buckets.remove_last_bucket()?;
+        records.append(&mut self.buckets.clear_bucket(sibling)?);
+        self.nbuckets -= 1;
+        self.nbits = nbits;
+        if let Some(ref mut filters) = self.filters {
+            filters.pop();
+            filters.reset(sibling);
+            for (key, _) in &records {
+                filters.insert(sibling, key);
+            }
+        }
+        debug!(bucket = last, into = sibling, nbits = self.nbits, nitems = self.nitems;
+               "merging bucket");
+        self.buckets.fill_bucket(sibling, records)?;
+        self.buckets.commit_split((self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.truncate_free_tail((self.nbits, self.nitems, self.nbuckets)).map(|_| ())
     }
 
-    /// Lookup `key` in hashtable
-    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key) {
-            SearchResult { page_id, row_num, val } => {
-                match val {
-                    Some(v) => Some(v),
-                    _ => None,
+    /// `merge` of a table that splits in partial expansions: the records
+    /// of the last bucket go back to the buckets of its group they came
+    /// from, appended to their chains.
+    fn merge_group(&mut self) -> io::Result<()> {
+        let last = self.nbuckets - 1;
+        let (first, stride, members) = linear::partial_group(last);
+        let group: Vec<usize> = (0..members).map(|i| first + i * stride).collect();
+
+        self.misses.clear();
+        self.buckets.begin_merge(last, &group, (self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.reserve_pages(members - 1)?;
+        let records = self.buckets.remove_last_bucket()?;
+        self.nbits = linear::shrink(self.nbits, self.nbuckets).0;
+        self.nbuckets -= 1;
+        if let Some(ref mut filters) = self.filters {
+            filters.pop();
+        }
+        debug!(bucket = last, into = first, stride = stride, nitems = self.nitems;
+               "merging bucket into its group");
+        let mut by_bucket: BTreeMap<usize, Vec<disk::Record>> = BTreeMap::new();
+        for (key, val) in records {
+            by_bucket.entry(self.bucket(&key)).or_default().push((key, val));
+        }
+        for (bucket, records) in by_bucket {
+            if let Some(ref mut filters) = self.filters {
+                for (key, _) in &records {
+                    filters.insert(bucket, key);
                 }
-            },
+            }
+            self.buckets.fill_bucket(bucket, records)?;
         }
+        self.buckets.commit_split((self.nbits, self.nitems, self.nbuckets))?;
+        self.buckets.truncate_free_tail((self.nbits, self.nitems, self.nbuckets)).map(|_| ())
     }
 
-    // Removes record with `key` in hashtable.
-    // pub fn remove(&mut self, key: K) -> Option<V> {
-    //     let bucket_index = self.bucket(&key);
-    //     let index_to_delete = self.search_bucket(bucket_index, &key);
+    /// Rewrites the overflow chain of `bucket` densely, returning the
+    /// overflow pages this frees to the free list. Removes leave
+    /// chains with gaps that only fill as records are added to the
+    /// same bucket; a split compacts the bucket it splits, and a merge
+    /// the one it merges into. Crash-safe like a split.
+    pub fn compact_bucket(&mut self, bucket: usize) -> error::Result<usize> {
+        self.check_writable()?;
+        if bucket >= self.nbuckets {
+            return Err(LinHashError::InvalidArgument(format!(
+                "bucket {} out of range; the table has {}", bucket, self.nbuckets)));
+        }
+        self.finish_split()?;
+        if !self.buckets.chain_is_sparse(bucket)? {
+            return Ok(0);
+        }
+        let header = (self.nbits, self.nitems, self.nbuckets);
+        let num_free = self.buckets.num_free();
+        self.buckets.begin_split(&[bucket], header)?;
+        let records = self.buckets.clear_bucket(bucket)?;
+        self.buckets.fill_bucket(bucket, records)?;
+        self.buckets.commit_split(header)?;
+        let freed = self.buckets.num_free() - num_free;
+        debug!(bucket = bucket, freed = freed; "compacted bucket");
+        Ok(freed)
+    }
 
-    //     // Delete item from bucket
-    //     match index_to_delete {
-    //         Some(x) => Some(self.buckets[bucket_index].remove(x).1),
-    //         None => None,
-    //     }
-    // }
+    /// `compact_bucket` of every bucket, then `reclaim_space`. Returns
+    /// the total number of pages freed.
+    pub fn compact_all(&mut self) -> error::Result<usize> {
+        self.compact_all_with_progress(&mut NoProgress)
+    }
 
-    pub fn close(&mut self) {
-        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets));
-        self.buckets.close();
+    /// Like `compact_all`, reporting pages compacted out of
+    /// `pages_in_use` after each bucket.
+    pub fn compact_all_with_progress<P: Progress>(&mut self, progress: &mut P)
+                                                  -> error::Result<usize> {
+        self.compact_all_cancellable(progress, &CancelToken::new())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use LinHash;
-    use std::fs;
-    use util::*;
+    /// Like `compact_all_with_progress`, stopping between buckets once
+    /// `cancel` is cancelled. Each bucket is compacted completely or
+    /// not at all, so the table stays consistent; the pages freed so
+    /// far are kept, but the file is not shrunk.
+    pub fn compact_all_cancellable<P: Progress>(&mut self, progress: &mut P,
+                                                cancel: &CancelToken) -> error::Result<usize> {
+        let total = self.pages_in_use();
+        let mut pages = 0;
+        let mut freed = 0;
+        for bucket in 0..self.nbuckets {
+            cancel.check()?;
+            pages += self.buckets.chain_length(bucket)?;
+            freed += self.compact_bucket(bucket)?;
+            progress.report(pages, total);
+        }
+        self.reclaim_space()?;
+        Ok(freed)
+    }
 
-    #[test]
-    fn all_ops() {
-        let mut h = LinHash::open("/tmp/test_all_ops", 32, 4);
-        h.put(b"hello", &[12]);
-        h.put(b"there", &[13]);
-        h.put(b"foo", &[42]);
-        h.put(b"bar", &[11]);
-        h.update(b"bar", &[22]);
-        h.update(b"foo", &[84]);
+    /// Shrinks the file by the free pages at its end. This happens by
+    /// itself after a merge and on `close`; other freed pages are only
+    /// recycled, so see `compact_all` to free more of them first.
+    /// Returns the number of pages given back.
+    pub fn reclaim_space(&mut self) -> error::Result<usize> {
+        self.check_writable()?;
+        let header = (self.nbits, self.nitems, self.nbuckets);
+        Ok(self.buckets.truncate_free_tail(header)?)
+    }
 
-        assert_eq!(h.get(b"hello"), Some(vec![12, 0, 0, 0]));
-        assert_eq!(h.get(b"there"), Some(vec![13, 0, 0, 0]));
-        assert_eq!(h.get(b"foo"), Some(vec![84, 0, 0, 0]));
-        assert_eq!(h.get(b"bar"), Some(vec![22, 0, 0, 0]));
+    /// Writes a compacted copy of the table to the new file
+    /// `filename`: every bucket packed densely, overflow pages after
+    /// all the root pages, and no free pages. The table itself is left
+    /// as it is. Tables of large values or encrypted pages cannot be
+    /// copied this way; `dump` and `restore` them instead. If it
+    /// fails, `filename` holds part of the copy and should be deleted.
+    pub fn compact_into<P: AsRef<Path>>(&mut self, filename: P) -> error::Result<()> {
+        self.compact_into_with_progress(filename, &mut NoProgress)
+    }
 
-        // assert_eq!(h.update(String::from("doesn't exist"), 99), false);
-        assert_eq!(h.contains(b"doesn't exist"), false);
-        assert_eq!(h.contains(b"hello"), true);
+    /// Like `compact_into`, reporting pages copied out of
+    /// `pages_in_use` after each bucket.
+    pub fn compact_into_with_progress<P, R>(&mut self, filename: P, progress: &mut R)
+                                            -> error::Result<()>
+        where P: AsRef<Path>, R: Progress {
+        self.compact_into_cancellable(filename, progress, &CancelToken::new())
+    }
 
-        h.close();
-        fs::remove_file("/tmp/test_all_ops").ok();
+    /// Like `compact_into_with_progress`, stopping between buckets once
+    /// `cancel` is cancelled. The table is only read, so it is
+    /// unaffected; `filename` then holds part of the copy, as after
+    /// any other failure.
+    pub fn compact_into_cancellable<P, R>(&mut self, filename: P, progress: &mut R,
+                                          cancel: &CancelToken) -> error::Result<()>
+        where P: AsRef<Path>, R: Progress {
+        let _lock = WriterLock::acquire(&filename)?;
+        self.write_compacted(filename.as_ref(), progress, cancel)
     }
 
-    #[test]
-    fn test_persistence() {
-        let mut h = LinHash::open("/tmp/test_persistence", 32, 4);
-        h.put(b"hello", &[12]);
-        h.put(b"world", &[13]);
-        h.put(b"linear", &[144]);
-        h.put(b"hashing", &[255]);
-        h.close();
+    /// `compact_into` a file next to the table's, which then takes its
+    /// place, still locked by this handle. Unlike `compact_all`, pages
+    /// move, so no free page is left over. A crash leaves either the
+    /// old file or the new one. Returns the number of pages given back.
+    pub fn compact(&mut self) -> error::Result<usize> {
+        self.compact_with_progress(&mut NoProgress)
+    }
 
-        // This reloads the file and creates a new hashtable
-        let mut h2 = LinHash::open("/tmp/test_persistence", 32, 4);
-        assert_eq!(h2.get(b"hello"), Some(vec![12, 0, 0, 0]));
+    /// Like `compact`, reporting pages copied out of `pages_in_use`
+    /// after each bucket.
+    pub fn compact_with_progress<P: Progress>(&mut self, progress: &mut P)
+                                              -> error::Result<usize> {
+        self.compact_cancellable(progress, &CancelToken::new())
+    }
 
-        h2.close();
-        fs::remove_file("/tmp/test_persistence").ok();
+    /// Like `compact_with_progress`, stopping between buckets once
+    /// `cancel` is cancelled. The partial copy is deleted, and the
+    /// table is left as it was.
+    pub fn compact_cancellable<P: Progress>(&mut self, progress: &mut P,
+                                            cancel: &CancelToken) -> error::Result<usize> {
+        self.check_writable()?;
+        if !self.buckets.has_file() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} is not kept in a file; use compact_into", self.buckets.path().display())));
+        }
+        let path = self.buckets.path().to_path_buf();
+        let copy = disk::sidecar_path(&path, ".compact");
+        let num_pages = self.buckets.num_pages();
+        self.checkpoint()?;
+        let written = self.write_compacted(&copy, progress, cancel)
+            .and_then(|()| Ok(std::fs::rename(&copy, &path)?));
+        if let Err(e) = written {
+            std::fs::remove_file(&copy).ok();
+            return Err(e);
+        }
+        self.buckets.reopen()?;
+        self.buckets.set_open_flag(true);
+        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets))?;
+        self.misses.clear();
+        let freed = num_pages.saturating_sub(self.buckets.num_pages());
+        debug!(freed = freed; "compacted {}", path.display());
+        Ok(freed)
     }
 
-    // TODO: figure out a better testing strategy for this. This test
-    // currently inserts 10,000 records and checks that they are all
-    // there.
-    #[test]
-    fn test_overflow_and_splitting() {
-        let mut h = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
-        for k in 0..10000 {
-            h.put(&i32_to_bytearray(k),
-                   &i32_to_bytearray(k+1));
+    /// `compact_into` without taking a lock on `filename`.
+    fn write_compacted<P: Progress>(&mut self, filename: &Path, progress: &mut P,
+                                    cancel: &CancelToken) -> error::Result<()> {
+        self.finish_split()?;
+        if self.buckets.large_values() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} holds large values, whose pages cannot be moved",
+                self.buckets.path().display())));
         }
-        h.close();
+        if self.buckets.sidecar_cipher().is_some() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} is encrypted, and its copy would not be", self.buckets.path().display())));
+        }
+        let mut dest = DbFile::try_new(filename, self.buckets.keysize(),
+                                       self.buckets.valsize())?;
+        if !dest.is_empty()? {
+            dest.discard();
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} already exists; compacting needs a new file", filename.display())));
+        }
+        let header = (self.nbits, self.nitems, self.nbuckets);
+        let total = self.pages_in_use();
+        let copied = self.buckets.copy_into(&mut dest, header, |pages| {
+            progress.report(pages, total);
+            cancel.check()
+        });
+        dest.discard();
+        Ok(copied?)
+    }
 
-        let mut h2 = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
-        for k in 0..10000 {
-            assert_eq!(h2.get(&i32_to_bytearray(k)),
-                       Some(i32_to_bytearray(k+1).to_vec()));
+    /// Removes every record, leaving the table as if new: two empty
+    /// buckets and no other pages, the file truncated to match. The
+    /// file stays the same, so other handles on it remain valid.
+    pub fn clear(&mut self) {
+        self.try_clear().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `clear`, but returns an error instead of panicking.
+    pub fn try_clear(&mut self) -> error::Result<()> {
+        self.check_writable()?;
+        // nothing in the log may outlive the records it describes
+        self.checkpoint()?;
+        self.buckets.clear((self.nbits, self.nitems, self.nbuckets), (1, 0, 2))?;
+        self.nbits = 1;
+        self.nitems = 0;
+        self.nbuckets = 2;
+        self.misses.clear();
+        if self.filters.is_some() {
+            self.filters = Some(self.empty_filters());
         }
+        Ok(())
+    }
 
-        fs::remove_file("/tmp/test_overflow_and_splitting").ok();
+    /// Does the hashmap contain a record with key `key`?
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.try_contains(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `contains`, but returns an error if a page of the bucket
+    /// cannot be read. Only keys are compared; no value is copied.
+    pub fn try_contains(&mut self, key: &[u8]) -> error::Result<bool> {
+        Ok(self.lookup(key)?.is_some())
+    }
+
+    /// Membership test for a batch of keys. See `get_many`.
+    /// `result[i]` is `contains(keys[i])`. Panics on error; see
+    /// `try_contains_many`.
+    pub fn contains_many<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Vec<bool> {
+        self.try_contains_many(keys).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `contains_many`, but returns an error if a page cannot be
+    /// read. Only keys are compared; no value is copied.
+    pub fn try_contains_many<K: AsRef<[u8]>>(&mut self, keys: &[K])
+                                             -> error::Result<Vec<bool>> {
+        Ok(self.lookup_many(keys)?.iter().map(Option::is_some).collect())
+    }
+
+    /// Lookup of a batch of keys. Keys are grouped by bucket so that
+    /// each bucket's chain is searched once per batch rather than once
+    /// per key. `result[i]` is `get(keys[i])`. Panics on error; see
+    /// `try_get_many`.
+    pub fn get_many<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        self.try_get_many(keys).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get_many`, but returns an error if a page cannot be read.
+    pub fn try_get_many<K: AsRef<[u8]>>(&mut self, keys: &[K])
+                                        -> error::Result<Vec<Option<Vec<u8>>>> {
+        counter!(GETS, keys.len());
+        let mut values = Vec::with_capacity(keys.len());
+        for found in self.lookup_many(keys)? {
+            values.push(match found {
+                Some((page_id, row_num)) =>
+                    Some(self.buckets.try_page(page_id)?.value(row_num).to_vec()),
+                None => None,
+            });
+        }
+        Ok(values)
+    }
+
+    /// The records of `bucket`, in chain order. While a split is in
+    /// progress, those of the new bucket include the ones not yet
+    /// moved, and those of the bucket split leave them out.
+    pub(crate) fn bucket_records(&mut self, bucket: usize) -> Vec<disk::Record> {
+        let source = self.buckets.split_source(bucket);
+        let mut records = vec![];
+        for b in Some(bucket).into_iter().chain(source) {
+            for (_, page_records) in self.buckets.all_records_in_bucket(b) {
+                records.extend(page_records);
+            }
+        }
+        if self.buckets.pending_split().is_some_and(|(from, to)| bucket == from || bucket == to) {
+            records.retain(|(k, _)| self.bucket(k) == bucket);
+        }
+        records
+    }
+
+    /// The record with key `key`, present or not, for reading and
+    /// then changing it with a single search of its bucket. Panics if
+    /// `key` is wider than the table's keys; see `try_entry`.
+    pub fn entry(&mut self, key: &[u8]) -> Entry<'_> {
+        self.try_entry(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_entry(&mut self, key: &[u8]) -> error::Result<Entry<'_>> {
+        self.check_record(key, &[])?;
+        Entry::find(self, key)
+    }
+
+    /// The value of `key`, after putting `default()` under it if it
+    /// had none, with a single search of its bucket. Panics on error;
+    /// see `try_get_or_insert_with`.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(&mut self, key: &[u8], default: F)
+                                                      -> Vec<u8> {
+        self.try_get_or_insert_with(key, default)
+            .unwrap_or_else(|e| panic!("put failed: {}", e))
+    }
+
+    pub fn try_get_or_insert_with<F: FnOnce() -> Vec<u8>>(&mut self, key: &[u8], default: F)
+                                                          -> error::Result<Vec<u8>> {
+        self.try_entry(key)?.try_or_insert_with(default)
+    }
+
+    /// Update the mapping of record with key `key`.
+    pub fn update(&mut self, key: &[u8], val: &[u8]) -> bool {
+        self.try_update(key, val).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `update`, but returns an error instead of panicking. In a
+    /// `PageLayout::Slotted` table a value that has outgrown its page
+    /// is moved, removing the record and inserting it again.
+    pub fn try_update(&mut self, key: &[u8], val: &[u8]) -> error::Result<bool> {
+        self.check_writable()?;
+        self.check_record(key, val)?;
+        let bucket_index = self.bucket(key);
+        let SearchResult { page_id, row_num, val: old_val } =
+            self.buckets.search_bucket(bucket_index, key)?;
+        match (page_id, row_num, old_val) {
+            (Some(page_id), Some(row_num), Some(_)) => {
+                self.replace(bucket_index, page_id, row_num, key, val)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Changes the value of `key` in place: `f` gets the value as
+    /// stored, in the page, with a single search of the key's bucket.
+    /// Returns false, without calling `f`, if the key has no record.
+    /// Panics on error; see `try_modify`.
+    pub fn modify<F: FnOnce(&mut [u8])>(&mut self, key: &[u8], f: F) -> bool {
+        self.try_modify(key, f).unwrap_or_else(|e| panic!("update failed: {}", e))
+    }
+
+    pub fn try_modify<F: FnOnce(&mut [u8])>(&mut self, key: &[u8], f: F) -> error::Result<bool> {
+        self.check_writable()?;
+        self.check_record(key, &[])?;
+        let bucket_index = self.bucket(key);
+        let (page_id, row_num, old_val) = match self.buckets.search_bucket(bucket_index, key)? {
+            SearchResult { page_id: Some(page_id), row_num: Some(row_num), val: Some(val) } =>
+                (page_id, row_num, val),
+            _ => return Ok(false),
+        };
+        trace!(bucket = bucket_index, page = page_id, row = row_num; "modify");
+        let val = self.buckets.page_mut(page_id).value_mut(row_num);
+        f(val);
+        let val = val.to_vec();
+        if let Err(e) = self.log_put(key, &val, true) {
+            self.buckets.page_mut(page_id).value_mut(row_num).copy_from_slice(&old_val);
+            return Err(e.into());
+        }
+        self.maybe_checkpoint()?;
+        Ok(true)
+    }
+
+    /// Sets the value of `key` to `new`, or removes its record if
+    /// `new` is None, but only if its value is `expected`, with None
+    /// expecting no record, and with a single search of its bucket.
+    /// Otherwise fails with `CasError::Mismatch`, holding the value
+    /// the key has. Values are compared as stored, ie. zero-padded
+    /// unless the table is slotted. In a table with duplicate keys,
+    /// acts on the key's first record.
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>)
+                            -> ::std::result::Result<(), CasError> {
+        if let Some(val) = new {
+            self.check_record(key, val)?;
+        }
+        let expected = expected.map(|val| {
+            let mut padded = val.to_vec();
+            if self.buckets.page_layout() != PageLayout::Slotted {
+                padded.resize(self.buckets.valsize().max(val.len()), 0);
+            }
+            padded
+        });
+        match self.try_entry(key)? {
+            Entry::Occupied(mut e) => {
+                if expected.as_deref() != Some(e.get()) {
+                    return Err(CasError::Mismatch(Some(e.get().to_vec())));
+                }
+                match new {
+                    Some(val) => { e.try_insert(val)?; },
+                    None => { e.try_remove()?; },
+                }
+            },
+            Entry::Vacant(e) => {
+                if expected.is_some() {
+                    return Err(CasError::Mismatch(None));
+                }
+                if let Some(val) = new {
+                    e.try_insert(val)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Appends `bytes` to the value of `key`, with a single search of
+    /// its bucket, and returns the value's new length. A key with no
+    /// record gets one holding just `bytes`. Only slotted tables keep
+    /// the length of each value, so only they can append; the value
+    /// can grow up to `valsize`. Panics on error; see `try_append`.
+    pub fn append(&mut self, key: &[u8], bytes: &[u8]) -> usize {
+        self.try_append(key, bytes).unwrap_or_else(|e| panic!("append failed: {}", e))
+    }
+
+    pub fn try_append(&mut self, key: &[u8], bytes: &[u8]) -> error::Result<usize> {
+        if self.buckets.page_layout() != PageLayout::Slotted {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} is not slotted, so its values have no length to append at",
+                self.buckets.path().display())));
+        }
+        let valsize = self.buckets.valsize();
+        match self.try_entry(key)? {
+            Entry::Occupied(mut e) => {
+                let mut val = e.get().to_vec();
+                if val.len() + bytes.len() > valsize {
+                    return Err(LinHashError::InvalidArgument(format!(
+                        "appending {} bytes to a {}-byte value exceeds valsize {}",
+                        bytes.len(), val.len(), valsize)));
+                }
+                val.extend_from_slice(bytes);
+                e.try_insert(&val)?;
+                Ok(val.len())
+            },
+            Entry::Vacant(e) => {
+                e.try_insert(bytes)?;
+                Ok(bytes.len())
+            },
+        }
+    }
+
+    /// Overwrites the value of the record found at `row_num` of
+    /// `page_id`, in bucket `bucket_index`. Returns false if the record
+    /// had to move to fit the value.
+    fn replace(&mut self, bucket_index: usize, page_id: usize, row_num: usize,
+               key: &[u8], val: &[u8]) -> error::Result<bool> {
+        trace!(bucket = bucket_index, page = page_id, row = row_num; "update");
+        self.log_put(key, val, true)?;
+        let in_place = self.buckets.page(page_id)
+            .has_room_to_replace(row_num, key.len(), val.len());
+        if in_place {
+            self.buckets.write_record(page_id, row_num, key, val);
+        } else {
+            self.buckets.remove_record(bucket_index, key)?;
+            self.insert(key, val)?;
+            self.buckets.update_ctrlpage((self.nbits, self.nitems, self.nbuckets))?;
+        }
+        self.maybe_checkpoint()?;
+        Ok(in_place)
+    }
+
+    /// Insert (key,value) pair into the hashtable.
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.try_put(key, val)
+            .unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    /// Like `put`, but returns errors such as a full disk instead of
+    /// panicking, and `InvalidArgument` if `key` is already present
+    /// (unless the table keeps duplicate keys); nothing is logged or
+    /// changed then. The table is consistent whenever this returns:
+    /// if there was no room for a new overflow page nothing was
+    /// inserted, and if the record went in but the split that follows
+    /// could not get a page, the record stays and the split is simply
+    /// attempted again by a later `put`.
+    pub fn try_put(&mut self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        self.put_found(key, val, None)
+    }
+
+    /// `try_put`, given the result of searching the key's bucket for
+    /// it, if there is one.
+    fn put_found(&mut self, key: &[u8], val: &[u8], found: Option<SearchResult>)
+                 -> error::Result<()> {
+        counter!(PUTS, 1);
+        self.check_writable()?;
+        self.check_record(key, val)?;
+        let found = match found {
+            Some(found) => Some(found),
+            None => self.search_absent(key)?,
+        };
+        let mark = self.log_put(key, val, false)?;
+        let chained = match self.insert_found(key, val, found) {
+            Ok(chained) => chained,
+            Err(e) => {
+                // nothing was inserted, so there is nothing to replay
+                self.unlog(mark).ok();
+                return Err(e.into());
+            },
+        };
+        self.nitems += 1;
+
+        let split = self.maybe_split(chained);
+        let ctrl = self.buckets.update_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+        split.and(ctrl)?;
+        Ok(self.maybe_checkpoint()?)
+    }
+
+    /// Searches the bucket of `key`, which `put` is about to insert, so
+    /// that the insert can start from where the search ended. Fails if
+    /// the key already has a record; None, with nothing to search, if
+    /// the table keeps duplicate keys.
+    fn search_absent(&mut self, key: &[u8]) -> error::Result<Option<SearchResult>> {
+        if self.buckets.duplicate_keys() {
+            return Ok(None);
+        }
+        let found = self.buckets.search_bucket(self.bucket(key), key)?;
+        if found.val.is_some() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "key {:?} is already present; use update to change its value", key)));
+        }
+        Ok(Some(found))
+    }
+
+    /// Inserts every pair, as `put` would one at a time. The table
+    /// first grows to the size the batch needs, then the records go in
+    /// grouped by bucket, so each touched page is written once, and
+    /// the ctrl page at most once, at the end.
+    pub fn put_many(&mut self, pairs: &[(&[u8], &[u8])]) {
+        self.try_put_many(pairs)
+            .unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    /// Like `put_many`, but returns an error instead of panicking,
+    /// including `InvalidArgument` for a key that is already present
+    /// or comes twice in `pairs`. On error, the records inserted so far
+    /// stay; they are not a prefix of `pairs`.
+    pub fn try_put_many(&mut self, pairs: &[(&[u8], &[u8])]) -> error::Result<()> {
+        counter!(PUTS, pairs.len());
+        self.check_writable()?;
+        for &(key, val) in pairs {
+            self.check_record(key, val)?;
+        }
+        while self.split_needed(self.nitems + pairs.len()) {
+            self.split()?;
+            counter!(SPLITS, 1);
+        }
+
+        let mut by_bucket: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, &(key, _)) in pairs.iter().enumerate() {
+            by_bucket.entry(self.bucket(key)).or_default().push(i);
+        }
+        let mut result = Ok(());
+        'buckets: for records in by_bucket.values() {
+            for &i in records {
+                let (key, val) = pairs[i];
+                let inserted = self.search_absent(key).and_then(|found| {
+                    let mark = self.log_put(key, val, false)?;
+                    self.insert_found(key, val, found).map_err(|e| {
+                        self.unlog(mark).ok();
+                        e.into()
+                    })
+                });
+                if let Err(e) = inserted {
+                    result = Err(e);
+                    break 'buckets;
+                }
+                self.nitems += 1;
+            }
+        }
+        let ctrl = self.buckets.update_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+        result.and(ctrl.map_err(LinHashError::from))?;
+        Ok(self.maybe_checkpoint()?)
+    }
+
+    /// Creates the table `filename` holding `records`, faster than
+    /// `put`ting them one by one: the table is sized for
+    /// `expected_count` records up front, so it need not split as it
+    /// fills, and the records are sorted by bucket and appended to
+    /// their bucket's pages in order. Of records with equal keys, the
+    /// last one wins. All records are held in memory until written.
+    /// Panics on error; see `try_bulk_load`.
+    pub fn bulk_load<P, I>(filename: P, keysize: usize, valsize: usize, records: I,
+                           expected_count: usize) -> LinHash
+        where P: AsRef<Path>, I: IntoIterator<Item = (Vec<u8>, Vec<u8>)> {
+        LinHash::try_bulk_load(filename, keysize, valsize, records, expected_count)
+            .unwrap_or_else(|e| panic!("bulk load failed: {}", e))
+    }
+
+    /// Like `bulk_load`, but returns an error instead of panicking,
+    /// also if the table already holds records. The load bypasses the
+    /// write-ahead log: if it fails part-way, the table holds some of
+    /// the records and should be deleted.
+    pub fn try_bulk_load<P, I>(filename: P, keysize: usize, valsize: usize, records: I,
+                               expected_count: usize) -> error::Result<LinHash>
+        where P: AsRef<Path>, I: IntoIterator<Item = (Vec<u8>, Vec<u8>)> {
+        LinHash::try_bulk_load_with_progress(filename, keysize, valsize, records,
+                                             expected_count, &mut NoProgress)
+    }
+
+    /// Like `try_bulk_load`, reporting records written out of the
+    /// records read after each bucket. Records are only written once
+    /// all are read.
+    pub fn try_bulk_load_with_progress<P, I, R>(filename: P, keysize: usize, valsize: usize,
+                                                records: I, expected_count: usize,
+                                                progress: &mut R) -> error::Result<LinHash>
+        where P: AsRef<Path>, I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>, R: Progress {
+        LinHash::try_bulk_load_cancellable(filename, keysize, valsize, records,
+                                           expected_count, progress, &CancelToken::new())
+    }
+
+    /// Like `try_bulk_load_with_progress`, stopping between buckets
+    /// once `cancel` is cancelled. The table is then closed cleanly,
+    /// holding the records of the buckets written so far: reopen it to
+    /// finish with `put`, or delete it.
+    pub fn try_bulk_load_cancellable<P, I, R>(filename: P, keysize: usize, valsize: usize,
+                                              records: I, expected_count: usize,
+                                              progress: &mut R, cancel: &CancelToken)
+                                              -> error::Result<LinHash>
+        where P: AsRef<Path>, I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>, R: Progress {
+        let mut table = LinHash::try_open(&filename, keysize, valsize)?;
+        if !table.is_empty() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} already holds records; bulk_load needs a new table",
+                filename.as_ref().display())));
+        }
+        let per_bucket = table.buckets.records_per_page as f32 * table.threshold;
+        let nbuckets = ((expected_count as f32 / per_bucket).ceil() as usize)
+            .clamp(2, DbFile::max_buckets());
+        // no records to move: each new bucket just gets a root page
+        while table.nbuckets < nbuckets {
+            table.buckets.allocate_new_bucket()?;
+            table.nbuckets += 1;
+            if table.nbuckets > 1 << table.nbits {
+                table.nbits += 1;
+            }
+        }
+
+        let layout = table.buckets.page_layout();
+        let mut loaded = Vec::with_capacity(expected_count);
+        for (mut key, val) in records {
+            table.check_record(&key, &val)?;
+            // so that equal keys sort together
+            if layout != PageLayout::Slotted {
+                key.resize(keysize, 0);
+            }
+            loaded.push((table.bucket(&key), key, val));
+        }
+        loaded.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let total = loaded.len();
+        let mut written = 0;
+        let mut loaded = loaded.into_iter().peekable();
+        while let Some(&(bucket_index, _, _)) = loaded.peek() {
+            cancel.check()?;
+            let mut records = vec![];
+            while let Some((_, key, val)) = loaded.next_if(|r| r.0 == bucket_index) {
+                written += 1;
+                if loaded.peek().is_some_and(|next| next.0 == bucket_index && next.1 == key) {
+                    continue;
+                }
+                records.push((key, val));
+            }
+            table.nitems += records.len();
+            table.buckets.fill_bucket(bucket_index, records)?;
+            progress.report(written, total);
+        }
+        debug!(nitems = table.nitems, nbuckets = table.nbuckets; "bulk loaded {}",
+               filename.as_ref().display());
+        // more records than expected
+        while table.split_needed(table.nitems) {
+            table.split()?;
+            counter!(SPLITS, 1);
+        }
+        table.checkpoint()?;
+        Ok(table)
+    }
+
+    /// Places (key, value) in its bucket, adding an overflow page if
+    /// the bucket is full. Doesn't count the record or split.
+    fn insert(&mut self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        self.insert_found(key, val, None).map(|_| ())
+    }
+
+    /// `insert`, starting from `found` instead of a first search.
+    /// Returns whether the bucket already had an overflow page and
+    /// needed another.
+    fn insert_found(&mut self, key: &[u8], val: &[u8], mut found: Option<SearchResult>)
+                    -> io::Result<bool> {
+        let mut chained = false;
+        loop {
+            let bucket_index = self.bucket(key);
+            self.misses.invalidate(bucket_index);
+            let SearchResult { page_id, row_num, val: old_val } = match found.take() {
+                Some(found) => found,
+                None if self.buckets.duplicate_keys() => self.buckets.free_row(bucket_index)?,
+                None => self.buckets.search_bucket(bucket_index, key)?,
+            };
+            match (page_id, row_num, old_val) {
+                // new insert
+                (Some(page_id), Some(pos), None) => {
+                    self.buckets.write_record_incr(page_id, pos, key, val);
+                    if let Some(ref mut filters) = self.filters {
+                        filters.insert(bucket_index, key);
+                    }
+                    return Ok(chained);
+                },
+                // case for update
+                (Some(_page_id), Some(_pos), Some(_old_val)) => {
+                    panic!("can't use put to reinsert old item: {:?}", (key, val));
+                },
+                // new insert, in overflow page
+                (Some(last_page_id), None, None) => { // overflow
+                    chained |= last_page_id != self.buckets.bucket_page(bucket_index);
+                    self.buckets.allocate_overflow(bucket_index, last_page_id)?;
+                },
+                _ => panic!("impossible case"),
+            }
+        }
+    }
+
+    /// Deletes the record with `key`, returning its value. Its slot is
+    /// filled with the last record of the same page, and an overflow
+    /// page left empty goes back on the free list. Once the table is
+    /// less than half as full as its split threshold, its last bucket
+    /// is merged back into its sibling, undoing the latest split.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.try_remove(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `remove`, but returns an error instead of panicking.
+    pub fn try_remove(&mut self, key: &[u8]) -> error::Result<Option<Vec<u8>>> {
+        counter!(REMOVES, 1);
+        self.check_writable()?;
+        let bucket_index = self.bucket(key);
+        let mark = self.log_remove(key)?;
+        let removed = self.buckets.remove_record(bucket_index, key)?;
+        if removed.is_some() {
+            self.nitems -= 1;
+            let merge = if self.merge_needed(self.nitems) { self.merge() } else { Ok(()) };
+            let ctrl = self.buckets.update_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+            merge.and(ctrl)?;
+            self.maybe_checkpoint()?;
+        } else {
+            self.unlog(mark)?;
+        }
+        Ok(removed)
+    }
+
+    /// Adds a record for `key` even if it already has one. Only tables
+    /// opened with `OpenOptions::duplicate_keys` allow this; there it
+    /// is the same as `put`.
+    pub fn put_dup(&mut self, key: &[u8], val: &[u8]) {
+        self.try_put_dup(key, val)
+            .unwrap_or_else(|e| panic!("put failed: {}", e));
+    }
+
+    /// Like `put_dup`, but returns an error instead of panicking.
+    pub fn try_put_dup(&mut self, key: &[u8], val: &[u8]) -> error::Result<()> {
+        if !self.buckets.duplicate_keys() {
+            return Err(LinHashError::InvalidArgument(format!(
+                "{} does not allow duplicate keys", self.buckets.path().display())));
+        }
+        self.try_put(key, val)
+    }
+
+    /// Deletes every record with `key`, returning how many there were.
+    pub fn remove_all(&mut self, key: &[u8]) -> usize {
+        self.try_remove_all(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `remove_all`, but returns an error instead of panicking.
+    pub fn try_remove_all(&mut self, key: &[u8]) -> error::Result<usize> {
+        self.check_writable()?;
+        let bucket_index = self.bucket(key);
+        let mark = self.log(wal::Entry::PutAll(key.to_vec(), vec![]))?;
+        let mut removed = 0;
+        while self.buckets.remove_record(bucket_index, key)?.is_some() {
+            removed += 1;
+        }
+        if removed == 0 {
+            self.unlog(mark)?;
+            return Ok(0);
+        }
+        self.nitems -= removed;
+        let merge = if self.merge_needed(self.nitems) { self.merge() } else { Ok(()) };
+        let ctrl = self.buckets.update_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+        merge.and(ctrl)?;
+        self.maybe_checkpoint()?;
+        Ok(removed)
+    }
+
+    /// Keeps only the records for which `keep(key, value)` is true,
+    /// with both zero-padded as stored, and returns how many were
+    /// removed. Makes one pass over the table, rewriting each page at
+    /// most once. Buckets are not merged; later removes shrink the
+    /// table as usual. A crash part way through leaves some of the
+    /// records removed.
+    pub fn retain<F: FnMut(&[u8], &[u8]) -> bool>(&mut self, keep: F) -> usize {
+        self.try_retain(keep).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `retain`, but returns an error instead of panicking.
+    pub fn try_retain<F: FnMut(&[u8], &[u8]) -> bool>(&mut self, mut keep: F)
+                                                     -> error::Result<usize> {
+        self.check_writable()?;
+        let mut removed = 0;
+        for bucket in 0..self.nbuckets {
+            removed += self.buckets.retain_in_bucket(bucket, &mut keep)?;
+        }
+        self.nitems -= removed;
+        // removals are not logged; make them durable instead
+        self.checkpoint()?;
+        Ok(removed)
+    }
+
+    /// Iterates over every (key, value) pair, with both zero-padded
+    /// as stored. Pages are read lazily, so memory use does not grow
+    /// with the table.
+    pub fn iter(&mut self) -> disk::Records<'_> {
+        self.buckets.records()
+    }
+
+    /// Iterates over every key, zero-padded as stored, in the order of
+    /// `iter`. Values are not copied.
+    pub fn keys(&mut self) -> disk::Records<'_, Vec<u8>> {
+        self.buckets.project_records(|k, _| k.to_vec())
+    }
+
+    /// Iterates over every value, as stored, in the order of `iter`.
+    /// Keys are not copied.
+    pub fn values(&mut self) -> disk::Records<'_, Vec<u8>> {
+        self.buckets.project_records(|_, v| v.to_vec())
+    }
+
+    /// A cursor before the first record, which stays valid as the
+    /// table changes. See `Cursor`.
+    pub fn cursor(&self) -> Cursor {
+        Cursor::new(self)
+    }
+
+    /// Lookup `key` in hashtable
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.try_get(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get`, but returns an error if a page of the bucket cannot
+    /// be read.
+    pub fn try_get(&mut self, key: &[u8]) -> error::Result<Option<Vec<u8>>> {
+        Ok(self.try_get_ref(key)?.map(<[u8]>::to_vec))
+    }
+
+    /// Up to `len` bytes of the value of `key`, from `offset` on,
+    /// copied straight out of its page: fewer if the value, as stored,
+    /// ends first. None if the key has no record.
+    pub fn get_range(&mut self, key: &[u8], offset: usize, len: usize) -> Option<Vec<u8>> {
+        self.try_get_range(key, offset, len).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_range(&mut self, key: &[u8], offset: usize, len: usize)
+                         -> error::Result<Option<Vec<u8>>> {
+        let bucket_index = self.bucket(key);
+        if !self.may_contain(bucket_index, key) {
+            return Ok(None);
+        }
+        Ok(self.buckets.locate(bucket_index, key)?.map(|(page_id, row_num)| {
+            let val = self.buckets.page(page_id).value(row_num);
+            let start = offset.min(val.len());
+            val[start..start.saturating_add(len).min(val.len())].to_vec()
+        }))
+    }
+
+    /// Copies the value of `key` into `buf`, without allocating, and
+    /// returns the length of the value: more than was copied if `buf`
+    /// is shorter. None if the key has no record.
+    pub fn get_into(&mut self, key: &[u8], buf: &mut [u8]) -> Option<usize> {
+        self.try_get_into(key, buf).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_into(&mut self, key: &[u8], buf: &mut [u8]) -> error::Result<Option<usize>> {
+        Ok(self.try_get_ref(key)?.map(|val| {
+            let n = val.len().min(buf.len());
+            buf[..n].copy_from_slice(&val[..n]);
+            val.len()
+        }))
+    }
+
+    /// The value of `key` where it lies in the buffer pool, as `get`
+    /// would return it. The page stays cached while the value is
+    /// borrowed, as the table cannot be used until then. Under
+    /// `IoBackend::Mmap` a page the pool does not hold is not read
+    /// into it: the value is borrowed from the map.
+    pub fn get_ref(&mut self, key: &[u8]) -> Option<&[u8]> {
+        self.try_get_ref(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_ref(&mut self, key: &[u8]) -> error::Result<Option<&[u8]>> {
+        counter!(GETS, 1);
+        match self.lookup(key)? {
+            Some((page_id, row_num)) => Ok(Some(self.buckets.try_value(page_id, row_num)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The values of every record with `key`, the one `get` returns
+    /// first. At most one unless the table allows duplicate keys.
+    pub fn get_all(&mut self, key: &[u8]) -> Vec<Vec<u8>> {
+        self.try_get_all(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get_all`, but returns an error if a page of the bucket
+    /// cannot be read.
+    pub fn try_get_all(&mut self, key: &[u8]) -> error::Result<Vec<Vec<u8>>> {
+        let bucket_index = self.bucket(key);
+        if !self.may_contain(bucket_index, key) {
+            return Ok(vec![]);
+        }
+        Ok(self.buckets.values_of(bucket_index, key)?)
+    }
+
+    /// Page and row of the record `get` returns for `key`, found by
+    /// comparing keys in the cached pages. The bucket filters and the
+    /// negative lookup cache are consulted first, and a miss is added
+    /// to the latter.
+    fn lookup(&mut self, key: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        let bucket_index = self.bucket(key);
+        let padded = self.padded_key(key);
+        if self.misses.contains(bucket_index, &padded) || !self.may_contain(bucket_index, key) {
+            return Ok(None);
+        }
+        let found = self.buckets.locate(bucket_index, key)?;
+        if found.is_none() {
+            self.misses.insert(bucket_index, padded);
+        }
+        Ok(found)
+    }
+
+    /// `lookup` for a batch of keys, searching each bucket's chain
+    /// once for all the keys in it.
+    fn lookup_many<K: AsRef<[u8]>>(&mut self, keys: &[K])
+                                   -> io::Result<Vec<Option<(usize, usize)>>> {
+        let mut by_bucket: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = key.as_ref();
+            let bucket_index = self.bucket(key);
+            if !self.misses.contains(bucket_index, &self.padded_key(key))
+                && self.may_contain(bucket_index, key) {
+                by_bucket.entry(bucket_index).or_default().push(i);
+            }
+        }
+
+        let mut found = vec![None; keys.len()];
+        for (bucket_index, probes) in by_bucket {
+            let probe_keys: Vec<&[u8]> = probes.iter().map(|&i| keys[i].as_ref()).collect();
+            let located = self.buckets.locate_many(bucket_index, &probe_keys)?;
+            for (&i, at) in probes.iter().zip(located) {
+                if at.is_none() {
+                    let padded = self.padded_key(keys[i].as_ref());
+                    self.misses.insert(bucket_index, padded);
+                }
+                found[i] = at;
+            }
+        }
+        Ok(found)
+    }
+
+    /// `key` as the negative lookup cache keeps it: zero-padded to
+    /// `keysize` unless pages are slotted.
+    fn padded_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut padded = key.to_vec();
+        if self.buckets.page_layout() != PageLayout::Slotted {
+            padded.resize(self.buckets.keysize().max(key.len()), 0);
+        }
+        padded
+    }
+
+    /// False if the bucket filters rule out `key` being in `bucket`.
+    fn may_contain(&self, bucket: usize, key: &[u8]) -> bool {
+        let source = self.buckets.split_source(bucket);
+        self.filters.as_ref().is_none_or(|f| {
+            f.may_contain(bucket, key) || source.is_some_and(|from| f.may_contain(from, key))
+        })
+    }
+
+    /// Whether the table allows duplicate keys; see
+    /// `OpenOptions::duplicate_keys`.
+    pub fn duplicate_keys(&self) -> bool {
+        self.buckets.duplicate_keys()
+    }
+
+    /// Number of pages holding buckets or the bucket map, ie.
+    /// everything but the ctrl pages and free pages. The total reported by operations that scan
+    /// the whole table.
+    pub fn pages_in_use(&self) -> usize {
+        self.buckets.num_pages() - self.buckets.ctrl_pages() - self.buckets.num_free()
+    }
+
+    /// Counts describing the table's layout; see `Stats`. Walks every
+    /// overflow chain, so it reads each page header once.
+    pub fn stats(&mut self) -> error::Result<Stats> {
+        self.check_handle()?;
+        let mut overflow_pages = 0;
+        let mut max_chain_len = 0;
+        for bucket in 0..self.nbuckets {
+            let len = self.buckets.chain_length(bucket)?;
+            overflow_pages += len - 1;
+            max_chain_len = max_chain_len.max(len);
+        }
+        Ok(Stats {
+            items: self.nitems,
+            buckets: self.nbuckets,
+            overflow_pages,
+            free_pages: self.buckets.num_free(),
+            avg_chain_len: (self.nbuckets + overflow_pages) as f64 / self.nbuckets as f64,
+            max_chain_len,
+            bytes_on_disk: self.buckets.store_len()?,
+            load_factor: self.nitems as f32
+                / (self.buckets.records_per_page * self.nbuckets) as f32,
+        })
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.nitems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nitems == 0
+    }
+
+    /// Number of buckets, ie. of chains the hash spreads keys over.
+    pub fn bucket_count(&self) -> usize {
+        self.nbuckets
+    }
+
+    /// The load factor above which the table splits a bucket.
+    pub fn split_threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    /// How many more records `put` can insert before one of them
+    /// splits a bucket, or `None` once the table has
+    /// `DbFile::max_buckets` buckets and no longer splits.
+    pub fn remaining_capacity(&self) -> Option<usize> {
+        if self.nbuckets >= DbFile::max_buckets() {
+            return None;
+        }
+        let mut n = ((self.buckets.records_per_page * self.nbuckets) as f32
+                     * self.threshold) as usize;
+        // settle the float rounding the way `split_needed` sees it
+        while n > 0 && self.split_needed(n) {
+            n -= 1;
+        }
+        while !self.split_needed(n + 1) {
+            n += 1;
+        }
+        Some(n.saturating_sub(self.nitems))
+    }
+
+    /// Reports every key that is only in this table, only in `other`,
+    /// or in both with different values. Returns the number of
+    /// differences. See `diff`.
+    pub fn diff<F: FnMut(Difference)>(&mut self, other: &mut LinHash, report: F) -> usize {
+        diff::diff(self, other, report)
+    }
+
+    /// Checks every page of the table for damage; see `verify`.
+    pub fn verify(&mut self) -> error::Result<Report> {
+        self.verify_with_progress(&mut NoProgress)
+    }
+
+    /// Like `verify`, reporting bucket pages checked out of
+    /// `pages_in_use` after each bucket.
+    pub fn verify_with_progress<P: Progress>(&mut self, progress: &mut P)
+                                             -> error::Result<Report> {
+        self.verify_cancellable(progress, &CancelToken::new())
+    }
+
+    /// Like `verify_with_progress`, stopping between buckets once
+    /// `cancel` is cancelled.
+    pub fn verify_cancellable<P: Progress>(&mut self, progress: &mut P, cancel: &CancelToken)
+                                           -> error::Result<Report> {
+        self.check_handle()?;
+        self.finish_split()?;
+        verify::verify_cancellable(self, progress, cancel)
+    }
+
+    /// Checks the table like `verify`, then repairs the damage found,
+    /// losing the records on damaged pages. Works on a table that is
+    /// read-only after corruption, and makes it writable again; not on
+    /// one opened read-only.
+    pub fn repair(&mut self) -> error::Result<Report> {
+        self.check_handle()?;
+        if self.read_only {
+            self.check_writable()?;
+        }
+        self.finish_split()?;
+        verify::repair(self)
+    }
+
+    /// Whether both tables hold the same records.
+    pub fn content_eq(&mut self, other: &mut LinHash) -> bool {
+        self.diff(other, |_| ()) == 0
+    }
+
+    /// Snapshot Bloom filter over all keys, using about `bits_per_key`
+    /// bits per key. See `bloom`.
+    pub fn build_filter(&mut self, bits_per_key: usize) -> BloomFilter {
+        BloomFilter::from_table(self, bits_per_key)
+    }
+
+    /// Stores `val` under `key` as a little-endian number, zero-padded
+    /// to `valsize`, with a single search of its bucket. Overwrites any
+    /// existing value. Panics on error; see `try_put_num`.
+    pub fn put_num<T: FixedWidth>(&mut self, key: &[u8], val: T) {
+        self.try_put_num(key, val).unwrap_or_else(|e| panic!("put failed: {}", e))
+    }
+
+    /// Like `put_num`, but returns an error instead of panicking, also
+    /// if `valsize` is narrower than `T`.
+    pub fn try_put_num<T: FixedWidth>(&mut self, key: &[u8], val: T) -> error::Result<()> {
+        self.check_num_width::<T>()?;
+        let mut buf = vec![0; self.buckets.valsize()];
+        val.encode_into(&mut buf);
+        match self.try_entry(key)? {
+            Entry::Occupied(mut e) => { e.try_insert(&buf)?; },
+            Entry::Vacant(e) => e.try_insert(&buf)?,
+        }
+        Ok(())
+    }
+
+    /// Reads the value under `key` as a little-endian number written
+    /// by `put_num`. Panics on error; see `try_get_num`.
+    pub fn get_num<T: FixedWidth>(&mut self, key: &[u8]) -> Option<T> {
+        self.try_get_num(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get_num`, but returns an error instead of panicking, also
+    /// if `valsize` is narrower than `T`.
+    pub fn try_get_num<T: FixedWidth>(&mut self, key: &[u8]) -> error::Result<Option<T>> {
+        self.check_num_width::<T>()?;
+        Ok(self.try_get_ref(key)?.map(util::decode))
+    }
+
+    fn check_num_width<T: FixedWidth>(&self) -> error::Result<()> {
+        let valsize = self.buckets.valsize();
+        if valsize < T::WIDTH {
+            return Err(LinHashError::InvalidArgument(format!(
+                "valsize {} too small for a {}-byte number", valsize, T::WIDTH)));
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` to the `i64` stored under `key` as by `put_i64`,
+    /// with a single search of its bucket, and returns the sum. A key
+    /// with no record counts from 0. Panics on error; see
+    /// `try_increment`.
+    pub fn increment(&mut self, key: &[u8], delta: i64) -> i64 {
+        self.try_increment(key, delta).unwrap_or_else(|e| panic!("increment failed: {}", e))
+    }
+
+    /// Like `increment`, but returns an error instead of panicking,
+    /// also if the sum overflows or `valsize` is narrower than 8
+    /// bytes. Bytes of the value past the first 8 are kept.
+    pub fn try_increment(&mut self, key: &[u8], delta: i64) -> error::Result<i64> {
+        let valsize = self.buckets.valsize();
+        if valsize < i64::WIDTH {
+            return Err(LinHashError::InvalidArgument(format!(
+                "valsize {} too small for an 8-byte counter", valsize)));
+        }
+        let (mut val, entry) = match self.try_entry(key)? {
+            Entry::Occupied(e) => (e.get().to_vec(), Entry::Occupied(e)),
+            vacant => (vec![], vacant),
+        };
+        if val.len() < i64::WIDTH {
+            val.resize(i64::WIDTH, 0);
+        }
+        let sum = util::decode::<i64>(&val).checked_add(delta)
+            .ok_or_else(|| LinHashError::InvalidArgument("counter overflow".to_string()))?;
+        sum.encode_into(&mut val);
+        match entry {
+            Entry::Occupied(mut e) => { e.try_insert(&val)?; },
+            Entry::Vacant(e) => e.try_insert(&val)?,
+        }
+        Ok(sum)
+    }
+
+    pub fn put_u32(&mut self, key: &[u8], val: u32) {
+        self.put_num(key, val)
+    }
+
+    pub fn get_u32(&mut self, key: &[u8]) -> Option<u32> {
+        self.get_num(key)
+    }
+
+    pub fn put_u64(&mut self, key: &[u8], val: u64) {
+        self.put_num(key, val)
+    }
+
+    pub fn get_u64(&mut self, key: &[u8]) -> Option<u64> {
+        self.get_num(key)
+    }
+
+    pub fn put_i32(&mut self, key: &[u8], val: i32) {
+        self.put_num(key, val)
+    }
+
+    pub fn get_i32(&mut self, key: &[u8]) -> Option<i32> {
+        self.get_num(key)
+    }
+
+    pub fn put_i64(&mut self, key: &[u8], val: i64) {
+        self.put_num(key, val)
+    }
+
+    pub fn get_i64(&mut self, key: &[u8]) -> Option<i64> {
+        self.get_num(key)
+    }
+
+    /// Application metadata (eg. a schema version) stored in the
+    /// table's header. Empty until `set_meta` is called.
+    pub fn get_meta(&self) -> Vec<u8> {
+        self.buckets.meta().to_vec()
+    }
+
+    /// Stores up to `disk::META_SIZE` bytes of application metadata in
+    /// the table's header, replacing any previous metadata.
+    pub fn set_meta(&mut self, meta: &[u8]) -> io::Result<()> {
+        self.check_writable().map_err(io::Error::from)?;
+        self.buckets.set_meta(meta)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets))
+    }
+
+    /// Fails if the file at this table's path was removed, replaced
+    /// or rewritten since it was opened, so that writes through this
+    /// handle would not reach it. `flush` and `close` check this.
+    pub fn check_handle(&self) -> io::Result<()> {
+        self.buckets.check_identity()
+    }
+
+    /// Writes all dirty pages, then the control page, so that
+    /// `SharedReader`s observe every change made so far.
+    pub fn flush(&mut self) {
+        self.check_handle().unwrap_or_else(|e| panic!("{}", e));
+        if self.is_read_only() {
+            return;
+        }
+        self.checkpoint().expect("flush failed");
+    }
+
+    pub fn close(&mut self) {
+        self.try_close().unwrap_or_else(|e| panic!("close failed: {}", e));
+    }
+
+    /// Like `close`, but returns an error instead of panicking. On
+    /// error the table stays open, so the caller may retry.
+    pub fn try_close(&mut self) -> error::Result<()> {
+        self.check_handle()?;
+        if self.is_read_only() {
+            self.abandon();
+            return Ok(());
+        }
+        self.finish_split()?;
+        self.buckets.truncate_free_tail((self.nbits, self.nitems, self.nbuckets))?;
+        // Pages first: the cleared open flag vouches for them.
+        self.buckets.try_close()?;
+        self.buckets.set_open_flag(false);
+        if let Err(e) = self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets)) {
+            self.buckets.set_open_flag(true);
+            return Err(e.into());
+        }
+        if self.durability() == Durability::OnClose {
+            self.buckets.sync()?;
+        }
+        self.clear_wal()?;
+        // Not synced, and a failure only means the next open rebuilds
+        // them: a torn or missing file is never trusted.
+        if let (Some(filters), Some(_)) = (self.filters.as_ref(), self.wal.as_ref()) {
+            let path = filters::filters_path(self.buckets.path());
+            let saved = filters.save(&path, self.buckets.hash_seed(), self.buckets.ctrl_seq(),
+                                     self.buckets.sidecar_cipher().as_ref());
+            if let Err(e) = saved {
+                warn!("{}: bucket filters not saved: {}", path.display(), e);
+            }
+        }
+        self.lock = None;
+        Ok(())
+    }
+
+    /// Closes the table without writing anything, as if the process
+    /// had died.
+    fn abandon(&mut self) {
+        self.buckets.discard();
+        self.lock = None;
+    }
+}
+
+/// Closes a table the owner forgot to `close`. Errors cannot be
+/// reported from here, so they are ignored; if the file is no longer
+/// the one opened (see `check_handle`) nothing is written to it.
+#[cfg(feature = "std")]
+impl Drop for LinHash {
+    fn drop(&mut self) {
+        if self.lock.is_some() && self.try_close().is_err() {
+            self.abandon();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use {disk, hasher, journal, linear, wal, CasError, Durability, IoBackend, KeyHasher,
+         LinHash, LinHashError, MemStore, PageLayout, SharedReader, Stats};
+    use disk::META_SIZE;
+    use page::{Page, PAGE_SIZE};
+    use std::fs;
+    use std::io;
+    use util::*;
+
+    #[test]
+    fn all_ops() {
+        let mut h = LinHash::open("/tmp/test_all_ops", 32, 4);
+        h.put(b"hello", &[12]);
+        h.put(b"there", &[13]);
+        h.put(b"foo", &[42]);
+        h.put(b"bar", &[11]);
+        h.update(b"bar", &[22]);
+        h.update(b"foo", &[84]);
+
+        assert_eq!(h.get(b"hello"), Some(vec![12, 0, 0, 0]));
+        assert_eq!(h.get(b"there"), Some(vec![13, 0, 0, 0]));
+        assert_eq!(h.get(b"foo"), Some(vec![84, 0, 0, 0]));
+        assert_eq!(h.get(b"bar"), Some(vec![22, 0, 0, 0]));
+
+        // assert_eq!(h.update(String::from("doesn't exist"), 99), false);
+        assert!(!h.contains(b"doesn't exist"));
+        assert!(h.contains(b"hello"));
+
+        h.close();
+        fs::remove_file("/tmp/test_all_ops").ok();
+    }
+
+    #[test]
+    fn split_events_carry_fields() {
+        use log::{self, kv, Log, Metadata, Record};
+        use std::sync::Mutex;
+
+        struct Capture(Mutex<Vec<String>>);
+        struct Fields(String);
+
+        impl<'kvs> kv::VisitSource<'kvs> for Fields {
+            fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>)
+                          -> Result<(), kv::Error> {
+                self.0.push_str(&format!(" {}={}", key, value));
+                Ok(())
+            }
+        }
+
+        impl Log for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                let mut line = Fields(format!("{} {}", record.level(), record.args()));
+                record.key_values().visit(&mut line).unwrap();
+                self.0.lock().unwrap().push(line.0);
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: Capture = Capture(Mutex::new(vec![]));
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        let path = "/tmp/test_split_events";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 8, 8);
+        for k in 0..2000u64 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+        log::set_max_level(log::LevelFilter::Off);
+        fs::remove_file(path).ok();
+
+        let lines = CAPTURE.0.lock().unwrap();
+        // other tests may log while the level is raised
+        assert!(lines.iter().any(|l| l.starts_with("DEBUG splitting bucket bucket=0 new_bucket=2")),
+                "{:?}", lines);
+        assert!(!lines.iter().any(|l| l.starts_with("TRACE")));
+    }
+
+    #[test]
+    fn modify_in_place() {
+        let path = "/tmp/test_modify";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 8);
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(0u64));
+        }
+        for _ in 0..3 {
+            for k in 0..1000 {
+                assert!(h.modify(&encode(k), |v| {
+                    assert_eq!(v.len(), 8);
+                    v.copy_from_slice(&encode(decode::<u64>(v) + k as u64));
+                }));
+            }
+        }
+        assert!(!h.modify(&encode(1000), |_| panic!("no record")));
+        h.close();
+        let mut h = LinHash::open(path, 4, 8);
+        for k in 0..1000 {
+            assert_eq!(h.get_u64(&encode(k)), Some(3 * k as u64));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+
+        // a slotted value keeps its length
+        let mut h = LinHash::open_with_layout(path, 4, 100, PageLayout::Slotted);
+        h.put(b"k", b"abc");
+        assert!(h.modify(b"k", |v| v.make_ascii_uppercase()));
+        assert_eq!(h.get(b"k"), Some(b"ABC".to_vec()));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let path = "/tmp/test_compare_and_swap";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        let k = &encode(1);
+        // insert if absent
+        assert!(h.compare_and_swap(k, None, Some(&[1])).is_ok());
+        match h.compare_and_swap(k, None, Some(&[2])) {
+            Err(CasError::Mismatch(Some(v))) => assert_eq!(v, vec![1, 0, 0, 0]),
+            r => panic!("{:?}", r),
+        }
+        // swap, comparing as stored
+        assert!(h.compare_and_swap(k, Some(&[1]), Some(&[3])).is_ok());
+        assert!(h.compare_and_swap(k, Some(&[1]), Some(&[4])).is_err());
+        assert_eq!(h.get(k), Some(vec![3, 0, 0, 0]));
+        // delete if equal
+        assert!(h.compare_and_swap(k, Some(&[3, 0, 0, 0]), None).is_ok());
+        assert_eq!(h.get(k), None);
+        match h.compare_and_swap(k, Some(&[3]), None) {
+            Err(CasError::Mismatch(None)) => (),
+            r => panic!("{:?}", r),
+        }
+        assert!(h.compare_and_swap(k, None, None).is_ok());
+        assert!(matches!(h.compare_and_swap(k, None, Some(&[0; 5])),
+                         Err(CasError::Table(LinHashError::InvalidArgument(_)))));
+        assert!(h.is_empty());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn counters() {
+        let path = "/tmp/test_counters";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 12);
+        for i in 0..3000 {
+            h.increment(&encode(i % 100), i64::from(i % 7) - 2);
+        }
+        for k in 0..100 {
+            let expected: i64 = (0..3000).filter(|i| i % 100 == k)
+                .map(|i| i64::from(i % 7) - 2)
+                .sum();
+            assert_eq!(h.get_i64(&encode(k)), Some(expected));
+            assert_eq!(h.increment(&encode(k), 0), expected);
+        }
+        assert_eq!(h.len(), 100);
+        h.put_i64(b"max", i64::MAX);
+        assert!(h.try_increment(b"max", 1).is_err());
+        assert_eq!(h.get_i64(b"max"), Some(i64::MAX));
+        h.close();
+        fs::remove_file(path).ok();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.try_increment(b"k", 1).is_err());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn append_to_values() {
+        let path = "/tmp/test_append";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open_with_layout(path, 4, 600, PageLayout::Slotted);
+        for i in 0..2000 {
+            h.append(&encode(i % 200), &[(i / 200) as u8; 30]);
+        }
+        for k in 0..200 {
+            let val = h.get(&encode(k)).unwrap();
+            assert_eq!(val.len(), 300);
+            assert!(val.chunks(30).enumerate().all(|(i, c)| c.iter().all(|&b| b == i as u8)));
+        }
+        assert_eq!(h.len(), 200);
+        assert!(h.try_append(&encode(0), &[0; 301]).is_err());
+        assert_eq!(h.append(&encode(0), &[0; 300]), 600);
+        h.close();
+        fs::remove_file(path).ok();
+
+        let mut h = LinHash::open(path, 4, 600);
+        assert!(h.try_append(b"k", b"v").is_err());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn partial_reads() {
+        let path = "/tmp/test_get_range";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 2000);
+        let val: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        for k in 0..100 {
+            h.put(&encode(k), &val);
+        }
+        assert_eq!(h.get_range(&encode(7), 0, 8), Some(val[..8].to_vec()));
+        assert_eq!(h.get_range(&encode(7), 1000, 10), Some(val[1000..1010].to_vec()));
+        // past the end of the value, as stored
+        assert_eq!(h.get_range(&encode(7), 1995, 10), Some(vec![0; 5]));
+        assert_eq!(h.get_range(&encode(7), 3000, usize::MAX), Some(vec![]));
+        assert_eq!(h.get_range(&encode(100), 0, 8), None);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn caller_buffer_reads() {
+        let path = "/tmp/test_get_into";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 16);
+        for k in 0..500 {
+            h.put(&encode(k), &[k as u8; 16]);
+        }
+        let mut buf = [0; 16];
+        assert_eq!(h.get_into(&encode(42), &mut buf), Some(16));
+        assert_eq!(buf, [42; 16]);
+        let mut short = [0; 4];
+        assert_eq!(h.get_into(&encode(7), &mut short), Some(16));
+        assert_eq!(short, [7; 4]);
+        assert_eq!(h.get_into(&encode(500), &mut buf), None);
+
+        for k in 0..500 {
+            let val = h.get(&encode(k));
+            assert_eq!(h.get_ref(&encode(k)), val.as_deref());
+        }
+        assert_eq!(h.get_ref(&encode(500)), None);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_persistence() {
+        let mut h = LinHash::open("/tmp/test_persistence", 32, 4);
+        h.put(b"hello", &[12]);
+        h.put(b"world", &[13]);
+        h.put(b"linear", &[144]);
+        h.put(b"hashing", &[255]);
+        h.close();
+
+        // This reloads the file and creates a new hashtable
+        let mut h2 = LinHash::open("/tmp/test_persistence", 32, 4);
+        assert_eq!(h2.get(b"hello"), Some(vec![12, 0, 0, 0]));
+
+        h2.close();
+        fs::remove_file("/tmp/test_persistence").ok();
+    }
+
+    // TODO: figure out a better testing strategy for this. This test
+    // currently inserts 10,000 records and checks that they are all
+    // there.
+    #[test]
+    fn test_overflow_and_splitting() {
+        let mut h = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k+1));
+        }
+        h.close();
+
+        let mut h2 = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
+        for k in 0..10000 {
+            assert_eq!(h2.get(&encode(k)), Some(encode(k+1)));
+        }
+
+        fs::remove_file("/tmp/test_overflow_and_splitting").ok();
+    }
+
+    #[test]
+    fn split_partitions_records() {
+        let path = "/tmp/test_split_partitions";
+        fs::remove_file(path).ok();
+        // long chains, so that a split has several pages to spread out
+        let mut h = LinHash::options().keysize(4).valsize(100).threshold(30.0)
+            .bucket_filters(true).open(path).unwrap();
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let (_, from) = linear::grow(h.nbits, h.nbuckets);
+        let mut before = h.bucket_records(from);
+        let nbuckets = h.nbuckets;
+        h.split().unwrap();
+        let to = h.nbuckets - 1;
+        assert_eq!(h.nbuckets, nbuckets + 1);
+
+        let mut after = vec![];
+        for &b in &[from, to] {
+            let pages = h.buckets.all_records_in_bucket(b);
+            // appended, not inserted into gaps: every page but the last is full
+            let full = pages[0].1.len();
+            assert!(pages.iter().rev().skip(1).all(|(_, records)| records.len() == full));
+            for (_, records) in pages {
+                for (k, v) in records {
+                    assert_eq!(h.bucket(&k), b);
+                    after.push((k, v));
+                }
+            }
+        }
+        before.sort();
+        after.sort();
+        assert_eq!(after, before);
+        assert!(!h.bucket_records(to).is_empty());
+        for k in 0..3000 {
+            assert_eq!(h.get_i32(&encode(k)), Some(k));
+        }
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 100);
+        assert_eq!(h.len(), 3000);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn full_disk() {
+        fs::remove_file("/tmp/test_full_disk").ok();
+        // a fixed hasher, so that the put that fails is not one whose
+        // record stays in although its split failed
+        let sip = KeyHasher::builtin(hasher::SIP_HASHER_ID, 0).unwrap();
+        let mut h = LinHash::open_with_hasher("/tmp/test_full_disk", 4, 4, PageLayout::Row, sip);
+        // ctrl pages, two buckets and a little room to grow
+        h.buckets.set_max_pages(Some(7));
+        let mut stored = 0;
+        let err = loop {
+            match h.try_put(&encode(stored), &encode(stored)) {
+                Ok(()) => stored += 1,
+                Err(e) => break e,
+            }
+        };
+        match err {
+            LinHashError::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::StorageFull),
+            e => panic!("expected a full disk, got {}", e),
+        }
+        // whatever the failed put left behind is consistent
+        let present = (0..stored + 1).filter(|&k| h.get(&encode(k)).is_some()).count();
+        assert_eq!(present, h.nitems);
+        assert!(present >= stored as usize);
+        for k in 0..stored {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+
+        h.buckets.set_max_pages(None);
+        for k in stored..stored + 3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..stored + 3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        fs::remove_file("/tmp/test_full_disk").ok();
+    }
+
+    #[test]
+    fn interrupted_split_is_rolled_back() {
+        fs::remove_file("/tmp/test_interrupted_split").ok();
+        let mut h = LinHash::open("/tmp/test_interrupted_split", 4, 4);
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.flush();
+
+        // Start splitting bucket 0 and "crash" after its root page
+        // has been cleared on disk and the ctrl page overwritten.
+        h.buckets.begin_split(&[0], (h.nbits, h.nitems, h.nbuckets)).unwrap();
+        h.buckets.allocate_new_bucket().unwrap();
+        h.buckets.clear_bucket(0).unwrap();
+        h.buckets.write_ctrlpage((2, h.nitems, 3)).unwrap();
+        h.abandon();
+
+        let mut h2 = LinHash::open("/tmp/test_interrupted_split", 4, 4);
+        assert_eq!((h2.nbits, h2.nitems, h2.nbuckets), (1, 100, 2));
+        for k in 0..100 {
+            assert_eq!(h2.get(&encode(k)), Some(encode(k)));
+        }
+        // later splits complete normally and leave no journal behind
+        for k in 100..3000 {
+            h2.put(&encode(k), &encode(k));
+        }
+        assert_eq!(fs::metadata("/tmp/test_interrupted_split.journal")
+                   .unwrap().len(), 0);
+        h2.close();
+
+        let mut h3 = LinHash::open("/tmp/test_interrupted_split", 4, 4);
+        for k in 0..3000 {
+            assert_eq!(h3.get(&encode(k)), Some(encode(k)));
+        }
+        h3.close();
+        fs::remove_file("/tmp/test_interrupted_split").ok();
+        fs::remove_file("/tmp/test_interrupted_split.journal").ok();
+    }
+
+    #[test]
+    fn batched_contains() {
+        fs::remove_file("/tmp/test_contains_many").ok();
+        let mut h = LinHash::open("/tmp/test_contains_many", 4, 4);
+        for k in (0..1000).step_by(2) {
+            h.put(&encode(k), &encode(k));
+        }
+        let keys: Vec<Vec<u8>> = (0..1000).map(encode::<i32>).collect();
+        let found = h.contains_many(&keys);
+        for (k, f) in found.into_iter().enumerate() {
+            assert_eq!(f, k % 2 == 0);
+            assert_eq!(h.contains(&keys[k]), f);
+        }
+        assert_eq!(h.contains_many(&[b"a", b"a"]), vec![false, false]);
+        let values = h.get_many(&[encode(4), encode(5), encode(4)]);
+        assert_eq!(values, vec![Some(encode(4)), None, Some(encode(4))]);
+        h.close();
+        fs::remove_file("/tmp/test_contains_many").ok();
+    }
+
+    #[test]
+    fn batched_get_in_overflow_chains() {
+        let path = "/tmp/test_get_many_chains";
+        fs::remove_file(path).ok();
+        // splits late, so buckets grow long chains
+        let mut h = LinHash::options().keysize(4).valsize(500).threshold(32.0).open(path).unwrap();
+        for k in (0..3000).step_by(3) {
+            h.put(&encode(k), &encode(k * 2));
+        }
+        assert!(h.buckets.chain_length(0).unwrap() > 3);
+        // present and missing keys, in no particular order, some twice
+        let keys: Vec<Vec<u8>> = (0..3000).rev().chain((0..300).step_by(7))
+            .map(encode::<i32>).collect();
+        let values = h.get_many(&keys);
+        let found = h.contains_many(&keys);
+        assert_eq!(values.len(), keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(values[i], h.get(key));
+            assert_eq!(found[i], h.contains(key));
+        }
+        assert_eq!(values.iter().filter(|v| v.is_some()).count(), 1000 + 15);
+        assert_eq!(h.try_get_many::<Vec<u8>>(&[]).unwrap(), vec![]);
+        assert_eq!(h.try_contains_many(&[encode(3), encode(4)]).unwrap(), vec![true, false]);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn mmap_backend() {
+        let path = "/tmp/test_mmap_backend";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        h.set_io_backend(IoBackend::Mmap).unwrap();
+        h.set_cache_pages(disk::MIN_BUFFERS).unwrap();
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+
+        let mut h = LinHash::options().io_backend(IoBackend::Mmap).open(path).unwrap();
+        assert_eq!(h.buckets.io_backend(),
+                   if cfg!(unix) { IoBackend::Mmap } else { IoBackend::File });
+        for k in 0..3000 {
+            assert_eq!(h.get_ref(&encode(k)), Some(&encode(k)[..]));
+        }
+        // pages written since are still found
+        for k in 3000..6000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..6000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        let store = LinHash::options().keysize(4).valsize(4).io_backend(IoBackend::Mmap)
+            .store(MemStore::new()).open(path);
+        assert!(store.is_err());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn temp_table() {
+        let mut h = LinHash::open_temp(4, 4);
+        let path = h.buckets.path().to_path_buf();
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.flush();
+        let other = LinHash::open_temp(4, 4);
+        assert_ne!(other.buckets.path(), path);
+        drop(h);
+        assert!(fs::metadata(&path).is_err());
+        assert!(fs::metadata(disk::sidecar_path(&path, ".lock")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = OsStr::from_bytes(b"/tmp/test_non_utf8_\xff");
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).durability(Durability::Always)
+            .open(path).unwrap();
+        h.put(&encode(1), &encode(1));
+        assert!(fs::metadata(wal::wal_path(path)).is_ok());
+        h.close();
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.get(&encode(1)), Some(encode(1)));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn memory_store() {
+        let path = "/tmp/test_memory_store";
+        let sidecars = [wal::wal_path(path), disk::sidecar_path(path.as_ref(), ".lock"),
+                        journal::journal_path(path)];
+        fs::remove_file(path).ok();
+        for file in &sidecars {
+            fs::remove_file(file).ok();
+        }
+        let mut h = LinHash::open_with_store(path, 4, 4, PageLayout::Row, MemStore::new());
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in (0..3000).step_by(2) {
+            assert_eq!(h.remove(&encode(k)), Some(encode(k)));
+        }
+        for k in 0..3000 {
+            let expected = if k % 2 == 0 { None } else { Some(encode(k)) };
+            assert_eq!(h.get(&encode(k)), expected);
+        }
+        assert!(h.set_io_backend(IoBackend::Mmap).is_err());
+        h.flush();
+        h.close();
+        assert!(fs::metadata(path).is_err());
+        for file in &sidecars {
+            assert!(fs::metadata(file).is_err(), "{} exists", file.display());
+        }
+    }
+
+    #[test]
+    fn size_accessors() {
+        fs::remove_file("/tmp/test_size_accessors").ok();
+        let mut h = LinHash::open("/tmp/test_size_accessors", 4, 4);
+        assert!(h.is_empty());
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!(h.len(), 1000);
+        let buckets = h.bucket_count();
+        let room = h.remaining_capacity().unwrap();
+        for k in 1000..1000 + room as i32 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!((h.bucket_count(), h.remaining_capacity()), (buckets, Some(0)));
+        h.put(&encode(-1), &encode(-1));
+        assert_eq!(h.bucket_count(), buckets + 1);
+        h.close();
+        fs::remove_file("/tmp/test_size_accessors").ok();
+    }
+
+    #[test]
+    fn batched_put() {
+        fs::remove_file("/tmp/test_put_many").ok();
+        let mut h = LinHash::open("/tmp/test_put_many", 4, 4);
+        h.put(&encode(-1), &encode(-1));
+        let keys: Vec<Vec<u8>> = (0..5000).map(encode::<i32>).collect();
+        let vals: Vec<Vec<u8>> = (0..5000).map(|k| encode::<i32>(k * 3)).collect();
+        let pairs: Vec<(&[u8], &[u8])> = keys.iter().zip(vals.iter())
+            .map(|(k, v)| (&k[..], &v[..])).collect();
+        h.put_many(&pairs);
+        assert_eq!(h.nitems, 5001);
+        assert!(!h.split_needed(h.nitems));
+        assert_eq!(h.len(), 5001);
+        h.close();
+
+        let mut h = LinHash::open("/tmp/test_put_many", 4, 4);
+        assert_eq!(h.nitems, 5001);
+        for k in -1..5000 {
+            assert_eq!(h.get_i32(&encode(k)), Some(if k < 0 { k } else { k * 3 }));
+        }
+        let wide = [0u8; 5];
+        assert!(h.try_put_many(&[(&encode(9000), &encode(1)), (&wide, &encode(1))]).is_err());
+        assert_eq!(h.get(&encode(9000)), None);
+        h.close();
+        fs::remove_file("/tmp/test_put_many").ok();
+    }
+
+    #[test]
+    fn bulk_load() {
+        let path = "/tmp/test_bulk_load";
+        fs::remove_file(path).ok();
+        // key 0 comes twice; the later value wins
+        let records = (0..20000).map(|k| (encode(k), encode(k * 2)))
+            .chain(Some((encode(0), encode(-1))));
+        let mut h = LinHash::bulk_load(path, 4, 4, records, 20000);
+        assert_eq!(h.len(), 20000);
+        let nbuckets = h.bucket_count();
+        assert!(!h.split_needed(h.nitems));
+        assert!(h.split_needed(h.nitems + h.buckets.records_per_page));
+        for k in 1..20000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k * 2)));
+        }
+        assert_eq!(h.get(&encode(0)), Some(encode(-1)));
+        h.put(&encode(-5), &encode(5));
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 20001);
+        assert_eq!(h.bucket_count(), nbuckets);
+        assert_eq!(h.get(&encode(19999)), Some(encode(39998)));
+        h.close();
+        assert!(LinHash::try_bulk_load(path, 4, 4, vec![(encode(1), encode(1))], 1).is_err());
+        fs::remove_file(path).ok();
+
+        // more records than expected still end up properly split
+        let mut h = LinHash::bulk_load(path, 4, 4, (0..5000).map(|k| (encode(k), encode(k))), 10);
+        assert!(!h.split_needed(h.nitems));
+        assert_eq!(h.get(&encode(4999)), Some(encode(4999)));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn shrinks_after_removes() {
+        let path = "/tmp/test_shrinks";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..20000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let grown = h.bucket_count();
+        let grown_pages = h.buckets.num_pages();
+        for k in 0..19900 {
+            assert_eq!(h.remove(&encode(k)), Some(encode(k)));
+        }
+        assert!(h.bucket_count() < grown / 10);
+        assert!(!h.merge_needed(h.nitems));
+        assert!(!h.split_needed(h.nitems));
+        for k in 19900..20000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        for k in 0..100 {
+            assert_eq!(h.remove(&encode(19900 + k)), Some(encode(19900 + k)));
+        }
+        assert_eq!(h.bucket_count(), 2);
+        assert_eq!(h.nbits, 1);
+        // the merges gave every page they freed back
+        assert!(grown_pages > h.buckets.ctrl_pages() + 2);
+        assert_eq!(h.buckets.num_pages(), h.buckets.ctrl_pages() + 2);
+        assert_eq!(h.buckets.num_free(), 0);
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 5000);
+        for k in 0..5000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn split_threshold() {
+        let path = "/tmp/test_split_threshold";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(0.5).open(path).unwrap();
+        assert_eq!(h.split_threshold(), 0.5);
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let load = h.len() as f32 / (h.buckets.records_per_page * h.bucket_count()) as f32;
+        assert!(load <= 0.5 && load > 0.4);
+        h.close();
+
+        // kept by the table, and changed by opening with another
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.split_threshold(), 0.5);
+        h.close();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(2.0).open(path).unwrap();
+        let buckets = h.bucket_count();
+        h.remove(&encode(0));
+        assert!(h.bucket_count() < buckets);
+        h.close();
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.split_threshold(), 2.0);
+        for k in 1..10000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+
+        for bad in &[0.0, -1.0, 40.0, f32::NAN] {
+            assert!(LinHash::options().keysize(4).threshold(*bad).open(path).is_err());
+        }
+        assert!(LinHash::options().valsize(8).open(path).is_err());
+        assert!(LinHash::options().keysize(4).page_size(8192).open(path).is_err());
+        let mut h = LinHash::options().keysize(4).valsize(4).page_size(PAGE_SIZE).open(path)
+            .unwrap();
+        assert_eq!(h.split_threshold(), 2.0);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn durability_modes() {
+        let path = "/tmp/test_durability";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).durability(Durability::EveryN(10))
+            .open(path).unwrap();
+        assert_eq!(h.durability(), Durability::EveryN(10));
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(fs::metadata(wal::wal_path(path)).unwrap().len() > 0);
+        h.sync().unwrap();
+        assert_eq!(fs::metadata(wal::wal_path(path)).unwrap().len(), 0);
+        h.set_durability(Durability::OnClose);
+        h.put(&encode(100), &encode(100));
+        h.abandon();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.durability(), Durability::Never);
+        assert_eq!(h.len(), 101);
+        h.set_sync_writes(true);
+        assert_eq!(h.durability(), Durability::Always);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn ctrl_page_written_on_change() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use PageStore;
+
+        struct CountCtrlWrites(MemStore, Arc<AtomicUsize>);
+        impl PageStore for CountCtrlWrites {
+            fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+                self.0.read_page(page_id, data)
+            }
+            fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+                // either copy of the ctrl page
+                if page_id < 2 {
+                    self.1.fetch_add(1, Ordering::SeqCst);
+                }
+                self.0.write_page(page_id, data)
+            }
+            fn sync(&mut self) -> io::Result<()> {
+                self.0.sync()
+            }
+            fn len(&self) -> io::Result<u64> {
+                self.0.len()
+            }
+        }
+
+        let path = "/tmp/test_ctrl_writes";
+        let writes = Arc::new(AtomicUsize::new(0));
+        let store = CountCtrlWrites(MemStore::new(), writes.clone());
+        let mut h = LinHash::open_with_store(path, 4, 4, PageLayout::Row, store);
+        let opened = writes.load(Ordering::SeqCst);
+        for k in 0..500 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..100 {
+            h.remove(&encode(k));
+        }
+        assert_eq!(writes.load(Ordering::SeqCst), opened);
+        // a split writes it
+        for k in 500..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(writes.load(Ordering::SeqCst) > opened);
+        h.flush();
+        assert_eq!(h.buckets.read_ctrlpage().unwrap().1, 1900);
+        h.close();
+    }
+
+    #[test]
+    fn partial_expansions() {
+        let path = "/tmp/test_partial_expansions";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).partial_expansions(true)
+            .open(path).unwrap();
+        let mut k = 0;
+        while h.bucket_count() < 12 {
+            h.put(&encode(k), &encode(k));
+            k += 1;
+        }
+        // after the first pass of a round every bucket is about as full
+        let counts: Vec<usize> = (0..12).map(|b| h.bucket_records(b).len()).collect();
+        let (min, max) = (counts.iter().min().unwrap(), counts.iter().max().unwrap());
+        assert!(max * 2 < min * 3, "{:?}", counts);
+        for k in k..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(h.cursor().next(&mut h).is_err());
+        h.flush();
+        let mut r = SharedReader::open(path, 4, 4).unwrap();
+        assert_eq!(r.get(&encode(4321)), Some(encode(4321)));
+        h.close();
+
+        assert!(LinHash::options().incremental_splits(true).open(path).is_err());
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.buckets.partial_expansions());
+        for k in (0..10000).step_by(3) {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        let buckets = h.bucket_count();
+        for k in 0..9000 {
+            assert_eq!(h.remove(&encode(k)), Some(encode(k)));
+        }
+        assert!(h.bucket_count() < buckets / 4);
+        for k in 9000..10000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn split_on_overflow() {
+        let path = "/tmp/test_split_on_overflow";
+        let mut chains = vec![];
+        for &enabled in &[false, true] {
+            fs::remove_file(path).ok();
+            let mut h = LinHash::options().keysize(4).valsize(4).threshold(4.0)
+                .split_on_overflow(enabled).open(path).unwrap();
+            for k in 0..6000 {
+                h.put(&encode(k), &encode(k));
+            }
+            for k in (0..6000).step_by(13) {
+                assert_eq!(h.get(&encode(k)), Some(encode(k)));
+            }
+            let stats = h.stats().unwrap();
+            chains.push((stats.max_chain_len, h.bucket_count()));
+            h.close();
+        }
+        // chains are cut short before the load factor calls for it,
+        // but never below half the threshold
+        let ((plain_chain, plain_buckets), (chain, buckets)) = (chains[0], chains[1]);
+        assert!(chain < plain_chain && buckets > plain_buckets);
+        assert!(buckets < plain_buckets * 2);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn incremental_splits() {
+        let path = "/tmp/test_incremental_splits";
+        fs::remove_file(path).ok();
+        LinHash::options().keysize(4).valsize(4).threshold(30.0).open(path).unwrap().close();
+        let records = (0..20000).map(|k| (encode(k), encode(k)));
+        LinHash::bulk_load(path, 4, 4, records, 0).close();
+        // just under the load of the two long chains: the next put
+        // splits bucket 0, and no other split follows for a while
+        let open = |nitems: usize, nbuckets: usize| {
+            let thousandths = nitems * 1000 / (nbuckets * Page::capacity(4, 4)) - 1;
+            LinHash::options().threshold(thousandths as f32 / 1000.0)
+                .incremental_splits(true).bucket_filters(true).open(path).unwrap()
+        };
+
+        let mut h = open(20000, 2);
+        let chain = h.buckets.chain_length(0).unwrap();
+        h.put(&encode(20000), &encode(20000));
+        assert_eq!(h.buckets.pending_split(), Some((0, 2)));
+        assert_eq!(h.buckets.chain_length(0).unwrap(), chain);
+        assert_eq!(h.buckets.chain_length(2).unwrap(), 1);
+        let unmoved = (0..20000).find(|&k| h.bucket(&encode(k)) == 2).unwrap();
+        assert_eq!(h.get(&encode(unmoved)), Some(encode(unmoved)));
+        assert!(h.contains(&encode(unmoved)) && !h.contains(&encode(20001)));
+        assert_eq!(h.get_many(&[encode(unmoved), encode(20001)]),
+                   vec![Some(encode(unmoved)), None]);
+        h.flush();
+        let mut r = SharedReader::open(path, 4, 4).unwrap();
+        assert_eq!(r.get(&encode(unmoved)), Some(encode(unmoved)));
+        let in_both = h.bucket_records(0).len() + h.bucket_records(2).len();
+
+        // each put moves one page
+        let end = 20001 + chain as i32 / 2;
+        for k in 20001..end {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(h.buckets.pending_split().is_some());
+        assert_eq!(h.buckets.chain_length(0).unwrap(), chain);
+        let moved = h.buckets.chain_length(2).unwrap();
+        assert!(moved > chain / 8 && moved < chain / 2);
+        assert_eq!(h.remove(&encode(unmoved)), Some(encode(unmoved)));
+        assert_eq!(h.get(&encode(unmoved)), None);
+        for k in (0..end).step_by(7).filter(|&k| k != unmoved) {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        let added = (20001..end).filter(|&k| h.bucket(&encode(k)) % 2 == 0).count();
+        assert_eq!(h.bucket_records(0).len() + h.bucket_records(2).len(), in_both + added - 1);
+        h.close();
+
+        let mut h = open(20000, 2);
+        assert!(!h.recovered());
+        assert_eq!(h.buckets.pending_split(), None);
+        assert!(h.verify().unwrap().is_ok());
+        // the pages of the bucket split are left half full
+        assert!(h.compact_bucket(0).unwrap() > chain / 4);
+        assert_eq!(h.len(), 20000 + chain / 2);
+        for k in (0..end).step_by(11).filter(|&k| k != unmoved) {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+
+        let nitems = h.len();
+        h.close();
+
+        // a split left part-way by a crash is finished on open
+        let mut h = open(nitems, 3);
+        for k in 30000..30010 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!(h.buckets.pending_split(), Some((1, 3)));
+        let nitems = h.len();
+        h.flush();
+        h.abandon();
+        let mut h = open(nitems, 3);
+        assert!(h.recovered());
+        assert_eq!(h.buckets.pending_split(), None);
+        assert_eq!(h.len(), nitems);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn clean_pages_are_not_written() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use {FileStore, PageStore};
+
+        struct CountPageWrites(FileStore, Arc<AtomicUsize>);
+        impl PageStore for CountPageWrites {
+            fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+                self.0.read_page(page_id, data)
+            }
+            fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+                // not either copy of the ctrl page
+                if page_id >= 2 {
+                    self.1.fetch_add(1, Ordering::SeqCst);
+                }
+                self.0.write_page(page_id, data)
+            }
+            fn sync(&mut self) -> io::Result<()> {
+                self.0.sync()
+            }
+            fn len(&self) -> io::Result<u64> {
+                self.0.len()
+            }
+        }
+
+        let path = "/tmp/test_clean_pages";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let store = CountPageWrites(FileStore::open(path).unwrap(), writes.clone());
+        let mut h = LinHash::options().store(store).open(path).unwrap();
+        for k in 0..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.sync().unwrap();
+        h.close();
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+
+        // a change writes only the page it is in
+        let store = CountPageWrites(FileStore::open(path).unwrap(), writes.clone());
+        let mut h = LinHash::options().store(store).open(path).unwrap();
+        for k in 0..3000 {
+            h.get(&encode(k));
+        }
+        h.update(&encode(7), &encode(8));
+        h.close();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn bucket_search() {
+        let path = "/tmp/test_bucket_search";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0).open(path).unwrap();
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!(h.bucket_count(), 2);
+        for k in (0..2000).step_by(97) {
+            let found = h.buckets.search_bucket(h.bucket(&encode(k)), &encode(k)).unwrap();
+            assert_eq!(found.val, Some(encode(k)));
+            assert_eq!(Some((found.page_id.unwrap(), found.row_num.unwrap())),
+                       h.buckets.locate(h.bucket(&encode(k)), &encode(k)).unwrap());
+        }
+        // a missing key: the room after the last record of the chain
+        let bucket = h.bucket(&encode(2000));
+        let mut last = h.buckets.bucket_page(bucket);
+        while let Some(next) = h.buckets.page(last).next {
+            last = next;
+        }
+        assert_ne!(last, h.buckets.bucket_page(bucket));
+        let rows = h.buckets.page(last).num_records;
+        let missing = h.buckets.search_bucket(bucket, &encode(2000)).unwrap();
+        assert_eq!((missing.page_id, missing.row_num, missing.val), (Some(last), Some(rows), None));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn overflow_chain_reads() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use {FileStore, PageStore};
+
+        struct CountReads(FileStore, Arc<AtomicUsize>, Arc<AtomicUsize>);
+        impl PageStore for CountReads {
+            fn prefetch(&mut self, page_id: usize) {
+                self.2.fetch_add(1, Ordering::SeqCst);
+                self.0.prefetch(page_id)
+            }
+            fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.read_page(page_id, data)
+            }
+            fn read_pages(&mut self, first: usize, data: &mut [&mut [u8]]) -> io::Result<()> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.read_pages(first, data)
+            }
+            fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+                self.0.write_page(page_id, data)
+            }
+            fn sync(&mut self) -> io::Result<()> {
+                self.0.sync()
+            }
+            fn len(&self) -> io::Result<u64> {
+                self.0.len()
+            }
+        }
+
+        let path = "/tmp/test_chain_reads";
+        fs::remove_file(path).ok();
+        // long chains, laid out in order by the bulk load
+        LinHash::options().keysize(4).valsize(4).threshold(30.0).open(path).unwrap().close();
+        let records = (0..20000).map(|k| (encode(k), encode(k)));
+        LinHash::bulk_load(path, 4, 4, records, 0).close();
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let prefetches = Arc::new(AtomicUsize::new(0));
+        let store = CountReads(FileStore::open(path).unwrap(), reads.clone(),
+                               prefetches.clone());
+        let mut h = LinHash::options().store(store).open(path).unwrap();
+        let mut nrecords = 0;
+        for bucket in 0..2 {
+            reads.store(0, Ordering::SeqCst);
+            let pages = h.buckets.all_records_in_bucket(bucket);
+            assert!(pages.len() > 2 * disk::CHAIN_READAHEAD);
+            assert!(reads.load(Ordering::SeqCst) < pages.len() / 2);
+            assert_eq!(pages.len(), h.buckets.chain_length(bucket).unwrap());
+            nrecords += pages.iter().map(|(_, records)| records.len()).sum::<usize>();
+        }
+        assert_eq!(nrecords, 20000);
+
+        // a search hints at each page it goes on to, unless it is cached
+        h.buckets.set_pool_size(disk::MIN_BUFFERS).unwrap();
+        assert_eq!(h.get(&encode(20000)), None);
+        let chain = h.buckets.chain_length(h.bucket(&encode(20000))).unwrap();
+        assert!(prefetches.load(Ordering::SeqCst) > chain - disk::MIN_BUFFERS);
+        assert!(prefetches.load(Ordering::SeqCst) < chain);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn prefetch_follows_the_chain() {
+        use std::sync::{Arc, Mutex};
+        use {FileStore, PageStore};
+
+        struct LogPrefetches(FileStore, Arc<Mutex<Vec<usize>>>);
+        impl PageStore for LogPrefetches {
+            fn prefetch(&mut self, page_id: usize) {
+                self.1.lock().unwrap().push(page_id);
+            }
+            fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+                self.0.read_page(page_id, data)
+            }
+            fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+                self.0.write_page(page_id, data)
+            }
+            fn sync(&mut self) -> io::Result<()> {
+                self.0.sync()
+            }
+            fn len(&self) -> io::Result<u64> {
+                self.0.len()
+            }
+        }
+
+        let path = "/tmp/test_prefetch_chain";
+        fs::remove_file(path).ok();
+        LinHash::options().keysize(4).valsize(100).threshold(30.0).open(path).unwrap().close();
+        let records = (0..8000).map(|k| (encode(k), encode(k)));
+        LinHash::bulk_load(path, 4, 100, records, 0).close();
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let store = LogPrefetches(FileStore::open(path).unwrap(), log.clone());
+        let mut h = LinHash::options().store(store).open(path).unwrap();
+        h.buckets.set_pool_size(disk::MIN_BUFFERS).unwrap();
+        let missing = encode(8000);
+        let bucket = h.bucket(&missing);
+        assert_eq!(h.get(&missing), None);
+        let prefetched = log.lock().unwrap().split_off(0);
+        let chain: Vec<usize> = h.buckets.all_records_in_bucket(bucket).into_iter()
+            .map(|(page_id, _)| page_id).collect();
+        assert!(chain.len() > 2 * disk::MIN_BUFFERS);
+        // only pages the search went on to, in chain order
+        assert!(!prefetched.is_empty());
+        let mut rest = chain[1..].iter();
+        for page_id in &prefetched {
+            assert!(rest.any(|p| p == page_id), "{} prefetched out of order", page_id);
+        }
+
+        // nothing to hint at once the whole chain is cached
+        h.buckets.set_pool_size(2 * chain.len()).unwrap();
+        assert_eq!(h.get(&missing), None);
+        log.lock().unwrap().clear();
+        assert_eq!(h.get(&missing), None);
+        assert_eq!(*log.lock().unwrap(), vec![]);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn compaction() {
+        let path = "/tmp/test_compaction";
+        fs::remove_file(path).ok();
+        // never splits, so both buckets grow long chains
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0)
+            .open(path).unwrap();
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!(h.compact_all().unwrap(), 0);
+        for k in (0..10000).filter(|k| k % 4 != 0) {
+            h.remove(&encode(k));
+        }
+        let in_use = h.pages_in_use();
+        let size = fs::metadata(path).unwrap().len();
+        let freed = h.compact_all().unwrap();
+        assert!(freed > 0);
+        assert_eq!(h.pages_in_use(), in_use - freed);
+        assert!(fs::metadata(path).unwrap().len() <= size);
+        assert!(h.verify().unwrap().is_ok());
+        assert_eq!(h.compact_bucket(0).unwrap(), 0);
+        assert!(h.compact_bucket(2).is_err());
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 2500);
+        for k in 0..10000 {
+            assert_eq!(h.get(&encode(k)), if k % 4 == 0 { Some(encode(k)) } else { None });
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn compact_into_new_file() {
+        let path = "/tmp/test_compact_into";
+        let copy = "/tmp/test_compact_into_copy";
+        fs::remove_file(path).ok();
+        fs::remove_file(copy).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0)
+            .open(path).unwrap();
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in (0..10000).filter(|k| k % 4 != 0) {
+            h.remove(&encode(k));
+        }
+        h.set_meta(b"v2").unwrap();
+        let in_use = h.pages_in_use();
+        h.compact_into(copy).unwrap();
+        assert!(h.compact_into(copy).is_err());
+        assert_eq!(h.pages_in_use(), in_use);
+
+        let mut c = LinHash::options().open(copy).unwrap();
+        assert_eq!(c.len(), 2500);
+        assert_eq!(c.split_threshold(), 30.0);
+        assert_eq!(c.get_meta(), b"v2");
+        assert_eq!(c.buckets.num_free(), 0);
+        assert!(c.pages_in_use() < in_use);
+        for k in 0..10000 {
+            assert_eq!(c.get(&encode(k)), if k % 4 == 0 { Some(encode(k)) } else { None });
+        }
+        assert!(c.verify().unwrap().is_ok());
+        c.close();
+
+        // in place, keeping the handle
+        let freed = h.compact().unwrap();
+        assert_eq!(h.pages_in_use(), in_use - freed);
+        assert_eq!(h.buckets.num_free(), 0);
+        assert_eq!(fs::metadata(path).unwrap().len(), fs::metadata(copy).unwrap().len());
+        h.put(&encode(1), &encode(1));
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 2501);
+        assert_eq!(h.get(&encode(8)), Some(encode(8)));
+        h.close();
+        fs::remove_file(path).ok();
+        fs::remove_file(copy).ok();
+    }
+
+    #[test]
+    fn maintenance_reports_progress() {
+        let path = "/tmp/test_maintenance_progress";
+        let copy = "/tmp/test_maintenance_progress_copy";
+        fs::remove_file(path).ok();
+        fs::remove_file(copy).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0)
+            .open(path).unwrap();
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in (0..10000).filter(|k| k % 4 != 0) {
+            h.remove(&encode(k));
+        }
+        let in_use = h.pages_in_use();
+        let mut seen = vec![];
+        h.verify_with_progress(&mut |done, total| seen.push((done, total))).unwrap();
+        assert_eq!(seen.len(), h.bucket_count());
+        assert!(seen.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert_eq!(seen.last(), Some(&(in_use, in_use)));
+
+        seen.clear();
+        h.compact_into_with_progress(copy, &mut |done, total| seen.push((done, total)))
+            .unwrap();
+        assert_eq!(seen.len(), h.bucket_count());
+        assert_eq!(seen.last(), Some(&(in_use, in_use)));
+
+        seen.clear();
+        h.compact_all_with_progress(&mut |done, total| seen.push((done, total))).unwrap();
+        assert_eq!(seen.len(), h.bucket_count());
+        assert_eq!(seen.last(), Some(&(in_use, in_use)));
+
+        seen.clear();
+        let in_use = h.pages_in_use();
+        h.compact_with_progress(&mut |done, total| seen.push((done, total))).unwrap();
+        assert_eq!(seen.last(), Some(&(in_use, in_use)));
+        assert_eq!(h.len(), 2500);
+        h.close();
+        fs::remove_file(path).ok();
+        fs::remove_file(copy).ok();
+
+        seen.clear();
+        let records = (0..5000).map(|k| (encode(k), encode(k)));
+        let mut h = LinHash::try_bulk_load_with_progress(
+            path, 4, 4, records, 5000, &mut |done, total| seen.push((done, total))).unwrap();
+        assert_eq!(seen.len(), h.bucket_count());
+        assert!(seen.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(seen.last(), Some(&(5000, 5000)));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn maintenance_can_be_cancelled() {
+        use progress::CancelToken;
+        let interrupted = |e: LinHashError| io::Error::from(e).kind() == io::ErrorKind::Interrupted;
+        let path = "/tmp/test_maintenance_cancel";
+        let copy = "/tmp/test_maintenance_cancel_copy";
+        fs::remove_file(path).ok();
+        fs::remove_file(copy).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0)
+            .open(path).unwrap();
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in (0..10000).filter(|k| k % 4 != 0) {
+            h.remove(&encode(k));
+        }
+        let in_use = h.pages_in_use();
+
+        // each stops after the first bucket
+        let cancel = CancelToken::new();
+        let mut calls = 0;
+        let err = h.verify_cancellable(&mut |_, _| { calls += 1; cancel.cancel() }, &cancel)
+            .unwrap_err();
+        assert!(interrupted(err));
+        assert_eq!(calls, 1);
+
+        let cancel = CancelToken::new();
+        let err = h.compact_into_cancellable(copy, &mut |_, _| cancel.cancel(), &cancel)
+            .unwrap_err();
+        assert!(interrupted(err));
+        assert_eq!(h.pages_in_use(), in_use);
+        fs::remove_file(copy).unwrap();
+
+        let cancel = CancelToken::new();
+        let size = fs::metadata(path).unwrap().len();
+        let err = h.compact_cancellable(&mut |_, _| cancel.cancel(), &cancel).unwrap_err();
+        assert!(interrupted(err));
+        assert!(!disk::sidecar_path(std::path::Path::new(path), ".compact").exists());
+        assert_eq!(fs::metadata(path).unwrap().len(), size);
+        assert_eq!(h.pages_in_use(), in_use);
+
+        // bucket 0 is compacted, bucket 1 is not
+        let cancel = CancelToken::new();
+        let err = h.compact_all_cancellable(&mut |_, _| cancel.cancel(), &cancel).unwrap_err();
+        assert!(interrupted(err));
+        assert!(h.pages_in_use() < in_use);
+        assert!(h.buckets.chain_is_sparse(1).unwrap());
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 2500);
+        assert_eq!(h.get(&encode(9996)), Some(encode(9996)));
+        h.close();
+        fs::remove_file(path).ok();
+
+        let cancel = CancelToken::new();
+        let records = (0..5000).map(|k| (encode(k), encode(k)));
+        let err = LinHash::try_bulk_load_cancellable(path, 4, 4, records, 5000,
+                                                     &mut |_, _| cancel.cancel(), &cancel);
+        assert!(interrupted(err.err().unwrap()));
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(!h.is_empty() && h.len() < 5000);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn maintenance_is_throttled() {
+        use progress::{NoProgress, Throttle};
+        use std::time::{Duration, Instant};
+        let path = "/tmp/test_maintenance_throttle";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(30.0)
+            .open(path).unwrap();
+        for k in 0..10000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in (0..10000).filter(|k| k % 4 != 0) {
+            h.remove(&encode(k));
+        }
+        // a budget of a fifth of a second for the whole table
+        let rate = h.pages_in_use() as f64 * 5.0;
+        let start = Instant::now();
+        let mut seen = 0;
+        h.verify_with_progress(&mut Throttle::new(rate, |done, _| seen = done)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(seen, h.pages_in_use());
+
+        let start = Instant::now();
+        assert!(h.compact_all_with_progress(&mut Throttle::new(rate, NoProgress)).unwrap() > 0);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn reclaim_space() {
+        let path = "/tmp/test_reclaim_space";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..20000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let size = fs::metadata(path).unwrap().len();
+        for k in 0..19000 {
+            h.remove(&encode(k));
+        }
+        // the merges gave the free pages at the end of the file back
+        let shrunk = fs::metadata(path).unwrap().len();
+        assert!(shrunk < size / 2);
+        assert_eq!(h.reclaim_space().unwrap(), 0);
+        assert!(h.verify().unwrap().is_ok());
+
+        // as if removes emptied the last overflow pages: the free list
+        // is used up, then a page appended, and all are freed again
+        fn free_last_page(h: &mut LinHash) {
+            let mut pages = vec![];
+            loop {
+                let page = h.buckets.allocate_page().unwrap();
+                pages.push(page);
+                if page + 1 == h.buckets.num_pages() {
+                    break;
+                }
+            }
+            for page in pages {
+                h.buckets.free_page(page);
+            }
+        }
+        free_last_page(&mut h);
+        assert_eq!(h.reclaim_space().unwrap(), 1);
+        // the pages left on the free list are still recycled
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        // and close gives back the free pages at the end
+        free_last_page(&mut h);
+        let pages = h.buckets.num_pages();
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.buckets.num_pages(), pages - 1);
+        assert_eq!(h.len(), 3000);
+        for k in (0..2000).chain(19000..20000) {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        assert_eq!(h.get(&encode(5000)), None);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn stats() {
+        let path = "/tmp/test_stats";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        let empty = h.stats().unwrap();
+        assert_eq!(empty, Stats {
+            items: 0, buckets: 2, overflow_pages: 0, free_pages: 0,
+            avg_chain_len: 1.0, max_chain_len: 1, bytes_on_disk: empty.bytes_on_disk,
+            load_factor: 0.0,
+        });
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..1000 {
+            h.remove(&encode(k));
+        }
+        h.flush();
+        let s = h.stats().unwrap();
+        assert_eq!(s.items, 4000);
+        assert_eq!(s.buckets, h.nbuckets);
+        assert_eq!(s.buckets + s.overflow_pages, h.pages_in_use());
+        assert_eq!(s.free_pages, h.buckets.num_free());
+        assert!(s.max_chain_len >= 1 && s.avg_chain_len <= s.max_chain_len as f64);
+        assert_eq!(s.bytes_on_disk, fs::metadata(path).unwrap().len());
+        assert!(s.load_factor > 0.0 && s.load_factor <= h.split_threshold());
+        assert!(s.free_ratio() < 1.0);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn user_metadata() {
+        fs::remove_file("/tmp/test_user_meta").ok();
+        let mut h = LinHash::open("/tmp/test_user_meta", 4, 4);
+        assert_eq!(h.get_meta(), Vec::<u8>::new());
+        h.set_meta(b"schema=3").unwrap();
+        assert!(h.set_meta(&[1; META_SIZE + 1]).is_err());
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        let mut h2 = LinHash::open("/tmp/test_user_meta", 4, 4);
+        assert_eq!(h2.get_meta(), b"schema=3");
+        h2.set_meta(b"v4").unwrap();
+        h2.close();
+        let mut h3 = LinHash::open("/tmp/test_user_meta", 4, 4);
+        assert_eq!(h3.get_meta(), b"v4");
+        assert_eq!(h3.get(&encode(1999)), Some(encode(1999)));
+        h3.close();
+        fs::remove_file("/tmp/test_user_meta").ok();
+    }
+
+    #[test]
+    fn unclean_shutdown_is_repaired() {
+        fs::remove_file("/tmp/test_unclean_shutdown").ok();
+        let mut h = LinHash::open("/tmp/test_unclean_shutdown", 4, 4);
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+        let mut h = LinHash::open("/tmp/test_unclean_shutdown", 4, 4);
+        assert!(!h.recovered());
+        // claim more items than the pages hold, then "crash"
+        h.nitems = 5000;
+        h.flush();
+        h.abandon();
+
+        let mut h2 = LinHash::open("/tmp/test_unclean_shutdown", 4, 4);
+        assert!(h2.recovered());
+        assert_eq!(h2.nitems, 1000);
+        for k in 0..1000 {
+            assert_eq!(h2.get(&encode(k)), Some(encode(k)));
+        }
+        h2.close();
+        let mut h3 = LinHash::open("/tmp/test_unclean_shutdown", 4, 4);
+        assert!(!h3.recovered());
+        h3.close();
+        fs::remove_file("/tmp/test_unclean_shutdown").ok();
+    }
+
+    #[test]
+    fn read_only_after_corruption() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+        fs::remove_file("/tmp/test_corrupt_readonly").ok();
+        let mut h = LinHash::open("/tmp/test_corrupt_readonly", 4, 4);
+        // checksums would reject the page outright; see page_checksums
+        h.buckets.set_page_checksums(false);
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        // give bucket 1's page an impossible record count
+        let mut f = OpenOptions::new().write(true)
+            .open("/tmp/test_corrupt_readonly").unwrap();
+        f.seek(SeekFrom::Start(2 * 4096)).unwrap();
+        f.write_all(&usize_to_bytearray(1_000_000)).unwrap();
+        drop(f);
+
+        let mut h = LinHash::open("/tmp/test_corrupt_readonly", 4, 4);
+        h.set_read_only_on_corruption(true);
+        let found = (0..100).filter(|&k| h.get(&encode(k)).is_some()).count();
+        assert_eq!(found, 100);
+        assert!(h.corruption().is_some());
+        assert!(h.is_read_only());
+        match h.try_put(&encode(500), &encode(500)) {
+            Err(LinHashError::Corruption(_)) => (),
+            r => panic!("expected a corruption error, got {:?}", r),
+        }
+        h.close();
+        fs::remove_file("/tmp/test_corrupt_readonly").ok();
+    }
+
+    #[test]
+    fn read_only_open() {
+        let path = "/tmp/test_read_only_open";
+        fs::remove_file(path).ok();
+        let read_only = || LinHash::options().keysize(4).valsize(4).read_only(true).open(path);
+        assert!(read_only().is_err());
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(k));
+        }
+        // not while a writer has it open
+        assert!(read_only().is_err());
+        h.close();
+        let before = fs::read(path).unwrap();
+
+        let mut h = read_only().unwrap();
+        assert!(h.is_read_only());
+        for k in 0..1000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        match h.try_put(&encode(1000), &encode(1)) {
+            Err(LinHashError::Io(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            r => panic!("expected a permission error, got {:?}", r),
+        }
+        assert!(h.try_remove(&encode(0)).is_err());
+        assert!(h.try_update(&encode(0), &encode(1)).is_err());
+        assert!(h.try_increment(&encode(0), 1).is_err());
+        assert!(h.try_clear().is_err());
+        assert!(h.set_meta(b"v2").is_err());
+        assert!(h.repair().is_err());
+        h.flush();
+        h.close();
+        // several readers at once, and one dropped without close, but
+        // no writer alongside them
+        let a = read_only().unwrap();
+        drop(read_only().unwrap());
+        assert!(LinHash::try_open(path, 4, 4).is_err());
+        drop(a);
+        assert_eq!(fs::read(path).unwrap(), before);
+        LinHash::open(path, 4, 4).close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn direct_io() {
+        let path = "/tmp/test_direct_io";
+        fs::remove_file(path).ok();
+        let mut h = match LinHash::options().keysize(4).valsize(4).direct_io(true).open(path) {
+            Ok(h) => h,
+            // eg. on tmpfs, or a system without it
+            Err(LinHashError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput
+                || e.kind() == io::ErrorKind::Unsupported => return,
+            Err(e) => panic!("{}", e),
+        };
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.set_io_backend(IoBackend::Mmap).unwrap();
+        assert_eq!(h.get(&encode(42)), Some(encode(42)));
+        h.close();
+        assert_eq!(fs::metadata(path).unwrap().len() % PAGE_SIZE as u64, 0);
+
+        let mut h = LinHash::options().direct_io(true).read_only(true).open(path).unwrap();
+        for k in 0..5000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        assert!(LinHash::options().keysize(4).direct_io(true).store(MemStore::new())
+            .open(path).is_err());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn page_checksums() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+        fs::remove_file("/tmp/test_page_checksums").ok();
+        let mut h = LinHash::open("/tmp/test_page_checksums", 4, 4);
+        assert!(h.buckets.page_checksums());
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        // flip a byte in the records of bucket 1's page
+        let mut f = OpenOptions::new().write(true)
+            .open("/tmp/test_page_checksums").unwrap();
+        f.seek(SeekFrom::Start(2 * 4096 + 100)).unwrap();
+        f.write_all(&[0xff]).unwrap();
+        drop(f);
+
+        let mut h = LinHash::open("/tmp/test_page_checksums", 4, 4);
+        let errors: Vec<_> = (0..100).filter_map(|k| h.try_get(&encode(k)).err()).collect();
+        assert!(!errors.is_empty() && errors.len() < 100);
+        match errors[0] {
+            LinHashError::Corruption(ref msg) => assert!(msg.contains("page 2")),
+            ref e => panic!("expected a corruption error, got {}", e),
+        }
+        assert_eq!(h.corruption(), Some("page 2 fails its checksum"));
+        h.close();
+        fs::remove_file("/tmp/test_page_checksums").ok();
+    }
+
+    #[test]
+    fn custom_hasher() {
+        use std::hash::{BuildHasherDefault, Hasher};
+
+        #[derive(Default)]
+        struct Fnv(u64);
+        impl Hasher for Fnv {
+            fn finish(&self) -> u64 {
+                self.0
+            }
+            fn write(&mut self, bytes: &[u8]) {
+                for &b in bytes {
+                    self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+                }
+            }
+        }
+        let fnv = || KeyHasher::new(7, BuildHasherDefault::<Fnv>::default());
+
+        let path = "/tmp/test_custom_hasher";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open_with_hasher(path, 4, 4, PageLayout::Row, fnv());
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k + 1));
+        }
+        h.close();
+
+        match LinHash::try_open(path, 4, 4) {
+            Err(LinHashError::InvalidArgument(ref msg)) => assert!(msg.contains("hasher 7")),
+            r => panic!("opened with the wrong hasher: {:?}", r.err()),
+        }
+        let mut reader = SharedReader::open(path, 4, 4).unwrap();
+        assert!(reader.try_get(&encode(1)).is_err());
+        let mut reader = SharedReader::open_with_hasher(path, 4, 4, fnv()).unwrap();
+        assert_eq!(reader.get(&encode(1)), Some(encode(2)));
+
+        let mut h = LinHash::open_with_hasher(path, 4, 4, PageLayout::Row, fnv());
+        for k in 0..2000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k + 1)));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    /// Applies `edit` to the copy of the ctrl page of `path` that
+    /// `open` would read, keeping its checksum valid.
+    fn edit_ctrl<F: FnOnce(&mut [u8])>(path: &str, edit: F) {
+        let mut file = fs::read(path).unwrap();
+        let (primary, shadow) = file.split_at_mut(PAGE_SIZE);
+        let ctrl = if disk::latest_ctrl(primary, Some(&shadow[..PAGE_SIZE])).as_ptr() == primary.as_ptr() {
+            primary
+        } else {
+            &mut shadow[..PAGE_SIZE]
+        };
+        edit(ctrl);
+        disk::stamp_checksum(ctrl);
+        fs::write(path, &file).unwrap();
+    }
+
+    #[test]
+    fn stable_hash_seed() {
+        use disk::CTRL_HASH_CHECK;
+        let path = "/tmp/test_stable_hash_seed";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.buckets.hasher_id(), hasher::STABLE_HASHER_ID);
+        let seed = h.buckets.hash_seed();
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.buckets.hash_seed(), seed);
+        assert_eq!(h.get(&encode(1999)), Some(encode(1999)));
+        h.close();
+        let mut reader = SharedReader::open(path, 4, 4).unwrap();
+        assert_eq!(reader.get(&encode(1999)), Some(encode(1999)));
+
+        // as if the hash function had changed since the file was written
+        edit_ctrl(path, |ctrl| {
+            let check = read_usize_at(ctrl, CTRL_HASH_CHECK).unwrap();
+            write_usize_at(ctrl, CTRL_HASH_CHECK, check ^ 1).unwrap();
+        });
+        match LinHash::try_open(path, 4, 4) {
+            Err(LinHashError::Corruption(ref msg)) => assert!(msg.contains("hash seed")),
+            r => panic!("opened despite a failed seed check: {:?}", r.err()),
+        }
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn foreign_files_are_refused() {
+        use disk::{CTRL_LAYOUT, FORMAT_VERSION, LAYOUT_WORD};
+        let path = "/tmp/test_foreign_file";
+        fs::write(path, vec![0xab; 10000]).unwrap();
+        match LinHash::try_open(path, 4, 4) {
+            Err(LinHashError::Corruption(ref msg)) => assert!(msg.contains("not a linhash file")),
+            r => panic!("opened a foreign file: {:?}", r.err()),
+        }
+
+        fs::remove_file(path).ok();
+        LinHash::open(path, 4, 4).close();
+        edit_ctrl(path, |ctrl| write_u64_at(ctrl, CTRL_LAYOUT, LAYOUT_WORD + 1).unwrap());
+        match LinHash::try_open(path, 4, 4) {
+            Err(LinHashError::Corruption(ref msg)) =>
+                assert!(msg.contains(&format!("version {}", FORMAT_VERSION + 1))),
+            r => panic!("opened a newer format: {:?}", r.err()),
+        }
+
+        // an empty file is a new table
+        fs::write(path, b"").unwrap();
+        let mut h = LinHash::open(path, 4, 4);
+        h.put(&encode(1), &encode(1));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn record_size_is_kept() {
+        use disk::{CTRL_GEOMETRY, CTRL_LAYOUT, FORMAT_VERSION, LAYOUT_WORD};
+        let path = "/tmp/test_record_size";
+        fs::remove_file(path).ok();
+        assert!(LinHash::options().valsize(4).open(path).is_err());
+        let mut h = LinHash::open(path, 12, 4);
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        assert!(LinHash::options().keysize(4).valsize(4).open(path).is_err());
+        assert!(LinHash::options().keysize(12).valsize(8).open(path).is_err());
+        assert!(LinHash::options().valsize(12).open(path).is_err());
+        let mut h = LinHash::options().open(path).unwrap();
+        assert_eq!((h.buckets.keysize(), h.buckets.valsize()), (12, 4));
+        assert_eq!(h.get(&encode(7)), Some(encode(7)));
+        h.close();
+
+        // a file of 8192-byte pages is refused, not misread
+        let pages = disk::geometry_word(0, 0);
+        edit_ctrl(path, |ctrl| {
+            write_u64_at(ctrl, CTRL_GEOMETRY, disk::geometry_word(12, 4) - pages + 2 * pages)
+                .unwrap();
+        });
+        assert!(LinHash::options().open(path).is_err());
+        assert!(LinHash::try_open(path, 12, 4).is_err());
+        edit_ctrl(path, |ctrl| {
+            write_u64_at(ctrl, CTRL_GEOMETRY, disk::geometry_word(12, 4)).unwrap();
+        });
+
+        // a version 4 file records no sizes, so they have to be given
+        edit_ctrl(path, |ctrl| {
+            write_usize_at(ctrl, CTRL_GEOMETRY, 0).unwrap();
+            write_u64_at(ctrl, CTRL_LAYOUT, LAYOUT_WORD - FORMAT_VERSION as u64 + 4).unwrap();
+        });
+        assert!(LinHash::options().open(path).is_err());
+        let mut h = LinHash::open(path, 12, 4);
+        assert_eq!(h.get(&encode(7)), Some(encode(7)));
+        h.close();
+        assert!(LinHash::options().keysize(4).open(path).is_err());
+        let mut h = LinHash::options().open(path).unwrap();
+        assert_eq!(h.len(), 1000);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn legacy_ctrl_layout_is_migrated() {
+        use disk::{CTRL_LAYOUT, CTRL_MAP_START, LAYOUT_WORD};
+        let path = "/tmp/test_legacy_layout";
+        fs::remove_file(path).ok();
+        // files of layout 1 hash with DefaultHasher
+        let sip = KeyHasher::builtin(hasher::SIP_HASHER_ID, 0).unwrap();
+        let mut h = LinHash::options().keysize(4).valsize(4).hasher(sip).extended_headers(false)
+            .open(path).unwrap();
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+
+        // rewrite the ctrl page the way layout 1 had it: no layout
+        // word, map at byte 48, and no shadow copy
+        let mut file = fs::read(path).unwrap();
+        let (primary, shadow) = file.split_at_mut(PAGE_SIZE);
+        let mut ctrl = disk::latest_ctrl(primary, Some(&shadow[..PAGE_SIZE])).to_vec();
+        ctrl.copy_within(CTRL_MAP_START..CTRL_MAP_START + 8 * 16, CTRL_LAYOUT);
+        primary.copy_from_slice(&ctrl);
+        shadow[..PAGE_SIZE].fill(0);
+        fs::write(path, &file).unwrap();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.buckets.legacy_layout());
+        for k in 0..2000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        let ctrl = fs::read(path).unwrap();
+        assert_eq!(read_u64_at(&ctrl, CTRL_LAYOUT).unwrap(), LAYOUT_WORD);
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(!h.buckets.legacy_layout());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn bucket_map_spans_pages() {
+        use disk::{CTRL_MAP_ENTRIES, MAP_PAGE_ENTRIES};
+        let path = "/tmp/test_bucket_map_pages";
+        fs::remove_file(path).ok();
+        // two records per page, so the map outgrows the ctrl page soon
+        let mut h = LinHash::open(path, 1012, 1012);
+        for k in 0..2400 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert!(h.nbuckets > CTRL_MAP_ENTRIES + MAP_PAGE_ENTRIES);
+        let map_pages = h.buckets.map_pages().to_vec();
+        assert_eq!(map_pages.len(), (h.nbuckets - CTRL_MAP_ENTRIES).div_ceil(MAP_PAGE_ENTRIES));
+        h.flush();
+        let mut r = SharedReader::open(path, 1012, 1012).unwrap();
+        for k in (0..2400).step_by(7) {
+            assert_eq!(r.get(&encode(k)).unwrap()[..4], encode(k)[..]);
+        }
+        h.close();
+
+        let mut h = LinHash::open(path, 1012, 1012);
+        assert_eq!(h.buckets.map_pages(), &map_pages[..]);
+        for k in 0..2400 {
+            assert_eq!(h.get_i32(&encode(k)), Some(k));
+        }
+        assert!(h.verify().unwrap().is_ok());
+        // the map pages stay when the map fits in the ctrl page again
+        for k in 100..2400 {
+            h.remove(&encode(k));
+        }
+        assert!(h.nbuckets < CTRL_MAP_ENTRIES);
+        h.close();
+        let mut h = LinHash::open(path, 1012, 1012);
+        assert_eq!(h.buckets.map_pages(), &map_pages[..]);
+        assert!(h.verify().unwrap().is_ok());
+        for k in 100..2400 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.close();
+        let mut h = LinHash::open(path, 1012, 1012);
+        for k in 0..2400 {
+            assert_eq!(h.get_i32(&encode(k)), Some(k));
+        }
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn negative_lookup_cache() {
+        fs::remove_file("/tmp/test_miss_cache").ok();
+        let mut h = LinHash::open("/tmp/test_miss_cache", 4, 4);
+        for k in 0..100 {
+            assert_eq!(h.get(&encode(k)), None);
+        }
+        let b = h.bucket(&encode(7));
+        assert!(h.misses.contains(b, &encode(7)));
+        // inserts and the splits they cause make misses stale
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        assert_eq!(h.get(b"ab"), None);
+        assert!(h.misses.contains(h.bucket(b"ab"), b"ab\0\0"));
+        assert_eq!(h.get(b"ab\0"), None);
+        h.put(b"ab", &encode(1));
+        assert_eq!(h.get(b"ab\0"), Some(encode(1)));
+
+        h.set_miss_cache_capacity(0);
+        assert_eq!(h.get(b"zz"), None);
+        assert!(!h.misses.contains(h.bucket(b"zz"), b"zz\0\0"));
+        h.close();
+        fs::remove_file("/tmp/test_miss_cache").ok();
+    }
+
+    #[test]
+    fn columnar_table() {
+        fs::remove_file("/tmp/test_columnar_table").ok();
+        let mut h = LinHash::open_with_layout("/tmp/test_columnar_table", 4, 8,
+                                              PageLayout::Columnar);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k as u64 * 7));
+        }
+        for k in (0..3000).step_by(3) {
+            h.remove(&encode(k));
+        }
+        h.close();
+
+        // the layout is the file's, whatever is asked for on reopen
+        let mut h = LinHash::open("/tmp/test_columnar_table", 4, 8);
+        assert_eq!(h.buckets.page_layout(), PageLayout::Columnar);
+        for k in 0..3000 {
+            let expected = if k % 3 == 0 { None } else { Some(k as u64 * 7) };
+            assert_eq!(h.get_u64(&encode(k)), expected);
+        }
+        h.close();
+        fs::remove_file("/tmp/test_columnar_table").ok();
+    }
+
+    #[test]
+    fn remove_reclaims_overflow_pages() {
+        fs::remove_file("/tmp/test_remove_reclaim").ok();
+        // two records per page, so most records live in overflow pages;
+        // a fixed hasher, so reinserting needs no more pages than before
+        let sip = KeyHasher::builtin(hasher::SIP_HASHER_ID, 0).unwrap();
+        let mut h = LinHash::open_with_hasher("/tmp/test_remove_reclaim", 1012, 1012,
+                                              PageLayout::Row, sip);
+        for k in 0..200 {
+            h.put(&encode(k), &encode(k));
+        }
+        let num_pages = h.buckets.num_pages();
+        for k in 0..200 {
+            assert_eq!(h.remove(&encode(k)).map(|v| v[..4].to_vec()), Some(encode(k)));
+        }
+        assert_eq!(h.nitems, 0);
+        // merges gave the pages at the end of the file back
+        assert!(h.buckets.num_pages() < num_pages);
+        assert_eq!(h.buckets.num_free(),
+                   h.buckets.num_pages() - h.buckets.ctrl_pages() - h.nbuckets);
+        for k in 0..200 {
+            h.put(&encode(k), &encode(k));
+        }
+        assert_eq!(h.buckets.num_pages(), num_pages);
+        for k in (0..200).step_by(2) {
+            h.remove(&encode(k));
+        }
+        h.close();
+
+        let sip = KeyHasher::builtin(hasher::SIP_HASHER_ID, 0).unwrap();
+        let mut h = LinHash::open_with_hasher("/tmp/test_remove_reclaim", 1012, 1012,
+                                              PageLayout::Row, sip);
+        for k in 0..200 {
+            assert_eq!(h.get_i32(&encode(k)), if k % 2 == 0 { None } else { Some(k) });
+        }
+        h.close();
+        fs::remove_file("/tmp/test_remove_reclaim").ok();
+    }
+
+    #[test]
+    fn numeric_values() {
+        let mut h = LinHash::open("/tmp/test_numeric_values", 8, 8);
+        h.put_u64(b"u64", u64::MAX - 1);
+        h.put_i32(b"i32", -7);
+        h.put_i64(b"i64", -1 << 40);
+        h.put_i32(b"i32", 42);
+        h.close();
+
+        let mut h2 = LinHash::open("/tmp/test_numeric_values", 8, 8);
+        assert_eq!(h2.get_u64(b"u64"), Some(u64::MAX - 1));
+        assert_eq!(h2.get_i32(b"i32"), Some(42));
+        assert_eq!(h2.get_i64(b"i64"), Some(-1 << 40));
+        assert_eq!(h2.get(b"i32"), Some(vec![42, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(h2.get_u32(b"missing"), None);
+        h2.close();
+        fs::remove_file("/tmp/test_numeric_values").ok();
+
+        // a u64 does not fit a 4-byte value
+        let mut h = LinHash::open("/tmp/test_numeric_values", 8, 4);
+        h.put_u32(b"u32", 5);
+        match h.try_get_num::<u64>(b"u32") {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            r => panic!("read a u64 from a 4-byte value: {:?}", r),
+        }
+        match h.try_put_num(b"u64", 5u64) {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            r => panic!("stored a u64 in a 4-byte value: {:?}", r),
+        }
+        assert_eq!(h.try_get_num::<u32>(b"u32").unwrap(), Some(5));
+        assert_eq!(h.len(), 1);
+        h.close();
+        fs::remove_file("/tmp/test_numeric_values").ok();
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        fs::remove_file("/tmp/test_errors").ok();
+        match LinHash::try_open("/tmp/test_errors", 0, 4) {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            _ => panic!("keysize 0 accepted"),
+        }
+        match LinHash::try_open("/tmp/test_errors", 4096, 4) {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            _ => panic!("record wider than a page accepted"),
+        }
+        match LinHash::try_open("/tmp/no_such_dir/test_errors", 4, 4) {
+            Err(LinHashError::Io(_)) => (),
+            _ => panic!("opened a file in a missing directory"),
+        }
+
+        let mut h = LinHash::try_open("/tmp/test_errors", 4, 4).unwrap();
+        h.try_put(&encode(1), &encode(2)).unwrap();
+        match h.try_put(b"too long", &encode(2)) {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            r => panic!("expected an invalid argument error, got {:?}", r),
+        }
+        assert!(h.try_update(&encode(1), &encode(3)).unwrap());
+        assert!(!h.try_update(&encode(2), &encode(3)).unwrap());
+        assert_eq!(h.try_get(&encode(1)).unwrap(), Some(encode(3)));
+        assert_eq!(h.try_remove(&encode(1)).unwrap(), Some(encode(3)));
+        assert_eq!(h.try_get(&encode(1)).unwrap(), None);
+        h.try_close().unwrap();
+        fs::remove_file("/tmp/test_errors").ok();
+    }
+
+    #[test]
+    fn present_keys_are_rejected() {
+        let path = "/tmp/test_present_keys";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..1000 {
+            h.put(&encode(k), &encode(k));
+        }
+        match h.try_put(&encode(7), &encode(70)) {
+            Err(LinHashError::InvalidArgument(_)) => (),
+            r => panic!("put an existing key: {:?}", r),
+        }
+        // already in the table, and twice in the batch
+        let (a, b, c) = (encode(1000), encode(1001), encode(8));
+        for batch in [vec![(&a[..], &a[..]), (&c[..], &a[..])],
+                      vec![(&b[..], &b[..]), (&b[..], &a[..])]] {
+            match h.try_put_many(&batch) {
+                Err(LinHashError::InvalidArgument(_)) => (),
+                r => panic!("put_many an existing key: {:?}", r),
+            }
+        }
+        // the batch goes bucket by bucket, so `a` is only in if its
+        // bucket came before that of `c`
+        let a_in = h.contains(&a);
+        assert_eq!(h.len(), 1001 + a_in as usize);
+        assert_eq!(h.get(&encode(7)), Some(encode(7)));
+        assert_eq!(h.get(&encode(8)), Some(encode(8)));
+        assert_eq!(h.get(&b), Some(b.clone()));
+        // the rejected puts were never logged, so recovery does not
+        // replay them
+        h.abandon();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.recovered());
+        assert_eq!(h.len(), 1001 + a_in as usize);
+        assert_eq!(h.get(&encode(7)), Some(encode(7)));
+        assert_eq!(h.get(&encode(8)), Some(encode(8)));
+        assert_eq!(h.contains(&a), a_in);
+        assert_eq!(h.get(&b), Some(b.clone()));
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn iterate_in_batches() {
+        let path = "/tmp/test_iter_batches";
+        fs::remove_file(path).ok();
+        // long chains, so batches cross pages and buckets
+        let mut h = LinHash::options().keysize(4).valsize(4).threshold(4.0)
+            .open(path).unwrap();
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k * 2));
+        }
+        let all: Vec<_> = h.iter().collect();
+        for &n in &[1, 7, 600, 10000] {
+            let mut it = h.iter();
+            let mut batched = vec![];
+            loop {
+                let batch = it.next_batch(n);
+                assert!(batch.len() <= n);
+                if batch.is_empty() {
+                    break;
+                }
+                // only the last batch comes up short
+                assert!(batch.len() == n || batched.len() + batch.len() == all.len());
+                batched.extend(batch);
+            }
+            assert_eq!(batched, all);
+            assert!(it.next().is_none());
+        }
+        // mixed with single steps
+        let mut it = h.keys();
+        let first = it.next().unwrap();
+        let rest = it.next_batch(99);
+        assert_eq!(rest.len(), 99);
+        assert_eq!(it.next(), Some(all[100].0.clone()));
+        assert_eq!(first, all[0].0);
+        assert_eq!(rest[..], all[1..100].iter().map(|(k, _)| k.clone()).collect::<Vec<_>>()[..]);
+        assert!(h.iter().next_batch(0).is_empty());
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn iterate_all_records() {
+        fs::remove_file("/tmp/test_iter").ok();
+        let mut h = LinHash::open("/tmp/test_iter", 4, 4);
+        assert_eq!(h.iter().count(), 0);
+        for k in 0..5000 {
+            h.put(&encode(k), &encode(k * 2));
+        }
+        let mut seen: Vec<i32> = h.iter()
+            .map(|(k, v)| {
+                assert_eq!(decode::<i32>(&v), decode::<i32>(&k) * 2);
+                decode(&k)
+            })
+            .collect();
+        seen.sort();
+        assert_eq!(seen, (0..5000).collect::<Vec<_>>());
+        let keys: Vec<Vec<u8>> = h.keys().collect();
+        let values: Vec<Vec<u8>> = h.values().collect();
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = h.iter().collect();
+        assert_eq!(keys.into_iter().zip(values).collect::<Vec<_>>(), pairs);
+        h.close();
+        fs::remove_file("/tmp/test_iter").ok();
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let path = "/tmp/test_keys_values";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 8, 6);
+        assert_eq!(h.keys().count(), 0);
+        assert_eq!(h.values().count(), 0);
+        for k in 0..300 {
+            h.put(&encode(k), &[k as u8, 1]);
+        }
+        for k in (0..300).step_by(3) {
+            h.remove(&encode(k));
+        }
+        // both come zero-padded to their slots, as stored
+        let mut keys: Vec<i32> = h.keys().map(|k| {
+            assert_eq!(k.len(), 8);
+            assert_eq!(k[4..], [0; 4]);
+            decode(&k)
+        }).collect();
+        keys.sort();
+        assert_eq!(keys, (0..300).filter(|k| k % 3 != 0).collect::<Vec<_>>());
+        let mut values: Vec<Vec<u8>> = h.values().collect();
+        values.sort();
+        let mut expected: Vec<Vec<u8>> = (0..300).filter(|k| k % 3 != 0)
+            .map(|k| vec![k as u8, 1, 0, 0, 0, 0]).collect();
+        expected.sort();
+        assert_eq!(values, expected);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn clear_table() {
+        let path = "/tmp/test_clear";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..20000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let size = fs::metadata(path).unwrap().len();
+        h.clear();
+        assert_eq!(h.len(), 0);
+        assert_eq!(h.get(&encode(7)), None);
+        assert_eq!(h.iter().count(), 0);
+        assert_eq!(h.buckets.num_pages(), h.buckets.ctrl_pages() + 2);
+        assert!(fs::metadata(path).unwrap().len() < size / 10);
+        assert!(h.verify().unwrap().is_ok());
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k + 1));
+        }
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 3000);
+        assert_eq!(h.get(&encode(2999)), Some(encode(3000)));
+        assert_eq!(h.get(&encode(3000)), None);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn retain_records() {
+        let path = "/tmp/test_retain";
+        fs::remove_file(path).ok();
+        // small pages, so chains have overflow pages to empty
+        let mut h = LinHash::open(path, 4, 1000);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        let free = h.buckets.num_free();
+        assert_eq!(h.retain(|k, _| decode::<i32>(k) % 3 == 0 || decode::<i32>(k) >= 2900), 1933);
+        assert_eq!(h.len(), 1067);
+        assert!(h.buckets.num_free() > free);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 1000);
+        for k in 0..3000 {
+            let kept = k % 3 == 0 || k >= 2900;
+            assert_eq!(h.get(&encode(k)).is_some(), kept);
+        }
+        assert_eq!(h.retain(|_, _| true), 0);
+        assert_eq!(h.retain(|_, _| false), 1067);
+        assert_eq!(h.iter().count(), 0);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn slotted_table() {
+        fs::remove_file("/tmp/test_slotted").ok();
+        let mut h = LinHash::open_with_layout("/tmp/test_slotted", 64, 256,
+                                              PageLayout::Slotted);
+        for k in 0..2000 {
+            h.put(format!("key{}", k).as_bytes(), format!("{}", k * 7).as_bytes());
+        }
+        // stored unpadded, and a padded key is a different key
+        assert_eq!(h.get(b"key42"), Some(b"294".to_vec()));
+        assert_eq!(h.get(b"key42\0"), None);
+        assert!(h.contains(b"key42"));
+        // grow values past what their pages can hold
+        for k in 0..2000 {
+            assert!(h.update(format!("key{}", k).as_bytes(), &[k as u8; 200]));
+        }
+        h.close();
+
+        let mut h = LinHash::open("/tmp/test_slotted", 64, 256);
+        assert_eq!(h.buckets.page_layout(), PageLayout::Slotted);
+        assert_eq!(h.nitems, 2000);
+        for k in 0..2000 {
+            assert_eq!(h.get(format!("key{}", k).as_bytes()), Some(vec![k as u8; 200]));
+        }
+        assert_eq!(h.remove(b"key7"), Some(vec![7; 200]));
+        assert_eq!(h.iter().count(), 1999);
+        h.close();
+        fs::remove_file("/tmp/test_slotted").ok();
+    }
+
+    #[test]
+    fn slotted_update_then_put() {
+        let path = "/tmp/test_slotted_update_put";
+        // the records a page ends up with depend on the seed
+        for seed in 0..8 {
+            fs::remove_file(path).ok();
+            let sip = KeyHasher::builtin(hasher::SIP_HASHER_ID, seed).unwrap();
+            let mut h = LinHash::open_with_hasher(path, 8, 16, PageLayout::Slotted, sip);
+            for k in 0..100u64 {
+                h.put(&k.to_le_bytes(), &[1; 16]);
+            }
+            for k in 0..100u64 {
+                assert!(h.update(&k.to_le_bytes(), &[2; 16]));
+            }
+            for k in 100..200u64 {
+                h.put(&k.to_le_bytes(), &[3; 16]);
+            }
+            for k in 0..200u64 {
+                let val = if k < 100 { [2; 16] } else { [3; 16] };
+                assert_eq!(h.get(&k.to_le_bytes()), Some(val.to_vec()), "seed {}", seed);
+            }
+            assert_eq!(h.iter().count(), 200);
+            assert!(h.verify().unwrap().is_ok());
+            h.close();
+        }
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn drop_without_close() {
+        fs::remove_file("/tmp/test_drop").ok();
+        {
+            let mut h = LinHash::open("/tmp/test_drop", 4, 4);
+            for k in 0..1000 {
+                h.put(&encode(k), &encode(k));
+            }
+        }
+        let mut h = LinHash::open("/tmp/test_drop", 4, 4);
+        assert!(!h.recovered());
+        assert_eq!(h.nitems, 1000);
+        for k in 0..1000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        drop(h);
+        let h = LinHash::open("/tmp/test_drop", 4, 4);
+        assert!(!h.recovered());
+        drop(h);
+        fs::remove_file("/tmp/test_drop").ok();
+    }
+
+    #[test]
+    fn crash_recovery_from_wal() {
+        fs::remove_file("/tmp/test_wal_recovery").ok();
+        let mut h = LinHash::open("/tmp/test_wal_recovery", 4, 4);
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.flush();
+        for k in 2000..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        for k in 0..100 {
+            h.update(&encode(k), &encode(-k));
+            h.remove(&encode(k + 100));
+        }
+        // "crash": nothing written since the flush but the log
+        h.abandon();
+
+        let mut h = LinHash::open("/tmp/test_wal_recovery", 4, 4);
+        assert!(h.recovered());
+        assert_eq!(h.nitems, 2900);
+        for k in 0..100 {
+            assert_eq!(h.get_i32(&encode(k)), Some(-k));
+            assert_eq!(h.get(&encode(k + 100)), None);
+        }
+        for k in 200..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        assert_eq!(fs::metadata("/tmp/test_wal_recovery.wal").unwrap().len(), 0);
+        fs::remove_file("/tmp/test_wal_recovery").ok();
+    }
+
+    #[test]
+    fn replay_skips_changes_on_disk() {
+        let path = "/tmp/test_wal_lsns";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.buckets.page_lsns());
+        h.set_cache_pages(1024).unwrap();
+        for k in 0..2000 {
+            h.put(&encode(k), &encode(k));
+        }
+        // the pages reach disk, but the log is kept
+        h.buckets.flush().unwrap();
+        for k in 0..100 {
+            h.update(&encode(k), &encode(-k));
+        }
+        let entries = h.wal.as_mut().unwrap().entries().unwrap();
+        assert_eq!(entries.len(), 2100);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        let (flushed, unflushed) = entries.split_at(2000);
+        let skipped = h.replay(flushed.to_vec()).unwrap();
+        assert!(skipped > 1900, "only {} of 2000 skipped", skipped);
+        assert_eq!(h.replay(unflushed.to_vec()).unwrap(), 0);
+        h.abandon();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.recovered());
+        for k in 0..2000 {
+            assert_eq!(h.get_i32(&encode(k)), Some(if k < 100 { -k } else { k }));
+        }
+        h.close();
+
+        // without page LSNs nothing is skipped
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).extended_headers(false)
+            .open(path).unwrap();
+        assert!(!h.buckets.page_lsns());
+        for k in 0..500 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.buckets.flush().unwrap();
+        let entries = h.wal.as_mut().unwrap().entries().unwrap();
+        assert_eq!(h.replay(entries).unwrap(), 0);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let path = "/tmp/test_duplicate_keys";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        h.put(&encode(1), &encode(1));
+        assert!(h.try_put_dup(&encode(1), &encode(2)).is_err());
+        h.close();
+        fs::remove_file(path).ok();
+
+        let mut h = LinHash::options().keysize(4).valsize(4).duplicate_keys(true)
+            .open(path).unwrap();
+        // enough records for many splits, which must keep every copy
+        for v in 0..10 {
+            for k in 0..300 {
+                h.put_dup(&encode(k), &encode(v));
+            }
+        }
+        assert_eq!(h.len(), 3000);
+        assert_eq!(h.get_all(&encode(7)), (0..10).map(encode).collect::<Vec<_>>());
+        assert_eq!(h.get(&encode(7)), Some(encode(0)));
+        assert!(h.update(&encode(7), &encode(70)));
+        assert_eq!(h.remove(&encode(7)), Some(encode(70)));
+        assert_eq!(h.get_all(&encode(7)).len(), 9);
+        assert_eq!(h.remove_all(&encode(8)), 10);
+        assert_eq!(h.remove_all(&encode(8)), 0);
+        assert!(h.get_all(&encode(8)).is_empty());
+        assert_eq!(h.len(), 2989);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+
+        // the setting is kept, and replaying the log keeps every copy
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.duplicate_keys());
+        h.flush();
+        h.put_dup(&encode(1), &encode(10));
+        h.remove(&encode(2));
+        h.remove_all(&encode(3));
+        h.abandon();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.recovered());
+        assert_eq!(h.len(), 2989 + 1 - 1 - 10);
+        assert_eq!(h.get_all(&encode(1)).len(), 11);
+        let mut twos = h.get_all(&encode(2));
+        twos.sort();
+        assert_eq!(twos, (1..10).map(encode).collect::<Vec<_>>());
+        assert!(h.get_all(&encode(3)).is_empty());
+        h.close();
+        fs::remove_file(path).ok();
     }
 }
@@ -0,0 +1,136 @@
+//! The addressing of linear hashing, apart from how buckets are
+//! stored: which bucket a hash belongs in, and which bucket a table
+//! splits or merges as it grows and shrinks one bucket at a time.
+//!
+//! A table of `nbuckets` buckets uses the low `nbits` bits of a hash,
+//! where `2^(nbits-1) < nbuckets <= 2^nbits`. Needs no `std`.
+
+/// Which bucket a key with hash `hash` belongs in, given the table's
+/// current `nbits` and `nbuckets`. If the target bucket does not yet
+/// exist, it is guaranteed that the MSB is a `1`. To find the bucket,
+/// the pair should be placed in, subtract this `1`.
+pub fn bucket_index(hash: u64, nbits: usize, nbuckets: usize) -> usize {
+    let bucket = (hash & ((1 << nbits) - 1)) as usize;
+    if bucket < nbuckets {
+        bucket
+    } else {
+        bucket - (1 << (nbits-1))
+    }
+}
+
+/// Adding a bucket to a table of `nbuckets`: returns the table's new
+/// `nbits` and the bucket whose records are split between itself and
+/// the new bucket, `nbuckets`.
+pub fn grow(nbits: usize, nbuckets: usize) -> (usize, usize) {
+    let nbits = if nbuckets + 1 > (1 << nbits) { nbits + 1 } else { nbits };
+    // the new bucket with the 1 at the MSB position taken off: eg.
+    // after bucket 11 is added, bucket 01 needs to be split
+    (nbits, nbuckets ^ (1 << (nbits - 1)))
+}
+
+/// Removing the last bucket of a table of `nbuckets`, the reverse of
+/// `grow`: returns the table's new `nbits` and the bucket the last
+/// bucket's records move back into.
+pub fn shrink(nbits: usize, nbuckets: usize) -> (usize, usize) {
+    let sibling = (nbuckets - 1) ^ (1 << (nbits - 1));
+    let nbits = if nbuckets - 1 <= 1 << (nbits - 1) { nbits - 1 } else { nbits };
+    (nbits, sibling)
+}
+
+/// Which bucket a key with hash `hash` belongs in, in a table of
+/// `nbuckets` that splits in partial expansions (after Larson). The
+/// buckets form groups, and a table of `2g` buckets grows to `4g` in
+/// two passes, each adding a bucket to every group: bucket `kg + j`
+/// joins the `k` buckets `j, j + g, ..` of group `j`, taking a share of
+/// the records of each of them. A third of each group of two moves,
+/// then a quarter of each group of three, so the buckets stay equally
+/// full all along, instead of those yet to split in a round of plain
+/// linear hashing holding twice the records of the rest.
+///
+/// Whether a key moves is drawn from its hash, for each pass the table
+/// has been through, so finding the bucket takes a step per pass:
+/// about twice `nbits`.
+pub fn partial_bucket_index(hash: u64, nbuckets: usize) -> usize {
+    let mut bucket = (hash & 1) as usize;
+    let mut g = 1;
+    let mut pass = 0;
+    loop {
+        for k in 2..4 {
+            let first = k * g;
+            if first >= nbuckets {
+                return bucket;
+            }
+            let joined = first + bucket % g;
+            if joined < nbuckets && draw(hash, pass).is_multiple_of(k as u64 + 1) {
+                bucket = joined;
+            }
+            pass += 1;
+        }
+        g *= 2;
+    }
+}
+
+/// The group bucket `new_bucket` joins when it is added to a table
+/// that splits in partial expansions: its first bucket, the stride
+/// between its buckets, and how many buckets it has until then. See
+/// `partial_bucket_index`.
+pub fn partial_group(new_bucket: usize) -> (usize, usize, usize) {
+    let top = 1 << (usize::BITS - 1 - new_bucket.leading_zeros());
+    let g = top / 2;
+    (new_bucket % g, g, new_bucket / g)
+}
+
+/// A number drawn from `hash` for pass `pass`: splitmix64's finalizer,
+/// so that the draws of different passes are independent.
+fn draw(hash: u64, pass: u64) -> u64 {
+    let mut x = hash ^ (pass + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use linear::*;
+
+    #[test]
+    fn grow_and_shrink() {
+        let (mut nbits, mut nbuckets) = (1, 2);
+        for _ in 0..100 {
+            let (grown, split) = grow(nbits, nbuckets);
+            assert!(split < nbuckets);
+            // keys of the split bucket land in it or the new one
+            for hash in (0..1000).map(|h| h * (1 << grown) + split as u64) {
+                let b = bucket_index(hash, grown, nbuckets + 1);
+                assert!(b == split || b == nbuckets);
+            }
+            assert_eq!(shrink(grown, nbuckets + 1), (nbits, split));
+            nbits = grown;
+            nbuckets += 1;
+        }
+        assert_eq!((nbits, nbuckets), (7, 102));
+    }
+
+    #[test]
+    fn partial_expansions() {
+        let hashes: Vec<u64> = (0..12000u64).map(|h| draw(h, 99)).collect();
+        for nbuckets in 2..64 {
+            let (first, stride, members) = partial_group(nbuckets);
+            assert!(members == 2 || members == 3);
+            assert_eq!(first + members * stride, nbuckets);
+            let mut counts = vec![0; nbuckets + 1];
+            for &hash in &hashes {
+                let before = partial_bucket_index(hash, nbuckets);
+                let after = partial_bucket_index(hash, nbuckets + 1);
+                // a key stays, or moves out of the group into the new bucket
+                assert!(after == before || (after == nbuckets && before % stride == first));
+                counts[after] += 1;
+            }
+            // every bucket holds its share, split or not
+            if nbuckets + 1 == 6 || nbuckets + 1 == 12 || nbuckets + 1 == 16 {
+                let share = hashes.len() / (nbuckets + 1);
+                assert!(counts.iter().all(|&c| c > share * 4 / 5 && c < share * 6 / 5));
+            }
+        }
+    }
+}
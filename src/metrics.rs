@@ -0,0 +1,132 @@
+//! Counters and histograms, reported through the `metrics` crate under
+//! the fixed names below. Nothing is recorded unless the application
+//! installs a recorder, eg. `metrics-exporter-prometheus` to serve them
+//! on `/metrics`. Enabled by the `metrics` feature; without it the
+//! call sites compile to nothing.
+//!
+//! Counters are summed over every table in the process.
+
+/// Lookups, by `get`, `get_ref`, `get_into` and `get_many`, one per
+/// key.
+pub const GETS: &str = "linhash_gets";
+/// Records added by `put`, `try_put` and `put_many`.
+pub const PUTS: &str = "linhash_puts";
+/// Calls to `remove` and `try_remove`.
+pub const REMOVES: &str = "linhash_removes";
+/// Buckets added, whichever way the table splits.
+pub const SPLITS: &str = "linhash_splits";
+/// Pages read from a table's store.
+pub const PAGES_READ: &str = "linhash_pages_read";
+/// Pages written to a table's store.
+pub const PAGES_WRITTEN: &str = "linhash_pages_written";
+/// Histogram of the pages in a bucket's chain, recorded each time a
+/// search walks a whole chain: a lookup of a missing key or a `put`.
+pub const CHAIN_LENGTH: &str = "linhash_chain_length";
+
+/// `counter!(GETS, n)` adds `n` to the counter named by the constant
+/// `GETS`.
+macro_rules! counter {
+    ($name:ident, $value:expr) => {
+        ::metrics_crate::counter!($crate::metrics::$name).increment($value as u64);
+    };
+}
+
+/// `histogram!(CHAIN_LENGTH, pages)` records one value.
+macro_rules! histogram {
+    ($name:ident, $value:expr) => {
+        ::metrics_crate::histogram!($crate::metrics::$name).record($value as f64);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics;
+    use metrics_crate::{self, Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName,
+                        Metadata, Recorder, SharedString, Unit};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use util::encode;
+    use LinHash;
+
+    /// Counter totals, and the number and sum of each histogram's values.
+    #[derive(Default)]
+    struct Totals {
+        counters: Mutex<BTreeMap<String, u64>>,
+        histograms: Mutex<BTreeMap<String, (u64, f64)>>,
+    }
+
+    struct Handle(Arc<Totals>, String);
+
+    impl CounterFn for Handle {
+        fn increment(&self, value: u64) {
+            *self.0.counters.lock().unwrap().entry(self.1.clone()).or_insert(0) += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            self.0.counters.lock().unwrap().insert(self.1.clone(), value);
+        }
+    }
+
+    impl HistogramFn for Handle {
+        fn record(&self, value: f64) {
+            let mut histograms = self.0.histograms.lock().unwrap();
+            let h = histograms.entry(self.1.clone()).or_insert((0, 0.0));
+            h.0 += 1;
+            h.1 += value;
+        }
+    }
+
+    struct Capture(Arc<Totals>);
+
+    impl Recorder for Capture {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata) -> Counter {
+            Counter::from_arc(Arc::new(Handle(self.0.clone(), key.name().to_string())))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata) -> Histogram {
+            Histogram::from_arc(Arc::new(Handle(self.0.clone(), key.name().to_string())))
+        }
+    }
+
+    #[test]
+    fn table_operations_are_counted() {
+        let path = "/tmp/test_metrics";
+        fs::remove_file(path).ok();
+        let totals = Arc::new(Totals::default());
+        metrics_crate::with_local_recorder(&Capture(totals.clone()), || {
+            let mut h = LinHash::open(path, 32, 4);
+            for k in 0..200 {
+                h.put(&encode(k), &encode(k));
+            }
+            for k in 0..300 {
+                h.get(&encode(k));
+            }
+            h.get_many(&[encode(1), encode(2)]);
+            h.remove(&encode(0));
+            h.close();
+        });
+
+        let counters = totals.counters.lock().unwrap();
+        let counter = |name| counters.get(name).cloned().unwrap_or(0);
+        assert_eq!(counter(metrics::PUTS), 200);
+        assert_eq!(counter(metrics::GETS), 302);
+        assert_eq!(counter(metrics::REMOVES), 1);
+        assert!(counter(metrics::SPLITS) >= 1);
+        assert!(counter(metrics::PAGES_WRITTEN) >= 2);
+        let (lookups, pages) = totals.histograms.lock().unwrap()[metrics::CHAIN_LENGTH];
+        assert!(lookups >= 300 && pages >= lookups as f64);
+
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,55 @@
+//! A small cache of keys recently looked up and found absent, so that
+//! repeated lookups of missing keys skip the bucket chain scan.
+//!
+//! Entries are grouped by bucket: an insert into a bucket drops that
+//! bucket's entries, and a split, which moves keys between buckets,
+//! drops everything. When full, the cache is emptied rather than
+//! tracking recency per key.
+
+use std::collections::{HashMap, HashSet};
+
+pub(crate) struct MissCache {
+    capacity: usize,
+    len: usize,
+    by_bucket: HashMap<usize, HashSet<Vec<u8>>>,
+}
+
+impl MissCache {
+    pub fn new(capacity: usize) -> MissCache {
+        MissCache { capacity, len: 0, by_bucket: HashMap::new() }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.clear();
+    }
+
+    /// `key` must be zero-padded to keysize, so that keys equal up to
+    /// padding share an entry.
+    pub fn contains(&self, bucket: usize, key: &[u8]) -> bool {
+        self.by_bucket.get(&bucket).is_some_and(|keys| keys.contains(key))
+    }
+
+    pub fn insert(&mut self, bucket: usize, key: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.len >= self.capacity {
+            self.clear();
+        }
+        if self.by_bucket.entry(bucket).or_default().insert(key) {
+            self.len += 1;
+        }
+    }
+
+    pub fn invalidate(&mut self, bucket: usize) {
+        if let Some(keys) = self.by_bucket.remove(&bucket) {
+            self.len -= keys.len();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.by_bucket.clear();
+        self.len = 0;
+    }
+}
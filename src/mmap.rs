@@ -0,0 +1,122 @@
+//! Read-only memory maps of whole files. On Unix this is a
+//! `MAP_SHARED` mapping, so writes made through other file handles
+//! (including other processes) become visible without remapping, as
+//! long as the file does not grow past the mapped length. Elsewhere
+//! the file is read into memory instead.
+
+use std::fs::File;
+use std::io;
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::raw::{c_int, c_long, c_void};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    const PROT_READ: c_int = 1;
+    const MAP_SHARED: c_int = 1;
+
+    extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int,
+                fd: c_int, offset: c_long) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    pub struct Map {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    impl Map {
+        pub fn new(file: &File, len: usize) -> io::Result<Map> {
+            if len == 0 {
+                return Ok(Map { ptr: ptr::null_mut(), len: 0 });
+            }
+            let ptr = unsafe {
+                mmap(ptr::null_mut(), len, PROT_READ, MAP_SHARED,
+                     file.as_raw_fd(), 0)
+            };
+            if ptr as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Map { ptr, len })
+        }
+
+        pub fn as_slice(&self) -> &[u8] {
+            if self.len == 0 {
+                return &[];
+            }
+            unsafe { ::std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    // The mapping is read-only and owned by this value alone, so it
+    // may move to, and be read from, other threads.
+    unsafe impl Send for Map {}
+    unsafe impl Sync for Map {}
+
+    impl Drop for Map {
+        fn drop(&mut self) {
+            if self.len != 0 {
+                unsafe { munmap(self.ptr, self.len); }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+
+    pub struct Map {
+        data: Vec<u8>,
+    }
+
+    impl Map {
+        pub fn new(mut file: &File, len: usize) -> io::Result<Map> {
+            let mut data = vec![0; len];
+            file.seek(SeekFrom::Start(0))?;
+            file.read_exact(&mut data)?;
+            Ok(Map { data })
+        }
+
+        pub fn as_slice(&self) -> &[u8] {
+            &self.data
+        }
+    }
+}
+
+/// A read-only view of the first `len()` bytes of a file.
+///
+/// The view must not outlive a truncation of the file below `len()`:
+/// on Unix, touching unmapped file pages raises `SIGBUS`. A table only
+/// shrinks its file when no `SharedReader` has it mapped, and a
+/// `FileStore` remaps as it truncates.
+pub struct MappedFile {
+    map: sys::Map,
+}
+
+impl MappedFile {
+    /// Maps the whole of `file`, at its current length.
+    pub fn map(file: &File) -> io::Result<MappedFile> {
+        let len = file.metadata()?.len() as usize;
+        Ok(MappedFile { map: sys::Map::new(file, len)? })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.map.as_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
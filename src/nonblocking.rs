@@ -0,0 +1,254 @@
+//! A table for async code. Enabled by the `async` feature.
+//!
+//! `AsyncLinHash` moves its `LinHash` onto a worker thread of its own
+//! and hands it one operation at a time, so the file I/O never runs on
+//! the caller's executor. Every operation returns a `Reply`, a future
+//! that resolves once the worker has carried it out; operations run in
+//! the order they were started. Nothing here depends on a particular
+//! runtime: a `Reply` wakes whichever task polled it last.
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use error;
+use {LinHash, LinHashError};
+
+// `None` once the table is closed
+type Job = Box<dyn FnOnce(&mut Option<LinHash>) + Send>;
+
+struct Slot<T> {
+    result: Option<error::Result<T>>,
+    waker: Option<Waker>,
+    // taken by `poll` once it has returned `Ready`
+    done: bool,
+}
+
+/// The outcome of an operation of `AsyncLinHash`, once the worker
+/// gets to it.
+pub struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+/// The worker's end of a `Reply`. Dropped unfilled, eg. because the
+/// worker is gone, it fails the reply instead of leaving it pending.
+struct Filler<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+fn reply<T>() -> (Reply<T>, Filler<T>) {
+    let slot = Arc::new(Mutex::new(Slot { result: None, waker: None, done: false }));
+    (Reply { slot: slot.clone() }, Filler { slot })
+}
+
+impl<T> Filler<T> {
+    fn fill(&self, result: error::Result<T>) {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        if slot.result.is_none() && !slot.done {
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Filler<T> {
+    fn drop(&mut self) {
+        self.fill(Err(LinHashError::Io(io::Error::other("the table's worker thread stopped"))));
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = error::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<error::Result<T>> {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.result.take() {
+            Some(result) => {
+                slot.done = true;
+                Poll::Ready(result)
+            },
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Linear Hashtable with an async interface. See the module docs.
+pub struct AsyncLinHash {
+    jobs: Sender<Job>,
+}
+
+impl AsyncLinHash {
+    /// Opens (or creates) the table in `filename` on a new worker
+    /// thread; see `LinHash::try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                -> Reply<AsyncLinHash> {
+        let (opened, filler) = reply();
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let filename = filename.as_ref().to_path_buf();
+        let spawned = thread::Builder::new()
+            .name(format!("linhash {}", filename.display()))
+            .spawn(move || {
+                let mut table = match LinHash::try_open(&filename, keysize, valsize) {
+                    Ok(table) => Some(table),
+                    Err(e) => return filler.fill(Err(e)),
+                };
+                filler.fill(Ok(AsyncLinHash { jobs }));
+                // until every handle is dropped
+                for job in queue {
+                    job(&mut table);
+                }
+            });
+        if let Err(e) = spawned {
+            // the closure, and with it the filler, is gone: replace
+            // its generic error with the real one
+            let mut slot = opened.slot.lock().unwrap_or_else(|e| e.into_inner());
+            slot.result = Some(Err(LinHashError::Io(e)));
+        }
+        opened
+    }
+
+    /// Queues `op` for the worker.
+    fn run<T, F>(&self, op: F) -> Reply<T>
+        where T: Send + 'static, F: FnOnce(&mut LinHash) -> error::Result<T> + Send + 'static {
+        self.run_job(move |table| match *table {
+            Some(ref mut table) => op(table),
+            None => Err(LinHashError::InvalidArgument("the table is closed".to_string())),
+        })
+    }
+
+    fn run_job<T, F>(&self, op: F) -> Reply<T>
+        where T: Send + 'static,
+              F: FnOnce(&mut Option<LinHash>) -> error::Result<T> + Send + 'static {
+        let (result, filler) = reply();
+        // if the worker is gone the job is dropped, failing the reply
+        let _ = self.jobs.send(Box::new(move |table: &mut Option<LinHash>| filler.fill(op(table))));
+        result
+    }
+
+    pub fn get(&self, key: &[u8]) -> Reply<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |table| table.try_get(&key))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> Reply<bool> {
+        let key = key.to_vec();
+        self.run(move |table| table.try_get(&key).map(|v| v.is_some()))
+    }
+
+    /// Inserts `key`, which must not be present; see `LinHash::try_put`.
+    pub fn put(&self, key: &[u8], val: &[u8]) -> Reply<()> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.run(move |table| table.try_put(&key, &val))
+    }
+
+    pub fn update(&self, key: &[u8], val: &[u8]) -> Reply<bool> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.run(move |table| table.try_update(&key, &val))
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Reply<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |table| table.try_remove(&key))
+    }
+
+    pub fn len(&self) -> Reply<usize> {
+        self.run(|table| Ok(table.len()))
+    }
+
+    pub fn is_empty(&self) -> Reply<bool> {
+        self.run(|table| Ok(table.is_empty()))
+    }
+
+    /// See `LinHash::flush`.
+    pub fn flush(&self) -> Reply<()> {
+        self.run(|table| {
+            table.check_handle()?;
+            if !table.is_read_only() {
+                table.checkpoint()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Closes the table once the operations already started are done.
+    /// Operations started after fail. On error the table stays open;
+    /// see `LinHash::try_close`.
+    pub fn close(&self) -> Reply<()> {
+        self.run_job(|table| match table.take() {
+            Some(mut t) => t.try_close().inspect_err(|_| *table = Some(t)),
+            None => Ok(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nonblocking::AsyncLinHash;
+    use std::fs;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::{self, Thread};
+    use util::*;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls `f` to completion on this thread.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut f = Box::pin(f);
+        loop {
+            if let Poll::Ready(out) = f.as_mut().poll(&mut cx) {
+                return out;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn async_ops() {
+        let path = "/tmp/test_async_linhash";
+        fs::remove_file(path).ok();
+        let h = block_on(AsyncLinHash::open(path, 4, 4)).unwrap();
+        // started together, carried out in order
+        let puts: Vec<_> = (0..1000).map(|k| h.put(&encode(k), &encode(k))).collect();
+        for p in puts {
+            block_on(p).unwrap();
+        }
+        assert_eq!(block_on(h.get(&encode(7))).unwrap(), Some(encode(7)));
+        assert!(block_on(h.update(&encode(7), &encode(70))).unwrap());
+        assert_eq!(block_on(h.remove(&encode(8))).unwrap(), Some(encode(8)));
+        assert!(!block_on(h.contains(&encode(8))).unwrap());
+        assert_eq!(block_on(h.len()).unwrap(), 999);
+        assert!(block_on(h.put(&[0; 5], &encode(0))).is_err());
+        // an existing key fails the put, not the worker
+        assert!(block_on(h.put(&encode(7), &encode(0))).is_err());
+        assert_eq!(block_on(h.get(&encode(7))).unwrap(), Some(encode(70)));
+        block_on(h.close()).unwrap();
+        assert!(block_on(h.get(&encode(7))).is_err());
+        drop(h);
+
+        let h = block_on(AsyncLinHash::open(path, 4, 4)).unwrap();
+        assert_eq!(block_on(h.get(&encode(7))).unwrap(), Some(encode(70)));
+        assert!(block_on(AsyncLinHash::open(path, 4, 4)).is_err());
+        drop(h);
+        fs::remove_file(path).ok();
+    }
+}
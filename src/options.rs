@@ -0,0 +1,244 @@
+//! Settings for opening a table, gathered by a builder:
+//!
+//! ```no_run
+//! use linhash::LinHash;
+//!
+//! let table = LinHash::options().keysize(8).valsize(256).threshold(0.6)
+//!     .page_size(4096).open("/tmp/table").unwrap();
+//! ```
+//!
+//! Settings a table records when it is created (its page layout,
+//! hasher, whether it keeps duplicate keys, whether it splits in
+//! partial expansions and whether its pages have extended headers)
+//! only apply to new tables. The split threshold
+//! is recorded too, but can be changed by opening with a different one.
+//! Pages are `page::PAGE_SIZE` bytes in every table: `page_size` only
+//! checks that, and opening a file recorded with other pages fails.
+
+use std::path::Path;
+
+use error;
+use page::PageLayout;
+use store::{IoBackend, PageStore};
+use wal::Durability;
+use {KeyHasher, LinHash};
+
+/// How to open a `LinHash`. See `LinHash::options`.
+pub struct OpenOptions {
+    pub(crate) keysize: Option<usize>,
+    pub(crate) valsize: Option<usize>,
+    pub(crate) layout: PageLayout,
+    pub(crate) hasher: Option<KeyHasher>,
+    pub(crate) threshold: Option<f32>,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) duplicate_keys: bool,
+    pub(crate) durability: Durability,
+    pub(crate) read_only: bool,
+    pub(crate) bucket_filters: bool,
+    pub(crate) direct_io: bool,
+    pub(crate) io_backend: IoBackend,
+    pub(crate) incremental_splits: bool,
+    pub(crate) split_on_overflow: bool,
+    pub(crate) partial_expansions: bool,
+    pub(crate) extended_headers: bool,
+    // set by `BlobLinHash`, whose records refer to value pages
+    pub(crate) large_values: bool,
+    pub(crate) store: Option<Box<dyn PageStore>>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
+    }
+}
+
+impl OpenOptions {
+    /// Row pages, the built-in hasher, the table's own split
+    /// threshold and `Durability::Never`. `keysize` has to be set to
+    /// create a table; an existing one's sizes are used where unset.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            keysize: None,
+            valsize: None,
+            layout: PageLayout::Row,
+            hasher: None,
+            threshold: None,
+            page_size: None,
+            duplicate_keys: false,
+            durability: Durability::Never,
+            read_only: false,
+            bucket_filters: false,
+            direct_io: false,
+            io_backend: IoBackend::File,
+            incremental_splits: false,
+            split_on_overflow: false,
+            partial_expansions: false,
+            extended_headers: true,
+            large_values: false,
+            store: None,
+        }
+    }
+
+    /// Opening a table of another key size fails.
+    pub fn keysize(mut self, keysize: usize) -> OpenOptions {
+        self.keysize = Some(keysize);
+        self
+    }
+
+    /// Opening a table of another value size fails; 0 if unset for a
+    /// new table.
+    pub fn valsize(mut self, valsize: usize) -> OpenOptions {
+        self.valsize = Some(valsize);
+        self
+    }
+
+    /// See `LinHash::open_with_layout`.
+    pub fn layout(mut self, layout: PageLayout) -> OpenOptions {
+        self.layout = layout;
+        self
+    }
+
+    /// See `LinHash::open_with_hasher`.
+    pub fn hasher(mut self, hasher: KeyHasher) -> OpenOptions {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    /// The load factor above which the table splits a bucket, and
+    /// below half of which it merges one: lower means shorter overflow
+    /// chains and a bigger file. Stored in the table, to three decimal
+    /// places; tables that never set one use `LinHash::THRESHOLD`.
+    /// Opening fails unless `0 < threshold <= 32`.
+    pub fn threshold(mut self, threshold: f32) -> OpenOptions {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// The page size the caller expects, in bytes. Pages are
+    /// `page::PAGE_SIZE` bytes in every table of a build, so opening
+    /// fails for any other size rather than misreading the file.
+    pub fn page_size(mut self, bytes: usize) -> OpenOptions {
+        self.page_size = Some(bytes);
+        self
+    }
+
+    /// Lets the table hold several records with the same key, as a
+    /// multimap: `put` then adds a record even if the key is there,
+    /// and `get_all` and `remove_all` see every record of a key.
+    /// `get`, `update` and `remove` act on the key's first record.
+    pub fn duplicate_keys(mut self, enabled: bool) -> OpenOptions {
+        self.duplicate_keys = enabled;
+        self
+    }
+
+    /// See `LinHash::set_durability`.
+    pub fn durability(mut self, durability: Durability) -> OpenOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Opens an existing table for reading only: the file is opened
+    /// without write permission, nothing is written to it on open or
+    /// close, and every change fails. Takes the lock file shared (see
+    /// `shared`): any number of read-only handles can be open at once,
+    /// but opening fails while a writer has the table open (see
+    /// `SharedReader` for reading alongside one), and a writer cannot
+    /// open it while they are. Also fails if the table was not closed
+    /// cleanly.
+    pub fn read_only(mut self, enabled: bool) -> OpenOptions {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Keeps a small Bloom filter per bucket in memory, so that most
+    /// `get`s of keys the table does not hold return without reading
+    /// the bucket. Costs 128 bytes per bucket; saved to
+    /// `<file>.filters` on close so the next open need not rebuild
+    /// them. See `filters`.
+    pub fn bucket_filters(mut self, enabled: bool) -> OpenOptions {
+        self.bucket_filters = enabled;
+        self
+    }
+
+    /// Reads and writes pages bypassing the OS page cache, for
+    /// benchmarks and for callers with a cache of their own; see
+    /// `FileStore::open_direct`. Every page not in the buffer pool is
+    /// then read from disk. Not for use with `store`.
+    pub fn direct_io(mut self, enabled: bool) -> OpenOptions {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Chooses how pages are read from the file; see `IoBackend` and
+    /// `LinHash::set_io_backend`. With `IoBackend::Mmap`, lookups and
+    /// `get_ref` read pages the buffer pool does not hold straight out
+    /// of the map. A store other than a file cannot be mapped, and
+    /// neither can an encrypted one: opening either fails.
+    pub fn io_backend(mut self, backend: IoBackend) -> OpenOptions {
+        self.io_backend = backend;
+        self
+    }
+
+    /// Splits a bucket a page at a time instead of all at once: the
+    /// split only adds the new bucket, and each later write moves the
+    /// records of one more page of the old bucket's chain, so no `put`
+    /// stalls on a bucket with a long chain. Lookups of keys not yet
+    /// moved read both chains. A split still in progress is finished
+    /// before the next one, a merge, compaction, `verify` or `close`.
+    /// The pages of the bucket split are left with gaps, which its
+    /// later records fill; see `LinHash::compact_bucket`.
+    pub fn incremental_splits(mut self, enabled: bool) -> OpenOptions {
+        self.incremental_splits = enabled;
+        self
+    }
+
+    /// Also splits when a `put` gives a bucket that already has an
+    /// overflow page another one, for keys that hash unevenly: their
+    /// chains grow long while the load factor stays under the split
+    /// threshold. As with any split, the next bucket in line splits,
+    /// not necessarily the one that overflowed, so a run of overflows
+    /// grows the table until it reaches the crowded buckets. Only done
+    /// while the load is at least half the threshold, where merging
+    /// starts. Not recorded in the table.
+    pub fn split_on_overflow(mut self, enabled: bool) -> OpenOptions {
+        self.split_on_overflow = enabled;
+        self
+    }
+
+    /// Grows a new table in partial expansions: each split adds a
+    /// bucket to a group of two or three and takes its share of all of
+    /// their records, so buckets stay about equally full rather than
+    /// those not yet split in a round holding twice as many records as
+    /// the rest. Lookups compute the bucket in about `2 * nbits` steps,
+    /// and splits and merges touch two or three chains. Recorded in the
+    /// table. Such a table has no `cursor`, and does not split
+    /// incrementally. See `linear::partial_bucket_index`.
+    pub fn partial_expansions(mut self, enabled: bool) -> OpenOptions {
+        self.partial_expansions = enabled;
+        self
+    }
+
+    /// Gives the pages of a new table extended headers (the default),
+    /// which say whether a page is a bucket's first, one of its
+    /// overflow pages or free, which bucket it belongs to, when it was
+    /// written and the LSN of its latest change; `verify` checks that
+    /// chains only link pages of their own bucket, and recovery skips
+    /// logged changes already on disk. Plain headers leave room for a
+    /// few more records per page. Tables of large values (see `blob`)
+    /// always have plain headers.
+    pub fn extended_headers(mut self, enabled: bool) -> OpenOptions {
+        self.extended_headers = enabled;
+        self
+    }
+
+    /// See `LinHash::open_with_store`.
+    pub fn store<S: PageStore + 'static>(mut self, store: S) -> OpenOptions {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Opens (or creates) the table in `filename`.
+    pub fn open<P: AsRef<Path>>(self, filename: P) -> error::Result<LinHash> {
+        LinHash::try_open_table(filename.as_ref(), self)
+    }
+}
@@ -1,7 +1,127 @@
+//! In-memory image of a single page: a small header (record count and
+//! overflow link) followed by the records, laid out either as fixed-width
+//! `| key | val |` rows, as a column of keys followed by a column of
+//! values for scan-heavy tables, or as a slot directory pointing at
+//! variable-length records (see `PageLayout`).
+//!
+//! An extended header also says what the page is, which bucket it
+//! belongs to and when it was written, so that a page can be told
+//! apart from the chains that link it; see `PageType`. In files with
+//! page LSNs it ends in the log sequence number of the last change
+//! the page holds, which lets write-ahead log replay skip changes
+//! already on disk (see `wal`).
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
 use util::*;
 
 pub const PAGE_SIZE : usize = 4096; // bytes
 pub const HEADER_SIZE : usize = 16; // bytes
+pub const EXTENDED_HEADER_SIZE : usize = 32; // bytes
+/// Size of an extended header that also holds the page LSN.
+pub const LSN_HEADER_SIZE : usize = 40; // bytes
+
+// Header fields. The record count was once a full word; its high half
+// now holds the page checksum, which is 0 in files without checksums.
+const NUM_RECORDS_OFFSET : usize = 0;
+const CHECKSUM_OFFSET : usize = 4;
+const NEXT_OFFSET : usize = 8;
+// Extended header fields: the page type and owning bucket, a u32 each
+// (there are at most `DbFile::max_buckets` buckets), and the sequence
+// number.
+const TYPE_OFFSET : usize = 16;
+const OWNER_OFFSET : usize = 20;
+const SEQ_OFFSET : usize = 24;
+const LSN_OFFSET : usize = 32;
+
+/// Width of a slotted page's directory entry: offset, key length and
+/// value length, each a little-endian u16.
+pub const SLOT_SIZE : usize = 6; // bytes
+
+/// How records are arranged within a page. Chosen when a table is
+/// created and recorded in its control page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageLayout {
+    /// `| key | val | key | val | ...`
+    #[default]
+    Row,
+    /// `| key | key | ... | val | val | ...`, with room for a full page
+    /// of keys before the first value. Keys are contiguous, so scans
+    /// that only look at keys touch fewer cache lines.
+    Columnar,
+    /// `| slot | slot | ... free ... | record | record |`: a directory
+    /// of slots growing from the header, each locating one record
+    /// packed at the end of the page. Keys and values are stored at
+    /// their own length, without padding, and `keysize`/`valsize` are
+    /// only upper bounds.
+    Slotted,
+}
+
+impl PageLayout {
+    pub fn to_word(self) -> usize {
+        match self {
+            PageLayout::Row => 0,
+            PageLayout::Columnar => 1,
+            PageLayout::Slotted => 2,
+        }
+    }
+
+    pub fn from_word(word: usize) -> Option<PageLayout> {
+        match word {
+            0 => Some(PageLayout::Row),
+            1 => Some(PageLayout::Columnar),
+            2 => Some(PageLayout::Slotted),
+            _ => None,
+        }
+    }
+
+    /// Does `stored`, as read from a page of this layout, hold `key`?
+    /// Fixed-width slots are zero-padded (see `key_eq`); slotted
+    /// records must match exactly.
+    pub fn key_eq(self, stored: &[u8], key: &[u8]) -> bool {
+        match self {
+            PageLayout::Slotted => stored == key,
+            _ => key_eq(stored, key),
+        }
+    }
+}
+
+/// What a page with an extended header holds. The control page and
+/// map pages have layouts of their own, and the control page says
+/// where they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageType {
+    /// Never labelled: a page of a pager client, or one not yet written.
+    #[default]
+    Unknown,
+    /// The first page of a bucket.
+    Bucket,
+    /// A page of a bucket's chain past the first.
+    Overflow,
+    /// A page on the free list.
+    Free,
+}
+
+impl PageType {
+    pub fn to_word(self) -> u32 {
+        match self {
+            PageType::Unknown => 0,
+            PageType::Bucket => 1,
+            PageType::Overflow => 2,
+            PageType::Free => 3,
+        }
+    }
+
+    /// The type a stored word stands for; `Unknown` for any other word.
+    pub fn from_word(word: u32) -> PageType {
+        match word {
+            1 => PageType::Bucket,
+            2 => PageType::Overflow,
+            3 => PageType::Free,
+            _ => PageType::Unknown,
+        }
+    }
+}
 
 pub struct Page {
     pub id: usize,
@@ -10,82 +130,499 @@ pub struct Page {
     // page_id of overflow bucket
     pub next: Option<usize>,
     pub dirty: bool,
+    /// Outstanding `DbFile::pin`s; pinned pages are never evicted.
+    pub pin_count: usize,
+    /// Buffer pool clock at the last access; the least recently used
+    /// unpinned page is evicted first.
+    pub last_used: u64,
+    /// Whether `write_header` stamps a checksum into the page.
+    pub checksums: bool,
+    /// Whether the page has an extended header, holding the fields
+    /// below.
+    pub extended: bool,
+    pub kind: PageType,
+    /// The bucket the page belongs to, if it belongs to one.
+    pub owner: usize,
+    /// Sequence number of the first ctrl page write after the page was
+    /// last written; see `DbFile::ctrl_seq`.
+    pub seq: usize,
+    /// Whether the extended header also holds `lsn`.
+    pub lsns: bool,
+    /// Log sequence number of the latest change the page holds all of
+    /// (and maybe some of a later one); see `DbFile::lsn`.
+    pub lsn: u64,
 
     keysize: usize,
     valsize: usize,
+    layout: PageLayout,
 }
 
-// Row layout:
-// | key | val |
 #[derive(Debug)]
 struct RowOffsets {
     key_offset: usize,
     val_offset: usize,
-    row_end: usize,
+}
+
+/// Where records go in a page: everything about its arrangement but
+/// the bytes themselves, which the methods take. Shared by `Page` and
+/// `PageView`.
+#[derive(Clone, Copy, Debug)]
+struct Shape {
+    keysize: usize,
+    valsize: usize,
+    layout: PageLayout,
+    header_size: usize,
+}
+
+impl Shape {
+    fn row_capacity(&self) -> usize {
+        Page::capacity_after(self.header_size, self.keysize, self.valsize)
+    }
+
+    fn max_records(&self) -> usize {
+        match self.layout {
+            PageLayout::Slotted => (PAGE_SIZE - self.header_size) / SLOT_SIZE,
+            _ => self.row_capacity(),
+        }
+    }
+
+    /// (offset, key length, value length) of slot `i` of `storage`.
+    fn slot(&self, storage: &[u8], i: usize) -> (usize, usize, usize) {
+        let at = self.header_size + i * SLOT_SIZE;
+        let word = |j: usize| usize::from(decode::<u16>(&storage[at + 2 * j..]));
+        (word(0), word(1), word(2))
+    }
+
+    fn offsets(&self, storage: &[u8], row_num: usize) -> RowOffsets {
+        match self.layout {
+            PageLayout::Slotted => {
+                let (offset, key_len, _) = self.slot(storage, row_num);
+                RowOffsets {
+                    key_offset: offset,
+                    val_offset: offset + key_len,
+                }
+            },
+            PageLayout::Row => {
+                let row_offset = self.header_size + row_num * (self.keysize + self.valsize);
+                RowOffsets {
+                    key_offset: row_offset,
+                    val_offset: row_offset + self.keysize,
+                }
+            },
+            PageLayout::Columnar => {
+                let (header, capacity) = (self.header_size, self.row_capacity());
+                RowOffsets {
+                    key_offset: header + row_num * self.keysize,
+                    val_offset: header + capacity * self.keysize
+                        + row_num * self.valsize,
+                }
+            },
+        }
+    }
+
+    /// Byte ranges of the key and the value of record `row_num`.
+    fn spans(&self, storage: &[u8], row_num: usize) -> ((usize, usize), (usize, usize)) {
+        let (key_len, val_len) = match self.layout {
+            PageLayout::Slotted => { let (_, k, v) = self.slot(storage, row_num); (k, v) },
+            _ => (self.keysize, self.valsize),
+        };
+        let RowOffsets { key_offset, val_offset } = self.offsets(storage, row_num);
+        ((key_offset, key_offset + key_len), (val_offset, val_offset + val_len))
+    }
+}
+
+/// A page read in place, out of bytes it borrows rather than owns, eg.
+/// a memory map of the file. Only the header fields needed to search
+/// the page are read; see `DbFile::locate`.
+pub struct PageView<'a> {
+    storage: &'a [u8],
+    shape: Shape,
+    pub num_records: usize,
+    pub next: Option<usize>,
+}
+
+impl<'a> PageView<'a> {
+    /// Reads the header of the page in `storage`, whose records are
+    /// laid out past a header of `header_size` bytes as in a `Page`
+    /// with these sizes and layout. Panics if `storage` is shorter
+    /// than a page.
+    pub fn new(storage: &'a [u8], keysize: usize, valsize: usize, layout: PageLayout,
+               header_size: usize) -> PageView<'a> {
+        let storage = &storage[..PAGE_SIZE];
+        let next = read_usize_at(storage, NEXT_OFFSET).expect("page too short");
+        PageView {
+            storage,
+            shape: Shape { keysize, valsize, layout, header_size },
+            num_records: decode::<u32>(&storage[NUM_RECORDS_OFFSET..]) as usize,
+            next: if next != 0 { Some(next) } else { None },
+        }
+    }
+
+    /// As `Page::max_records`.
+    pub fn max_records(&self) -> usize {
+        self.shape.max_records()
+    }
+
+    /// As `Page::checksum_ok`.
+    pub fn checksum_ok(&self) -> bool {
+        checksum_ok(self.storage)
+    }
+
+    /// The key and value of record `row_num`, borrowed for as long as
+    /// the bytes are.
+    pub fn record(&self, row_num: usize) -> (&'a [u8], &'a [u8]) {
+        let ((k0, k1), (v0, v1)) = self.shape.spans(self.storage, row_num);
+        (&self.storage[k0..k1], &self.storage[v0..v1])
+    }
+}
+
+/// CRC-32 of every byte of a page but its checksum field. Never 0, so
+/// that 0 can mark a page that was never written.
+fn checksum(storage: &[u8]) -> u32 {
+    let crc = crc32(&storage[..CHECKSUM_OFFSET]);
+    crc32_extend(crc, &storage[NEXT_OFFSET..]).max(1)
+}
+
+/// Does the checksum stored in a page match its contents? A page that
+/// was never written, ie. is all zeroes, has none and passes.
+fn checksum_ok(storage: &[u8]) -> bool {
+    match decode::<u32>(&storage[CHECKSUM_OFFSET..]) {
+        0 => storage.iter().all(|&b| b == 0),
+        stored => stored == checksum(storage),
+    }
 }
 
 impl Page {
     pub fn new(keysize: usize, valsize: usize) -> Page {
+        Page::with_layout(keysize, valsize, PageLayout::Row)
+    }
+
+    pub fn with_layout(keysize: usize, valsize: usize, layout: PageLayout) -> Page {
         Page {
             id: 0,
             num_records: 0,
             storage: [0; PAGE_SIZE],
             next: None,
-            keysize: keysize,
-            valsize: valsize,
+            keysize,
+            valsize,
             dirty: false,
+            pin_count: 0,
+            last_used: 0,
+            checksums: false,
+            extended: false,
+            kind: PageType::Unknown,
+            owner: 0,
+            seq: 0,
+            lsns: false,
+            lsn: 0,
+            layout,
         }
     }
 
-    /// Compute where in the page the row should be placed. Within the
-    /// row, calculate the offsets of the header, key and value.
-    fn compute_offsets(&self, row_num: usize) -> RowOffsets {
-        let total_size = self.keysize + self.valsize;
+    pub fn layout(&self) -> PageLayout {
+        self.layout
+    }
 
-        let row_offset = HEADER_SIZE + (row_num * total_size);
-        let key_offset = row_offset;
-        let val_offset = key_offset + self.keysize;
-        let row_end = val_offset + self.valsize;
+    fn shape(&self) -> Shape {
+        Shape {
+            keysize: self.keysize,
+            valsize: self.valsize,
+            layout: self.layout,
+            header_size: self.header_size(),
+        }
+    }
 
-        RowOffsets {
-            key_offset: key_offset,
-            val_offset: val_offset,
-            row_end: row_end,
+    /// How many `keysize + valsize` records fit in one page.
+    pub fn capacity(keysize: usize, valsize: usize) -> usize {
+        Page::capacity_after(HEADER_SIZE, keysize, valsize)
+    }
+
+    /// Like `capacity`, for pages with a header of `header_size` bytes.
+    pub fn capacity_after(header_size: usize, keysize: usize, valsize: usize) -> usize {
+        (PAGE_SIZE - header_size)
+            .checked_div(keysize + valsize)
+            .unwrap_or(0)
+    }
+
+    /// Bytes the page's header takes.
+    pub fn header_size(&self) -> usize {
+        match (self.extended, self.lsns) {
+            (false, _) => HEADER_SIZE,
+            (true, false) => EXTENDED_HEADER_SIZE,
+            (true, true) => LSN_HEADER_SIZE,
         }
     }
 
+    /// How many fixed-width records fit in this page.
+    fn row_capacity(&self) -> usize {
+        self.shape().row_capacity()
+    }
+
+    /// Upper bound on `num_records` in a sound page of this layout.
+    pub fn max_records(&self) -> usize {
+        self.shape().max_records()
+    }
+
+    /// Can a new record of these lengths be added?
+    pub fn has_room(&self, key_len: usize, val_len: usize) -> bool {
+        match self.layout {
+            PageLayout::Slotted =>
+                self.free_space(self.num_records + 1) >= key_len + val_len,
+            _ => self.num_records < self.row_capacity(),
+        }
+    }
+
+    /// Would all of `records` fit in the page along with its own?
+    pub fn has_room_for(&self, records: &[(Vec<u8>, Vec<u8>)]) -> bool {
+        match self.layout {
+            PageLayout::Slotted => {
+                let bytes: usize = records.iter().map(|(k, v)| k.len() + v.len()).sum();
+                self.free_space(self.num_records + records.len()) >= bytes
+            },
+            _ => self.num_records + records.len() <= self.row_capacity(),
+        }
+    }
+
+    /// Can record `row_num` be rewritten with these lengths?
+    pub fn has_room_to_replace(&self, row_num: usize, key_len: usize,
+                               val_len: usize) -> bool {
+        match self.layout {
+            PageLayout::Slotted => {
+                let (_, old_key, old_val) = self.slot(row_num);
+                self.free_space(self.num_records) + old_key + old_val
+                    >= key_len + val_len
+            },
+            _ => true,
+        }
+    }
+
+    /// Bytes left for record data once the page is compacted and its
+    /// directory holds `nslots` slots.
+    fn free_space(&self, nslots: usize) -> usize {
+        let used: usize = (0..self.num_records)
+            .map(|i| { let (_, k, v) = self.slot(i); k + v })
+            .sum();
+        (PAGE_SIZE - self.header_size()).saturating_sub(nslots * SLOT_SIZE + used)
+    }
+
+    /// (offset, key length, value length) of slot `i`. An offset of 0
+    /// marks an empty slot.
+    fn slot(&self, i: usize) -> (usize, usize, usize) {
+        self.shape().slot(&self.storage, i)
+    }
+
+    fn set_slot(&mut self, i: usize, (offset, key_len, val_len): (usize, usize, usize)) {
+        let at = self.header_size() + i * SLOT_SIZE;
+        for (j, &word) in [offset, key_len, val_len].iter().enumerate() {
+            (word as u16).encode_into(&mut self.storage[at + 2 * j..]);
+        }
+    }
+
+    /// Start of the record data, ie. the lowest offset in use among
+    /// the first `nslots` slots.
+    fn data_start(&self, nslots: usize) -> usize {
+        (0..nslots).map(|i| self.slot(i).0)
+            .filter(|&off| off != 0)
+            .min()
+            .unwrap_or(PAGE_SIZE)
+    }
+
+    /// Repacks the records of the first `nslots` slots against the end
+    /// of the page, closing the holes left by removed or rewritten
+    /// records.
+    fn compact(&mut self, nslots: usize) {
+        let mut records: Vec<(usize, Vec<u8>)> = (0..nslots)
+            .filter(|&i| self.slot(i).0 != 0)
+            .map(|i| {
+                let (off, k, v) = self.slot(i);
+                (i, self.storage[off..off + k + v].to_vec())
+            })
+            .collect();
+        let mut end = PAGE_SIZE;
+        for (i, data) in records.drain(..) {
+            let (_, k, v) = self.slot(i);
+            end -= data.len();
+            self.storage[end..end + data.len()].copy_from_slice(&data);
+            self.set_slot(i, (end, k, v));
+        }
+        let dir_end = self.header_size() + nslots * SLOT_SIZE;
+        self.storage[dir_end..end].fill(0);
+    }
+
+    fn write_slotted(&mut self, row_num: usize, key: &[u8], val: &[u8]) {
+        // slots past the directory may lie over record data, so only
+        // those in use are read or written until there is room for more
+        let in_use = self.num_records;
+        if row_num < in_use {
+            self.set_slot(row_num, (0, 0, 0));
+        }
+        let nslots = in_use.max(row_num + 1);
+        let len = key.len() + val.len();
+        let dir_end = self.header_size() + nslots * SLOT_SIZE;
+        if self.data_start(in_use) < dir_end + len {
+            self.compact(in_use);
+        }
+        let start = self.data_start(in_use).checked_sub(len)
+            .filter(|&start| start >= dir_end)
+            .expect("record does not fit in page");
+        for i in in_use..nslots {
+            self.set_slot(i, (0, 0, 0));
+        }
+        self.storage[start..start + key.len()].copy_from_slice(key);
+        self.storage[start + key.len()..start + len].copy_from_slice(val);
+        self.set_slot(row_num, (start, key.len(), val.len()));
+    }
+
+    /// Compute where in the page the key and value of record `row_num`
+    /// are placed, past the header.
+    fn compute_offsets(&self, row_num: usize) -> RowOffsets {
+        self.shape().offsets(&self.storage, row_num)
+    }
+
+    /// The keys of all records, back to back, if the page is columnar.
+    pub fn key_column(&self) -> Option<&[u8]> {
+        match self.layout {
+            PageLayout::Row | PageLayout::Slotted => None,
+            PageLayout::Columnar => {
+                let header = self.header_size();
+                Some(&self.storage[header..header + self.num_records * self.keysize])
+            },
+        }
+    }
+
+    fn clear_record(&mut self, offsets: &RowOffsets) {
+        let (k, v) = (offsets.key_offset, offsets.val_offset);
+        self.storage[k..k + self.keysize].fill(0);
+        self.storage[v..v + self.valsize].fill(0);
+    }
+
 
     pub fn read_header(&mut self) {
-        let num_records : usize = bytearray_to_usize(self.storage[0..8].to_vec());
-        let next : usize = bytearray_to_usize(self.storage[8..16].to_vec());
+        let num_records = decode::<u32>(&self.storage[NUM_RECORDS_OFFSET..]) as usize;
+        let next = read_usize_at(&self.storage, NEXT_OFFSET)
+            .expect("page too short");
         self.num_records = num_records;
         self.next = if next != 0 {
             Some(next)
         } else {
             None
         };
+        if self.extended {
+            self.kind = PageType::from_word(decode(&self.storage[TYPE_OFFSET..]));
+            self.owner = decode::<u32>(&self.storage[OWNER_OFFSET..]) as usize;
+            self.seq = read_usize_at(&self.storage, SEQ_OFFSET).expect("page too short");
+            if self.lsns {
+                self.lsn = read_u64_at(&self.storage, LSN_OFFSET).expect("page too short");
+            }
+        }
     }
 
+    /// Writes the header fields into `storage`, then, if `checksums`
+    /// is set, the checksum of the whole page.
     pub fn write_header(&mut self) {
-        mem_move(&mut self.storage[0..8], &usize_to_bytearray(self.num_records));
-        mem_move(&mut self.storage[8..16], &usize_to_bytearray(self.next.unwrap_or(0)));
+        write_usize_at(&mut self.storage, NUM_RECORDS_OFFSET, self.num_records)
+            .expect("page too short");
+        write_usize_at(&mut self.storage, NEXT_OFFSET, self.next.unwrap_or(0))
+            .expect("page too short");
+        if self.extended {
+            self.kind.to_word().encode_into(&mut self.storage[TYPE_OFFSET..]);
+            (self.owner as u32).encode_into(&mut self.storage[OWNER_OFFSET..]);
+            write_usize_at(&mut self.storage, SEQ_OFFSET, self.seq).expect("page too short");
+            if self.lsns {
+                write_u64_at(&mut self.storage, LSN_OFFSET, self.lsn).expect("page too short");
+            }
+        }
+        if self.checksums {
+            let crc = self.checksum();
+            crc.encode_into(&mut self.storage[CHECKSUM_OFFSET..]);
+        }
     }
 
-    pub fn read_record(&mut self, row_num: usize) -> (&[u8], &[u8]) {
-        let offsets = self.compute_offsets(row_num);
-        let key = &self.storage[offsets.key_offset..offsets.val_offset];
-        let val = &self.storage[offsets.val_offset..offsets.row_end];
-        (key, val)
+    /// CRC-32 of every byte but the checksum field itself.
+    fn checksum(&self) -> u32 {
+        checksum(&self.storage)
+    }
+
+    /// Does the stored checksum match the contents? A page that was
+    /// never written, ie. is all zeroes, has none and passes.
+    pub fn checksum_ok(&self) -> bool {
+        checksum_ok(&self.storage)
+    }
+
+    pub fn read_record(&self, row_num: usize) -> (&[u8], &[u8]) {
+        let ((k0, k1), (v0, v1)) = self.shape().spans(&self.storage, row_num);
+        (&self.storage[k0..k1], &self.storage[v0..v1])
+    }
+
+    /// Everything past the header, for a page that holds part of a
+    /// value rather than records; see `blob`. Value pages have plain
+    /// headers.
+    pub fn payload(&self) -> &[u8] {
+        &self.storage[HEADER_SIZE..]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[HEADER_SIZE..]
+    }
+
+    /// The value of record `row_num`, without copying it.
+    pub fn value(&self, row_num: usize) -> &[u8] {
+        let (start, end) = self.value_span(row_num);
+        &self.storage[start..end]
+    }
+
+    /// The value of record `row_num`, to change in place.
+    pub fn value_mut(&mut self, row_num: usize) -> &mut [u8] {
+        let (start, end) = self.value_span(row_num);
+        &mut self.storage[start..end]
+    }
+
+    fn value_span(&self, row_num: usize) -> (usize, usize) {
+        self.shape().spans(&self.storage, row_num).1
     }
 
     /// Write record to offset specified by `row_num`. The offset is
-    /// calculated to accomodate header as well.
+    /// calculated to accomodate header as well. Key and value are
+    /// zero-padded to the slot widths; panics if either is wider than
+    /// its slot. Slotted pages store them as they are, and panic if
+    /// the page has no room (see `has_room`).
     pub fn write_record(&mut self, row_num: usize, key: &[u8], val: &[u8]) {
+        if self.layout == PageLayout::Slotted {
+            return self.write_slotted(row_num, key, val);
+        }
         let offsets = self.compute_offsets(row_num);
-        mem_move(&mut self.storage[offsets.key_offset..offsets.val_offset],
-                 key);
-        mem_move(&mut self.storage[offsets.val_offset..offsets.row_end],
-                 val);
+        self.clear_record(&offsets);
+        let RowOffsets { key_offset, val_offset } = offsets;
+        mem_move(&mut self.storage[key_offset..key_offset + self.keysize],
+                 key)
+            .expect("key wider than keysize");
+        mem_move(&mut self.storage[val_offset..val_offset + self.valsize],
+                 val)
+            .expect("value wider than valsize");
+    }
+
+    /// Removes the record at `row_num`, moving the page's last record
+    /// into its slot so records stay densely packed.
+    pub fn remove_record(&mut self, row_num: usize) {
+        let last = self.num_records - 1;
+        if self.layout == PageLayout::Slotted {
+            // the record's bytes stay behind until the next compaction
+            let moved = self.slot(last);
+            self.set_slot(row_num, moved);
+            self.set_slot(last, (0, 0, 0));
+            self.num_records -= 1;
+            self.dirty = true;
+            return;
+        }
+        if row_num != last {
+            let (k, v) = self.read_record(last);
+            let (k, v) = (k.to_vec(), v.to_vec());
+            self.write_record(row_num, &k, &v);
+        }
+        let offsets = self.compute_offsets(last);
+        self.clear_record(&offsets);
+        self.num_records -= 1;
+        self.dirty = true;
     }
 
     /// Increment number of records in page
@@ -93,3 +630,168 @@ impl Page {
         self.num_records += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use page::{Page, PageLayout, PageType, PageView, EXTENDED_HEADER_SIZE, HEADER_SIZE,
+               LSN_HEADER_SIZE, PAGE_SIZE, SLOT_SIZE};
+
+    #[test]
+    fn columnar_layout() {
+        let mut p = Page::with_layout(4, 8, PageLayout::Columnar);
+        let capacity = Page::capacity(4, 8);
+        for i in 0..capacity as u32 {
+            p.write_record(i as usize, &i.to_le_bytes(), &(i as u64 + 1).to_le_bytes());
+            p.incr_num_records();
+        }
+        for i in 0..capacity as u32 {
+            let (k, v) = p.read_record(i as usize);
+            assert_eq!((k, v), (&i.to_le_bytes()[..], &(i as u64 + 1).to_le_bytes()[..]));
+        }
+        let keys = p.key_column().unwrap();
+        assert_eq!(keys.len(), capacity * 4);
+        assert_eq!(&keys[4..8], &1u32.to_le_bytes());
+        // values start after room for a full page of keys
+        assert_eq!(p.storage[HEADER_SIZE + capacity * 4], 1);
+
+        p.remove_record(0);
+        assert_eq!(p.read_record(0).0, &(capacity as u32 - 1).to_le_bytes());
+        assert!(Page::new(4, 8).key_column().is_none());
+    }
+
+    #[test]
+    fn slotted_layout() {
+        let mut p = Page::with_layout(64, 64, PageLayout::Slotted);
+        let mut n = 0;
+        while p.has_room(5, 10) {
+            p.write_record(n, format!("k{:04}", n).as_bytes(), &[n as u8; 10]);
+            p.incr_num_records();
+            n += 1;
+        }
+        // far more than the 32 records of the widths given
+        assert_eq!(n, (PAGE_SIZE - HEADER_SIZE) / (SLOT_SIZE + 15));
+        assert_eq!(p.read_record(3), (&b"k0003"[..], &[3u8; 10][..]));
+
+        // holes left by removals are reused after compaction
+        p.remove_record(0);
+        p.remove_record(1);
+        assert!(p.has_room(5, 10));
+        assert!(p.has_room_to_replace(0, 5, 20));
+        p.write_record(0, b"grown", &[7; 20]);
+        assert_eq!(p.read_record(0), (&b"grown"[..], &[7u8; 20][..]));
+        assert_eq!(p.read_record(2), (&b"k0002"[..], &[2u8; 10][..]));
+        assert!(PageLayout::Slotted.key_eq(b"ab", b"ab"));
+        assert!(!PageLayout::Slotted.key_eq(b"ab\0", b"ab"));
+    }
+
+    #[test]
+    fn slotted_update_then_append() {
+        let key = |i: usize| (i as u64).to_le_bytes();
+        // however many records are rewritten first
+        for first in 1..(PAGE_SIZE - HEADER_SIZE) / (SLOT_SIZE + 24) {
+            let mut p = Page::with_layout(8, 16, PageLayout::Slotted);
+            for i in 0..first {
+                p.write_record(i, &key(i), &[1; 16]);
+                p.incr_num_records();
+            }
+            // rewrites leave holes, and can leave the lowest record
+            // right past the directory
+            for i in 0..first {
+                p.write_record(i, &key(i), &[2; 16]);
+            }
+            let mut n = first;
+            while p.has_room(8, 16) {
+                p.write_record(n, &key(n), &[3; 16]);
+                p.incr_num_records();
+                n += 1;
+            }
+            for i in 0..n {
+                let val = if i < first { [2; 16] } else { [3; 16] };
+                assert_eq!(p.read_record(i), (&key(i)[..], &val[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let mut p = Page::new(4, 4);
+        assert!(p.checksum_ok());
+        p.checksums = true;
+        p.write_record(0, b"abcd", b"efgh");
+        p.incr_num_records();
+        p.write_header();
+        assert!(p.checksum_ok());
+        p.storage[HEADER_SIZE + 5] ^= 1;
+        assert!(!p.checksum_ok());
+        p.storage[HEADER_SIZE + 5] ^= 1;
+        p.read_header();
+        assert_eq!(p.num_records, 1);
+    }
+
+    #[test]
+    fn page_view() {
+        for &layout in &[PageLayout::Row, PageLayout::Columnar, PageLayout::Slotted] {
+            let mut p = Page::with_layout(8, 8, layout);
+            p.extended = true;
+            p.checksums = true;
+            for i in 0..10u64 {
+                p.write_record(i as usize, &i.to_le_bytes(), &(i * 3).to_le_bytes());
+                p.incr_num_records();
+            }
+            p.next = Some(7);
+            p.write_header();
+            let view = PageView::new(&p.storage, 8, 8, layout, EXTENDED_HEADER_SIZE);
+            assert_eq!((view.num_records, view.next), (10, Some(7)));
+            assert_eq!(view.max_records(), p.max_records());
+            assert!(view.checksum_ok());
+            for i in 0..10 {
+                assert_eq!(view.record(i), p.read_record(i));
+            }
+        }
+    }
+
+    #[test]
+    fn extended_header() {
+        let mut p = Page::with_layout(4, 4, PageLayout::Columnar);
+        p.extended = true;
+        p.checksums = true;
+        assert_eq!(p.max_records(), (PAGE_SIZE - EXTENDED_HEADER_SIZE) / 8);
+        p.write_record(0, b"abcd", b"efgh");
+        p.incr_num_records();
+        p.kind = PageType::Overflow;
+        p.owner = 70000;
+        p.seq = 12;
+        p.write_header();
+        assert!(p.checksum_ok());
+        assert_eq!(&p.key_column().unwrap()[..4], b"abcd");
+
+        let mut q = Page::with_layout(4, 4, PageLayout::Columnar);
+        q.extended = true;
+        q.storage = p.storage;
+        q.read_header();
+        assert_eq!((q.kind, q.owner, q.seq, q.num_records), (PageType::Overflow, 70000, 12, 1));
+        assert_eq!(q.read_record(0), (&b"abcd"[..], &b"efgh"[..]));
+    }
+
+    #[test]
+    fn lsn_header() {
+        let mut p = Page::new(4, 4);
+        p.extended = true;
+        p.lsns = true;
+        p.checksums = true;
+        assert_eq!(p.max_records(), (PAGE_SIZE - LSN_HEADER_SIZE) / 8);
+        p.write_record(0, b"abcd", b"efgh");
+        p.incr_num_records();
+        p.lsn = (7 << 32) | 3;
+        p.write_header();
+        assert!(p.checksum_ok());
+
+        let mut q = Page::new(4, 4);
+        q.extended = true;
+        q.lsns = true;
+        q.storage = p.storage;
+        q.read_header();
+        assert_eq!((q.lsn, q.num_records), ((7 << 32) | 3, 1));
+        assert_eq!(q.read_record(0), (&b"abcd"[..], &b"efgh"[..]));
+    }
+}
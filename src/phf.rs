@@ -0,0 +1,236 @@
+//! Read-only, minimal perfect hash export of a `LinHash`.
+//!
+//! `FrozenTable::build` takes every record of a table and lays them
+//! out in a single array of exactly `len()` slots, using the "hash
+//! and displace" scheme: keys are first grouped by a primary hash,
+//! and each group is assigned a displacement seed under which all of
+//! its keys land in distinct, still-empty slots. A lookup is then one
+//! hash of the key, one read of its group's displacement, and a
+//! single record comparison.
+//!
+//! File layout (all integers little-endian):
+//!
+//! | magic | nrecords | ngroups | keysize | valsize |
+//! displacements (u32 * ngroups) | records (nrecords * (keysize + valsize)) |
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+pub(crate) use hasher::phf_hash;
+use LinHash;
+use util::*;
+
+const MAGIC: &[u8; 8] = b"LHPHF\0\0\x01";
+const HEADER_WORDS: usize = 5;
+
+/// Average number of keys per displacement group.
+const GROUP_SIZE: usize = 4;
+
+/// Displacements tried per group before giving up, per record: the
+/// last groups placed need about `len()` tries to find a free slot.
+const TRIES_PER_RECORD: u64 = 64;
+
+/// A frozen, read-only copy of a table, held fully in memory.
+pub struct FrozenTable {
+    keysize: usize,
+    valsize: usize,
+    displacements: Vec<u32>,
+    records: Vec<u8>,
+}
+
+impl FrozenTable {
+    /// Writes a perfect-hash export of every record in `table` to
+    /// `path`. Fails with `InvalidInput` if the table holds a key more
+    /// than once, as a lookup could only find one of its records.
+    pub fn build<P: AsRef<Path>>(table: &mut LinHash, path: P) -> io::Result<()> {
+        let keysize = table.buckets.keysize();
+        let valsize = table.buckets.valsize();
+        let mut records = vec![];
+        for bucket in 0..table.nbuckets {
+            for (_, page_records) in table.buckets.all_records_in_bucket(bucket) {
+                records.extend(page_records);
+            }
+        }
+
+        let mut keys: Vec<&[u8]> = records.iter().map(|(k, _)| &k[..]).collect();
+        keys.sort_unstable();
+        if keys.windows(2).any(|w| w[0] == w[1]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "cannot export a table with duplicate keys"));
+        }
+
+        let n = records.len();
+        let ngroups = n.div_ceil(GROUP_SIZE).max(1);
+        let mut groups: Vec<Vec<usize>> = vec![vec![]; ngroups];
+        for (i, (k, _)) in records.iter().enumerate() {
+            groups[(phf_hash(k, keysize, 0) % ngroups as u64) as usize].push(i);
+        }
+
+        // Place the largest groups first, while most slots are free.
+        let mut order: Vec<usize> = (0..ngroups).collect();
+        order.sort_by_key(|&g| ::std::cmp::Reverse(groups[g].len()));
+
+        let mut displacements = vec![0u32; ngroups];
+        let mut slot_of = vec![0usize; n];
+        let mut taken = vec![false; n];
+        let mut slots = vec![];
+        let max_d = (n as u64 * TRIES_PER_RECORD).clamp(1 << 16, u64::from(u32::MAX)) as u32;
+        for g in order {
+            if groups[g].is_empty() {
+                break;
+            }
+            let mut d: u32 = 1;
+            loop {
+                slots.clear();
+                let fits = groups[g].iter().all(|&i| {
+                    let slot = (phf_hash(&records[i].0, keysize, u64::from(d))
+                                % n as u64) as usize;
+                    let free = !taken[slot] && !slots.contains(&slot);
+                    slots.push(slot);
+                    free
+                });
+                if fits {
+                    break;
+                }
+                if d == max_d {
+                    return Err(io::Error::other(format!(
+                        "no displacement places a group of {} keys", groups[g].len())));
+                }
+                d += 1;
+            }
+            displacements[g] = d;
+            for (&i, &slot) in groups[g].iter().zip(slots.iter()) {
+                taken[slot] = true;
+                slot_of[i] = slot;
+            }
+        }
+
+        let mut body = vec![0; n * (keysize + valsize)];
+        for (i, (k, v)) in records.iter().enumerate() {
+            let offset = slot_of[i] * (keysize + valsize);
+            mem_move(&mut body[offset..], k).expect("record too wide");
+            mem_move(&mut body[offset + keysize..], v).expect("record too wide");
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        for &word in &[n, ngroups, keysize, valsize] {
+            file.write_all(&usize_to_bytearray(word))?;
+        }
+        for &d in &displacements {
+            file.write_all(&d.to_le_bytes())?;
+        }
+        file.write_all(&body)?;
+        file.sync_all()
+    }
+
+    /// Loads an export written by `build`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FrozenTable> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if data.len() < HEADER_WORDS * USIZE_WIDTH || &data[..8] != MAGIC {
+            return Err(invalid("not a linhash perfect-hash export"));
+        }
+        let word = |i: usize| read_usize_at(&data, i * USIZE_WIDTH)
+            .expect("header length checked above");
+        let (n, ngroups, keysize, valsize) = (word(1), word(2), word(3), word(4));
+
+        let disp_start = HEADER_WORDS * USIZE_WIDTH;
+        let records_start = disp_start + ngroups * 4;
+        if data.len() != records_start + n * (keysize + valsize) {
+            return Err(invalid("truncated perfect-hash export"));
+        }
+        let displacements = data[disp_start..records_start]
+            .chunks(4)
+            .map(decode::<u32>)
+            .collect();
+
+        Ok(FrozenTable {
+            keysize,
+            valsize,
+            displacements,
+            records: data[records_start..].to_vec(),
+        })
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.records.len().checked_div(self.keysize + self.valsize)
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Looks up `key` with a single probe.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let n = self.len() as u64;
+        if n == 0 {
+            return None;
+        }
+        let ngroups = self.displacements.len() as u64;
+        let group = (phf_hash(key, self.keysize, 0) % ngroups) as usize;
+        let d = u64::from(self.displacements[group]);
+        let slot = (phf_hash(key, self.keysize, d) % n) as usize;
+
+        let offset = slot * (self.keysize + self.valsize);
+        let stored_key = &self.records[offset..offset + self.keysize];
+        if key_eq(stored_key, key) {
+            Some(&self.records[offset + self.keysize..
+                               offset + self.keysize + self.valsize])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use LinHash;
+    use phf::FrozenTable;
+    use std::fs;
+    use std::io;
+    use util::*;
+
+    #[test]
+    fn frozen_export() {
+        fs::remove_file("/tmp/test_frozen_export").ok();
+        let mut h = LinHash::open("/tmp/test_frozen_export", 4, 4);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k * 2));
+        }
+        FrozenTable::build(&mut h, "/tmp/test_frozen_export.phf").unwrap();
+        h.close();
+
+        let frozen = FrozenTable::open("/tmp/test_frozen_export.phf").unwrap();
+        assert_eq!(frozen.len(), 3000);
+        for k in 0..3000 {
+            assert_eq!(frozen.get(&encode(k)), Some(&encode(k * 2)[..]));
+        }
+        assert_eq!(frozen.get(&encode(3000)), None);
+
+        fs::remove_file("/tmp/test_frozen_export").ok();
+        fs::remove_file("/tmp/test_frozen_export.phf").ok();
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        let path = "/tmp/test_frozen_duplicates";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::options().keysize(4).valsize(4).duplicate_keys(true)
+            .open(path).unwrap();
+        h.put(&encode(1), &encode(1));
+        h.put(&encode(2), &encode(2));
+        h.put(&encode(1), &encode(3));
+        let e = FrozenTable::build(&mut h, "/tmp/test_frozen_duplicates.phf").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        h.close();
+        fs::remove_file(path).ok();
+        fs::remove_file("/tmp/test_frozen_duplicates.phf").ok();
+    }
+}
@@ -0,0 +1,119 @@
+//! Progress reporting, cancellation and throttling for long-running
+//! operations: `dump` and `restore`, `verify`, the `compact` family,
+//! `try_bulk_load` and `ExpiringLinHash::purge_expired`.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Receives `(done, total)` reports as an operation advances. Units
+/// are pages for operations that scan a table, and records for those
+/// that read a stream, where `total` is 0 because it is not known in
+/// advance. Any `FnMut(usize, usize)` closure is a `Progress`.
+pub trait Progress {
+    fn report(&mut self, done: usize, total: usize);
+}
+
+impl<F: FnMut(usize, usize)> Progress for F {
+    fn report(&mut self, done: usize, total: usize) {
+        self(done, total)
+    }
+}
+
+/// Discards all reports.
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&mut self, _done: usize, _total: usize) {}
+}
+
+/// Shared flag asking a running operation to stop. Clone it, hand one
+/// copy to the operation and call `cancel` on another, eg. from a
+/// signal handler thread. Operations check it between units of work
+/// that leave the table consistent and then fail with
+/// `ErrorKind::Interrupted`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// `Err(Interrupted)` once cancelled, for use with `?` at a safe
+    /// stopping point.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "operation cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Limits a scan to `pages_per_sec` by sleeping in `report` whenever
+/// the scan is ahead of its budget, then passes the report on to the
+/// wrapped `Progress`. Wrap the progress argument of a scanning
+/// operation, such as a background `verify`, `compact_all` or
+/// `purge_expired`, to keep it from starving foreground reads and
+/// writes of disk bandwidth.
+pub struct Throttle<P> {
+    inner: P,
+    pages_per_sec: f64,
+    start: Instant,
+}
+
+impl<P: Progress> Throttle<P> {
+    pub fn new(pages_per_sec: f64, inner: P) -> Throttle<P> {
+        assert!(pages_per_sec > 0.0, "pages_per_sec must be positive");
+        Throttle { inner, pages_per_sec, start: Instant::now() }
+    }
+}
+
+impl<P: Progress> Progress for Throttle<P> {
+    fn report(&mut self, done: usize, total: usize) {
+        let due = Duration::from_secs_f64(done as f64 / self.pages_per_sec);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        self.inner.report(done, total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn throttle_and_cancel() {
+        let mut seen = vec![];
+        {
+            let mut t = Throttle::new(100.0, |done, total| seen.push((done, total)));
+            let start = Instant::now();
+            t.report(5, 10);
+            t.report(10, 10);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+        assert_eq!(seen, vec![(5, 10), (10, 10)]);
+
+        let cancel = CancelToken::new();
+        assert!(cancel.check().is_ok());
+        cancel.clone().cancel();
+        assert!(cancel.is_cancelled());
+        assert_eq!(cancel.check().unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+}
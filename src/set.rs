@@ -0,0 +1,119 @@
+//! A persistent set of fixed-width keys, built on `LinHash` with an
+//! empty value slot.
+
+use std::path::Path;
+
+use disk::Records;
+use LinHash;
+
+/// Linear Hash Set. Stores only keys, so each page holds as many
+/// records as `keysize` allows, while growing and persisting exactly
+/// like `LinHash`.
+pub struct LinSet {
+    table: LinHash,
+}
+
+impl LinSet {
+    /// Opens (or creates) the set stored in `filename`.
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize) -> LinSet {
+        LinSet {
+            table: LinHash::open(filename, keysize, 0),
+        }
+    }
+
+    /// Adds `key` to the set. Returns `false` if it was already present.
+    pub fn insert(&mut self, key: &[u8]) -> bool {
+        if self.table.contains(key) {
+            return false;
+        }
+        self.table.put(key, &[]);
+        true
+    }
+
+    /// Is `key` in the set?
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.table.contains(key)
+    }
+
+    /// Removes `key` from the set. Returns `false` if it wasn't present.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.table.remove(key).is_some()
+    }
+
+    /// Iterates over all keys, one page at a time. Keys are returned
+    /// zero-padded to `keysize`.
+    pub fn iter(&mut self) -> Iter<'_> {
+        Iter {
+            keys: self.table.keys(),
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.table.close();
+    }
+}
+
+/// Iterator over the keys of a `LinSet`. Only the keys of the page
+/// currently being visited are held in memory.
+pub struct Iter<'a> {
+    keys: Records<'a, Vec<u8>>,
+}
+
+impl<'a> Iter<'a> {
+    /// Up to `n` keys in one call; see `Records::next_batch`. Returns
+    /// an empty Vec once the set is exhausted.
+    pub fn next_batch(&mut self, n: usize) -> Vec<Vec<u8>> {
+        self.keys.next_batch(n)
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.keys.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use LinSet;
+    use std::fs;
+
+    #[test]
+    fn set_ops() {
+        fs::remove_file("/tmp/test_set_ops").ok();
+        let mut s = LinSet::open("/tmp/test_set_ops", 4);
+        assert!(s.insert(b"ab"));
+        assert!(s.insert(b"abc"));
+        assert!(!s.insert(b"ab"));
+        assert!(s.contains(b"abc"));
+        assert!(s.remove(b"abc"));
+        assert!(!s.remove(b"abc"));
+        assert!(!s.contains(b"abc"));
+        for k in 0..2000u32 {
+            s.insert(&k.to_le_bytes());
+        }
+        s.close();
+
+        let mut s2 = LinSet::open("/tmp/test_set_ops", 4);
+        assert!(s2.contains(b"ab"));
+        assert!(s2.contains(&1999u32.to_le_bytes()));
+        assert_eq!(s2.iter().count(), 2001);
+        let mut it = s2.iter();
+        let mut batched = vec![];
+        loop {
+            let batch = it.next_batch(300);
+            assert!(batch.len() <= 300);
+            if batch.is_empty() {
+                break;
+            }
+            batched.extend(batch);
+        }
+        batched.sort();
+        batched.dedup();
+        assert_eq!(batched.len(), 2001);
+        s2.close();
+        fs::remove_file("/tmp/test_set_ops").ok();
+    }
+}
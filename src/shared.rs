@@ -0,0 +1,493 @@
+//! Sharing one database between processes: a single writer, arbitrated
+//! by an advisory lock on a `<file>.lock` sidecar, and any number of
+//! `SharedReader`s looking at the file through a shared memory map.
+//! Tables opened read-only take the lock shared instead, so they can
+//! be open together, but not alongside a writer.
+//! Readers in turn hold a shared lock on `<file>.readers`, and the
+//! writer only gives free pages at the end of the file back while it
+//! can take that lock exclusively: a reader's map would fault past the
+//! new end.
+//!
+//! Readers see the table as of the writer's last `LinHash::flush` (or
+//! `close`). Every structural change bumps the epoch counter in the
+//! control page; a reader samples it before and after each lookup and
+//! retries if it moved, so it never follows a bucket chain that was
+//! rearranged halfway through the read. Pages the writer evicts from
+//! its buffer pool between flushes can still reach the file early, so
+//! a lookup racing with a split may miss a key that is being moved;
+//! it never returns another key's value.
+//!
+//! A reader also remembers the file id and epoch it first saw. If the
+//! file is later rewritten in place by something other than its
+//! writer (a restore or migration into the same path), the id changes
+//! or the epoch goes backwards, and lookups fail instead of reading
+//! unrelated contents.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use disk::{format_version, latest_ctrl, sidecar_path, map_slot, map_start, CTRL_EPOCH,
+           CTRL_FILE_ID, CTRL_GEOMETRY, CTRL_MAP_PAGES, CTRL_MAP_START, CTRL_HASH_SEED,
+           CTRL_PAGE_LAYOUT, EXTENDED_HEADERS, HASHER_SHIFT, LAYOUT_MASK, PAGE_CHECKSUMS,
+           PAGE_LSNS, PARTIAL_EXPANSIONS};
+use hasher::{KeyHasher, SIP_HASHER_ID};
+use linear::{self, bucket_index};
+use mmap::MappedFile;
+use page::{Page, PageLayout, PAGE_SIZE};
+use util::*;
+
+/// Number of attempts a `SharedReader` makes before giving up on a
+/// lookup that keeps racing with the writer.
+const MAX_RETRIES: usize = 64;
+
+/// Exclusive (or shared) lock on `<path>.lock`, released on drop.
+pub struct WriterLock {
+    _file: Option<File>,
+}
+
+impl WriterLock {
+    /// Takes the writer lock for the database at `path`, failing
+    /// immediately if another handle (in this or another process)
+    /// holds it.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> io::Result<WriterLock> {
+        let lock_path = sidecar_path(path.as_ref(), ".lock");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(WriterLock { _file: Some(file) }),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by another writer or by read-only handles",
+                        lock_path.display()))),
+        }
+    }
+
+    /// Takes the lock for the database at `path` shared, as a
+    /// read-only handle, failing immediately if a writer holds it.
+    /// Only creates the lock file if it is missing, so an existing one
+    /// needs no write permission.
+    pub fn acquire_shared<P: AsRef<Path>>(path: P) -> io::Result<WriterLock> {
+        let lock_path = sidecar_path(path.as_ref(), ".lock");
+        let file = match OpenOptions::new().read(true).open(&lock_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)?,
+            file => file?,
+        };
+        match file.try_lock_shared() {
+            Ok(()) => Ok(WriterLock { _file: Some(file) }),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by a writer", lock_path.display()))),
+        }
+    }
+
+    /// A lock that excludes no one, for a table without sidecar files
+    /// (see `PageStore::sidecar_files`), which no other handle can
+    /// open.
+    pub fn unlocked() -> WriterLock {
+        WriterLock { _file: None }
+    }
+}
+
+/// Takes the lock on `<path>.readers` shared, as a `SharedReader`
+/// does for as long as it has the file mapped: the writer only
+/// shrinks the file under the exclusive lock (see `exclude_readers`),
+/// as a map past the new end would fault. Waits out a shrink in
+/// progress.
+fn lock_readers(path: &Path) -> io::Result<File> {
+    let lock_path = sidecar_path(path, ".readers");
+    let file = match OpenOptions::new().read(true).open(&lock_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?,
+        file => file?,
+    };
+    file.lock_shared()?;
+    Ok(file)
+}
+
+/// The lock on `<path>.readers` exclusive, or None if a `SharedReader`
+/// has the file mapped. Shrinking the file is only safe while it is
+/// held.
+pub fn exclude_readers(path: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(sidecar_path(path, ".readers"))?;
+    Ok(file.try_lock().ok().map(|()| file))
+}
+
+/// Read-only view of a database that another process may be writing.
+pub struct SharedReader {
+    file: File,
+    map: MappedFile,
+    // shared lock on `<file>.readers`, see `lock_readers`
+    _readers: File,
+    keysize: usize,
+    valsize: usize,
+    file_id: usize,
+    last_epoch: usize,
+    // the table's built-in hasher is learnt from its first ctrl page
+    hasher: Option<KeyHasher>,
+}
+
+impl SharedReader {
+    pub fn open<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize)
+                                -> io::Result<SharedReader> {
+        SharedReader::open_table(filename, keysize, valsize, None)
+    }
+
+    /// Like `open`, for a table created with `LinHash::open_with_hasher`.
+    pub fn open_with_hasher<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                            hasher: KeyHasher) -> io::Result<SharedReader> {
+        SharedReader::open_table(filename, keysize, valsize, Some(hasher))
+    }
+
+    fn open_table<P: AsRef<Path>>(filename: P, keysize: usize, valsize: usize,
+                                  hasher: Option<KeyHasher>) -> io::Result<SharedReader> {
+        let file = File::open(&filename)?;
+        let readers = lock_readers(filename.as_ref())?;
+        let map = MappedFile::map(&file)?;
+        let mut reader = SharedReader {
+            file, map, _readers: readers, keysize, valsize, file_id: 0, last_epoch: 0, hasher,
+        };
+        reader.file_id = reader.word(CTRL_FILE_ID).unwrap_or(0);
+        reader.last_epoch = reader.epoch();
+        Ok(reader)
+    }
+
+    /// Fails if the file no longer looks like the one this reader
+    /// opened. Otherwise records the current epoch.
+    fn validate(&mut self) -> io::Result<()> {
+        let file_id = self.word(CTRL_FILE_ID).unwrap_or(0);
+        let epoch = self.epoch();
+        if self.file_id == 0 {
+            // the writer had not flushed a ctrl page yet
+            self.file_id = file_id;
+        } else if file_id != self.file_id {
+            return Err(io::Error::other("stale reader: file id changed"));
+        }
+        if epoch < self.last_epoch {
+            return Err(io::Error::other("stale reader: file generation went backwards"));
+        }
+        if self.file_id != 0 {
+            self.check_hasher()?;
+        }
+        self.last_epoch = epoch;
+        Ok(())
+    }
+
+    /// Fails unless the reader's hasher is the table's, taking the
+    /// table's built-in hasher if the reader was opened without one.
+    fn check_hasher(&mut self) -> io::Result<()> {
+        let id = self.layout_word().map_or(SIP_HASHER_ID, |word| (word >> HASHER_SHIFT) as u16);
+        let hasher = match self.hasher.take() {
+            Some(h) => if h.id() == id { Some(h) } else { None },
+            None => {
+                let seed = read_u64_at(self.ctrl(), CTRL_HASH_SEED).unwrap_or(0);
+                KeyHasher::builtin(id, seed)
+            },
+        };
+        match hasher {
+            Some(h) => {
+                self.hasher = Some(h);
+                Ok(())
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("table was created with hasher {}; open it with that hasher", id))),
+        }
+    }
+
+    /// Epoch of the last control page the writer flushed.
+    pub fn epoch(&self) -> usize {
+        self.word(CTRL_EPOCH).unwrap_or(0)
+    }
+
+    /// The latest intact copy of the ctrl page; see `disk`.
+    fn ctrl(&self) -> &[u8] {
+        let data = self.map.as_slice();
+        let primary = &data[..data.len().min(PAGE_SIZE)];
+        latest_ctrl(primary, data.get(PAGE_SIZE..2 * PAGE_SIZE))
+    }
+
+    /// A word of the ctrl page.
+    fn word(&self, offset: usize) -> Option<usize> {
+        read_usize_at(self.ctrl(), offset).ok()
+    }
+
+    /// The page layout word; `None` for files from before it existed.
+    fn layout_word(&self) -> Option<u64> {
+        if map_start(self.ctrl()) == CTRL_MAP_START {
+            read_u64_at(self.ctrl(), CTRL_PAGE_LAYOUT).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Lookup `key`. Panics if the file was replaced underneath this
+    /// reader; see `try_get`.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.try_get(key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get`, but returns an error instead of panicking when the
+    /// file is no longer the one this reader opened.
+    pub fn try_get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        for _ in 0..MAX_RETRIES {
+            self.validate()?;
+            let before = self.epoch();
+            let found = self.search(key);
+            if self.epoch() == before {
+                if let Ok(found) = found {
+                    return Ok(found);
+                }
+            }
+            // The file may have grown past our mapping.
+            if let Ok(map) = MappedFile::map(&self.file) {
+                self.map = map;
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// One attempt at a lookup. `Err` means the file looked
+    /// inconsistent (a page or bucket out of range), which happens
+    /// when the writer is mid-change or the mapping is stale.
+    fn search(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        let nbits = self.word(0).ok_or(())?;
+        let nbuckets = self.word(16).ok_or(())?;
+        if nbits == 0 || nbits >= 64 || nbuckets == 0 {
+            return Err(());
+        }
+        let hasher = self.hasher.as_ref().ok_or(())?;
+        let map_start = map_start(self.ctrl());
+        let (layout, checksums, partial, extended, lsns) = match self.layout_word() {
+            Some(word) => (PageLayout::from_word((word & LAYOUT_MASK) as usize)
+                               .ok_or(())?,
+                           word & PAGE_CHECKSUMS != 0, word & PARTIAL_EXPANSIONS != 0,
+                           word & EXTENDED_HEADERS != 0, word & PAGE_LSNS != 0),
+            None => (PageLayout::Row, false, false, false, false),
+        };
+        let mut page = Page::with_layout(self.keysize, self.valsize, layout);
+        page.extended = extended;
+        page.lsns = lsns;
+        let hash = hasher.hash_key(key, self.keysize);
+        let bucket = if partial {
+            linear::partial_bucket_index(hash, nbuckets)
+        } else {
+            bucket_index(hash, nbits, nbuckets)
+        };
+        let found = self.search_bucket(bucket, key, map_start, &mut page, checksums)?;
+        // the writer may be moving the last bucket's records out of the
+        // one it was split from; see `OpenOptions::incremental_splits`
+        if found.is_none() && bucket == nbuckets - 1 && nbuckets > 2 && !partial {
+            let from = linear::shrink(nbits, nbuckets).1;
+            return self.search_bucket(from, key, map_start, &mut page, checksums);
+        }
+        Ok(found)
+    }
+
+    /// Entry `bucket` of the bucket map, which may be in a map page.
+    fn map_entry(&self, bucket: usize, map_start: usize) -> Result<usize, ()> {
+        if format_version(self.ctrl()) < Some(6) {
+            let entry = map_start + bucket * USIZE_WIDTH;
+            return self.word(entry).filter(|_| entry < CTRL_GEOMETRY).ok_or(());
+        }
+        let (links, offset) = match map_slot(bucket) {
+            (None, offset) => return self.word(offset).ok_or(()),
+            (Some(links), offset) => (links, offset),
+        };
+        let data = self.map.as_slice();
+        let word = |page_id: usize, offset: usize| page_id.checked_mul(PAGE_SIZE)
+            .filter(|_| page_id != 0)
+            .and_then(|start| read_usize_at(data, start + offset).ok())
+            .ok_or(());
+        let mut page_id = self.word(CTRL_MAP_PAGES).ok_or(())?;
+        for _ in 0..links {
+            page_id = word(page_id, 0)?;
+        }
+        word(page_id, offset)
+    }
+
+    /// Looks for `key` in the chain of `bucket`.
+    /// `page` is a blank page of the table's layout to read pages into.
+    fn search_bucket(&self, bucket: usize, key: &[u8], map_start: usize, page: &mut Page,
+                     checksums: bool) -> Result<Option<Vec<u8>>, ()> {
+        let mut page_id = self.map_entry(bucket, map_start)?;
+        let data = self.map.as_slice();
+        let layout = page.layout();
+        let capacity = page.max_records();
+        // a chain can't be longer than the file has pages
+        for _ in 0..data.len() / PAGE_SIZE {
+            let start = page_id * PAGE_SIZE;
+            let bytes = data.get(start..start + PAGE_SIZE).ok_or(())?;
+            page.storage.copy_from_slice(bytes);
+            if checksums && !page.checksum_ok() {
+                return Err(());
+            }
+            page.read_header();
+            if page.num_records > capacity {
+                return Err(());
+            }
+            for row in 0..page.num_records {
+                let (k, v) = page.read_record(row);
+                if layout.key_eq(k, key) {
+                    return Ok(Some(v.to_vec()));
+                }
+            }
+            match page.next {
+                Some(next) => page_id = next,
+                None => return Ok(None),
+            }
+        }
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use LinHash;
+    use shared::{SharedReader, WriterLock};
+    use std::fs;
+    use std::io;
+    use util::*;
+
+    #[test]
+    fn shared_reader_follows_writer() {
+        fs::remove_file("/tmp/test_shared_reader").ok();
+        let mut h = LinHash::open("/tmp/test_shared_reader", 4, 4);
+        assert!(WriterLock::acquire("/tmp/test_shared_reader").is_err());
+        for k in 0..100 {
+            h.put(&encode(k), &encode(k + 1));
+        }
+        h.flush();
+
+        let mut r = SharedReader::open("/tmp/test_shared_reader", 4, 4).unwrap();
+        assert_eq!(r.get(&encode(7)), Some(encode(8)));
+        assert_eq!(r.get(&encode(500)), None);
+
+        let epoch = r.epoch();
+        for k in 100..3000 {
+            h.put(&encode(k), &encode(k + 1));
+        }
+        h.flush();
+        assert!(r.epoch() > epoch);
+        assert_eq!(r.get(&encode(2999)), Some(encode(3000)));
+        assert_eq!(r.get(&encode(7)), Some(encode(8)));
+
+        h.close();
+        assert!(WriterLock::acquire("/tmp/test_shared_reader").is_ok());
+        fs::remove_file("/tmp/test_shared_reader").ok();
+        fs::remove_file("/tmp/test_shared_reader.lock").ok();
+        fs::remove_file("/tmp/test_shared_reader.readers").ok();
+    }
+
+    #[test]
+    fn file_keeps_its_length_while_mapped() {
+        let path = "/tmp/test_shared_shrink";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..20000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.flush();
+        let size = fs::metadata(path).unwrap().len();
+        let mut r = SharedReader::open(path, 4, 4).unwrap();
+        // merges free the pages at the end, which stay while mapped
+        for k in 0..19990 {
+            h.remove(&encode(k));
+        }
+        h.flush();
+        assert_eq!(fs::metadata(path).unwrap().len(), size);
+        assert_eq!(r.get(&encode(19995)), Some(encode(19995)));
+        assert_eq!(r.get(&encode(5)), None);
+        drop(r);
+        h.close();
+        assert!(fs::metadata(path).unwrap().len() < size / 10);
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert_eq!(h.len(), 10);
+        assert!(h.verify().unwrap().is_ok());
+        h.close();
+        for ext in &["", ".lock", ".readers"] {
+            fs::remove_file(format!("{}{}", path, ext)).ok();
+        }
+    }
+
+    #[test]
+    fn replaced_file_is_detected() {
+        let path = "/tmp/test_stale_handle";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        h.put(&encode(1), &encode(1));
+        h.flush();
+        let mut r = SharedReader::open(path, 4, 4).unwrap();
+        assert_eq!(r.try_get(&encode(1)).unwrap(), Some(encode(1)));
+
+        // rewrite the file in place with another table's contents
+        fs::remove_file("/tmp/test_stale_handle_other").ok();
+        let mut other = LinHash::open("/tmp/test_stale_handle_other", 4, 4);
+        other.put(&encode(1), &encode(2));
+        other.close();
+        h.flush();
+        fs::write(path, fs::read("/tmp/test_stale_handle_other").unwrap()).unwrap();
+        assert!(r.try_get(&encode(1)).is_err());
+        assert!(h.check_handle().is_err());
+
+        // replaced by a new file at the same path
+        fs::remove_file(path).ok();
+        fs::copy("/tmp/test_stale_handle_other", path).unwrap();
+        assert!(h.check_handle().is_err());
+        drop(h);
+
+        for p in &[path, "/tmp/test_stale_handle_other"] {
+            fs::remove_file(p).ok();
+            fs::remove_file(format!("{}.lock", p)).ok();
+            fs::remove_file(format!("{}.journal", p)).ok();
+        }
+    }
+
+    #[test]
+    fn writer_and_reader_locks() {
+        let path = "/tmp/test_lock_modes";
+        let lock_path = "/tmp/test_lock_modes.lock";
+        fs::remove_file(lock_path).ok();
+        // the shared lock creates a missing lock file
+        let a = WriterLock::acquire_shared(path).unwrap();
+        assert!(fs::metadata(lock_path).is_ok());
+        let b = WriterLock::acquire_shared(path).unwrap();
+        let e = WriterLock::acquire(path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert!(e.to_string().contains("read-only handles"));
+        drop(a);
+        assert!(WriterLock::acquire(path).is_err());
+        drop(b);
+
+        let w = WriterLock::acquire(path).unwrap();
+        let e = WriterLock::acquire_shared(path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert!(e.to_string().contains("locked by a writer"));
+        drop(w);
+        assert!(WriterLock::acquire_shared(path).is_ok());
+        fs::remove_file(lock_path).ok();
+    }
+}
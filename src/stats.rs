@@ -0,0 +1,31 @@
+//! A snapshot of how a table is laid out, for monitoring. See
+//! `LinHash::stats`.
+
+/// Counts and ratios describing a table at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of records.
+    pub items: usize,
+    pub buckets: usize,
+    /// Pages chained after a bucket's first.
+    pub overflow_pages: usize,
+    /// Pages on the free list, waiting to be reused.
+    pub free_pages: usize,
+    /// Pages per bucket, first page included.
+    pub avg_chain_len: f64,
+    pub max_chain_len: usize,
+    /// Size of the table's store, not counting the sidecar files.
+    pub bytes_on_disk: u64,
+    /// Records per bucket page slot; the table splits when this goes
+    /// over its split threshold.
+    pub load_factor: f32,
+}
+
+impl Stats {
+    /// Share of the table's pages sitting on the free list: how much
+    /// `LinHash::reclaim_space` could give back at best.
+    pub fn free_ratio(&self) -> f64 {
+        let pages = self.buckets + self.overflow_pages + self.free_pages;
+        self.free_pages as f64 / pages as f64
+    }
+}
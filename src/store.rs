@@ -0,0 +1,520 @@
+//! Where the pager keeps its pages. `DbFile` reads and writes whole
+//! pages through a `PageStore`, so a table can live somewhere other
+//! than a plain file (an encrypted file, a block device over the
+//! network, memory in a test) with everything above the pager
+//! unchanged.
+//!
+//! The split journal, the write-ahead log and the writer lock are
+//! sidecar files named after the table, unless the store says it has
+//! no use for them (see `PageStore::sidecar_files`). A table in a
+//! `MemStore` touches no file at all, which is what lets the crate run
+//! where there is no file system, eg. on `wasm32-unknown-unknown`: a
+//! browser store (IndexedDB, OPFS) is a `PageStore` implemented
+//! outside the crate.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{IoSliceMut, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crypt::Cipher;
+use mmap::MappedFile;
+use page::PAGE_SIZE;
+
+/// Storage for the pages of one table, addressed by page id. Every
+/// read and write is of exactly `PAGE_SIZE` bytes.
+pub trait PageStore: Send {
+    /// Reads page `page_id` into `data`. A page that was never written
+    /// reads as zeroes.
+    fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()>;
+
+    fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Reads the pages from `first` on into `data`, one slice per page,
+    /// eg. an overflow chain laid out in order. Stores that can should
+    /// do it in one request.
+    fn read_pages(&mut self, first: usize, data: &mut [&mut [u8]]) -> io::Result<()> {
+        for (i, page) in data.iter_mut().enumerate() {
+            self.read_page(first + i, page)?;
+        }
+        Ok(())
+    }
+
+    /// A hint that page `page_id` is about to be read, eg. the next page
+    /// of an overflow chain, so that fetching it can overlap other
+    /// work. Stores that cannot act on it ignore it.
+    fn prefetch(&mut self, _page_id: usize) {}
+
+    /// Page `page_id` where it lies in memory, for a store that can
+    /// lend it out without copying, eg. a memory map. None if it
+    /// cannot, in which case the page is read with `read_page`.
+    fn mapped_page(&self, _page_id: usize) -> Option<&[u8]> {
+        None
+    }
+
+    /// Reserves page `page_id`, which lies past every page written so
+    /// far, as zeroes, so that running out of space shows here rather
+    /// than when the page is first written back.
+    fn allocate(&mut self, page_id: usize) -> io::Result<()> {
+        self.write_page(page_id, &[0; PAGE_SIZE])
+    }
+
+    /// Makes every write so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Shrinks the store to `len` bytes, dropping the pages past it.
+    /// Stores that cannot shrink may leave them be: they are never read
+    /// again before being reallocated.
+    fn truncate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Size of the store in bytes; 0 for a new table.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// The file underneath, if there is one: lets `DbFile` notice the
+    /// file being replaced, and map it for `IoBackend::Mmap`.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Whether the table keeps its split journal, write-ahead log and
+    /// writer lock in files next to it. Without them a crash can leave
+    /// the table inconsistent, which is only safe for a store that
+    /// does not outlive the process anyway.
+    fn sidecar_files(&self) -> bool {
+        true
+    }
+
+    /// For a store that encrypts its pages, a cipher under the same
+    /// key for the split journal and write-ahead log, which would
+    /// otherwise hold records in the clear.
+    fn sidecar_cipher(&self) -> Option<Cipher> {
+        None
+    }
+}
+
+/// How a `FileStore` reads pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// A seek and a read per page.
+    File,
+    /// Out of a shared memory map of the file, remapped as the file
+    /// grows. Lookups search the map in place and `get_ref` borrows
+    /// values from it; pages brought into the buffer pool are copied
+    /// out. Writes still go through the file, which the map sees.
+    /// Where there is no mmap, the same as `File`.
+    Mmap,
+}
+
+/// Alignment of the buffers, file offsets and lengths of unbuffered
+/// I/O: the largest logical block size in common use.
+const DIRECT_ALIGN: usize = 4096;
+
+/// Flag asking `open` to bypass the page cache.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const DIRECT_FLAG: i32 =
+    if cfg!(any(target_arch = "aarch64", target_arch = "arm")) {
+        0o200000
+    } else if cfg!(any(target_arch = "powerpc", target_arch = "powerpc64")) {
+        0o400000
+    } else if cfg!(any(target_arch = "mips", target_arch = "mips64")) {
+        0o100000
+    } else {
+        0o40000
+    };
+// FILE_FLAG_NO_BUFFERING
+#[cfg(windows)]
+const DIRECT_FLAG: u32 = 0x2000_0000;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_direct(options: &mut OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(DIRECT_FLAG);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_direct(options: &mut OpenOptions) -> io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    options.custom_flags(DIRECT_FLAG);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn set_direct(_options: &mut OpenOptions) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "unbuffered I/O is only supported on Linux and Windows"))
+}
+
+/// Asks the OS to start reading `len` bytes at `offset` of `file` into
+/// the page cache. Only a hint, so failures are ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn advise_willneed(file: &File, offset: u64, len: usize) {
+    use std::convert::TryFrom;
+    use std::os::raw::{c_int, c_long};
+    use std::os::unix::io::AsRawFd;
+
+    const POSIX_FADV_WILLNEED: c_int = 3;
+
+    extern "C" {
+        fn posix_fadvise(fd: c_int, offset: c_long, len: c_long, advice: c_int) -> c_int;
+    }
+
+    if let (Ok(offset), Ok(len)) = (c_long::try_from(offset), c_long::try_from(len)) {
+        unsafe { posix_fadvise(file.as_raw_fd(), offset, len, POSIX_FADV_WILLNEED); }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn advise_willneed(_file: &File, _offset: u64, _len: usize) {}
+
+/// The `PAGE_SIZE` bytes of `buf` that start at a multiple of
+/// `DIRECT_ALIGN`; `buf` has to be that much longer than a page.
+fn aligned_page(buf: &mut [u8]) -> &mut [u8] {
+    let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+    &mut buf[start..start + PAGE_SIZE]
+}
+
+/// Reads page `page_id` of `file` into `data`, zeroing what lies past
+/// the end of the file.
+fn read_at(file: &mut File, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+    let offset = (page_id * PAGE_SIZE) as u64;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < data.len() {
+        let n = match file.read(&mut data[filled..]) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    for b in data[filled..].iter_mut() {
+        *b = 0;
+    }
+    Ok(())
+}
+
+/// Pages stored in a file, page `n` at byte `n * PAGE_SIZE`.
+pub struct FileStore {
+    file: File,
+    // set for `IoBackend::Mmap`
+    map: Option<MappedFile>,
+    // for a file opened with `open_direct`, room for an aligned page
+    // that every read and write goes through
+    direct: Option<Vec<u8>>,
+}
+
+impl FileStore {
+    /// Opens `path`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(FileStore::with_file(file))
+    }
+
+    /// Opens the existing file `path` for reading only: writing a page
+    /// fails.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(FileStore::with_file(file))
+    }
+
+    /// Like `open`, or `open_read_only`, but bypassing the OS page
+    /// cache: O_DIRECT on Linux, FILE_FLAG_NO_BUFFERING on Windows.
+    /// Fails with `ErrorKind::Unsupported` on other systems, and with
+    /// `InvalidInput` on file systems that refuse it, such as tmpfs.
+    pub fn open_direct<P: AsRef<Path>>(path: P, read_only: bool) -> io::Result<FileStore> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        if !read_only {
+            options.write(true).create(true).truncate(false);
+        }
+        set_direct(&mut options)?;
+        Ok(FileStore::with_direct_file(options.open(path)?))
+    }
+
+    pub fn with_file(file: File) -> FileStore {
+        FileStore { file, map: None, direct: None }
+    }
+
+    /// A store over `file`, opened for unbuffered I/O as by
+    /// `open_direct`.
+    pub fn with_direct_file(file: File) -> FileStore {
+        FileStore { file, map: None, direct: Some(vec![0; PAGE_SIZE + DIRECT_ALIGN]) }
+    }
+
+    /// Whether the page cache is bypassed; see `open_direct`.
+    pub fn is_direct(&self) -> bool {
+        self.direct.is_some()
+    }
+
+    pub fn io_backend(&self) -> IoBackend {
+        if self.map.is_some() { IoBackend::Mmap } else { IoBackend::File }
+    }
+
+    /// Switches how pages are read; see `IoBackend`.
+    pub fn set_io_backend(&mut self, backend: IoBackend) -> io::Result<()> {
+        self.map = match backend {
+            IoBackend::Mmap if cfg!(unix) => Some(MappedFile::map(&self.file)?),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn read_file(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+        match self.direct {
+            Some(ref mut buf) => {
+                let page = aligned_page(buf);
+                read_at(&mut self.file, page_id, page)?;
+                data.copy_from_slice(page);
+                Ok(())
+            },
+            None => read_at(&mut self.file, page_id, data),
+        }
+    }
+}
+
+impl PageStore for FileStore {
+    fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+        let end = (page_id + 1) * PAGE_SIZE;
+        let grown = match self.map {
+            Some(ref map) => map.len() < end && self.file.metadata()?.len() as usize > map.len(),
+            None => false,
+        };
+        if grown {
+            self.map = Some(MappedFile::map(&self.file)?);
+        }
+        match self.map.as_ref().and_then(|map| map.as_slice().get(end - PAGE_SIZE..end)) {
+            Some(bytes) => {
+                data.copy_from_slice(bytes);
+                Ok(())
+            },
+            // past the end of the file, or no map
+            None => self.read_file(page_id, data),
+        }
+    }
+
+    /// Only what was mapped so far: pages the file grew by since are
+    /// read, which remaps it.
+    fn mapped_page(&self, page_id: usize) -> Option<&[u8]> {
+        let end = (page_id + 1) * PAGE_SIZE;
+        self.map.as_ref().and_then(|map| map.as_slice().get(end - PAGE_SIZE..end))
+    }
+
+    /// Unbuffered files have no page cache to read ahead into.
+    fn prefetch(&mut self, page_id: usize) {
+        if self.direct.is_none() {
+            advise_willneed(&self.file, (page_id * PAGE_SIZE) as u64, PAGE_SIZE);
+        }
+    }
+
+    /// A single seek and vectored read, unless the file is mapped or
+    /// unbuffered.
+    fn read_pages(&mut self, first: usize, data: &mut [&mut [u8]]) -> io::Result<()> {
+        if self.map.is_some() || self.direct.is_some() {
+            for (i, page) in data.iter_mut().enumerate() {
+                self.read_page(first + i, page)?;
+            }
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start((first * PAGE_SIZE) as u64))?;
+        let mut filled = 0;
+        {
+            let mut slices: Vec<IoSliceMut> = data.iter_mut().map(|p| IoSliceMut::new(p))
+                .collect();
+            let mut rest = &mut slices[..];
+            while !rest.is_empty() {
+                let n = match self.file.read_vectored(rest) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+                IoSliceMut::advance_slices(&mut rest, n);
+            }
+        }
+        // past the end of the file
+        for (i, page) in data.iter_mut().enumerate() {
+            let start = filled.saturating_sub(i * PAGE_SIZE).min(page.len());
+            for b in page[start..].iter_mut() {
+                *b = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+        let offset = (page_id * PAGE_SIZE) as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        match self.direct {
+            Some(ref mut buf) => {
+                let page = aligned_page(buf);
+                page.copy_from_slice(data);
+                self.file.write_all(page)?;
+            },
+            None => self.file.write_all(data)?,
+        }
+        self.file.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        // the old map would fault past the new end of the file
+        if self.map.is_some() {
+            self.map = Some(MappedFile::map(&self.file)?);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Pages in a new file in the temp dir, which is gone once the store
+/// is dropped: on unix its name is removed as soon as it is open, so
+/// not even a crash leaves it behind. Like `MemStore`, but for tables
+/// bigger than memory. Uses no sidecar files.
+pub struct TempStore {
+    file: FileStore,
+    path: PathBuf,
+}
+
+impl TempStore {
+    pub fn new() -> io::Result<TempStore> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let name = format!("linhash-{}-{}-{}", process::id(),
+                               COUNT.fetch_add(1, Ordering::Relaxed), nanos);
+            let path = env::temp_dir().join(name);
+            let file = match OpenOptions::new().read(true).write(true).create_new(true)
+                .open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            if cfg!(unix) {
+                fs::remove_file(&path)?;
+            }
+            return Ok(TempStore { file: FileStore::with_file(file), path });
+        }
+    }
+
+    /// Where the file was created; on unix, no longer its name.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl PageStore for TempStore {
+    fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+        self.file.read_page(page_id, data)
+    }
+
+    fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+        self.file.write_page(page_id, data)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.truncate(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+
+    fn sidecar_files(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        if !cfg!(unix) {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Pages held in memory, lost on drop. Useful in tests, for scratch
+/// tables, and where there are no files. Uses no sidecar files.
+#[derive(Default)]
+pub struct MemStore {
+    pages: Vec<Vec<u8>>,
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        MemStore::default()
+    }
+}
+
+impl PageStore for MemStore {
+    fn read_page(&mut self, page_id: usize, data: &mut [u8]) -> io::Result<()> {
+        match self.pages.get(page_id) {
+            Some(page) => data.copy_from_slice(page),
+            None => data.iter_mut().for_each(|b| *b = 0),
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: usize, data: &[u8]) -> io::Result<()> {
+        if page_id >= self.pages.len() {
+            self.pages.resize(page_id + 1, vec![0; PAGE_SIZE]);
+        }
+        self.pages[page_id].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.pages.truncate(len as usize / PAGE_SIZE);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok((self.pages.len() * PAGE_SIZE) as u64)
+    }
+
+    fn sidecar_files(&self) -> bool {
+        false
+    }
+}
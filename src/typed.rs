@@ -0,0 +1,114 @@
+//! A typed layer over `LinHash`: keys and values are Rust values
+//! implementing `FixedWidth`, so their widths fix the table's geometry
+//! and no caller ever handles padding.
+
+use std::marker::PhantomData;
+use std::path::Path;
+
+use entry::Entry;
+use error;
+use util::{decode, FixedWidth};
+use LinHash;
+
+/// Linear Hashtable with keys of type `K` and values of type `V`,
+/// stored with `keysize = K::WIDTH` and `valsize = V::WIDTH`. The
+/// byte-level table underneath is reachable through `raw`.
+pub struct TypedLinHash<K: FixedWidth, V: FixedWidth> {
+    table: LinHash,
+    types: PhantomData<(K, V)>,
+}
+
+impl<K: FixedWidth, V: FixedWidth> TypedLinHash<K, V> {
+    /// Opens (or creates) the table stored in `filename`. Panics on
+    /// error; see `try_open`.
+    pub fn open<P: AsRef<Path>>(filename: P) -> TypedLinHash<K, V> {
+        TypedLinHash::try_open(filename).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_open<P: AsRef<Path>>(filename: P) -> error::Result<TypedLinHash<K, V>> {
+        Ok(TypedLinHash {
+            table: LinHash::try_open(filename, K::WIDTH, V::WIDTH)?,
+            types: PhantomData,
+        })
+    }
+
+    /// Stores `val` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: &K, val: &V) -> Option<V> {
+        let (k, v) = (encode_ref(key), encode_ref(val));
+        match self.table.entry(&k) {
+            Entry::Occupied(mut e) => Some(decode(&e.insert(&v))),
+            Entry::Vacant(e) => {
+                e.insert(&v);
+                None
+            },
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.table.get(&encode_ref(key)).map(|b| decode(&b))
+    }
+
+    pub fn contains_key(&mut self, key: &K) -> bool {
+        self.table.contains(&encode_ref(key))
+    }
+
+    /// Deletes `key`, returning its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.table.remove(&encode_ref(key)).map(|b| decode(&b))
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over every (key, value) pair. See `LinHash::iter`.
+    pub fn iter(&mut self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.table.iter().map(|(k, v)| (decode(&k), decode(&v)))
+    }
+
+    /// The byte-level table, eg. for `dump` or `build_filter`.
+    pub fn raw(&mut self) -> &mut LinHash {
+        &mut self.table
+    }
+
+    pub fn close(&mut self) {
+        self.table.close();
+    }
+}
+
+fn encode_ref<T: FixedWidth>(t: &T) -> Vec<u8> {
+    let mut buf = vec![0; T::WIDTH];
+    t.encode_into(&mut buf);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use typed::TypedLinHash;
+
+    #[test]
+    fn typed_ops() {
+        fs::remove_file("/tmp/test_typed_ops").ok();
+        let mut h: TypedLinHash<[u8; 8], u64> = TypedLinHash::open("/tmp/test_typed_ops");
+        assert_eq!(h.insert(b"apple\0\0\0", &3), None);
+        assert_eq!(h.insert(b"pear\0\0\0\0", &5), None);
+        assert_eq!(h.insert(b"apple\0\0\0", &4), Some(3));
+        assert_eq!(h.len(), 2);
+        h.close();
+
+        let mut h: TypedLinHash<[u8; 8], u64> = TypedLinHash::open("/tmp/test_typed_ops");
+        assert_eq!(h.get(b"apple\0\0\0"), Some(4));
+        assert!(h.contains_key(b"pear\0\0\0\0"));
+        assert_eq!(h.remove(b"pear\0\0\0\0"), Some(5));
+        assert_eq!(h.get(b"pear\0\0\0\0"), None);
+        assert_eq!(h.iter().collect::<Vec<_>>(), vec![(*b"apple\0\0\0", 4)]);
+        h.close();
+        fs::remove_file("/tmp/test_typed_ops").ok();
+    }
+}
@@ -1,53 +1,299 @@
-use std::mem::transmute;
+//! Byte-level helpers shared by the page and file layers, and a small
+//! fixed-width codec for storing numbers as values.
+//!
+//! Everything here uses little-endian byte order: both the `usize`
+//! conversions backing page and control-page headers, and the codec
+//! (`FixedWidth`, `encode`, `decode`) for values. Conversions that
+//! can be handed a slice of the wrong length return a `LengthError`
+//! instead of panicking or silently truncating, and a stored `usize`
+//! too big for the host's returns a `UsizeError`.
 
-pub fn mem_move(dest: &mut [u8], src: &[u8]) {
-    for (d, s) in dest.iter_mut().zip(src) {
-        *d = *s
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+/// Width in bytes of every `usize` stored on disk.
+pub const USIZE_WIDTH: usize = 8;
+
+/// A byte slice did not have the length a conversion required.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthError {
+    /// Length (or, for `bytevec_to_usize_vec`, multiple of length)
+    /// the conversion required.
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for LengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} bytes, got {}", self.expected, self.actual)
     }
 }
 
-pub fn usize_to_bytearray(n: usize) -> [u8; 8] {
-    unsafe {
-        transmute::<usize, [u8;8]>(n)
+impl Error for LengthError {}
+
+/// Bytes could not be read back as a `usize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsizeError {
+    /// The bytes did not have the length required.
+    Length(LengthError),
+    /// The stored number does not fit in a `usize` on this host.
+    Overflow(u64),
+}
+
+impl From<LengthError> for UsizeError {
+    fn from(e: LengthError) -> UsizeError {
+        UsizeError::Length(e)
     }
 }
 
-pub fn i32_to_bytearray(n: i32) -> [u8; 4] {
-    unsafe {
-        transmute::<i32, [u8;4]>(n)
+impl fmt::Display for UsizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsizeError::Length(ref e) => e.fmt(f),
+            UsizeError::Overflow(n) => write!(f, "{} does not fit in a usize", n),
+        }
+    }
+}
+
+impl Error for UsizeError {}
+
+/// Copies `src` to the start of `dest`. Fails without touching
+/// `dest` if `src` does not fit; bytes of `dest` beyond `src.len()`
+/// are left as they were.
+pub fn mem_move(dest: &mut [u8], src: &[u8]) -> Result<(), LengthError> {
+    if src.len() > dest.len() {
+        return Err(LengthError { expected: dest.len(), actual: src.len() });
+    }
+    dest[..src.len()].copy_from_slice(src);
+    Ok(())
+}
+
+/// Encodes `n` as 8 little-endian bytes, regardless of the width of
+/// `usize` on the host.
+pub fn usize_to_bytearray(n: usize) -> [u8; USIZE_WIDTH] {
+    (n as u64).to_le_bytes()
+}
+
+/// Decodes exactly 8 little-endian bytes written by
+/// `usize_to_bytearray`.
+pub fn bytearray_to_usize(b: &[u8]) -> Result<usize, UsizeError> {
+    if b.len() != USIZE_WIDTH {
+        return Err(LengthError { expected: USIZE_WIDTH, actual: b.len() }.into());
     }
+    let mut a = [0; USIZE_WIDTH];
+    a.copy_from_slice(b);
+    let n = u64::from_le_bytes(a);
+    usize::try_from(n).map_err(|_| UsizeError::Overflow(n))
 }
 
-pub fn usize_vec_to_bytevec(v: Vec<usize>) -> Vec<u8> {
-    let mut bv : Vec<u8> = vec![];
-    for i in v {
-        bv.append(&mut usize_to_bytearray(i).to_vec());
+#[deprecated(note = "use `util::encode` or the `LinHash::put_i32` helpers")]
+pub fn i32_to_bytearray(n: i32) -> [u8; 4] {
+    n.to_le_bytes()
+}
+
+/// Encodes each element with `usize_to_bytearray`, back to back.
+pub fn usize_vec_to_bytevec(v: &[usize]) -> Vec<u8> {
+    let mut bv = Vec::with_capacity(v.len() * USIZE_WIDTH);
+    for &i in v {
+        bv.extend_from_slice(&usize_to_bytearray(i));
     }
     bv
 }
 
-pub fn bytevec_to_usize_vec(b: Vec<u8>) -> Vec<usize> {
-    let mut v = vec![];
-    for i in 0..(b.len() / 8) {
-        v.push(bytearray_to_usize(b[i*8..(i+1)*8].to_vec()));
+/// Inverse of `usize_vec_to_bytevec`. `b.len()` must be a multiple
+/// of 8.
+pub fn bytevec_to_usize_vec(b: &[u8]) -> Result<Vec<usize>, UsizeError> {
+    if !b.len().is_multiple_of(USIZE_WIDTH) {
+        return Err(LengthError { expected: USIZE_WIDTH, actual: b.len() }.into());
     }
+    b.chunks(USIZE_WIDTH).map(bytearray_to_usize).collect()
+}
 
-    v
+/// Reads the `usize` stored at `buf[offset..offset+8]`.
+pub fn read_usize_at(buf: &[u8], offset: usize) -> Result<usize, UsizeError> {
+    match word_at(buf.len(), offset, USIZE_WIDTH) {
+        Ok(end) => bytearray_to_usize(&buf[offset..end]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `n` to `buf[offset..offset+8]`.
+pub fn write_usize_at(buf: &mut [u8], offset: usize, n: usize)
+                      -> Result<(), LengthError> {
+    let end = word_at(buf.len(), offset, USIZE_WIDTH)?;
+    mem_move(&mut buf[offset..end], &usize_to_bytearray(n))
 }
 
-pub fn bytearray_to_usize(b: Vec<u8>) -> usize {
-    assert_eq!(b.len(), 8);
+/// Reads the `u64` stored at `buf[offset..offset+8]`: for words whose
+/// bits mean the same on every host, like the ctrl page's flag words.
+pub fn read_u64_at(buf: &[u8], offset: usize) -> Result<u64, LengthError> {
+    let end = word_at(buf.len(), offset, 8)?;
     let mut a = [0; 8];
+    a.copy_from_slice(&buf[offset..end]);
+    Ok(u64::from_le_bytes(a))
+}
 
-    for i in 0..b.len() {
-        a[i] = b[i];
-    }
+/// Writes `n` to `buf[offset..offset+8]`.
+pub fn write_u64_at(buf: &mut [u8], offset: usize, n: u64) -> Result<(), LengthError> {
+    let end = word_at(buf.len(), offset, 8)?;
+    mem_move(&mut buf[offset..end], &n.to_le_bytes())
+}
 
-    unsafe {
-        transmute::<[u8;8], usize>(a)
+/// The end of a `width`-byte word at `offset` in a buffer of `len`
+/// bytes, if the buffer holds it.
+fn word_at(len: usize, offset: usize, width: usize) -> Result<usize, LengthError> {
+    match offset.checked_add(width) {
+        Some(end) if end <= len => Ok(end),
+        end => Err(LengthError { expected: end.unwrap_or(usize::MAX), actual: len }),
     }
 }
 
 pub fn slices_eq<T: PartialEq>(s1: &[T], s2: &[T]) -> bool {
     s1.iter().zip(s2).all(|(a,b)| a == b)
 }
+
+/// Does the zero-padded key slot `stored` hold `key`? Unlike
+/// `slices_eq`, `b"ab"` does not match a slot holding `b"abc"`.
+pub fn key_eq(stored: &[u8], key: &[u8]) -> bool {
+    stored.len() >= key.len()
+        && stored[..key.len()] == *key
+        && stored[key.len()..].iter().all(|&b| b == 0)
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 (IEEE 802.3, as used by zlib and PNG) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_extend(0, data)
+}
+
+/// CRC-32 of the concatenation of the data checksummed as `crc` and
+/// `data`, ie. `crc32_extend(crc32(a), b) == crc32(a ++ b)`.
+pub fn crc32_extend(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC32_TABLE[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+/// A number that can be stored as a fixed-width, little-endian value.
+pub trait FixedWidth: Sized {
+    /// Encoded width in bytes.
+    const WIDTH: usize;
+
+    /// Writes `self` into the first `WIDTH` bytes of `buf`. Panics if
+    /// `buf` is shorter than `WIDTH`.
+    fn encode_into(&self, buf: &mut [u8]);
+
+    /// Reads a value from the first `WIDTH` bytes of `buf`. Panics if
+    /// `buf` is shorter than `WIDTH`.
+    fn decode_from(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed_width {
+    ($($t:ty),*) => {
+        $(
+            impl FixedWidth for $t {
+                const WIDTH: usize = ::std::mem::size_of::<$t>();
+
+                fn encode_into(&self, buf: &mut [u8]) {
+                    buf[..Self::WIDTH].copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode_from(buf: &[u8]) -> Self {
+                    let mut a = [0; ::std::mem::size_of::<$t>()];
+                    a.copy_from_slice(&buf[..Self::WIDTH]);
+                    <$t>::from_le_bytes(a)
+                }
+            }
+        )*
+    }
+}
+
+impl_fixed_width!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// Byte arrays are stored as is, eg. for fixed-width string keys.
+impl<const N: usize> FixedWidth for [u8; N] {
+    const WIDTH: usize = N;
+
+    fn encode_into(&self, buf: &mut [u8]) {
+        buf[..N].copy_from_slice(self);
+    }
+
+    fn decode_from(buf: &[u8]) -> Self {
+        let mut a = [0; N];
+        a.copy_from_slice(&buf[..N]);
+        a
+    }
+}
+
+/// Encodes `n` as `T::WIDTH` little-endian bytes.
+pub fn encode<T: FixedWidth>(n: T) -> Vec<u8> {
+    let mut buf = vec![0; T::WIDTH];
+    n.encode_into(&mut buf);
+    buf
+}
+
+/// Decodes a `T` from the first `T::WIDTH` bytes of `b`. Trailing
+/// bytes (eg. the zero padding of a wider value slot) are ignored.
+pub fn decode<T: FixedWidth>(b: &[u8]) -> T {
+    T::decode_from(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use util::*;
+
+    #[test]
+    fn usize_conversions_validate_lengths() {
+        assert_eq!(usize_to_bytearray(258), [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytearray_to_usize(&[2, 1, 0, 0, 0, 0, 0, 0]), Ok(258));
+        assert_eq!(bytearray_to_usize(&[2, 1]),
+                   Err(UsizeError::Length(LengthError { expected: 8, actual: 2 })));
+
+        let bv = usize_vec_to_bytevec(&[1, 2, 3]);
+        assert_eq!(bytevec_to_usize_vec(&bv), Ok(vec![1, 2, 3]));
+        assert!(bytevec_to_usize_vec(&bv[1..]).is_err());
+
+        let mut buf = [0; 12];
+        assert!(write_usize_at(&mut buf, 4, 7).is_ok());
+        assert_eq!(read_usize_at(&buf, 4), Ok(7));
+        assert!(read_usize_at(&buf, 5).is_err());
+        assert!(write_u64_at(&mut buf, 4, 1 << 40).is_ok());
+        assert_eq!(read_u64_at(&buf, 4), Ok(1 << 40));
+        assert!(write_u64_at(&mut buf, 5, 1).is_err());
+        // offsets near usize::MAX fail rather than wrap
+        assert!(read_usize_at(&buf, usize::MAX - 3).is_err());
+        assert!(write_usize_at(&mut buf, usize::MAX, 1).is_err());
+        assert!(read_u64_at(&buf, usize::MAX - 3).is_err());
+        assert!(mem_move(&mut buf[..2], b"abc").is_err());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_extend(crc32(b"1234"), b"56789"), crc32(b"123456789"));
+    }
+}
@@ -0,0 +1,388 @@
+//! Checking a table for damage, and repairing it: an fsck.
+//!
+//! `verify` walks every bucket's chain and the free list, reading each
+//! page header as stored, and checks that records fit their pages,
+//! links stay inside the file and in one chain (and, with extended
+//! headers, only reach pages of the chain's own bucket), every key
+//! lives in the bucket it hashes to and only once, and the counts in
+//! the control page add up. In a table of large values (see `blob`), it also walks
+//! the value pages of every record. `repair` then fixes what it found:
+//! unreadable pages are emptied, bad links cut, stray records moved (or
+//! dropped, if their key is already in place), records with broken
+//! values dropped, and the free list and record count rebuilt from what
+//! is left. Records on damaged pages are lost.
+
+use std::collections::HashSet;
+use std::io;
+
+use blob;
+use error;
+use page::PageType;
+use progress::{CancelToken, NoProgress, Progress};
+use LinHash;
+
+/// Something wrong with a table, as found by `verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The page cannot be read: it fails its checksum.
+    Unreadable { page: usize, error: String },
+    /// The page claims more records than fit in it.
+    TooManyRecords { page: usize, num_records: usize, capacity: usize },
+    /// The page links outside the file, or into a chain already
+    /// walked.
+    BadLink { page: usize, next: usize },
+    /// The page is in the chain of `bucket`, but its extended header
+    /// says it is a `kind` page of bucket `owner`: two chains are
+    /// cross-linked, or a link points at a page since reused.
+    ForeignPage { page: usize, bucket: usize, kind: PageType, owner: usize },
+    /// A key in another bucket than the one it hashes to.
+    MisplacedKey { bucket: usize, key: Vec<u8> },
+    /// A key stored more than once in its bucket, in a table without
+    /// duplicate keys.
+    DuplicateKey { bucket: usize, key: Vec<u8> },
+    /// The value pages of the record with `key` are unreadable, or
+    /// their chain is broken.
+    BrokenValue { bucket: usize, key: Vec<u8> },
+    /// The free list is broken at `page`, or, with no page, holds
+    /// another number of pages than the control page says.
+    BadFreeList { page: Option<usize> },
+    /// Pages neither in a bucket nor on the free list.
+    LeakedPages(usize),
+    /// The control page's record count is not the number stored.
+    WrongCount { recorded: usize, counted: usize },
+}
+
+/// What `verify` or `repair` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Bucket pages checked.
+    pub pages: usize,
+    /// Records found in them.
+    pub records: usize,
+    pub problems: Vec<Problem>,
+    /// Whether the problems have been repaired.
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What `repair` has to do, gathered while checking.
+#[derive(Default)]
+struct Fixes {
+    blank: Vec<usize>,
+    trim: Vec<usize>,
+    cut: Vec<usize>,
+    // (bucket, key) to take out
+    remove: Vec<(usize, Vec<u8>)>,
+    // (key, value) to put back where they belong
+    move_back: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Checks `table` for damage. Nothing is changed, so this works on a
+/// table that is read-only after corruption too.
+pub fn verify(table: &mut LinHash) -> error::Result<Report> {
+    verify_with_progress(table, &mut NoProgress)
+}
+
+/// Like `verify`, reporting bucket pages checked out of
+/// `table.pages_in_use()` after each bucket.
+pub fn verify_with_progress<P: Progress>(table: &mut LinHash, progress: &mut P)
+                                         -> error::Result<Report> {
+    verify_cancellable(table, progress, &CancelToken::new())
+}
+
+/// Like `verify_with_progress`, stopping between buckets once
+/// `cancel` is cancelled. Nothing is changed either way.
+pub fn verify_cancellable<P: Progress>(table: &mut LinHash, progress: &mut P,
+                                       cancel: &CancelToken) -> error::Result<Report> {
+    check(table, progress, cancel).map(|(report, _)| report)
+}
+
+/// Like `verify`, then fixes the problems found. The repair is not
+/// journaled: a crash part way through can leave more to repair.
+pub fn repair(table: &mut LinHash) -> error::Result<Report> {
+    let (mut report, fixes) = check(table, &mut NoProgress, &CancelToken::new())?;
+    if report.is_ok() {
+        return Ok(report);
+    }
+    let file = &mut table.buckets;
+    file.clear_corruption();
+    for &page_id in &fixes.blank {
+        file.blank_buffer(page_id)?;
+    }
+    for &page_id in &fixes.trim {
+        // `fetch_page` already trimmed the cached copy
+        file.page_mut(page_id);
+    }
+    for &page_id in &fixes.cut {
+        file.page_mut(page_id).next = None;
+    }
+    for &(bucket, ref key) in &fixes.remove {
+        file.remove_record(bucket, key)?;
+    }
+
+    let mut in_use: HashSet<usize> = file.map_pages().iter().cloned().collect();
+    let mut nitems = 0;
+    for bucket in 0..table.nbuckets {
+        let chain = file.all_records_in_bucket(bucket);
+        if file.extended_headers() {
+            let pages: Vec<usize> = chain.iter().map(|&(page_id, _)| page_id).collect();
+            file.tag_chain(bucket, &pages);
+        }
+        for (page_id, records) in chain {
+            in_use.insert(page_id);
+            nitems += records.len();
+            if file.large_values() {
+                for (_, val) in records {
+                    in_use.extend(blob::value_pages(file, &val)?.unwrap_or_default());
+                }
+            }
+        }
+    }
+    let free = (file.ctrl_pages()..file.num_pages()).filter(|p| !in_use.contains(p)).collect();
+    file.set_free_pages(free)?;
+    table.nitems = nitems;
+    table.misses.clear();
+    for (key, val) in fixes.move_back {
+        if table.try_get(&key)?.is_none() {
+            table.try_put(&key, &val)?;
+        }
+    }
+    table.checkpoint()?;
+    warn!(problems = report.problems.len(); "{}: repaired", table.buckets.path().display());
+    report.repaired = true;
+    Ok(report)
+}
+
+fn check<P: Progress>(table: &mut LinHash, progress: &mut P, cancel: &CancelToken)
+                      -> error::Result<(Report, Fixes)> {
+    let mut report = Report::default();
+    let mut fixes = Fixes::default();
+    let num_pages = table.buckets.num_pages();
+    let total = table.pages_in_use();
+    let mut in_use: HashSet<usize> = table.buckets.map_pages().iter().cloned().collect();
+    // (bucket, key, reference) of every record of large values
+    let mut values = vec![];
+    for bucket in 0..table.nbuckets {
+        cancel.check()?;
+        let mut keys = HashSet::new();
+        let mut prev = None;
+        let mut next = Some(table.buckets.bucket_page(bucket));
+        while let Some(page_id) = next {
+            let (num_records, capacity, link) = match table.buckets.page_header(page_id) {
+                Ok(header) => header,
+                Err(e) => {
+                    in_use.insert(page_id);
+                    let e = unreadable(e)?;
+                    report.problems.push(Problem::Unreadable { page: page_id, error: e });
+                    fixes.blank.push(page_id);
+                    break;
+                },
+            };
+            if table.buckets.extended_headers() {
+                let page = table.buckets.try_page(page_id)?;
+                let kind = if prev.is_some() { PageType::Overflow } else { PageType::Bucket };
+                if (page.kind, page.owner) != (kind, bucket) {
+                    report.problems.push(Problem::ForeignPage {
+                        page: page_id, bucket, kind: page.kind, owner: page.owner });
+                    // the page is left to the chain it belongs to; a
+                    // first page is relabelled instead
+                    if let Some(prev) = prev {
+                        fixes.cut.push(prev);
+                        break;
+                    }
+                }
+            }
+            in_use.insert(page_id);
+            prev = Some(page_id);
+            report.pages += 1;
+            if num_records > capacity {
+                report.problems.push(Problem::TooManyRecords {
+                    page: page_id, num_records, capacity });
+                fixes.trim.push(page_id);
+            }
+            for (key, val) in table.buckets.all_records_in_page(page_id)? {
+                report.records += 1;
+                if table.buckets.large_values() {
+                    values.push((bucket, key.clone(), val.clone()));
+                }
+                if table.bucket(&key) != bucket {
+                    report.problems.push(Problem::MisplacedKey { bucket, key: key.clone() });
+                    fixes.remove.push((bucket, key.clone()));
+                    fixes.move_back.push((key, val));
+                } else if !table.buckets.duplicate_keys() && !keys.insert(key.clone()) {
+                    report.problems.push(Problem::DuplicateKey { bucket, key: key.clone() });
+                    fixes.remove.push((bucket, key));
+                }
+            }
+            next = match link {
+                Some(n) if n >= num_pages || in_use.contains(&n) => {
+                    report.problems.push(Problem::BadLink { page: page_id, next: n });
+                    fixes.cut.push(page_id);
+                    None
+                },
+                link => link,
+            };
+        }
+        progress.report(report.pages, total);
+    }
+
+    // only now that every bucket page is known, as a value must not
+    // share pages with a bucket or another value
+    for (bucket, key, reference) in values {
+        match blob::value_pages(&mut table.buckets, &reference)? {
+            Some(pages) if pages.iter().all(|p| !in_use.contains(p)) => in_use.extend(pages),
+            _ => {
+                report.problems.push(Problem::BrokenValue { bucket, key: key.clone() });
+                fixes.move_back.retain(|(k, _)| *k != key);
+                fixes.remove.push((bucket, key));
+            },
+        }
+    }
+
+    let mut free = HashSet::new();
+    let mut next = table.buckets.free_list_head();
+    let mut broken = false;
+    while let Some(page_id) = next.filter(|&p| p != num_pages) {
+        if page_id > num_pages || in_use.contains(&page_id) || !free.insert(page_id) {
+            report.problems.push(Problem::BadFreeList { page: Some(page_id) });
+            broken = true;
+            break;
+        }
+        next = match table.buckets.page_header(page_id) {
+            Ok((_, _, link)) => link,
+            Err(e) => {
+                unreadable(e)?;
+                report.problems.push(Problem::BadFreeList { page: Some(page_id) });
+                broken = true;
+                break;
+            },
+        };
+    }
+    if !broken && free.len() != table.buckets.num_free() {
+        report.problems.push(Problem::BadFreeList { page: None });
+    }
+    let leaked = (table.buckets.ctrl_pages()..num_pages).filter(|p| !in_use.contains(p) && !free.contains(p)).count();
+    if leaked > 0 {
+        report.problems.push(Problem::LeakedPages(leaked));
+    }
+    if report.records != table.nitems {
+        report.problems.push(Problem::WrongCount {
+            recorded: table.nitems, counted: report.records });
+    }
+    Ok((report, fixes))
+}
+
+/// The description of a page that fails its checksum; other errors
+/// are passed on.
+fn unreadable(e: io::Error) -> error::Result<String> {
+    if e.kind() == io::ErrorKind::InvalidData {
+        Ok(e.to_string())
+    } else {
+        Err(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+    use page::{PageType, PAGE_SIZE};
+    use util::*;
+    use verify::Problem;
+    use LinHash;
+
+    #[test]
+    fn verify_and_repair() {
+        let path = "/tmp/test_verify";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        h.flush();
+        let report = h.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.records, 3000);
+        assert_eq!(report.pages, h.pages_in_use());
+
+        // a key in the wrong bucket
+        let stray = encode(5000);
+        // not one of the buckets damaged below
+        let wrong = (2..h.nbuckets).find(|&b| b != h.bucket(&stray)).unwrap();
+        h.buckets.fill_bucket(wrong, vec![(stray.clone(), encode(5000))]).unwrap();
+        // a link out of the file
+        let root = h.buckets.bucket_page(0);
+        let num_pages = h.buckets.num_pages();
+        let cut_off = h.buckets.page_header(root).unwrap().2;
+        h.buckets.page_mut(root).next = Some(num_pages + 7);
+        h.flush();
+        // a page overwritten on disk
+        let victim = h.buckets.bucket_page(1);
+        let mut f = OpenOptions::new().write(true).open(path).unwrap();
+        f.seek(SeekFrom::Start((victim * PAGE_SIZE + 100) as u64)).unwrap();
+        f.write_all(&[0xff; 16]).unwrap();
+        drop(f);
+
+        let report = h.verify().unwrap();
+        let problems = &report.problems;
+        assert!(problems.contains(&Problem::MisplacedKey { bucket: wrong, key: stray.clone() }));
+        assert!(problems.contains(&Problem::BadLink { page: root, next: num_pages + 7 }));
+        assert!(problems.iter().any(|p| matches!(*p, Problem::Unreadable { page, .. }
+                                                  if page == victim)));
+        assert!(problems.iter().any(|p| matches!(*p, Problem::WrongCount { recorded: 3000, .. })));
+        if cut_off.is_some() {
+            assert!(problems.iter().any(|p| matches!(*p, Problem::LeakedPages(_))));
+        }
+        assert!(!report.repaired);
+
+        let report = h.repair().unwrap();
+        assert!(report.repaired);
+        let clean = h.verify().unwrap();
+        assert!(clean.is_ok(), "{:?}", clean.problems);
+        assert_eq!(h.get(&stray), Some(encode(5000)));
+        let found = (0..3000).filter(|&k| h.get(&encode(k)) == Some(encode(k))).count();
+        assert_eq!(h.len(), found + 1);
+        assert!(found > 0 && found < 3000);
+        h.close();
+
+        let mut h = LinHash::open(path, 4, 4);
+        assert!(h.verify().unwrap().is_ok());
+        assert_eq!(h.len(), found + 1);
+        h.close();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn cross_linked_chains() {
+        let path = "/tmp/test_verify_cross_link";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4);
+        for k in 0..3000 {
+            h.put(&encode(k), &encode(k));
+        }
+        // the last page of bucket 0 links into the chain of another
+        let last = h.buckets.all_records_in_bucket(0).last().unwrap().0;
+        let target = h.buckets.bucket_page(1);
+        h.buckets.page_mut(last).next = Some(target);
+        h.flush();
+
+        let report = h.verify().unwrap();
+        assert!(report.problems.contains(&Problem::ForeignPage {
+            page: target, bucket: 0, kind: PageType::Bucket, owner: 1 }));
+        // the link is cut, and the page stays with its own bucket
+        h.repair().unwrap();
+        let clean = h.verify().unwrap();
+        assert!(clean.is_ok(), "{:?}", clean.problems);
+        for k in 0..3000 {
+            assert_eq!(h.get(&encode(k)), Some(encode(k)));
+        }
+        h.close();
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,351 @@
+//! Write-ahead log of record changes.
+//!
+//! Pages reach the database file only when they are evicted or
+//! flushed, and the control page only on the operations that write
+//! it, so a crash loses whatever changed since. Before `LinHash`
+//! changes a record it appends the change to `<file>.wal`; once a
+//! checkpoint (`flush`, `close`, or the log outgrowing
+//! `LinHash::WAL_CHECKPOINT_BYTES`) has written every page and the
+//! control page, the log is truncated. The next `open` after a crash
+//! replays whatever the log still holds on top of the repaired table.
+//!
+//! Entries are logical: "`key` now maps to `val`", "`key` is gone",
+//! or, in a table with duplicate keys, "`key` now maps to these".
+//! Replaying one is idempotent, and the last entry for a key decides
+//! its value whatever state its page reached before the crash. Splits
+//! are not logged; the split journal (see `journal`) rolls back an
+//! interrupted one, and replay splits again as needed.
+//!
+//! Each entry carries a log sequence number, and in files with page
+//! LSNs every page is stamped with the LSN of the latest change it
+//! holds (see `DbFile::lsn`). Replay skips an entry if every page of
+//! its key's bucket is stamped with its LSN or a later one: the change
+//! reached disk before the crash. So a recovery that is itself
+//! interrupted only redoes what did not reach disk the first time.
+//!
+//! Format of each entry (integers little-endian):
+//!
+//! | op (u8) | key length (u32) | value length (u32) | LSN (u64) | key | value | crc32 |
+//!
+//! Entries written before LSNs existed have none, and no `OP_LSN` bit
+//! in their op; they read as LSN 0, which is never skipped.
+//!
+//! A torn entry at the end fails its checksum; it and anything after
+//! it are ignored, as the change it describes never took place.
+//!
+//! The log of an encrypted table (see `crypt`) holds each entry sealed,
+//! as | sealed length (u32) | sealed entry |.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use crypt::Cipher;
+use disk::sidecar_path;
+use util::*;
+
+const OP_PUT: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const OP_PUT_ALL: u8 = 3;
+/// Set in the op of entries that carry an LSN.
+const OP_LSN: u8 = 0x80;
+const ENTRY_HEADER: usize = 9;
+const LSN_WIDTH: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    /// Insert `key`, or overwrite its value.
+    Put(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    /// Replace every record with `key` by one per value, in order.
+    /// The values are stored as a u32 length and the bytes each.
+    PutAll(Vec<u8>, Vec<Vec<u8>>),
+}
+
+/// When changes are synced to disk, trading throughput for how much a
+/// power loss can take. A crash of the process alone loses nothing
+/// logged, whatever the mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Every change is synced to the log before it is made.
+    Always,
+    /// The log is synced after every `n` changes, so a power loss
+    /// takes at most the last `n - 1`.
+    EveryN(usize),
+    /// The database file is synced when the table is closed (and by
+    /// `LinHash::sync`), and not before.
+    OnClose,
+    /// Nothing is synced but by `LinHash::sync`; the OS writes the
+    /// data back in its own time.
+    Never,
+}
+
+pub fn wal_path<P: AsRef<Path>>(db_path: P) -> PathBuf {
+    sidecar_path(db_path.as_ref(), ".wal")
+}
+
+fn join_values(vals: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = vec![];
+    for v in vals {
+        buf.extend_from_slice(&(v.len() as u32).to_le_bytes());
+        buf.extend_from_slice(v);
+    }
+    buf
+}
+
+fn split_values(mut buf: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut vals = vec![];
+    while !buf.is_empty() {
+        let len = decode::<u32>(buf.get(..4)?) as usize;
+        vals.push(buf.get(4..4 + len)?.to_vec());
+        buf = &buf[4 + len..];
+    }
+    Some(vals)
+}
+
+/// The entries of every sealed frame in `buf`, up to the first torn
+/// one.
+fn unseal_frames(cipher: &Cipher, mut buf: &[u8]) -> Vec<u8> {
+    let mut plain = vec![];
+    while buf.len() >= 4 {
+        let len = decode::<u32>(buf) as usize;
+        match buf.get(4..4 + len).and_then(|sealed| cipher.open(b"wal", sealed)) {
+            Some(entry) => plain.extend_from_slice(&entry),
+            None => break,
+        }
+        buf = &buf[4 + len..];
+    }
+    plain
+}
+
+/// An open log file.
+pub struct Wal {
+    file: File,
+    len: u64,
+    durability: Durability,
+    // entries appended since the last sync
+    unsynced: usize,
+    cipher: Option<Cipher>,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path`, keeping its contents.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Wal> {
+        Wal::open_sealed(path, None)
+    }
+
+    /// Like `open`, for the log of an encrypted table, whose entries
+    /// are sealed with `cipher`.
+    pub fn open_sealed<P: AsRef<Path>>(path: P, cipher: Option<Cipher>) -> io::Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Wal { file, len, durability: Durability::Never, unsynced: 0, cipher })
+    }
+
+    /// How often `append` syncs the log; see `Durability`.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Whether the log is ever synced, in which case the database file
+    /// has to be synced before the log is cleared.
+    pub fn sync(&self) -> bool {
+        match self.durability {
+            Durability::Always | Durability::EveryN(_) => true,
+            Durability::OnClose | Durability::Never => false,
+        }
+    }
+
+    /// Entries appended since the log was last synced.
+    pub fn unsynced(&self) -> usize {
+        self.unsynced
+    }
+
+    /// Size of the log in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Every complete entry, oldest first, with its LSN.
+    pub fn entries(&mut self) -> io::Result<Vec<(u64, Entry)>> {
+        let mut buf = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+        if let Some(ref cipher) = self.cipher {
+            buf = unseal_frames(cipher, &buf);
+        }
+
+        let mut entries = vec![];
+        let mut rest = &buf[..];
+        while rest.len() >= ENTRY_HEADER {
+            let lsns = rest[0] & OP_LSN != 0;
+            let header = if lsns { ENTRY_HEADER + LSN_WIDTH } else { ENTRY_HEADER };
+            let key_len = decode::<u32>(&rest[1..5]) as usize;
+            let val_len = decode::<u32>(&rest[5..9]) as usize;
+            let body_len = header + key_len + val_len;
+            if rest.len() < body_len + 4
+                || crc32(&rest[..body_len]) != decode::<u32>(&rest[body_len..]) {
+                break;
+            }
+            let lsn = if lsns { decode::<u64>(&rest[ENTRY_HEADER..]) } else { 0 };
+            let key = rest[header..header + key_len].to_vec();
+            let val = rest[header + key_len..body_len].to_vec();
+            entries.push((lsn, match rest[0] & !OP_LSN {
+                OP_PUT => Entry::Put(key, val),
+                OP_REMOVE => Entry::Remove(key),
+                OP_PUT_ALL => match split_values(&val) {
+                    Some(vals) => Entry::PutAll(key, vals),
+                    None => break,
+                },
+                _ => break,
+            }));
+            rest = &rest[body_len + 4..];
+        }
+        Ok(entries)
+    }
+
+    /// Appends `entry`, the change with LSN `lsn`, returning the length
+    /// of the log before it, for `truncate`.
+    pub fn append(&mut self, lsn: u64, entry: &Entry) -> io::Result<u64> {
+        let joined;
+        let (op, key, val): (u8, &[u8], &[u8]) = match *entry {
+            Entry::Put(ref k, ref v) => (OP_PUT, k, v),
+            Entry::Remove(ref k) => (OP_REMOVE, k, &[]),
+            Entry::PutAll(ref k, ref vals) => {
+                joined = join_values(vals);
+                (OP_PUT_ALL, k, &joined)
+            },
+        };
+        let mut buf = Vec::with_capacity(ENTRY_HEADER + LSN_WIDTH + key.len() + val.len() + 4);
+        buf.push(op | OP_LSN);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&lsn.to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(val);
+        let crc = crc32(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        if let Some(ref cipher) = self.cipher {
+            let sealed = cipher.seal(b"wal", &buf);
+            buf = (sealed.len() as u32).to_le_bytes().to_vec();
+            buf.extend_from_slice(&sealed);
+        }
+
+        let start = self.len;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        self.unsynced += 1;
+        match self.durability {
+            Durability::Always => self.sync_data()?,
+            Durability::EveryN(n) if self.unsynced >= n => self.sync_data()?,
+            _ => (),
+        }
+        Ok(start)
+    }
+
+    /// Drops every entry from byte `len` on, eg. one whose change
+    /// failed and was undone.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        if self.sync() {
+            self.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Empties the log once its changes are all in the database file.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.truncate(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn wal_roundtrip() {
+        let path = "/tmp/test_wal";
+        fs::remove_file(path).ok();
+        let mut wal = Wal::open(path).unwrap();
+        let a = Entry::Put(b"a".to_vec(), b"1".to_vec());
+        let b = Entry::Remove(b"b".to_vec());
+        let c = Entry::PutAll(b"c".to_vec(), vec![b"1".to_vec(), vec![], b"22".to_vec()]);
+        wal.append(1, &a).unwrap();
+        wal.append(2, &c).unwrap();
+        let end = wal.append(3, &b).unwrap();
+        wal.append(4, &a).unwrap();
+        wal.truncate(end + 1).unwrap();
+        drop(wal);
+
+        // a torn last entry is ignored
+        let mut wal = Wal::open(path).unwrap();
+        assert_eq!(wal.entries().unwrap(), vec![(1, a.clone()), (2, c.clone())]);
+        wal.truncate(end).unwrap();
+        wal.append(5, &b).unwrap();
+        OpenOptions::new().append(true).open(path).unwrap()
+            .write_all(&[OP_PUT, 200, 0, 0, 0]).unwrap();
+        assert_eq!(Wal::open(path).unwrap().entries().unwrap(),
+                   vec![(1, a.clone()), (2, c), (5, b)]);
+
+        // entries from before LSNs read as LSN 0
+        wal.clear().unwrap();
+        let mut old = vec![OP_PUT, 1, 0, 0, 0, 1, 0, 0, 0, b'a', b'1'];
+        let crc = crc32(&old);
+        old.extend_from_slice(&crc.to_le_bytes());
+        OpenOptions::new().append(true).open(path).unwrap().write_all(&old).unwrap();
+        assert_eq!(Wal::open(path).unwrap().entries().unwrap(), vec![(0, a)]);
+
+        wal.clear().unwrap();
+        assert!(wal.entries().unwrap().is_empty());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn durability() {
+        let path = "/tmp/test_wal_durability";
+        fs::remove_file(path).ok();
+        let mut wal = Wal::open(path).unwrap();
+        let a = Entry::Put(b"a".to_vec(), b"1".to_vec());
+        wal.set_durability(Durability::EveryN(3));
+        for i in 1..8 {
+            wal.append(1, &a).unwrap();
+            assert_eq!(wal.unsynced(), i % 3);
+        }
+        wal.set_durability(Durability::Always);
+        wal.append(1, &a).unwrap();
+        assert_eq!(wal.unsynced(), 0);
+        wal.set_durability(Durability::OnClose);
+        wal.append(1, &a).unwrap();
+        wal.append(1, &a).unwrap();
+        assert_eq!(wal.unsynced(), 2);
+        assert!(!wal.sync());
+        fs::remove_file(path).ok();
+    }
+}